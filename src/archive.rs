@@ -0,0 +1,247 @@
+// === Archive Browsing ===
+// Read-only access to ZIP and tar archives without extracting them.
+// Members are addressed as `<archive>!/<inner path>`, e.g. `logs/bundle.zip!/var/log/app.log`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+use crate::FileEntry;
+
+pub const ARCHIVE_SEPARATOR: &str = "!/";
+
+#[derive(Clone, Copy)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Split `dir/bundle.zip!/inner/dir` into (`dir/bundle.zip`, `inner/dir`).
+/// Returns None unless the outer part names a supported archive, so folders
+/// that merely contain `!` in their name keep working.
+pub fn split_archive_path(path: &str) -> Option<(&str, &str)> {
+    let (outer, inner) = match path.split_once(ARCHIVE_SEPARATOR) {
+        Some(parts) => parts,
+        None => (path.strip_suffix('!')?, ""),
+    };
+    ArchiveKind::from_name(outer)?;
+    Some((outer, inner))
+}
+
+/// Normalize a member name so `./a/b/`, `/a/b` and `a/b` compare equal.
+fn normalize_member(name: &str) -> String {
+    name.split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+struct Member {
+    path: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn open_tar(archive: &Path, kind: ArchiveKind) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match kind {
+        ArchiveKind::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+        _ => Box::new(file),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Convert a ZIP (MS-DOS) timestamp to unix seconds.
+fn zip_time_to_unix(dt: zip::DateTime) -> u64 {
+    let days = crate::days_from_civil(dt.year() as i64, dt.month() as i64, dt.day() as i64);
+    let secs = days * 86400
+        + dt.hour() as i64 * 3600
+        + dt.minute() as i64 * 60
+        + dt.second() as i64;
+    secs.max(0) as u64
+}
+
+fn read_members(archive: &Path, kind: ArchiveKind) -> io::Result<Vec<Member>> {
+    let mut members = Vec::new();
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))
+                .map_err(invalid_data)?;
+            for i in 0..zip.len() {
+                let file = zip.by_index_raw(i).map_err(invalid_data)?;
+                members.push(Member {
+                    path: normalize_member(file.name()),
+                    is_dir: file.is_dir(),
+                    size: file.size(),
+                    modified: file.last_modified().map(zip_time_to_unix).unwrap_or(0),
+                });
+            }
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = open_tar(archive, kind)?;
+            for entry in tar.entries()? {
+                let entry = entry?;
+                let header = entry.header();
+                members.push(Member {
+                    path: normalize_member(&entry.path()?.to_string_lossy()),
+                    is_dir: header.entry_type().is_dir(),
+                    size: header.size().unwrap_or(0),
+                    modified: header.mtime().unwrap_or(0),
+                });
+            }
+        }
+    }
+    Ok(members)
+}
+
+/// List the direct children of `inner_dir` inside an archive.
+/// Intermediate directories that have no explicit entry are synthesized.
+pub fn list_entries(archive: &Path, inner_dir: &str) -> io::Result<Vec<FileEntry>> {
+    let kind = archive
+        .to_str()
+        .and_then(ArchiveKind::from_name)
+        .ok_or_else(|| invalid_data("Unsupported archive type"))?;
+    let inner_dir = normalize_member(inner_dir);
+    let prefix = if inner_dir.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", inner_dir)
+    };
+
+    let mut children: BTreeMap<String, FileEntry> = BTreeMap::new();
+    let mut dir_found = inner_dir.is_empty();
+
+    for member in read_members(archive, kind)? {
+        if member.path == inner_dir && member.is_dir {
+            dir_found = true;
+            continue;
+        }
+        let Some(rest) = member.path.strip_prefix(&prefix) else {
+            continue;
+        };
+        if rest.is_empty() {
+            continue;
+        }
+        dir_found = true;
+
+        match rest.split_once('/') {
+            Some((dir_name, _)) => {
+                children
+                    .entry(dir_name.to_string())
+                    .or_insert_with(|| FileEntry {
+                        name: dir_name.to_string(),
+                        is_dir: true,
+                        size: 0,
+                        modified: 0,
+                        ..Default::default()
+                    });
+            }
+            None => {
+                children.insert(
+                    rest.to_string(),
+                    FileEntry {
+                        name: rest.to_string(),
+                        is_dir: member.is_dir,
+                        size: if member.is_dir { 0 } else { member.size },
+                        modified: member.modified,
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    }
+
+    if !dir_found {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "Folder not found in archive",
+        ));
+    }
+
+    Ok(children.into_values().collect())
+}
+
+/// Largest member `read_entry` will hold in memory.
+pub const MAX_ENTRY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Hand `f` a reader over a single file in an archive. `f` is only called once the
+/// member has been found. Sizes recorded in the archive aren't trusted, so readers
+/// should bound what they read.
+pub fn with_entry<T>(
+    archive: &Path,
+    inner_path: &str,
+    f: impl FnOnce(&mut dyn Read) -> io::Result<T>,
+) -> io::Result<T> {
+    let kind = archive
+        .to_str()
+        .and_then(ArchiveKind::from_name)
+        .ok_or_else(|| invalid_data("Unsupported archive type"))?;
+    let inner_path = normalize_member(inner_path);
+    let not_found = || io::Error::new(io::ErrorKind::NotFound, "File not found in archive");
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?))
+                .map_err(invalid_data)?;
+            let index = (0..zip.len())
+                .find(|&i| {
+                    zip.name_for_index(i)
+                        .map(|name| normalize_member(name) == inner_path)
+                        .unwrap_or(false)
+                })
+                .ok_or_else(not_found)?;
+            let mut file = zip.by_index(index).map_err(invalid_data)?;
+            if file.is_dir() {
+                return Err(not_found());
+            }
+            f(&mut file)
+        }
+        ArchiveKind::Tar | ArchiveKind::TarGz => {
+            let mut tar = open_tar(archive, kind)?;
+            for entry in tar.entries()? {
+                let mut entry = entry?;
+                if entry.header().entry_type().is_dir() {
+                    continue;
+                }
+                if normalize_member(&entry.path()?.to_string_lossy()) == inner_path {
+                    return f(&mut entry);
+                }
+            }
+            Err(not_found())
+        }
+    }
+}
+
+/// Read a single file out of an archive, up to `MAX_ENTRY_BYTES`.
+pub fn read_entry(archive: &Path, inner_path: &str) -> io::Result<Vec<u8>> {
+    with_entry(archive, inner_path, |reader| {
+        let mut content = Vec::new();
+        reader.take(MAX_ENTRY_BYTES + 1).read_to_end(&mut content)?;
+        if content.len() as u64 > MAX_ENTRY_BYTES {
+            return Err(invalid_data("File in archive is too large to open here"));
+        }
+        Ok(content)
+    })
+}
@@ -0,0 +1,323 @@
+// === Full-text Content Index ===
+// Tantivy index over extracted file text, stored under `.boxy/index`.
+// Handlers only queue changed paths; a background thread owns the writer,
+// re-indexes those paths and commits, so no request waits on indexing.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::Serialize;
+use tantivy::collector::{DocSetCollector, TopDocs};
+use tantivy::directory::MmapDirectory;
+use tantivy::query::{AllQuery, QueryParser};
+use tantivy::schema::{Field, Schema, Value, FAST, STORED, STRING, TEXT};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::{Index, IndexReader, IndexWriter, ReloadPolicy, TantivyDocument, Term};
+
+use crate::extract;
+
+const INDEX_DIR: &str = ".boxy/index";
+const WRITER_MEMORY_BYTES: usize = 50_000_000;
+/// Changes arriving within this window are committed together.
+const COMMIT_DELAY: Duration = Duration::from_millis(500);
+const SNIPPET_MAX_CHARS: usize = 240;
+
+#[derive(Clone, Copy)]
+struct Fields {
+    /// Path relative to the upload root, the unique key of a document
+    path: Field,
+    /// Every ancestor folder of `path`, so a folder's documents can be dropped at once
+    parents: Field,
+    name: Field,
+    body: Field,
+    size: Field,
+    modified: Field,
+}
+
+impl Fields {
+    fn schema() -> (Schema, Self) {
+        let mut builder = Schema::builder();
+        let fields = Fields {
+            path: builder.add_text_field("path", STRING | STORED),
+            parents: builder.add_text_field("parents", STRING),
+            name: builder.add_text_field("name", TEXT | STORED),
+            body: builder.add_text_field("body", TEXT | STORED),
+            size: builder.add_u64_field("size", STORED),
+            modified: builder.add_u64_field("modified", STORED | FAST),
+        };
+        (builder.build(), fields)
+    }
+}
+
+#[derive(Serialize)]
+pub struct ContentHit {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub modified: u64,
+    pub score: f32,
+    /// HTML fragment with matches wrapped in `<b>`; the text itself is escaped
+    pub snippet: String,
+}
+
+#[derive(Clone)]
+pub struct ContentIndex {
+    index: Index,
+    reader: IndexReader,
+    fields: Fields,
+    queue: mpsc::Sender<String>,
+}
+
+fn open_index(dir: &Path, schema: Schema) -> tantivy::Result<Index> {
+    fs::create_dir_all(dir)?;
+    let directory = MmapDirectory::open(dir)?;
+    Index::open_or_create(directory, schema)
+}
+
+impl ContentIndex {
+    /// Open (or create) the index for `upload_dir` and start the indexer thread,
+    /// which first reconciles the index with what is on disk.
+    pub fn open(upload_dir: &Path) -> tantivy::Result<Self> {
+        let dir = upload_dir.join(INDEX_DIR);
+        let (schema, fields) = Fields::schema();
+
+        // A schema change from an older version means the index has to be rebuilt
+        let index = match open_index(&dir, schema.clone()) {
+            Ok(index) => index,
+            Err(_) => {
+                let _ = fs::remove_dir_all(&dir);
+                open_index(&dir, schema)?
+            }
+        };
+
+        let reader = index
+            .reader_builder()
+            .reload_policy(ReloadPolicy::OnCommitWithDelay)
+            .try_into()?;
+        let writer = index.writer_with_num_threads(1, WRITER_MEMORY_BYTES)?;
+
+        let (queue, jobs) = mpsc::channel();
+        let content_index = ContentIndex {
+            index,
+            reader,
+            fields,
+            queue,
+        };
+
+        let indexer = Indexer {
+            upload_dir: upload_dir.to_path_buf(),
+            fields,
+            writer,
+            reader: content_index.reader.clone(),
+        };
+        std::thread::Builder::new()
+            .name("content-indexer".to_string())
+            .spawn(move || indexer.run(jobs))?;
+
+        Ok(content_index)
+    }
+
+    /// Queue a path (file or folder, relative to the upload root) for re-indexing.
+    /// Works for additions, edits and removals alike.
+    pub fn refresh(&self, rel_path: &str) {
+        let _ = self.queue.send(rel_path.to_string());
+    }
+
+    pub fn search(&self, query: &str, limit: usize) -> tantivy::Result<Vec<ContentHit>> {
+        let searcher = self.reader.searcher();
+        let mut parser = QueryParser::for_index(&self.index, vec![self.fields.name, self.fields.body]);
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(self.fields.name, 2.0);
+        let (query, _errors) = parser.parse_query_lenient(query);
+
+        let mut snippets = SnippetGenerator::create(&searcher, &*query, self.fields.body)?;
+        snippets.set_max_num_chars(SNIPPET_MAX_CHARS);
+
+        let top_docs = searcher.search(&query, &TopDocs::with_limit(limit))?;
+        let mut hits = Vec::with_capacity(top_docs.len());
+        for (score, address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let text = |field: Field| {
+                doc.get_first(field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let number = |field: Field| doc.get_first(field).and_then(|v| v.as_u64()).unwrap_or(0);
+            hits.push(ContentHit {
+                name: text(self.fields.name),
+                path: text(self.fields.path),
+                size: number(self.fields.size),
+                modified: number(self.fields.modified),
+                score,
+                snippet: snippets.snippet_from_doc(&doc).to_html(),
+            });
+        }
+        Ok(hits)
+    }
+}
+
+struct Indexer {
+    upload_dir: PathBuf,
+    fields: Fields,
+    writer: IndexWriter,
+    reader: IndexReader,
+}
+
+fn modified_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Indexer {
+    fn run(mut self, jobs: mpsc::Receiver<String>) {
+        if let Err(e) = self.sync_all() {
+            eprintln!("Content index: initial sync failed: {}", e);
+        }
+
+        while let Ok(first) = jobs.recv() {
+            // Collect a burst of changes (e.g. a folder upload) into one commit
+            let mut pending: HashSet<String> = HashSet::from([first]);
+            while let Ok(path) = jobs.recv_timeout(COMMIT_DELAY) {
+                pending.insert(path);
+            }
+
+            for rel_path in &pending {
+                self.refresh(rel_path);
+            }
+            if let Err(e) = self.writer.commit() {
+                eprintln!("Content index: commit failed: {}", e);
+            }
+        }
+    }
+
+    fn refresh(&mut self, rel_path: &str) {
+        if rel_path.is_empty() {
+            if let Err(e) = self.sync_all() {
+                eprintln!("Content index: sync failed: {}", e);
+            }
+            return;
+        }
+
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.path, rel_path));
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.parents, rel_path));
+
+        let mut files = Vec::new();
+        collect_files(&self.upload_dir, rel_path, &mut files);
+        for (rel, meta) in files {
+            self.add_file(&rel, &meta);
+        }
+    }
+
+    /// Reconcile the whole index with the upload tree, touching only what changed.
+    fn sync_all(&mut self) -> tantivy::Result<()> {
+        self.reader.reload()?;
+        let searcher = self.reader.searcher();
+        let mut indexed: HashMap<String, u64> = HashMap::new();
+        for address in searcher.search(&AllQuery, &DocSetCollector)? {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            if let Some(path) = doc.get_first(self.fields.path).and_then(|v| v.as_str()) {
+                let modified = doc
+                    .get_first(self.fields.modified)
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(0);
+                indexed.insert(path.to_string(), modified);
+            }
+        }
+
+        let mut files = Vec::new();
+        collect_files(&self.upload_dir, "", &mut files);
+
+        for (rel, meta) in files {
+            match indexed.remove(&rel) {
+                Some(modified) if modified == modified_secs(&meta) => {}
+                Some(_) => {
+                    self.writer
+                        .delete_term(Term::from_field_text(self.fields.path, &rel));
+                    self.add_file(&rel, &meta);
+                }
+                None => self.add_file(&rel, &meta),
+            }
+        }
+
+        // Whatever is left was removed while the server was down
+        for rel in indexed.keys() {
+            self.writer
+                .delete_term(Term::from_field_text(self.fields.path, rel));
+        }
+
+        self.writer.commit()?;
+        Ok(())
+    }
+
+    fn add_file(&mut self, rel_path: &str, meta: &fs::Metadata) {
+        let abs_path = self.upload_dir.join(rel_path);
+        let name = abs_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        // Unreadable documents are still findable by name
+        let body = if meta.len() <= extract::MAX_EXTRACT_FILE_BYTES {
+            extract::extract_text(&abs_path).unwrap_or_default()
+        } else {
+            String::new()
+        };
+
+        let mut doc = TantivyDocument::default();
+        doc.add_text(self.fields.path, rel_path);
+        let mut parent = String::new();
+        for segment in rel_path.split('/').take(rel_path.matches('/').count()) {
+            if !parent.is_empty() {
+                parent.push('/');
+            }
+            parent.push_str(segment);
+            doc.add_text(self.fields.parents, &parent);
+        }
+        doc.add_text(self.fields.name, &name);
+        doc.add_text(self.fields.body, &body);
+        doc.add_u64(self.fields.size, meta.len());
+        doc.add_u64(self.fields.modified, modified_secs(meta));
+
+        if let Err(e) = self.writer.add_document(doc) {
+            eprintln!("Content index: failed to add {}: {}", rel_path, e);
+        }
+    }
+}
+
+/// Collect indexable files at or below `rel_path`, skipping the data directory and symlinks.
+fn collect_files(upload_dir: &Path, rel_path: &str, files: &mut Vec<(String, fs::Metadata)>) {
+    if rel_path == crate::DATA_DIR || rel_path.starts_with(&format!("{}/", crate::DATA_DIR)) {
+        return;
+    }
+
+    let abs_path = upload_dir.join(rel_path);
+    let Ok(meta) = fs::symlink_metadata(&abs_path) else {
+        return;
+    };
+
+    if meta.is_dir() {
+        let Ok(entries) = fs::read_dir(&abs_path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let child = if rel_path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", rel_path, name)
+            };
+            collect_files(upload_dir, &child, files);
+        }
+    } else if meta.is_file() && extract::is_extractable(&abs_path) {
+        files.push((rel_path.to_string(), meta));
+    }
+}
@@ -0,0 +1,89 @@
+// === Recursive Copy ===
+// Blocking tree copy used by `/api/copy`. Runs inside `web::block`, so it uses std::fs.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Minimum time between progress callbacks for large trees.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Default, serde::Serialize)]
+pub struct CopyStats {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Count the files and bytes under `src` so progress can be reported as a fraction.
+/// Symlinks are skipped, matching `copy_tree`.
+pub fn measure_tree(src: &Path) -> io::Result<CopyStats> {
+    let meta = fs::symlink_metadata(src)?;
+    let mut stats = CopyStats::default();
+    if meta.is_dir() {
+        for entry in fs::read_dir(src)? {
+            let child = measure_tree(&entry?.path())?;
+            stats.files += child.files;
+            stats.bytes += child.bytes;
+        }
+    } else if meta.is_file() {
+        stats.files = 1;
+        stats.bytes = meta.len();
+    }
+    Ok(stats)
+}
+
+/// Copy a file or folder tree from `src` to `dst`, preserving modification times.
+/// Files are reflinked (copy-on-write) where the filesystem supports it.
+/// Symlinks are skipped so a copy can never pull in content from outside the upload root.
+pub fn copy_tree(
+    src: &Path,
+    dst: &Path,
+    on_progress: &mut dyn FnMut(CopyStats),
+) -> io::Result<CopyStats> {
+    let mut stats = CopyStats::default();
+    let mut last_progress = Instant::now();
+    copy_recursive(src, dst, &mut stats, &mut last_progress, on_progress)?;
+    Ok(stats)
+}
+
+fn copy_recursive(
+    src: &Path,
+    dst: &Path,
+    stats: &mut CopyStats,
+    last_progress: &mut Instant,
+    on_progress: &mut dyn FnMut(CopyStats),
+) -> io::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+
+    if meta.is_dir() {
+        fs::create_dir_all(dst)?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            copy_recursive(
+                &entry.path(),
+                &dst.join(entry.file_name()),
+                stats,
+                last_progress,
+                on_progress,
+            )?;
+        }
+    } else if meta.is_file() {
+        reflink_copy::reflink_or_copy(src, dst)?;
+        stats.files += 1;
+        stats.bytes += meta.len();
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            *last_progress = Instant::now();
+            on_progress(*stats);
+        }
+    } else {
+        return Ok(());
+    }
+
+    // Directory mtimes are set last, after their contents stop changing
+    let mtime = filetime::FileTime::from_last_modification_time(&meta);
+    let _ = filetime::set_file_mtime(dst, mtime);
+
+    Ok(())
+}
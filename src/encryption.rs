@@ -0,0 +1,520 @@
+// === Encryption at Rest ===
+// With `BOX_ENCRYPTION_KEY_FILE` set, each root's storage is wrapped in `Encrypted`,
+// so everything the core file API writes (uploads, edits, new files, copies) is stored
+// encrypted and decrypted again as it is read. Files use the STREAM construction over
+// XChaCha20-Poly1305: a header naming the key and a random nonce prefix, then 64 KiB
+// chunks sealed with the prefix, their index and a last-chunk flag, so truncating or
+// reordering chunks fails to decrypt. Ranges are served by decrypting only the chunks
+// they touch.
+//
+// The key file lists keys by id and the last one encrypts new files; the others are
+// only kept for reading. At startup, files under an older key (or not encrypted yet)
+// are re-encrypted with the current one before the server accepts requests, after
+// which the old keys can be removed from the file.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use futures_util::StreamExt;
+
+use crate::storage::{self, ByteStream, Entry, Storage};
+
+const MAGIC: &[u8; 8] = b"BOXYENC1";
+/// Random part of each chunk's nonce; the rest is the chunk index and last flag.
+const NONCE_PREFIX: usize = 19;
+const HEADER_LEN: u64 = (MAGIC.len() + 4 + NONCE_PREFIX) as u64;
+const CHUNK: u64 = 64 * 1024;
+const TAG: u64 = 16;
+const SEALED_CHUNK: u64 = CHUNK + TAG;
+/// Records the key every file was last brought to, so startup can skip the walk.
+const MARKER: &str = ".boxy/encryption";
+
+// --- Keys ---
+
+pub struct Keyring {
+    /// In file order; the last one encrypts
+    keys: Vec<(u32, XChaCha20Poly1305)>,
+}
+
+impl Keyring {
+    /// Read the key file: one key per line as `<id> <64 hex digits>`, with `#`
+    /// comments. A missing file is created with a new key.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Self::create(path).map_err(|e| format!("{}: {}", path.display(), e))?
+            }
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        let mut keys: Vec<(u32, XChaCha20Poly1305)> = Vec::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (id, key) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| format!("expected `<id> <key>` in {}", path.display()))?;
+            let id: u32 = id
+                .parse()
+                .map_err(|_| format!("key id is not a number: {}", id))?;
+            let key = hex::decode(key.trim())
+                .ok()
+                .filter(|key| key.len() == 32)
+                .ok_or_else(|| format!("key {} is not 64 hex digits", id))?;
+            if keys.iter().any(|(other, _)| *other == id) {
+                return Err(format!("duplicate key id {}", id));
+            }
+            let cipher = XChaCha20Poly1305::new_from_slice(&key)
+                .map_err(|_| format!("invalid key {}", id))?;
+            keys.push((id, cipher));
+        }
+        if keys.is_empty() {
+            return Err(format!("no keys in {}", path.display()));
+        }
+        Ok(Self { keys })
+    }
+
+    /// Write a key file with one new key, readable by the owner only.
+    fn create(path: &Path) -> io::Result<String> {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        let text = format!(
+            "# Boxy encryption keys: `<id> <key>`, the last one encrypts new files.\n\
+             # To rotate, append a key with a higher id and restart.\n\
+             1 {}\n",
+            hex::encode(key)
+        );
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options.open(path)?.write_all(text.as_bytes())?;
+        println!("Encryption: created key file {}", path.display());
+        Ok(text)
+    }
+
+    fn current(&self) -> (u32, &XChaCha20Poly1305) {
+        let (id, cipher) = self.keys.last().expect("a keyring has keys");
+        (*id, cipher)
+    }
+
+    fn get(&self, id: u32) -> Option<&XChaCha20Poly1305> {
+        self.keys
+            .iter()
+            .find(|(other, _)| *other == id)
+            .map(|(_, cipher)| cipher)
+    }
+}
+
+// --- Format ---
+
+/// STREAM nonce (BE32 layout): the file's prefix, the chunk index and the last flag.
+fn nonce(prefix: &[u8; NONCE_PREFIX], index: u32, last: bool) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..NONCE_PREFIX].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX..NONCE_PREFIX + 4].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_PREFIX + 4] = u8::from(last);
+    nonce
+}
+
+/// Chunks in an encrypted file of `size` bytes; even an empty file has one.
+fn chunk_count(size: u64) -> u64 {
+    size.saturating_sub(HEADER_LEN)
+        .div_ceil(SEALED_CHUNK)
+        .max(1)
+}
+
+/// Plaintext size of an encrypted file of `size` bytes.
+fn plain_size(size: u64) -> u64 {
+    size.saturating_sub(HEADER_LEN)
+        .saturating_sub(chunk_count(size) * TAG)
+}
+
+/// Plaintext size of a file stored as `size` bytes, for indexes that read sizes off the
+/// disk without checking headers: files too short to carry one are taken as they are.
+pub fn listed_size(size: u64) -> u64 {
+    if size >= HEADER_LEN + TAG {
+        plain_size(size)
+    } else {
+        size
+    }
+}
+
+fn chunk_index(index: u64) -> io::Result<u32> {
+    u32::try_from(index).map_err(|_| io::Error::other("File too large to encrypt"))
+}
+
+fn decryption_failed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Decryption failed: wrong key or damaged file",
+    )
+}
+
+struct Header {
+    key_id: u32,
+    prefix: [u8; NONCE_PREFIX],
+}
+
+impl Header {
+    fn parse(data: &[u8]) -> Option<Self> {
+        let rest = data.strip_prefix(MAGIC)?;
+        Some(Header {
+            key_id: u32::from_be_bytes(rest.get(..4)?.try_into().ok()?),
+            prefix: rest.get(4..4 + NONCE_PREFIX)?.try_into().ok()?,
+        })
+    }
+
+    fn bytes(&self) -> Bytes {
+        let mut data = Vec::with_capacity(HEADER_LEN as usize);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&self.key_id.to_be_bytes());
+        data.extend_from_slice(&self.prefix);
+        Bytes::from(data)
+    }
+}
+
+/// Encrypt `body` into the header and sealed chunks. A chunk is only sealed once more
+/// data follows it or the body ends, since the last one is sealed differently.
+fn seal(cipher: XChaCha20Poly1305, header: Header, body: ByteStream) -> ByteStream {
+    struct Sealing {
+        body: ByteStream,
+        cipher: XChaCha20Poly1305,
+        prefix: [u8; NONCE_PREFIX],
+        buf: Vec<u8>,
+        index: u64,
+        ended: bool,
+        done: bool,
+    }
+
+    let head = storage::once(header.bytes());
+    let state = Sealing {
+        body,
+        cipher,
+        prefix: header.prefix,
+        buf: Vec::new(),
+        index: 0,
+        ended: false,
+        done: false,
+    };
+    let chunks = futures_util::stream::unfold(state, |mut s| async move {
+        loop {
+            if s.done {
+                return None;
+            }
+            let last = s.ended && s.buf.len() as u64 <= CHUNK;
+            if s.buf.len() as u64 > CHUNK || last {
+                let take = s.buf.len().min(CHUNK as usize);
+                let plain: Vec<u8> = s.buf.drain(..take).collect();
+                let sealed = chunk_index(s.index).and_then(|index| {
+                    s.cipher
+                        .encrypt(&nonce(&s.prefix, index, last), plain.as_slice())
+                        .map_err(|_| io::Error::other("Encryption failed"))
+                });
+                s.index += 1;
+                s.done = last || sealed.is_err();
+                return Some((sealed.map(Bytes::from), s));
+            }
+            match s.body.next().await {
+                Some(Ok(data)) => s.buf.extend_from_slice(&data),
+                Some(Err(e)) => {
+                    s.done = true;
+                    return Some((Err(e), s));
+                }
+                None => s.ended = true,
+            }
+        }
+    });
+    Box::pin(head.chain(chunks))
+}
+
+/// Decrypt sealed chunks from `first` on, of a file with `count` chunks, dropping
+/// `skip` bytes from the start and stopping after `length` bytes.
+fn open(
+    cipher: XChaCha20Poly1305,
+    header: Header,
+    body: ByteStream,
+    (first, count): (u64, u64),
+    skip: usize,
+    length: u64,
+) -> ByteStream {
+    struct Opening {
+        body: ByteStream,
+        cipher: XChaCha20Poly1305,
+        prefix: [u8; NONCE_PREFIX],
+        buf: Vec<u8>,
+        index: u64,
+        count: u64,
+        skip: usize,
+        remaining: u64,
+        ended: bool,
+    }
+
+    let state = Opening {
+        body,
+        cipher,
+        prefix: header.prefix,
+        buf: Vec::new(),
+        index: first,
+        count,
+        skip,
+        remaining: length,
+        ended: false,
+    };
+    Box::pin(futures_util::stream::unfold(state, |mut s| async move {
+        while s.remaining > 0 {
+            if (s.buf.len() as u64) < SEALED_CHUNK && !s.ended {
+                match s.body.next().await {
+                    Some(Ok(data)) => s.buf.extend_from_slice(&data),
+                    Some(Err(e)) => {
+                        s.remaining = 0;
+                        return Some((Err(e), s));
+                    }
+                    None => s.ended = true,
+                }
+                continue;
+            }
+            // Past the last chunk there is nothing left to read
+            let take = s.buf.len().min(SEALED_CHUNK as usize);
+            if s.index >= s.count || take < TAG as usize {
+                s.remaining = 0;
+                return Some((Err(decryption_failed()), s));
+            }
+            let sealed: Vec<u8> = s.buf.drain(..take).collect();
+            let last = s.index + 1 == s.count;
+            let plain = chunk_index(s.index).and_then(|index| {
+                s.cipher
+                    .decrypt(&nonce(&s.prefix, index, last), sealed.as_slice())
+                    .map_err(|_| decryption_failed())
+            });
+            s.index += 1;
+            let plain = match plain {
+                Ok(plain) => plain,
+                Err(e) => {
+                    s.remaining = 0;
+                    return Some((Err(e), s));
+                }
+            };
+            let start = s.skip.min(plain.len());
+            let end = (start as u64 + s.remaining).min(plain.len() as u64) as usize;
+            s.skip = 0;
+            s.remaining -= (end - start) as u64;
+            if end > start {
+                return Some((Ok(Bytes::copy_from_slice(&plain[start..end])), s));
+            }
+        }
+        None
+    }))
+}
+
+// --- Storage ---
+
+/// Encrypts files on their way into `inner` and decrypts them on the way out. Files
+/// without a header (stored before encryption was turned on) are passed through.
+pub struct Encrypted {
+    inner: Arc<dyn Storage>,
+    keys: Arc<Keyring>,
+}
+
+impl Encrypted {
+    /// Wrap a root's storage, first re-encrypting files that aren't under the current
+    /// key yet.
+    pub async fn open(
+        inner: Arc<dyn Storage>,
+        keys: Arc<Keyring>,
+        root: &str,
+    ) -> io::Result<Arc<dyn Storage>> {
+        let storage = Encrypted { inner, keys };
+        storage.migrate(root).await?;
+        Ok(Arc::new(storage))
+    }
+
+    /// `entry` for the file at `path` with its plaintext size. Files without a header,
+    /// stored since startup by something other than Boxy, keep theirs; one that can't
+    /// be read keeps it too, so a listing doesn't fail over it.
+    async fn plain_entry(&self, path: &str, mut entry: Entry) -> Entry {
+        if !entry.is_dir
+            && entry.size >= HEADER_LEN + TAG
+            && matches!(self.header(path).await, Ok(Some(_)))
+        {
+            entry.size = plain_size(entry.size);
+        }
+        entry
+    }
+
+    async fn header(&self, path: &str) -> io::Result<Option<Header>> {
+        let mut stream = self.inner.read(path, Some((0, HEADER_LEN))).await?;
+        let mut data = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(Header::parse(&data))
+    }
+
+    /// Bring every file to the current key, unless the marker says they already are.
+    async fn migrate(&self, root: &str) -> io::Result<()> {
+        let (current, _) = self.keys.current();
+        let marker = storage::read_all(self.inner.as_ref(), MARKER)
+            .await
+            .ok()
+            .and_then(|data| String::from_utf8(data).ok());
+        if marker.as_deref().map(str::trim) == Some(current.to_string().as_str()) {
+            return Ok(());
+        }
+
+        // The data dir holds indexes and app data that never go through storage, but
+        // kept revisions do
+        let mut migrated = 0;
+        let mut pending = vec![String::new(), crate::REVISIONS_DIR.to_string()];
+        while let Some(folder) = pending.pop() {
+            let entries = match self.inner.list(&folder).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                if folder.is_empty() && entry.name == crate::DATA_DIR {
+                    continue;
+                }
+                let path = storage::join(&folder, &entry.name);
+                if entry.is_dir {
+                    pending.push(path);
+                    continue;
+                }
+                let stale = match self.header(&path).await? {
+                    Some(header) => header.key_id != current,
+                    None => true,
+                };
+                if stale {
+                    let plain = self.read(&path, None).await?;
+                    self.write(&path, plain).await?;
+                    migrated += 1;
+                }
+            }
+        }
+        self.inner
+            .write(MARKER, storage::once(current.to_string()))
+            .await?;
+        println!(
+            "Encryption: {} file{} in {} re-encrypted with key {}",
+            migrated,
+            if migrated == 1 { "" } else { "s" },
+            root,
+            current
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Storage for Encrypted {
+    // No `local_root`: the files on disk are ciphertext, so nothing may read them there,
+    // though they can still be moved to the trash and kept as revisions
+    fn disk_root(&self) -> Option<&Path> {
+        self.inner.disk_root()
+    }
+
+    fn copies_are_free(&self) -> bool {
+        self.inner.copies_are_free()
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for entry in self.inner.list(path).await? {
+            let child = storage::join(path, &entry.name);
+            entries.push(self.plain_entry(&child, entry).await);
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Entry> {
+        let entry = self.inner.stat(path).await?;
+        Ok(self.plain_entry(path, entry).await)
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let entry = self.inner.stat(path).await?;
+        if entry.is_dir {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, "Not a file"));
+        }
+        let Some(header) = self.header(path).await? else {
+            return self.inner.read(path, range).await;
+        };
+        let cipher = self
+            .keys
+            .get(header.key_id)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Encryption key {} is not in the key file", header.key_id),
+                )
+            })?
+            .clone();
+
+        let size = plain_size(entry.size);
+        let count = chunk_count(entry.size);
+        let (offset, length) = match range {
+            Some((offset, length)) => {
+                let offset = offset.min(size);
+                (offset, length.min(size - offset))
+            }
+            None => (0, size),
+        };
+
+        if length == 0 {
+            return Ok(storage::once(Bytes::new()));
+        }
+
+        // Only the chunks the range falls in
+        let (first, last) = (offset / CHUNK, (offset + length - 1) / CHUNK);
+        let sealed = self
+            .inner
+            .read(
+                path,
+                Some((
+                    HEADER_LEN + first * SEALED_CHUNK,
+                    (last - first + 1) * SEALED_CHUNK,
+                )),
+            )
+            .await?;
+        let skip = (offset - first * CHUNK) as usize;
+        Ok(open(cipher, header, sealed, (first, count), skip, length))
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        let (key_id, cipher) = self.keys.current();
+        let mut prefix = [0u8; NONCE_PREFIX];
+        OsRng.fill_bytes(&mut prefix);
+        let header = Header { key_id, prefix };
+        let written = self
+            .inner
+            .write(path, seal(cipher.clone(), header, body))
+            .await?;
+        Ok(plain_size(written))
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.rename(from, to).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        self.inner.copy(from, to).await
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        self.inner.delete(path).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.inner.mkdir(path).await
+    }
+}
@@ -0,0 +1,408 @@
+// === Text Extraction ===
+// Plain text from the editable text types, PDFs and Office (OOXML) documents.
+// Used by the content index; everything here is blocking and pure Rust.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+use quick_xml::events::Event;
+use serde::Serialize;
+
+use crate::EDITABLE_EXTENSIONS;
+
+/// Document types we can pull text out of, besides `EDITABLE_EXTENSIONS`.
+const DOCUMENT_EXTENSIONS: &[&str] = &["pdf", "docx", "xlsx", "pptx"];
+
+/// Files above this size are not opened for extraction at all.
+pub const MAX_EXTRACT_FILE_BYTES: u64 = 50 * 1024 * 1024; // 50 MB
+
+/// Extracted text is truncated to this many bytes.
+pub const MAX_EXTRACT_TEXT_BYTES: usize = 2 * 1024 * 1024; // 2 MB
+
+/// Uncompressed size of a single OOXML part we are willing to read; a small file can
+/// inflate to gigabytes.
+const MAX_PART_BYTES: u64 = 64 * 1024 * 1024; // 64 MB
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default()
+}
+
+pub fn is_extractable(path: &Path) -> bool {
+    let ext = extension(path);
+    EDITABLE_EXTENSIONS.contains(&ext.as_str()) || DOCUMENT_EXTENSIONS.contains(&ext.as_str())
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn truncate_text(mut text: String) -> String {
+    if text.len() > MAX_EXTRACT_TEXT_BYTES {
+        let mut end = MAX_EXTRACT_TEXT_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+/// Extract the text content of a file.
+pub fn extract_text(path: &Path) -> io::Result<String> {
+    if is_document(path) {
+        let file = BufReader::new(File::open(path)?);
+        return Ok(truncate_text(extract_document(path, file)?.text()));
+    }
+    let mut bytes = Vec::new();
+    File::open(path)?
+        .take(MAX_EXTRACT_TEXT_BYTES as u64)
+        .read_to_end(&mut bytes)?;
+    Ok(truncate_text(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+// --- Document structure ---
+
+pub fn is_document(path: &Path) -> bool {
+    DOCUMENT_EXTENSIONS.contains(&extension(path).as_str())
+}
+
+/// Text of a PDF or Office document, split into pages, slides or sheets.
+#[derive(Default, Serialize)]
+pub struct Document {
+    pub sections: Vec<Section>,
+    /// Extraction stopped at `MAX_EXTRACT_TEXT_BYTES`
+    pub truncated: bool,
+    #[serde(skip)]
+    bytes: usize,
+}
+
+#[derive(Serialize)]
+pub struct Section {
+    /// `Page 3`, `Slide 2` or the sheet name; Word documents have a single untitled section
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Block {
+    Heading { level: u8, text: String },
+    Paragraph { text: String },
+    Table { rows: Vec<Vec<String>> },
+}
+
+impl Document {
+    fn section(&mut self, title: Option<String>) {
+        self.bytes += title.as_ref().map_or(0, String::len);
+        self.sections.push(Section {
+            title,
+            blocks: Vec::new(),
+        });
+    }
+
+    /// Append to the last section. Returns false once the text budget is spent.
+    fn push(&mut self, block: Block) -> bool {
+        if self.truncated {
+            return false;
+        }
+        self.bytes += match &block {
+            Block::Heading { text, .. } | Block::Paragraph { text } => text.len(),
+            Block::Table { rows } => rows.iter().flatten().map(|c| c.len() + 1).sum(),
+        };
+        if self.sections.is_empty() {
+            self.section(None);
+        }
+        if let Some(section) = self.sections.last_mut() {
+            section.blocks.push(block);
+        }
+        self.truncated = self.bytes > MAX_EXTRACT_TEXT_BYTES;
+        !self.truncated
+    }
+
+    /// Plain text for the search index: one line per paragraph, tab-separated table rows.
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        for section in &self.sections {
+            if let Some(title) = &section.title {
+                text.push_str(title);
+                text.push('\n');
+            }
+            for block in &section.blocks {
+                match block {
+                    Block::Heading { text: t, .. } | Block::Paragraph { text: t } => {
+                        text.push_str(t);
+                        text.push('\n');
+                    }
+                    Block::Table { rows } => {
+                        for row in rows {
+                            text.push_str(row.join("\t").trim_end());
+                            text.push('\n');
+                        }
+                    }
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+}
+
+/// Structured text of the PDF, DOCX, PPTX or XLSX document `name`, read from `source`.
+pub fn extract_document<R: Read + Seek>(name: &Path, source: R) -> io::Result<Document> {
+    match extension(name).as_str() {
+        "pdf" => extract_pdf(source),
+        "docx" => extract_docx(source),
+        "pptx" => extract_pptx(source),
+        "xlsx" => extract_xlsx(source),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Not a PDF or Office document",
+        )),
+    }
+}
+
+fn extract_pdf<R: Read>(mut source: R) -> io::Result<Document> {
+    let mut bytes = Vec::new();
+    source.read_to_end(&mut bytes)?;
+    // pdf-extract can panic on malformed input, keep that contained to this file
+    let pages =
+        std::panic::catch_unwind(move || pdf_extract::extract_text_from_mem_by_pages(&bytes))
+            .map_err(|_| invalid_data("PDF could not be parsed"))?
+            .map_err(invalid_data)?;
+
+    let mut doc = Document::default();
+    for (i, page) in pages.iter().enumerate() {
+        doc.section(Some(format!("Page {}", i + 1)));
+        // Blank lines separate paragraphs; single breaks are just line wrapping
+        for paragraph in page.split("\n\n") {
+            let text = paragraph.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !text.is_empty() && !doc.push(Block::Paragraph { text }) {
+                return Ok(doc);
+            }
+        }
+    }
+    Ok(doc)
+}
+
+fn open_package<R: Read + Seek>(source: R) -> io::Result<zip::ZipArchive<R>> {
+    zip::ZipArchive::new(source).map_err(invalid_data)
+}
+
+/// Contents of one XML part of an OOXML package, or `None` if it is missing.
+fn read_part<R: Read + Seek>(
+    zip: &mut zip::ZipArchive<R>,
+    name: &str,
+) -> io::Result<Option<String>> {
+    let mut xml = String::new();
+    match zip.by_name(name) {
+        Ok(file) => file.take(MAX_PART_BYTES + 1).read_to_string(&mut xml)?,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(invalid_data(e)),
+    };
+    if xml.len() as u64 > MAX_PART_BYTES {
+        return Err(invalid_data(format!("{} is too large", name)));
+    }
+    Ok(Some(xml))
+}
+
+/// Names of the numbered parts starting with `prefix` (e.g. `ppt/slides/slide`), in order.
+fn ooxml_parts<R: Read + Seek>(zip: &zip::ZipArchive<R>, prefix: &str) -> Vec<String> {
+    let mut parts: Vec<(u32, String)> = zip
+        .file_names()
+        .filter_map(|name| {
+            let number = name.strip_prefix(prefix)?.strip_suffix(".xml")?;
+            Some((number.parse().ok()?, name.to_string()))
+        })
+        .collect();
+    parts.sort();
+    parts.into_iter().map(|(_, name)| name).collect()
+}
+
+fn attribute(e: &quick_xml::events::BytesStart, name: &[u8]) -> Option<String> {
+    e.attributes()
+        .flatten()
+        .find(|a| a.key.local_name().as_ref() == name)
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.into_owned())
+}
+
+/// Heading level from a Word paragraph style id (`Title`, `Heading1`..`Heading9`).
+fn heading_level(style: &str) -> Option<u8> {
+    if style.eq_ignore_ascii_case("title") {
+        return Some(1);
+    }
+    let level: u8 = style
+        .strip_prefix("Heading")
+        .or_else(|| style.strip_prefix("heading"))?
+        .parse()
+        .ok()?;
+    (1..=6).contains(&level).then_some(level)
+}
+
+/// Paragraphs, headings and tables of `word/document.xml`. Nested tables are
+/// flattened into the text of their cell.
+fn extract_docx<R: Read + Seek>(source: R) -> io::Result<Document> {
+    let mut zip = open_package(source)?;
+    let mut doc = Document::default();
+    let Some(xml) = read_part(&mut zip, "word/document.xml")? else {
+        return Ok(doc);
+    };
+
+    let mut reader = quick_xml::Reader::from_str(&xml);
+    let mut in_text = false;
+    let mut paragraph = String::new();
+    let mut style: Option<String> = None;
+    let mut table_depth = 0;
+    let (mut rows, mut row, mut cell) = (Vec::new(), Vec::new(), String::new());
+    loop {
+        match reader.read_event().map_err(invalid_data)? {
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"t" => in_text = true,
+                b"tbl" => table_depth += 1,
+                _ => {}
+            },
+            Event::Empty(e) => match e.local_name().as_ref() {
+                b"pStyle" => style = attribute(&e, b"val"),
+                b"tab" => paragraph.push('\t'),
+                b"br" => paragraph.push('\n'),
+                _ => {}
+            },
+            Event::Text(t) if in_text => paragraph.push_str(&t.unescape().map_err(invalid_data)?),
+            Event::End(e) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"p" => {
+                    let text = std::mem::take(&mut paragraph);
+                    let style = style.take();
+                    if table_depth > 0 {
+                        if !cell.is_empty() {
+                            cell.push('\n');
+                        }
+                        cell.push_str(text.trim());
+                        continue;
+                    }
+                    let text = text.trim().to_string();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    let block = match style.as_deref().and_then(heading_level) {
+                        Some(level) => Block::Heading { level, text },
+                        None => Block::Paragraph { text },
+                    };
+                    if !doc.push(block) {
+                        break;
+                    }
+                }
+                b"tc" if table_depth == 1 => row.push(std::mem::take(&mut cell)),
+                b"tr" if table_depth == 1 => rows.push(std::mem::take(&mut row)),
+                b"tbl" => {
+                    table_depth -= 1;
+                    if table_depth == 0
+                        && !rows.is_empty()
+                        && !doc.push(Block::Table {
+                            rows: std::mem::take(&mut rows),
+                        })
+                    {
+                        break;
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(doc)
+}
+
+/// One section per slide; paragraphs in title placeholders become headings.
+fn extract_pptx<R: Read + Seek>(source: R) -> io::Result<Document> {
+    let mut zip = open_package(source)?;
+    let mut doc = Document::default();
+
+    for (i, part) in ooxml_parts(&zip, "ppt/slides/slide").iter().enumerate() {
+        let Some(xml) = read_part(&mut zip, part)? else {
+            continue;
+        };
+        doc.section(Some(format!("Slide {}", i + 1)));
+
+        let mut reader = quick_xml::Reader::from_str(&xml);
+        let mut in_text = false;
+        let mut is_title = false;
+        let mut paragraph = String::new();
+        loop {
+            match reader.read_event().map_err(invalid_data)? {
+                Event::Start(e) => match e.local_name().as_ref() {
+                    b"t" => in_text = true,
+                    b"sp" => is_title = false,
+                    _ => {}
+                },
+                Event::Empty(e) if e.local_name().as_ref() == b"ph" => {
+                    is_title = matches!(
+                        attribute(&e, b"type").as_deref(),
+                        Some("title" | "ctrTitle")
+                    );
+                }
+                Event::Text(t) if in_text => {
+                    paragraph.push_str(&t.unescape().map_err(invalid_data)?)
+                }
+                Event::End(e) => match e.local_name().as_ref() {
+                    b"t" => in_text = false,
+                    b"p" => {
+                        let text = std::mem::take(&mut paragraph).trim().to_string();
+                        if text.is_empty() {
+                            continue;
+                        }
+                        let block = if is_title {
+                            Block::Heading { level: 1, text }
+                        } else {
+                            Block::Paragraph { text }
+                        };
+                        if !doc.push(block) {
+                            return Ok(doc);
+                        }
+                    }
+                    _ => {}
+                },
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+    }
+    Ok(doc)
+}
+
+/// One section per sheet, holding its used range as a table.
+fn extract_xlsx<R: Read + Seek>(source: R) -> io::Result<Document> {
+    use calamine::Reader;
+
+    let mut workbook: calamine::Xlsx<R> =
+        calamine::open_workbook_from_rs(source).map_err(invalid_data)?;
+    let mut doc = Document::default();
+
+    for name in workbook.sheet_names() {
+        let Ok(range) = workbook.worksheet_range(&name) else {
+            continue;
+        };
+        doc.section(Some(name));
+        // Stop converting rows once the budget is gone, huge sheets would otherwise be built whole
+        let mut remaining = (MAX_EXTRACT_TEXT_BYTES + 1).saturating_sub(doc.bytes);
+        let rows: Vec<Vec<String>> = range
+            .rows()
+            .map(|row| row.iter().map(|c| c.to_string()).collect::<Vec<_>>())
+            .take_while(|row| {
+                let fits = remaining > 0;
+                remaining = remaining.saturating_sub(row.iter().map(|c| c.len() + 1).sum());
+                fits
+            })
+            .collect();
+        if !rows.is_empty() && !doc.push(Block::Table { rows }) {
+            break;
+        }
+    }
+    Ok(doc)
+}
@@ -0,0 +1,175 @@
+// === Fuzzy Matching ===
+// Subsequence matching with fzf-style scoring for quick-open name search.
+// Every pattern character must appear in order; the score rewards matches at
+// word and path-segment boundaries and runs of consecutive characters, and
+// penalises gaps, so `rq3` puts `report_q3.pdf` above `error_query3.txt`.
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_START: i64 = 12;
+const BONUS_SEGMENT: i64 = 12;
+const BONUS_WORD: i64 = 10;
+const BONUS_CAMEL: i64 = 8;
+const BONUS_CONSECUTIVE: i64 = 8;
+const PENALTY_GAP: i64 = 1;
+const MAX_LEADING_PENALTY: i64 = 3;
+
+/// Above this many DP cells the match falls back to the leftmost greedy alignment.
+const MAX_DP_CELLS: usize = 64 * 1024;
+
+const NONE: i64 = i64::MIN / 2;
+
+/// Lowercased pattern characters, ignoring whitespace so `my report` finds `my-report`.
+pub fn pattern(query: &str) -> Vec<char> {
+    query
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .map(lowercase)
+        .collect()
+}
+
+/// Single-char lowercase, so positions in the lowered text line up with the original.
+fn lowercase(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+/// Cheap check that `pattern` is a subsequence of `text`, without scoring.
+pub fn is_match(pattern: &[char], text: &str) -> bool {
+    let mut rest = pattern.iter().peekable();
+    for c in text.chars().map(lowercase) {
+        if rest.peek() == Some(&&c) {
+            rest.next();
+        }
+    }
+    rest.peek().is_none()
+}
+
+/// Bonus for matching at character `j`, based on the character before it.
+fn boundary_bonus(text: &[char], j: usize) -> i64 {
+    if j == 0 {
+        return BONUS_START;
+    }
+    let (prev, cur) = (text[j - 1], text[j]);
+    if prev == '/' {
+        BONUS_SEGMENT
+    } else if prev.is_whitespace() || matches!(prev, '_' | '-' | '.' | '(' | '[') {
+        BONUS_WORD
+    } else if (prev.is_lowercase() && cur.is_uppercase())
+        || (!prev.is_ascii_digit() && cur.is_ascii_digit())
+    {
+        BONUS_CAMEL
+    } else {
+        0
+    }
+}
+
+fn char_score(text: &[char], j: usize) -> i64 {
+    SCORE_MATCH + boundary_bonus(text, j)
+}
+
+/// Score and matched character positions of the best alignment of `pattern` in `text`,
+/// or `None` if it doesn't match. Positions are char indices into `text`.
+pub fn score(pattern: &[char], text: &str) -> Option<(i64, Vec<usize>)> {
+    if pattern.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    if !is_match(pattern, text) {
+        return None;
+    }
+
+    let chars: Vec<char> = text.chars().collect();
+    let lower: Vec<char> = chars.iter().copied().map(lowercase).collect();
+    let (m, n) = (pattern.len(), chars.len());
+
+    if m * n > MAX_DP_CELLS {
+        return Some(greedy(pattern, &chars, &lower));
+    }
+
+    // best[i][j]: best score with pattern[i] matched at text[j]; from[i][j]: where pattern[i - 1] sat
+    let mut best = vec![vec![NONE; n]; m];
+    let mut from = vec![vec![0usize; n]; m];
+
+    for j in 0..n {
+        if lower[j] == pattern[0] {
+            let leading = (j as i64).min(MAX_LEADING_PENALTY) * PENALTY_GAP;
+            best[0][j] = char_score(&chars, j) - leading;
+        }
+    }
+
+    for i in 1..m {
+        // Best predecessor at least two characters back, with its gap penalty applied
+        let mut gapped = NONE;
+        let mut gapped_at = 0;
+        for j in i..n {
+            if j >= 2 && best[i - 1][j - 2] > NONE && best[i - 1][j - 2] - PENALTY_GAP > gapped {
+                gapped = best[i - 1][j - 2] - PENALTY_GAP;
+                gapped_at = j - 2;
+            }
+            if lower[j] != pattern[i] {
+                gapped -= PENALTY_GAP;
+                continue;
+            }
+
+            let adjacent = best[i - 1][j - 1];
+            let (prev, prev_at) = if adjacent > NONE && adjacent + BONUS_CONSECUTIVE >= gapped {
+                (adjacent + BONUS_CONSECUTIVE, j - 1)
+            } else {
+                (gapped, gapped_at)
+            };
+            if prev > NONE {
+                best[i][j] = prev + char_score(&chars, j);
+                from[i][j] = prev_at;
+            }
+            gapped -= PENALTY_GAP;
+        }
+    }
+
+    let (mut j, score) = best[m - 1]
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| **s > NONE)
+        .max_by_key(|(j, s)| (**s, std::cmp::Reverse(*j)))
+        .map(|(j, s)| (j, *s))?;
+
+    let mut positions = vec![0; m];
+    for i in (0..m).rev() {
+        positions[i] = j;
+        j = from[i][j];
+    }
+
+    Some((score - trailing_penalty(n, m), positions))
+}
+
+/// Shorter names rank above longer ones with the same match.
+fn trailing_penalty(len: usize, matched: usize) -> i64 {
+    (len.saturating_sub(matched) as i64) / 4
+}
+
+/// Leftmost alignment for very long texts, scored the same way as the DP.
+fn greedy(pattern: &[char], chars: &[char], lower: &[char]) -> (i64, Vec<usize>) {
+    let mut positions = Vec::with_capacity(pattern.len());
+    let mut j = 0;
+    for &p in pattern {
+        while lower[j] != p {
+            j += 1;
+        }
+        positions.push(j);
+        j += 1;
+    }
+
+    let mut score = -(positions[0] as i64).min(MAX_LEADING_PENALTY) * PENALTY_GAP;
+    for (i, &pos) in positions.iter().enumerate() {
+        score += char_score(chars, pos);
+        if i > 0 {
+            let gap = pos - positions[i - 1] - 1;
+            if gap == 0 {
+                score += BONUS_CONSECUTIVE;
+            } else {
+                score -= gap as i64 * PENALTY_GAP;
+            }
+        }
+    }
+    (
+        score - trailing_penalty(chars.len(), pattern.len()),
+        positions,
+    )
+}
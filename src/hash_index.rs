@@ -0,0 +1,450 @@
+// === Content Hashes ===
+// BLAKE3 hashes of the files in a root, for finding duplicates. A background thread
+// hashes whatever isn't known yet: everything on the first start, then the paths the
+// API and the tree index's watcher report as changed. Uploads are hashed while they
+// stream, so they are known as soon as they land. Hashes are saved to
+// `.boxy/hashes.tsv` with the size and mtime they were taken at, and a hash whose
+// file no longer matches is dropped and taken again.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::storage::is_within;
+
+const HASHES_FILE: &str = ".boxy/hashes.tsv";
+/// Changes arriving within this window are hashed together.
+const HASH_DELAY: Duration = Duration::from_millis(500);
+/// How often a long pass saves what it has hashed so far.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub type Hash = [u8; 32];
+/// Device and inode, where the platform has them
+type FileId = Option<(u64, u64)>;
+
+/// What a hash was taken of; mtimes are in nanoseconds, so quick rewrites show.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    modified: u64,
+}
+
+struct Entry {
+    stamp: Stamp,
+    hash: Hash,
+    /// Files sharing one are hardlinks of each other
+    file_id: FileId,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    /// Size of each copy
+    pub size: u64,
+    pub paths: Vec<String>,
+    /// Bytes freed by keeping a single copy; hardlinked paths already share theirs
+    pub reclaimable: u64,
+}
+
+#[derive(Serialize)]
+pub struct Duplicates {
+    /// Largest savings first
+    pub groups: Vec<DuplicateGroup>,
+    pub reclaimable: u64,
+    pub hashed_files: u64,
+    /// Files are still being hashed, so more duplicates may turn up
+    pub pending: bool,
+}
+
+pub struct HashIndex {
+    upload_dir: PathBuf,
+    entries: Mutex<HashMap<String, Entry>>,
+    /// Paths to (re)hash; `None` when hashing is disabled
+    queue: Option<mpsc::Sender<String>>,
+    /// Queued paths not hashed yet
+    pending: AtomicUsize,
+    /// Entries changed since the last save
+    dirty: AtomicBool,
+}
+
+fn stamp(meta: &fs::Metadata) -> Stamp {
+    Stamp {
+        size: meta.len(),
+        modified: meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0),
+    }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> FileId {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> FileId {
+    None
+}
+
+pub fn hash_file(path: &Path) -> io::Result<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update_reader(fs::File::open(path)?)?;
+    Ok(*hasher.finalize().as_bytes())
+}
+
+fn is_data_path(rel_path: &str) -> bool {
+    is_within(rel_path, crate::DATA_DIR)
+}
+
+/// A saved hash: `hash<TAB>size<TAB>mtime<TAB>path`. The inode is left for `rehash`
+/// to fill in, or the entry dropped if the file changed meanwhile.
+fn parse_line(line: &str) -> Option<(&str, Entry)> {
+    let mut fields = line.splitn(4, '\t');
+    let hash = Hash::try_from(hex::decode(fields.next()?).ok()?).ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let modified = fields.next()?.parse().ok()?;
+    let entry = Entry {
+        stamp: Stamp { size, modified },
+        hash,
+        file_id: None,
+    };
+    Some((fields.next()?, entry))
+}
+
+/// Regular files at or below `rel_path`, skipping the data directory. Symlinks are
+/// neither followed nor hashed.
+fn walk(upload_dir: &Path, rel_path: &str, found: &mut HashMap<String, (Stamp, FileId)>) {
+    if is_data_path(rel_path) {
+        return;
+    }
+    let abs_path = upload_dir.join(rel_path);
+    let Ok(meta) = fs::symlink_metadata(&abs_path) else {
+        return;
+    };
+    if meta.is_file() {
+        found.insert(rel_path.to_string(), (stamp(&meta), file_id(&meta)));
+    } else if meta.is_dir() {
+        for entry in fs::read_dir(&abs_path).into_iter().flatten().flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let child = if rel_path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", rel_path, name)
+            };
+            walk(upload_dir, &child, found);
+        }
+    }
+}
+
+impl HashIndex {
+    /// Load the saved hashes for `upload_dir` and start the hashing thread, which first
+    /// catches up with what is on disk. Disabled, nothing is ever hashed.
+    pub fn open(upload_dir: &Path, enabled: bool) -> Arc<Self> {
+        let (queue, jobs) = mpsc::channel();
+        let index = Arc::new(HashIndex {
+            upload_dir: upload_dir.to_path_buf(),
+            entries: Mutex::new(HashMap::new()),
+            queue: enabled.then_some(queue),
+            pending: AtomicUsize::new(0),
+            dirty: AtomicBool::new(false),
+        });
+        if !enabled {
+            return index;
+        }
+
+        index.refresh("");
+        let hasher = index.clone();
+        let spawned = std::thread::Builder::new()
+            .name("hasher".to_string())
+            .spawn(move || hasher.run(jobs));
+        if let Err(e) = spawned {
+            eprintln!("Content hashing disabled: {}", e);
+        }
+        index
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Queue a path (relative to the upload root) and everything below it for hashing,
+    /// after it was created, changed or removed.
+    pub fn refresh(&self, rel_path: &str) {
+        if let Some(queue) = &self.queue {
+            self.pending.fetch_add(1, Ordering::AcqRel);
+            if queue.send(rel_path.to_string()).is_err() {
+                self.pending.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Remember the hash of a file that was hashed as it was written.
+    pub fn record(&self, rel_path: &str, hash: Hash) {
+        if !self.is_enabled() {
+            return;
+        }
+        if let Ok(meta) = fs::symlink_metadata(self.upload_dir.join(rel_path)) {
+            self.insert(rel_path, &meta, hash);
+        }
+    }
+
+    fn insert(&self, rel_path: &str, meta: &fs::Metadata, hash: Hash) {
+        let entry = Entry {
+            stamp: stamp(meta),
+            hash,
+            file_id: file_id(meta),
+        };
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(rel_path.to_string(), entry);
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    fn run(&self, jobs: mpsc::Receiver<String>) {
+        self.load();
+        while let Ok(first) = jobs.recv() {
+            let mut paths = vec![first];
+            while let Ok(path) = jobs.recv_timeout(HASH_DELAY) {
+                paths.push(path);
+            }
+
+            // Sorted, folders come before what they contain, which they cover
+            let queued = paths.len();
+            paths.sort();
+            paths.dedup();
+            let mut done: Vec<String> = Vec::new();
+            for path in paths {
+                if done.iter().any(|folder| is_within(&path, folder)) {
+                    continue;
+                }
+                self.rehash(&path);
+                done.push(path);
+            }
+            self.save();
+            self.pending.fetch_sub(queued, Ordering::AcqRel);
+        }
+    }
+
+    /// Drop the hashes below `rel_path` that no longer match their file, and hash the
+    /// files there that have none.
+    fn rehash(&self, rel_path: &str) {
+        let mut found = HashMap::new();
+        walk(&self.upload_dir, rel_path, &mut found);
+
+        let mut missing = Vec::new();
+        {
+            let mut entries = self.entries.lock().unwrap();
+            let before = entries.len();
+            entries.retain(|path, entry| {
+                !is_within(path, rel_path)
+                    || found
+                        .get(path)
+                        .is_some_and(|(stamp, _)| *stamp == entry.stamp)
+            });
+            if entries.len() != before {
+                self.dirty.store(true, Ordering::Release);
+            }
+            for (path, (stamp, id)) in found {
+                match entries.get_mut(&path) {
+                    // Saved hashes don't know the inode
+                    Some(entry) => entry.file_id = id,
+                    None => missing.push((path, stamp)),
+                }
+            }
+        }
+
+        let mut saved = Instant::now();
+        for (path, stamp) in missing {
+            let abs_path = self.upload_dir.join(&path);
+            let Ok(hash) = hash_file(&abs_path) else {
+                continue;
+            };
+            // A file that changed while being read is queued again by that change
+            match fs::symlink_metadata(&abs_path) {
+                Ok(meta) if self::stamp(&meta) == stamp => self.insert(&path, &meta, hash),
+                _ => continue,
+            }
+            if saved.elapsed() > SAVE_INTERVAL {
+                self.save();
+                saved = Instant::now();
+            }
+        }
+    }
+
+    fn load(&self) {
+        let Ok(file) = fs::File::open(self.upload_dir.join(HASHES_FILE)) else {
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        for line in io::BufReader::new(file).lines().map_while(Result::ok) {
+            if let Some((path, entry)) = parse_line(&line) {
+                // Hashes recorded by uploads since the start are newer
+                entries.entry(path.to_string()).or_insert(entry);
+            }
+        }
+    }
+
+    fn save(&self) {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return;
+        }
+        let path = self.upload_dir.join(HASHES_FILE);
+        let staging = path.with_extension("tsv.tmp");
+        let written = (|| -> io::Result<()> {
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let mut out = BufWriter::new(fs::File::create(&staging)?);
+            let entries = self.entries.lock().unwrap();
+            for (rel_path, entry) in entries.iter().filter(|(p, _)| !p.contains('\n')) {
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}",
+                    hex::encode(entry.hash),
+                    entry.stamp.size,
+                    entry.stamp.modified,
+                    rel_path
+                )?;
+            }
+            drop(entries);
+            out.into_inner()?.sync_all()?;
+            fs::rename(&staging, &path)
+        })();
+        if let Err(e) = written {
+            eprintln!("Could not save content hashes: {}", e);
+            self.dirty.store(true, Ordering::Release);
+        }
+    }
+
+    /// Groups of identical files of at least `min_size` bytes below `rel_path` that
+    /// would free space if they were one file.
+    pub fn duplicates(&self, rel_path: &str, min_size: u64) -> Duplicates {
+        let entries = self.entries.lock().unwrap();
+        let mut by_hash: HashMap<Hash, Vec<(&String, &Entry)>> = HashMap::new();
+        let mut hashed_files = 0;
+        for (path, entry) in entries.iter().filter(|(p, _)| is_within(p, rel_path)) {
+            hashed_files += 1;
+            // Empty files are all alike, and there is nothing to gain from them
+            if entry.stamp.size > 0 && entry.stamp.size >= min_size {
+                by_hash.entry(entry.hash).or_default().push((path, entry));
+            }
+        }
+
+        let mut groups: Vec<DuplicateGroup> = by_hash
+            .into_iter()
+            .filter(|(_, files)| files.len() > 1)
+            .filter_map(|(hash, files)| {
+                let size = files[0].1.stamp.size;
+                let mut ids: Vec<FileId> = files.iter().map(|(_, e)| e.file_id).collect();
+                ids.sort();
+                ids.dedup_by(|a, b| a.is_some() && a == b);
+                let reclaimable = size * (ids.len() as u64 - 1);
+                let mut paths: Vec<String> = files.into_iter().map(|(p, _)| p.clone()).collect();
+                paths.sort();
+                (reclaimable > 0).then(|| DuplicateGroup {
+                    hash: hex::encode(hash),
+                    size,
+                    paths,
+                    reclaimable,
+                })
+            })
+            .collect();
+        groups.sort_by(|a, b| {
+            (b.reclaimable, b.size)
+                .cmp(&(a.reclaimable, a.size))
+                .then_with(|| a.paths.cmp(&b.paths))
+        });
+
+        Duplicates {
+            reclaimable: groups.iter().map(|g| g.reclaimable).sum(),
+            groups,
+            hashed_files,
+            pending: self.pending.load(Ordering::Acquire) > 0,
+        }
+    }
+
+    /// Replace each of `duplicates` with a hardlink to `keep`, after checking that their
+    /// contents really are the same. Those already linked to it are left alone. The
+    /// replaced files stay linked under `backup_dir` so the change can be undone; on an
+    /// error, every file is put back. Returns each replaced path with its backup.
+    pub fn link(
+        &self,
+        keep: &str,
+        duplicates: &[String],
+        backup_dir: &Path,
+    ) -> io::Result<Vec<(String, PathBuf)>> {
+        let keep_path = self.upload_dir.join(keep);
+        let keep_meta = fs::symlink_metadata(&keep_path)?;
+        if !keep_meta.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a file", keep),
+            ));
+        }
+        let hash = hash_file(&keep_path)?;
+
+        let mut replace = Vec::new();
+        for path in duplicates {
+            let meta = fs::symlink_metadata(self.upload_dir.join(path))?;
+            if !meta.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{} is not a file", path),
+                ));
+            }
+            if file_id(&meta).is_some() && file_id(&meta) == file_id(&keep_meta) {
+                continue;
+            }
+            if meta.len() != keep_meta.len() || hash_file(&self.upload_dir.join(path))? != hash {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} differs from {}", path, keep),
+                ));
+            }
+            replace.push(path.clone());
+        }
+
+        // Keep each duplicate as a second link under `backup_dir`, link `keep` next to
+        // it, then swap that in, so a failure leaves it intact
+        fs::create_dir_all(backup_dir)?;
+        let mut replaced: Vec<(String, PathBuf)> = Vec::with_capacity(replace.len());
+        let swapped = replace.iter().try_for_each(|path| {
+            let target = self.upload_dir.join(path);
+            let backup = backup_dir.join(uuid::Uuid::new_v4().to_string());
+            fs::hard_link(&target, &backup)?;
+            let staging = target.with_file_name(format!(".link-{}", uuid::Uuid::new_v4()));
+            let linked = fs::hard_link(&keep_path, &staging).and_then(|_| {
+                fs::rename(&staging, &target).inspect_err(|_| {
+                    let _ = fs::remove_file(&staging);
+                })
+            });
+            if let Err(e) = linked {
+                let _ = fs::remove_file(&backup);
+                return Err(e);
+            }
+            replaced.push((path.clone(), backup));
+            Ok(())
+        });
+        if let Err(e) = swapped {
+            for (path, backup) in replaced {
+                let _ = fs::rename(&backup, self.upload_dir.join(&path));
+            }
+            return Err(e);
+        }
+        for (path, _) in &replaced {
+            self.insert(path, &keep_meta, hash);
+        }
+        Ok(replaced)
+    }
+}
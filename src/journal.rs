@@ -0,0 +1,128 @@
+// === Operation Journal ===
+// Keeps recently applied changes around so `/api/undo` can revert them.
+// Entries expire after the retention window; their retained copies are purged then.
+
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::Applied;
+
+/// Upper bound on remembered operations, regardless of retention time.
+const MAX_JOURNAL_ENTRIES: usize = 100;
+
+pub struct JournalEntry {
+    pub id: u64,
+    pub action: &'static str,
+    pub path: String,
+    pub created: u64,
+    pub changes: Vec<Applied>,
+}
+
+#[derive(Serialize)]
+pub struct JournalSummary {
+    id: u64,
+    action: &'static str,
+    path: String,
+    created: u64,
+    expires: u64,
+}
+
+pub struct Journal {
+    entries: VecDeque<JournalEntry>,
+    next_id: u64,
+    retention_secs: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Journal {
+    pub fn new(retention_secs: u64) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            next_id: 1,
+            retention_secs,
+        }
+    }
+
+    /// Remember an operation. Returns its id plus any changes pushed out of the
+    /// journal, which the caller must commit once the lock is released.
+    pub fn record(
+        &mut self,
+        action: &'static str,
+        path: String,
+        changes: Vec<Applied>,
+    ) -> (u64, Vec<Applied>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push_back(JournalEntry {
+            id,
+            action,
+            path,
+            created: now_secs(),
+            changes,
+        });
+
+        let mut evicted = self.expire();
+        while self.entries.len() > MAX_JOURNAL_ENTRIES {
+            if let Some(entry) = self.entries.pop_front() {
+                evicted.extend(entry.changes);
+            }
+        }
+        (id, evicted)
+    }
+
+    /// Drop entries older than the retention window, returning their changes to commit.
+    pub fn expire(&mut self) -> Vec<Applied> {
+        let cutoff = now_secs().saturating_sub(self.retention_secs);
+        let mut expired = Vec::new();
+        while self
+            .entries
+            .front()
+            .map(|e| e.created < cutoff)
+            .unwrap_or(false)
+        {
+            if let Some(entry) = self.entries.pop_front() {
+                expired.extend(entry.changes);
+            }
+        }
+        expired
+    }
+
+    /// Remove an entry for undoing: the given id, or the most recent one.
+    pub fn take(&mut self, id: Option<u64>) -> Option<JournalEntry> {
+        let index = match id {
+            Some(id) => self.entries.iter().position(|e| e.id == id)?,
+            None => self.entries.len().checked_sub(1)?,
+        };
+        self.entries.remove(index)
+    }
+
+    /// Return an entry that couldn't be undone, so its changes stay around until it
+    /// expires like any other.
+    pub fn put_back(&mut self, entry: JournalEntry) {
+        let index = self.entries.partition_point(|e| e.id < entry.id);
+        self.entries.insert(index, entry);
+    }
+
+    /// Newest first, as shown to users.
+    pub fn list(&self) -> Vec<JournalSummary> {
+        self.entries
+            .iter()
+            .rev()
+            .map(|e| JournalSummary {
+                id: e.id,
+                action: e.action,
+                path: e.path.clone(),
+                created: e.created,
+                expires: e.created + self.retention_secs,
+            })
+            .collect()
+    }
+}
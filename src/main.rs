@@ -7,22 +7,56 @@ use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::path::{Path, PathBuf};
-use tokio::io::AsyncWriteExt;
+use std::sync::{Arc, Mutex};
 use tokio::sync::broadcast;
 
+mod archive;
+mod content_index;
+mod copy;
+mod encryption;
+mod extract;
+mod fuzzy;
+mod hash_index;
+mod journal;
+mod media;
+mod notebook;
+mod preview;
+mod quota;
+mod s3;
+mod search_filter;
+mod storage;
+mod thumbnail;
+mod tree_index;
+mod webdav;
+
 const DEFAULT_UPLOAD_DIR: &str = "./uploads";
+/// Name of the root when `BOX_ROOTS` isn't set
+const DEFAULT_ROOT: &str = "uploads";
 const DEFAULT_PORT: u16 = 8086;
 const DEFAULT_MAX_UPLOAD_BYTES: usize = 1024 * 1024 * 200; // 200 MB
+const DEFAULT_UNDO_RETENTION_SECS: u64 = 15 * 60; // 15 minutes
+const DEFAULT_REVISIONS_KEEP: usize = 10;
+const DEFAULT_REVISIONS_MAX_AGE_SECS: u64 = 30 * 24 * 60 * 60; // 30 days
+/// Server-side data (sync JSON, trash) lives here; it is hidden from search and folder lists
+const DATA_DIR: &str = ".boxy";
 const EDITABLE_EXTENSIONS: &[&str] = &[
-    "txt", "csv", "py", "json", "md", "rs", "js", "html", "css", "toml", "yaml", "yml",
+    "txt", "csv", "py", "json", "md", "rs", "js", "html", "css", "toml", "yaml", "yml", "ipynb",
 ];
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 struct FileEntry {
     name: String,
     is_dir: bool,
     size: u64,
     modified: u64,
+    /// Image or video dimensions, once known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    /// Audio or video length in seconds, once known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration: Option<f64>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -33,35 +67,411 @@ struct WsMessage {
 
 type Broadcaster = broadcast::Sender<String>;
 
+/// One storage root. Each root has its own files, indexes, undo journal and live
+/// updates; see `Roots` for how a request picks one.
 #[derive(Clone)]
 struct AppState {
+    /// Name clients select the root by
+    root: String,
+    read_only: bool,
+    /// Limits on the root and on folders in it
+    quotas: Vec<quota::Quota>,
+    /// What changes under way add below the quotas
+    quota_ledger: Arc<quota::Ledger>,
     broadcaster: Broadcaster,
     upload_dir: PathBuf,
     max_upload_bytes: usize,
+    journal: Arc<Mutex<journal::Journal>>,
+    revisions: RevisionRetention,
+    content_index: Option<content_index::ContentIndex>,
+    tree: Arc<tree_index::TreeIndex>,
+    /// Indexes of the kept revisions and the trash, which count against quotas
+    revision_tree: Arc<tree_index::TreeIndex>,
+    trash_tree: Arc<tree_index::TreeIndex>,
+    hashes: Arc<hash_index::HashIndex>,
+    /// Files whose media details a listing found missing, for the media worker
+    media_queue: std::sync::mpsc::SyncSender<(String, u64)>,
+    dav_locks: Arc<Mutex<webdav::Locks>>,
+    s3: Option<Arc<s3::Credentials>>,
+    storage: Arc<dyn storage::Storage>,
 }
 
-struct Settings {
+struct RootSettings {
+    name: String,
     upload_dir: PathBuf,
-    port: u16,
     max_upload_bytes: usize,
+    read_only: bool,
+    quotas: Vec<quota::Quota>,
+}
+
+struct Settings {
+    /// The first root is the default
+    roots: Vec<RootSettings>,
+    port: u16,
+    undo_retention_secs: u64,
+    revisions: RevisionRetention,
+    content_index: bool,
+    tree_index: bool,
+    hashing: bool,
+    encryption_key_file: Option<PathBuf>,
+    s3_credentials: Option<s3::Credentials>,
+    storage: storage::Backend,
 }
 
 impl Settings {
     fn from_env() -> Self {
         Self {
-            upload_dir: env::var("BOX_UPLOAD_DIR")
-                .map(PathBuf::from)
-                .unwrap_or_else(|_| PathBuf::from(DEFAULT_UPLOAD_DIR)),
+            roots: Self::roots_from_env().unwrap_or_else(|e| {
+                eprintln!("Invalid root settings: {}", e);
+                std::process::exit(1);
+            }),
             port: env::var("BOX_PORT")
                 .ok()
                 .and_then(|p| p.parse().ok())
                 .unwrap_or(DEFAULT_PORT),
-            max_upload_bytes: env::var("BOX_MAX_UPLOAD_BYTES")
+            undo_retention_secs: env::var("BOX_UNDO_RETENTION_SECS")
                 .ok()
                 .and_then(|p| p.parse().ok())
-                .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES),
+                .unwrap_or(DEFAULT_UNDO_RETENTION_SECS),
+            revisions: RevisionRetention {
+                keep: env::var("BOX_REVISIONS_KEEP")
+                    .ok()
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(DEFAULT_REVISIONS_KEEP),
+                max_age_secs: env::var("BOX_REVISIONS_MAX_AGE_SECS")
+                    .ok()
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(DEFAULT_REVISIONS_MAX_AGE_SECS),
+            },
+            content_index: env::var("BOX_CONTENT_INDEX")
+                .map(|v| v != "0" && v != "false")
+                .unwrap_or(true),
+            tree_index: env::var("BOX_TREE_INDEX")
+                .map(|v| v != "0" && v != "false")
+                .unwrap_or(true),
+            hashing: env::var("BOX_HASHING")
+                .map(|v| v != "0" && v != "false")
+                .unwrap_or(true),
+            encryption_key_file: env::var("BOX_ENCRYPTION_KEY_FILE")
+                .ok()
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from),
+            s3_credentials: match (env::var("BOX_S3_ACCESS_KEY"), env::var("BOX_S3_SECRET_KEY")) {
+                (Ok(access_key), Ok(secret_key)) if !access_key.is_empty() => {
+                    Some(s3::Credentials {
+                        access_key,
+                        secret_key,
+                    })
+                }
+                _ => None,
+            },
+            storage: storage::Backend::from_env().unwrap_or_else(|e| {
+                eprintln!("Invalid storage settings: {}", e);
+                std::process::exit(1);
+            }),
+        }
+    }
+
+    /// `BOX_ROOTS` lists named roots as `name=/path,name=/path`; without it there is one
+    /// root, `uploads`, at `BOX_UPLOAD_DIR`. `BOX_ROOT_<NAME>_MAX_UPLOAD_BYTES`,
+    /// `_READ_ONLY`, `_QUOTA_BYTES`, `_QUOTA_FILES` and `_QUOTAS` (folder quotas as
+    /// `folder=bytes[:files],...`) configure a root, with `BOX_MAX_UPLOAD_BYTES` as the
+    /// default upload limit.
+    fn roots_from_env() -> std::result::Result<Vec<RootSettings>, String> {
+        let upload_dir = env::var("BOX_UPLOAD_DIR").unwrap_or_else(|_| DEFAULT_UPLOAD_DIR.into());
+        let roots = env::var("BOX_ROOTS")
+            .ok()
+            .filter(|v| !v.trim().is_empty())
+            .unwrap_or_else(|| format!("{}={}", DEFAULT_ROOT, upload_dir));
+        let max_upload_bytes = env::var("BOX_MAX_UPLOAD_BYTES")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(DEFAULT_MAX_UPLOAD_BYTES);
+
+        let mut settings: Vec<RootSettings> = Vec::new();
+        for spec in roots.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let (name, dir) = spec
+                .split_once('=')
+                .map(|(name, dir)| (name.trim(), dir.trim()))
+                .filter(|(_, dir)| !dir.is_empty())
+                .ok_or_else(|| format!("expected name=/path, got {}", spec))?;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(format!(
+                    "root names may only use letters, digits, - and _: {}",
+                    name
+                ));
+            }
+            let key = name.to_ascii_uppercase().replace('-', "_");
+            if settings
+                .iter()
+                .any(|root| root.name.to_ascii_uppercase().replace('-', "_") == key)
+            {
+                return Err(format!("duplicate root: {}", name));
+            }
+
+            let var = |setting: &str| env::var(format!("BOX_ROOT_{}_{}", key, setting)).ok();
+            let number = |setting: &str| match var(setting) {
+                Some(v) => v
+                    .parse::<u64>()
+                    .map(Some)
+                    .map_err(|_| format!("BOX_ROOT_{}_{} is not a number", key, setting)),
+                None => Ok(None),
+            };
+            let mut quotas = Vec::new();
+            let (max_bytes, max_files) = (number("QUOTA_BYTES")?, number("QUOTA_FILES")?);
+            if max_bytes.is_some() || max_files.is_some() {
+                quotas.push(quota::Quota {
+                    path: String::new(),
+                    max_bytes,
+                    max_files,
+                });
+            }
+            for spec in var("QUOTAS").iter().flat_map(|v| v.split(',')) {
+                if !spec.trim().is_empty() {
+                    quotas.push(quota::Quota::parse(spec)?);
+                }
+            }
+
+            settings.push(RootSettings {
+                name: name.to_string(),
+                upload_dir: PathBuf::from(dir),
+                max_upload_bytes: number("MAX_UPLOAD_BYTES")?
+                    .map_or(max_upload_bytes, |n| n as usize),
+                read_only: var("READ_ONLY").is_some_and(|v| v == "1" || v == "true"),
+                quotas,
+            });
+        }
+        if settings.is_empty() {
+            return Err("BOX_ROOTS lists no roots".to_string());
+        }
+        Ok(settings)
+    }
+}
+
+// === Roots ===
+// REST handlers take a `RootState`, the root the request is for: the `root` query
+// parameter, else the `boxy_root` cookie the UI's root switcher sets, else the default
+// (first) root. Paths stay relative to that root. WebDAV, the S3 API and the synced UI
+// data serve the default root, which is also registered as `web::Data<AppState>`.
+
+/// Every configured root, the default first.
+struct Roots(Vec<web::Data<AppState>>);
+
+/// Root the UI last switched to, which new tabs start on; requests themselves name
+/// their root, so tabs on different roots don't interfere
+const ROOT_COOKIE: &str = "boxy_root";
+/// `root=*` asks the folder list and search to span every root
+const ALL_ROOTS: &str = "*";
+
+#[derive(Deserialize)]
+struct RootQuery {
+    root: Option<String>,
+}
+
+impl Roots {
+    fn default_root(&self) -> &web::Data<AppState> {
+        &self.0[0]
+    }
+
+    fn get(&self, name: &str) -> Option<&web::Data<AppState>> {
+        self.0.iter().find(|state| state.root == name)
+    }
+
+    /// The roots a request covers, the default root unless `root` names another. An
+    /// unknown `root` is an error.
+    fn select(&self, req: &HttpRequest) -> Result<Vec<&web::Data<AppState>>> {
+        let query = web::Query::<RootQuery>::from_query(req.query_string())
+            .map(|q| q.into_inner().root)
+            .unwrap_or_default();
+        match query.as_deref().filter(|name| !name.is_empty()) {
+            Some(ALL_ROOTS) => Ok(self.0.iter().collect()),
+            Some(name) => self
+                .get(name)
+                .map(|state| vec![state])
+                .ok_or_else(|| actix_web::error::ErrorNotFound(format!("Unknown root: {}", name))),
+            None => Ok(vec![self.default_root()]),
+        }
+    }
+}
+
+/// The root a request works on.
+#[derive(Clone)]
+struct RootState(web::Data<AppState>);
+
+impl std::ops::Deref for RootState {
+    type Target = AppState;
+
+    fn deref(&self) -> &AppState {
+        &self.0
+    }
+}
+
+impl actix_web::FromRequest for RootState {
+    type Error = actix_web::Error;
+    type Future = std::future::Ready<Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut actix_web::dev::Payload) -> Self::Future {
+        let roots = req
+            .app_data::<web::Data<Roots>>()
+            .expect("roots are registered");
+        std::future::ready(roots.select(req).and_then(|selected| match selected[..] {
+            [state] => Ok(RootState(state.clone())),
+            _ => Err(actix_web::error::ErrorBadRequest(
+                "This request works on a single root",
+            )),
+        }))
+    }
+}
+
+/// Refuse changes to a read-only root.
+fn check_writable(state: &AppState) -> Result<()> {
+    if state.read_only {
+        return Err(actix_web::error::ErrorForbidden(format!(
+            "Root {} is read-only",
+            state.root
+        )));
+    }
+    Ok(())
+}
+
+/// Refuse paths in the data dir: the journal, trash, revisions and indexes there are
+/// only changed by Boxy itself.
+fn reject_data_path(rel_path: &str) -> Result<()> {
+    if storage::is_within(rel_path, DATA_DIR) {
+        return Err(actix_web::error::ErrorForbidden("Invalid path"));
+    }
+    Ok(())
+}
+
+/// Most one uploaded file may write: the root's upload limit, and the room left in the
+/// quotas covering it, which the file claims as its chunks arrive.
+struct UploadLimit<'a> {
+    bytes: u64,
+    quotas: &'a quota::Tracker,
+    rel_path: &'a str,
+}
+
+impl<'a> UploadLimit<'a> {
+    fn new(state: &AppState, quotas: &'a quota::Tracker, rel_path: &'a str) -> Self {
+        Self {
+            bytes: state.max_upload_bytes as u64,
+            quotas,
+            rel_path,
+        }
+    }
+
+    /// Admit a chunk of `len` bytes after `received`, or fail if it outgrows a limit.
+    fn admit(&self, received: u64, len: u64) -> Result<()> {
+        if received + len > self.bytes {
+            return Err(actix_web::error::ErrorPayloadTooLarge(format!(
+                "File is larger than the upload limit of {} bytes",
+                self.bytes
+            )));
         }
+        self.quotas.claim(self.rel_path, 0, len)
+    }
+}
+
+#[derive(Serialize)]
+struct RootInfo {
+    name: String,
+    default: bool,
+    /// Root the UI starts on, from the switcher's cookie
+    selected: bool,
+    read_only: bool,
+    /// Whether deletes, edits and copies go through the trash and can be undone; on
+    /// backends without a local disk they are permanent
+    undo: bool,
+    max_upload_bytes: usize,
+    quota_bytes: Option<u64>,
+    quota_files: Option<u64>,
+    used_bytes: u64,
+    used_files: u64,
+}
+
+async fn list_roots(req: HttpRequest, roots: web::Data<Roots>) -> Result<HttpResponse> {
+    let selected = req
+        .cookie(ROOT_COOKIE)
+        .and_then(|cookie| roots.get(cookie.value()))
+        .unwrap_or_else(|| roots.default_root())
+        .root
+        .clone();
+    let mut infos = Vec::with_capacity(roots.0.len());
+    for (index, state) in roots.0.iter().enumerate() {
+        let root_quota = state.quotas.iter().find(|q| q.path.is_empty());
+        let (used_files, used_bytes) = quota::usage(state, "").await?;
+        infos.push(RootInfo {
+            name: state.root.clone(),
+            default: index == 0,
+            selected: state.root == selected,
+            read_only: state.read_only,
+            undo: state.storage.disk_root().is_some(),
+            max_upload_bytes: state.max_upload_bytes,
+            quota_bytes: root_quota.and_then(|q| q.max_bytes),
+            quota_files: root_quota.and_then(|q| q.max_files),
+            used_bytes,
+            used_files,
+        });
+    }
+    Ok(HttpResponse::Ok().json(infos))
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    root: String,
+    used_files: u64,
+    used_bytes: u64,
+    quotas: Vec<quota::QuotaUsage>,
+}
+
+/// Usage of the selected root and of each of its quotas.
+async fn get_usage(state: RootState) -> Result<HttpResponse> {
+    let (used_files, used_bytes) = quota::usage(&state, "").await?;
+    let mut quotas = Vec::with_capacity(state.quotas.len());
+    for quota in &state.quotas {
+        let (used_files, used_bytes) = quota::usage(&state, &quota.path).await?;
+        quotas.push(quota::QuotaUsage {
+            quota: quota.clone(),
+            used_files,
+            used_bytes,
+        });
     }
+    Ok(HttpResponse::Ok().json(UsageReport {
+        root: state.root.clone(),
+        used_files,
+        used_bytes,
+        quotas,
+    }))
+}
+
+/// Days since the unix epoch for a proleptic Gregorian date (days-from-civil algorithm).
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Proleptic Gregorian `(year, month, day)` for days since the unix epoch (inverse of
+/// `days_from_civil`).
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
 }
 
 fn broadcast_update(tx: &Broadcaster, action: &str, path: &str) {
@@ -73,10 +483,40 @@ fn broadcast_update(tx: &Broadcaster, action: &str, path: &str) {
     let _ = tx.send(msg);
 }
 
+/// Tell clients about files changed outside Boxy (shell, sync tools), as found by the
+/// tree index's watcher: one event for a single change, otherwise a batch.
+fn broadcast_external_changes(tx: &Broadcaster, changes: Vec<(String, tree_index::Change)>) {
+    let mut items: Vec<WsMessage> = changes
+        .into_iter()
+        .map(|(path, change)| WsMessage {
+            action: match change {
+                tree_index::Change::Added { is_dir: true } => "folder",
+                tree_index::Change::Added { is_dir: false } => "upload",
+                tree_index::Change::Modified => "edit",
+                tree_index::Change::Removed => "delete",
+            }
+            .to_string(),
+            path,
+        })
+        .collect();
+    if items.len() == 1 {
+        let item = items.remove(0);
+        broadcast_update(tx, &item.action, &item.path);
+        return;
+    }
+    let msg = serde_json::to_string(&BatchMessage {
+        action: "batch".to_string(),
+        path: String::new(),
+        items,
+    })
+    .unwrap_or_default();
+    let _ = tx.send(msg);
+}
+
 async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
-    state: web::Data<AppState>,
+    state: RootState,
 ) -> Result<HttpResponse> {
     let (res, mut session, mut msg_stream) = actix_ws::handle(&req, stream)?;
     let mut rx = state.broadcaster.subscribe();
@@ -166,10 +606,18 @@ fn resolve_path_safe(base: &Path, path: Option<&String>) -> Option<PathBuf> {
     }
 }
 
-async fn list_files(
-    state: web::Data<AppState>,
-    query: web::Query<PathQuery>,
-) -> Result<HttpResponse> {
+async fn list_files(state: RootState, query: web::Query<PathQuery>) -> Result<HttpResponse> {
+    if let Some((archive_path, inner_dir)) =
+        query.path.as_deref().and_then(archive::split_archive_path)
+    {
+        return list_archive_entries(&state, archive_path, inner_dir).await;
+    }
+
+    // The tree index only covers the local backend
+    if state.storage.local_root().is_none() {
+        return list_storage_entries(&state, query.path.as_deref().unwrap_or("")).await;
+    }
+
     let base_path = resolve_path_safe(&state.upload_dir, query.path.as_ref())
         .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
 
@@ -177,47 +625,341 @@ async fn list_files(
         return Ok(HttpResponse::Ok().json(Vec::<FileEntry>::new()));
     }
 
-    let mut entries = Vec::new();
-    let mut dir = tokio::fs::read_dir(&base_path).await?;
+    let tree = state.tree.clone();
+    let media_queue = state.media_queue.clone();
+    let rel_path = relative_to_root(&state.upload_dir, &base_path);
+    let mut entries: Vec<FileEntry> = web::block(move || {
+        tree.children(&rel_path)
+            .into_iter()
+            .map(|entry| {
+                // Media details come from the index only; missing ones are extracted in
+                // the background and show up in later listings
+                let media = entry.media;
+                if media.is_none()
+                    && !entry.is_dir
+                    && tree.is_ready()
+                    && media::kind(&entry.name).is_some()
+                {
+                    // A full queue just means the file is picked up by a later listing
+                    let child = storage::join(&rel_path, &entry.name);
+                    let _ = media_queue.try_send((child, entry.modified));
+                }
+                FileEntry {
+                    name: entry.name,
+                    is_dir: entry.is_dir,
+                    size: entry.size,
+                    modified: entry.modified,
+                    width: media.as_ref().and_then(|m| m.width),
+                    height: media.as_ref().and_then(|m| m.height),
+                    duration: media.as_ref().and_then(|m| m.duration),
+                }
+            })
+            .collect()
+    })
+    .await?;
 
-    while let Some(entry) = dir.next_entry().await? {
-        let meta = entry.metadata().await?;
-        let modified = meta
-            .modified()
-            .ok()
-            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-            .map(|d| d.as_secs())
-            .unwrap_or(0);
-
-        entries.push(FileEntry {
-            name: entry.file_name().to_string_lossy().to_string(),
-            is_dir: meta.is_dir(),
-            size: meta.len(),
-            modified,
-        });
-    }
+    sort_entries(&mut entries);
+    Ok(HttpResponse::Ok().json(entries))
+}
 
+/// Folders first, then by name, case-insensitively.
+fn sort_entries(entries: &mut [FileEntry]) {
     entries.sort_by(|a, b| {
         b.is_dir
             .cmp(&a.is_dir)
             .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
     });
+}
+
+/// Folder listing straight from a storage backend without a tree index.
+async fn list_storage_entries(state: &AppState, path: &str) -> Result<HttpResponse> {
+    let rel_path = storage::normalize(path);
+    let items = match state.storage.list(&rel_path).await {
+        Ok(items) => items,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut entries: Vec<FileEntry> = items
+        .into_iter()
+        .filter(|item| !(rel_path.is_empty() && item.name == DATA_DIR))
+        .map(|item| FileEntry {
+            name: item.name,
+            is_dir: item.is_dir,
+            size: item.size,
+            modified: item.modified,
+            ..Default::default()
+        })
+        .collect();
+    sort_entries(&mut entries);
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+/// The item at `rel_path` in storage, or None if there is nothing there.
+async fn find_item(state: &AppState, rel_path: &str) -> Result<Option<storage::Entry>> {
+    match state.storage.stat(rel_path).await {
+        Ok(entry) => Ok(Some(entry)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The upload dir, for handlers that read files there directly. Backends that keep
+/// files elsewhere, or encrypted, have nothing readable in it, so `feature` is refused.
+fn plain_files<'a>(state: &'a AppState, feature: &str) -> Result<&'a Path> {
+    state
+        .storage
+        .local_root()
+        .ok_or_else(|| unavailable(state, feature))
+}
+
+/// Why a root's files can't be read from the upload dir.
+fn unreadable_reason(storage: &dyn storage::Storage) -> &'static str {
+    if storage.disk_root().is_some() {
+        "its files are encrypted"
+    } else {
+        "its files are not on local disk"
+    }
+}
+
+/// 503 for a feature that needs the files as they are on local disk.
+fn unavailable(state: &AppState, feature: &str) -> actix_web::Error {
+    actix_web::error::ErrorServiceUnavailable(format!(
+        "{}: not available for root {}, as {}",
+        feature,
+        state.root,
+        unreadable_reason(state.storage.as_ref())
+    ))
+}
+
+async fn no_s3(state: web::Data<AppState>) -> Result<HttpResponse> {
+    Err(unavailable(&state, "The S3 API"))
+}
 
+async fn no_webdav(state: web::Data<AppState>) -> Result<HttpResponse> {
+    Err(unavailable(&state, "WebDAV"))
+}
+
+/// Tell at startup what a root without readable local files goes without.
+/// `hashing` and `content_index` are whether those were asked for.
+fn warn_unreadable_features(
+    root: &str,
+    storage: &dyn storage::Storage,
+    hashing: bool,
+    content_index: bool,
+) {
+    let mut off = vec![
+        "thumbnails",
+        "media metadata",
+        "archive browsing",
+        "the WebDAV and S3 APIs",
+    ];
+    if hashing {
+        off.push("content hashing and duplicate detection");
+    }
+    if content_index {
+        off.push("content search");
+    }
+    if storage.disk_root().is_none() {
+        off.push("disk usage");
+    }
+    for feature in off {
+        eprintln!(
+            "Root {}: {} off, as {}",
+            root,
+            feature,
+            unreadable_reason(storage)
+        );
+    }
+}
+
+/// Resolve the archive part of an `archive!/inner` path to a file on disk.
+fn resolve_archive_safe(base: &Path, archive_path: &str) -> Result<PathBuf> {
+    let filepath = resolve_path_safe(base, Some(&archive_path.to_string()))
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+
+    if !filepath.is_file() {
+        return Err(actix_web::error::ErrorNotFound("Archive not found"));
+    }
+
+    Ok(filepath)
+}
+
+fn archive_error(e: std::io::Error) -> actix_web::Error {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => actix_web::error::ErrorNotFound(e.to_string()),
+        std::io::ErrorKind::InvalidData | std::io::ErrorKind::UnexpectedEof => {
+            actix_web::error::ErrorBadRequest(format!("Unreadable archive: {}", e))
+        }
+        _ => actix_web::error::ErrorInternalServerError(e),
+    }
+}
+
+async fn list_archive_entries(
+    state: &AppState,
+    archive_path: &str,
+    inner_dir: &str,
+) -> Result<HttpResponse> {
+    let filepath = resolve_archive_safe(plain_files(state, "Archive browsing")?, archive_path)?;
+    let inner_dir = inner_dir.to_string();
+
+    let mut entries = web::block(move || archive::list_entries(&filepath, &inner_dir))
+        .await?
+        .map_err(archive_error)?;
+
+    sort_entries(&mut entries);
     Ok(HttpResponse::Ok().json(entries))
 }
 
+#[derive(Deserialize)]
+struct UploadQuery {
+    path: Option<String>,
+    /// Default conflict policy for this upload, `rename` if not given
+    on_conflict: Option<ConflictPolicy>,
+}
+
+#[derive(Serialize)]
+struct UploadResult {
+    /// Name as sent by the client (relative path for folder uploads)
+    name: String,
+    /// Where the file ended up, relative to the upload root
+    path: String,
+    /// created, renamed, overwritten or skipped
+    status: &'static str,
+    /// Previous version kept when overwriting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    revision: Option<String>,
+}
+
+fn relative_to_root(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Where the version of a file that is replaced now is kept:
+/// `.boxy/revisions/<path>/<unix millis>`.
+fn revision_path(rel_path: &str) -> String {
+    let stamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    format!("{}/{}/{}", REVISIONS_DIR, rel_path, stamp)
+}
+
+/// Move the current version of a file into the revision store before it is replaced.
+async fn keep_revision(state: &AppState, filepath: &Path) -> std::io::Result<PathBuf> {
+    let rel = relative_to_root(&state.upload_dir, filepath);
+    let revision = state.upload_dir.join(revision_path(&rel));
+    if let Some(revision_dir) = revision.parent() {
+        tokio::fs::create_dir_all(revision_dir).await?;
+    }
+    tokio::fs::rename(filepath, &revision).await?;
+    prune_revisions(state, &format!("{}/{}", REVISIONS_DIR, rel)).await;
+    index_kept(&state.revision_tree, &rel).await;
+    Ok(revision)
+}
+
+/// How many old versions of a file the revision store keeps, and for how long.
+#[derive(Clone, Copy)]
+struct RevisionRetention {
+    /// Newest revisions kept per file
+    keep: usize,
+    /// Older revisions are dropped; 0 keeps them regardless of age
+    max_age_secs: u64,
+}
+
+/// Drop the revisions in `folder` (one file's, named by unix millis) beyond the
+/// retention count or age. Returns how many are left.
+async fn prune_revision_folder(state: &AppState, folder: &str, mut stamps: Vec<u128>) -> usize {
+    let retention = state.revisions;
+    let cutoff = match retention.max_age_secs {
+        0 => 0,
+        secs => u128::from(unix_now().saturating_sub(secs)) * 1000,
+    };
+    stamps.sort_unstable_by(|a, b| b.cmp(a));
+    let mut kept = 0;
+    for (i, stamp) in stamps.into_iter().enumerate() {
+        if i < retention.keep && stamp >= cutoff {
+            kept += 1;
+        } else if let Err(e) = state
+            .storage
+            .delete(&storage::join(folder, &stamp.to_string()))
+            .await
+        {
+            eprintln!("Pruning revision {}/{}: {}", folder, stamp, e);
+        }
+    }
+    kept
+}
+
+/// Apply the retention settings to one file's revisions after a new one was kept.
+async fn prune_revisions(state: &AppState, folder: &str) {
+    let stamps = match state.storage.list(folder).await {
+        Ok(entries) => entries
+            .iter()
+            .filter(|e| !e.is_dir)
+            .filter_map(|e| e.name.parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    prune_revision_folder(state, folder, stamps).await;
+}
+
+/// Apply the retention settings to the whole revision store, removing folders left
+/// empty, so revisions of files that are never overwritten again still age out.
+async fn prune_all_revisions(state: &AppState) {
+    // Folders are pruned after their children, so ones left empty can go too
+    let mut pending = vec![(REVISIONS_DIR.to_string(), false)];
+    while let Some((folder, children_done)) = pending.pop() {
+        let Ok(entries) = state.storage.list(&folder).await else {
+            continue;
+        };
+        if !children_done {
+            pending.push((folder.clone(), true));
+            for entry in entries.iter().filter(|e| e.is_dir) {
+                pending.push((storage::join(&folder, &entry.name), false));
+            }
+            continue;
+        }
+        let stamps: Vec<u128> = entries
+            .iter()
+            .filter(|e| !e.is_dir)
+            .filter_map(|e| e.name.parse().ok())
+            .collect();
+        let others = entries.len() - stamps.len();
+        let kept = prune_revision_folder(state, &folder, stamps).await;
+        if others + kept == 0 && folder != REVISIONS_DIR {
+            let _ = state.storage.delete(&folder).await;
+        }
+    }
+}
+
 async fn upload_file(
     mut payload: Multipart,
-    query: web::Query<PathQuery>,
-    state: web::Data<AppState>,
+    query: web::Query<UploadQuery>,
+    state: RootState,
 ) -> Result<HttpResponse> {
-    let base_path = resolve_path_safe(&state.upload_dir, query.path.as_ref())
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    check_writable(&state)?;
+    let base_path = storage::normalize(query.path.as_deref().unwrap_or(""));
+    reject_data_path(&base_path)?;
+    let default_policy = query.on_conflict.unwrap_or(ConflictPolicy::Rename);
 
-    tokio::fs::create_dir_all(&base_path).await?;
+    state.storage.mkdir(&base_path).await?;
 
+    let quotas = quota::Tracker::new(&state, &base_path, true).await?;
     let mut uploaded = Vec::new();
     let mut mtimes: std::collections::HashMap<String, u64> = std::collections::HashMap::new();
+    let mut policies: std::collections::HashMap<String, ConflictPolicy> =
+        std::collections::HashMap::new();
 
     while let Some(item) = payload.next().await {
         let mut field = item?;
@@ -237,6 +979,18 @@ async fn upload_file(
             continue;
         }
 
+        // Per-file conflict policies, keyed by filename like mtimes
+        if field_name == "policies" {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.next().await {
+                bytes.extend_from_slice(&chunk?);
+            }
+            policies = serde_json::from_slice(&bytes).map_err(|e| {
+                actix_web::error::ErrorBadRequest(format!("Invalid policies: {}", e))
+            })?;
+            continue;
+        }
+
         let filename = field
             .content_disposition()
             .and_then(|cd| cd.get_filename().map(|s| s.to_string()))
@@ -244,57 +998,180 @@ async fn upload_file(
 
         // Support nested paths for folder uploads - clean each segment
         let clean_path = clean_relative_path(&filename);
-        let filepath = base_path.join(&clean_path);
-
-        // Create parent directories if needed (for folder uploads)
-        if let Some(parent) = filepath.parent() {
-            tokio::fs::create_dir_all(parent).await?;
-        }
-
-        // Handle filename conflicts
-        let filepath = get_unique_filepath(&filepath).await;
         let final_name = clean_path.to_string_lossy().to_string();
+        let target = storage::join(&base_path, &final_name);
+        reject_data_path(&target)?;
+
+        // Handle filename conflicts; parent folders are created by the backend
+        let policy = policies.get(&filename).copied().unwrap_or(default_policy);
+        let (rel_path, status) = match find_item(&state, &target).await? {
+            None => (target.clone(), "created"),
+            Some(existing) => match policy {
+                ConflictPolicy::Fail => {
+                    return Ok(HttpResponse::Conflict().json(serde_json::json!({
+                        "error": format!("{} already exists", final_name),
+                        "files": uploaded,
+                    })));
+                }
+                ConflictPolicy::Skip => {
+                    while let Some(chunk) = field.next().await {
+                        chunk?;
+                    }
+                    uploaded.push(UploadResult {
+                        name: final_name,
+                        path: target,
+                        status: "skipped",
+                        revision: None,
+                    });
+                    continue;
+                }
+                // A folder can't be replaced by a file, fall back to a new name
+                ConflictPolicy::Rename | ConflictPolicy::Overwrite if existing.is_dir => {
+                    (unique_path(&state, &target).await?, "renamed")
+                }
+                ConflictPolicy::Rename => (unique_path(&state, &target).await?, "renamed"),
+                ConflictPolicy::Overwrite => (target, "overwritten"),
+            },
+        };
 
-        let mut file = tokio::fs::File::create(&filepath).await?;
-
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            file.write_all(&data).await?;
-        }
+        // Refuse a file over the count before streaming it, and stop streaming as soon
+        // as it outgrows what is left of the byte quotas
+        let new_files = u64::from(status != "overwritten");
+        quotas.claim(&rel_path, new_files, 0)?;
+        let limit = UploadLimit::new(&state, &quotas, &rel_path);
+
+        let (revision, (_, hash)) = match state.storage.disk_root() {
+            // Stream to a staging file so the old version survives a failed upload,
+            // then move the old version into the revision store
+            Some(root) if status == "overwritten" => {
+                let staging = storage::join(
+                    storage::split(&rel_path).0,
+                    &format!(".upload-{}", uuid::Uuid::new_v4()),
+                );
+                let replaced = async {
+                    let written = write_field(&state, &staging, &mut field, &limit).await?;
+                    let revision = keep_revision(&state, &root.join(&rel_path)).await?;
+                    state.storage.rename(&staging, &rel_path).await?;
+                    Ok::<_, actix_web::Error>((revision, written))
+                }
+                .await;
+                let (revision, written) = match replaced {
+                    Ok(replaced) => replaced,
+                    Err(e) => {
+                        let _ = state.storage.delete(&staging).await;
+                        return Err(e);
+                    }
+                };
+                (
+                    Some(relative_to_root(&state.upload_dir, &revision)),
+                    written,
+                )
+            }
+            // Where copies share their contents, keeping the old version is free
+            None if status == "overwritten" && state.storage.copies_are_free() => {
+                let revision = revision_path(&rel_path);
+                state.storage.copy(&rel_path, &revision).await?;
+                prune_revisions(&state, storage::split(&revision).0).await;
+                (
+                    Some(revision),
+                    write_field(&state, &rel_path, &mut field, &limit).await?,
+                )
+            }
+            _ => (
+                None,
+                write_field(&state, &rel_path, &mut field, &limit).await?,
+            ),
+        };
 
         // Preserve original modification time if provided
-        if let Some(&mtime_ms) = mtimes.get(&filename) {
+        if let (Some(&mtime_ms), Some(root)) = (mtimes.get(&filename), state.storage.disk_root()) {
             let mtime = filetime::FileTime::from_unix_time(
                 (mtime_ms / 1000) as i64,
                 ((mtime_ms % 1000) * 1_000_000) as u32,
             );
-            let _ = filetime::set_file_mtime(&filepath, mtime);
+            let _ = filetime::set_file_mtime(root.join(&rel_path), mtime);
         }
 
-        let rel_path = query
-            .path
-            .as_ref()
-            .map(|p| format!("{}/{}", p, final_name))
-            .unwrap_or(final_name.clone());
-
+        // After the mtime is set, which the hash is recorded against
+        if let Some(hash) = hash {
+            state.hashes.record(&rel_path, hash);
+        }
+        reindex_paths(&state, [state.upload_dir.join(&rel_path).as_path()]).await;
         broadcast_update(&state.broadcaster, "upload", &rel_path);
-        uploaded.push(final_name);
+        uploaded.push(UploadResult {
+            name: final_name,
+            path: rel_path,
+            status,
+            revision,
+        });
     }
 
     Ok(HttpResponse::Ok().json(uploaded))
 }
 
-async fn get_unique_filepath(original: &Path) -> PathBuf {
-    if !original.exists() {
-        return original.to_path_buf();
+/// Stream a multipart field into storage, up to `limit`, hashing it on the way when
+/// content hashing is on. Fields can't leave the request's thread, so their chunks are
+/// handed to the backend through a channel.
+async fn write_field(
+    state: &AppState,
+    rel_path: &str,
+    field: &mut actix_multipart::Field,
+    limit: &UploadLimit<'_>,
+) -> Result<(u64, Option<hash_index::Hash>)> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<web::Bytes>>(8);
+    let body: storage::ByteStream = Box::pin(futures_util::stream::unfold(rx, |mut rx| async {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+
+    let mut field_error: Option<actix_web::Error> = None;
+    let mut hasher = state.hashes.is_enabled().then(blake3::Hasher::new);
+    let pump = async {
+        let mut received = 0u64;
+        while let Some(chunk) = field.next().await {
+            match chunk {
+                Ok(data) => {
+                    if let Err(e) = limit.admit(received, data.len() as u64) {
+                        let _ = tx
+                            .send(Err(std::io::Error::other("Upload too large")))
+                            .await;
+                        field_error = Some(e);
+                        break;
+                    }
+                    received += data.len() as u64;
+                    if let Some(hasher) = hasher.as_mut() {
+                        hasher.update(&data);
+                    }
+                    if tx.send(Ok(data)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx
+                        .send(Err(std::io::Error::other("Upload interrupted")))
+                        .await;
+                    field_error = Some(e.into());
+                    break;
+                }
+            }
+        }
+        drop(tx);
+    };
+    let (_, written) = futures_util::future::join(pump, state.storage.write(rel_path, body)).await;
+
+    // Report the client's error (size limit, aborted upload) over the backend's
+    if let Some(e) = field_error {
+        return Err(e);
     }
+    Ok((written?, hasher.map(|h| *h.finalize().as_bytes())))
+}
 
-    let parent = original.parent().unwrap_or(Path::new(""));
-    let stem = original
-        .file_stem()
-        .and_then(|s| s.to_str())
-        .unwrap_or("file");
-    let ext = original.extension().and_then(|s| s.to_str());
+/// First free `name_1.ext`, `name_2.ext`, ... next to `rel_path`.
+async fn unique_path(state: &AppState, rel_path: &str) -> Result<String> {
+    let (parent, name) = storage::split(rel_path);
+    let (stem, ext) = match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => (stem, Some(ext)),
+        _ => (name, None),
+    };
 
     let mut counter = 1;
     loop {
@@ -302,9 +1179,9 @@ async fn get_unique_filepath(original: &Path) -> PathBuf {
             Some(e) => format!("{}_{}.{}", stem, counter, e),
             None => format!("{}_{}", stem, counter),
         };
-        let filepath = parent.join(&new_name);
-        if !filepath.exists() {
-            return filepath;
+        let candidate = storage::join(parent, &new_name);
+        if find_item(state, &candidate).await?.is_none() {
+            return Ok(candidate);
         }
         counter += 1;
     }
@@ -316,23 +1193,14 @@ struct CreateFolderReq {
     path: Option<String>,
 }
 
-async fn create_folder(
-    body: web::Json<CreateFolderReq>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let base = resolve_path_safe(&state.upload_dir, body.path.as_ref())
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
-
+async fn create_folder(body: web::Json<CreateFolderReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
     let safe_name = body.name.replace(['/', '\\', '\0'], "_");
-    let folder_path = base.join(&safe_name);
-
-    tokio::fs::create_dir_all(&folder_path).await?;
+    let rel_path = storage::join(body.path.as_deref().unwrap_or(""), &safe_name);
+    reject_data_path(&rel_path)?;
 
-    let rel_path = body
-        .path
-        .as_ref()
-        .map(|p| format!("{}/{}", p, safe_name))
-        .unwrap_or(safe_name);
+    state.storage.mkdir(&rel_path).await?;
+    reindex_paths(&state, [state.upload_dir.join(&rel_path).as_path()]).await;
 
     broadcast_update(&state.broadcaster, "folder", &rel_path);
 
@@ -344,38 +1212,301 @@ struct DeleteReq {
     path: String,
 }
 
-#[derive(Deserialize)]
-struct RenameReq {
-    path: String,
-    new_name: String,
+// === Reversible Operations ===
+// Rename, move, copy and delete are applied through `apply_*` so that the single-item
+// handlers and `/api/batch` share one implementation. Each returns an `Applied` record
+// that can be reverted (batch rollback, `/api/undo`) or committed (purging anything kept
+// for rollback) once it leaves the undo journal.
+
+const TRASH_DIR: &str = ".boxy/trash";
+const REVISIONS_DIR: &str = ".boxy/revisions";
+
+/// A mutation that has been applied to the upload tree.
+enum Applied {
+    /// Item moved from `from` to `to` (rename and move)
+    Moved { from: PathBuf, to: PathBuf },
+    /// New item created at `path` (copy), optionally replacing an item parked in the trash
+    Created {
+        path: PathBuf,
+        replaced: Option<PathBuf>,
+    },
+    /// Deleted item parked in the trash until the operation is committed
+    Trashed { original: PathBuf, trash: PathBuf },
+    /// File content overwritten, previous version kept in the trash
+    Edited { path: PathBuf, backup: PathBuf },
+    /// Change made on a storage backend without local files, which can't be undone
+    Untracked,
 }
 
-async fn rename_item(
-    body: web::Json<RenameReq>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let old_path = resolve_path_safe(&state.upload_dir, Some(&body.path))
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
-    let safe_name = body.new_name.replace(['/', '\\', '\0'], "_");
-
-    if !old_path.exists() {
-        return Err(actix_web::error::ErrorNotFound("Item not found"));
+impl Applied {
+    /// Revert the change. Content it would discard (a copy, an edited version) is
+    /// parked in `trash_dir` instead, so the change can still be rolled forward with
+    /// `reapply`; the parked path is returned and is the caller's to purge.
+    async fn revert(&self, trash_dir: &Path) -> std::io::Result<Option<PathBuf>> {
+        let park = |path: PathBuf| async move {
+            tokio::fs::create_dir_all(trash_dir).await?;
+            let parked = trash_dir.join(uuid::Uuid::new_v4().to_string());
+            tokio::fs::rename(&path, &parked).await?;
+            Ok::<_, std::io::Error>(parked)
+        };
+        match self {
+            Applied::Moved { from, to } => tokio::fs::rename(to, from).await.map(|_| None),
+            Applied::Created { path, replaced } => {
+                let parked = park(path.clone()).await?;
+                if let Some(trash) = replaced {
+                    if let Err(e) = tokio::fs::rename(trash, path).await {
+                        let _ = tokio::fs::rename(&parked, path).await;
+                        return Err(e);
+                    }
+                }
+                Ok(Some(parked))
+            }
+            Applied::Trashed { original, trash } => {
+                if let Some(parent) = original.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                tokio::fs::rename(trash, original).await.map(|_| None)
+            }
+            Applied::Edited { path, backup } => {
+                let parked = park(path.clone()).await?;
+                if let Err(e) = tokio::fs::rename(backup, path).await {
+                    let _ = tokio::fs::rename(&parked, path).await;
+                    return Err(e);
+                }
+                Ok(Some(parked))
+            }
+            Applied::Untracked => Err(std::io::Error::other("Change cannot be undone")),
+        }
     }
 
-    let parent = old_path
-        .parent()
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid path for rename"))?;
-    let new_path = parent.join(&safe_name);
-
-    if new_path.exists() {
+    /// Apply the change again after `revert`, given what the revert parked.
+    async fn reapply(&self, parked: Option<&Path>) -> std::io::Result<()> {
+        match (self, parked) {
+            (Applied::Moved { from, to }, _) => tokio::fs::rename(from, to).await,
+            (Applied::Created { path, replaced }, Some(parked)) => {
+                if let Some(trash) = replaced {
+                    tokio::fs::rename(path, trash).await?;
+                }
+                tokio::fs::rename(parked, path).await
+            }
+            (Applied::Trashed { original, trash }, _) => tokio::fs::rename(original, trash).await,
+            (Applied::Edited { path, backup }, Some(parked)) => {
+                tokio::fs::rename(path, backup).await?;
+                tokio::fs::rename(parked, path).await
+            }
+            _ => Err(std::io::Error::other("Change cannot be reapplied")),
+        }
+    }
+
+    /// Whether the tree still looks the way this change left it, so reverting is safe.
+    fn can_revert(&self) -> bool {
+        match self {
+            Applied::Moved { from, to } => to.exists() && !from.exists(),
+            Applied::Created { path, .. } => path.exists(),
+            Applied::Trashed { original, trash } => trash.exists() && !original.exists(),
+            Applied::Edited { path, backup } => path.is_file() && backup.exists(),
+            Applied::Untracked => false,
+        }
+    }
+
+    /// Paths in the upload tree whose contents this change affected.
+    fn touched(&self) -> Vec<&Path> {
+        match self {
+            Applied::Moved { from, to } => vec![from, to],
+            Applied::Created { path, .. }
+            | Applied::Trashed { original: path, .. }
+            | Applied::Edited { path, .. } => vec![path],
+            Applied::Untracked => vec![],
+        }
+    }
+
+    async fn commit(&self) {
+        match self {
+            Applied::Trashed { trash, .. }
+            | Applied::Edited { backup: trash, .. }
+            | Applied::Created {
+                replaced: Some(trash),
+                ..
+            } => {
+                let _ = remove_path(trash).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+struct OpOutcome {
+    /// WebSocket action name
+    action: &'static str,
+    /// Path reported in the WebSocket event
+    path: String,
+    /// Extra fields for the response body
+    details: serde_json::Value,
+    /// None when the request turned out to be a no-op
+    applied: Option<Applied>,
+}
+
+async fn remove_path(path: &Path) -> std::io::Result<()> {
+    if tokio::fs::symlink_metadata(path).await?.is_dir() {
+        tokio::fs::remove_dir_all(path).await
+    } else {
+        tokio::fs::remove_file(path).await
+    }
+}
+
+/// The change as built by `local` when storage is on local disk, otherwise a change
+/// that can't be undone.
+fn local_change(state: &AppState, local: impl FnOnce(&Path) -> Applied) -> Applied {
+    state.storage.disk_root().map_or(Applied::Untracked, local)
+}
+
+/// Park an item in the trash so it can still be restored.
+/// Returns None if the item is the trash itself (or one of its parents).
+async fn move_to_trash(state: &AppState, path: &Path) -> std::io::Result<Option<PathBuf>> {
+    let trash_dir = state.upload_dir.join(TRASH_DIR);
+    if trash_dir.starts_with(path) || path.starts_with(&trash_dir) {
+        return Ok(None);
+    }
+
+    tokio::fs::create_dir_all(&trash_dir).await?;
+    let name = uuid::Uuid::new_v4().to_string();
+    let trash_path = trash_dir.join(&name);
+    tokio::fs::rename(path, &trash_path).await?;
+    index_kept(&state.trash_tree, &name).await;
+    Ok(Some(trash_path))
+}
+
+/// Count a new revision or trash entry in its index right away, rather than once the
+/// watcher gets to it, so a quota check just after can't miss it.
+async fn index_kept(tree: &Arc<tree_index::TreeIndex>, rel_path: &str) {
+    let (tree, rel_path) = (tree.clone(), rel_path.to_string());
+    let _ = web::block(move || tree.refresh(&rel_path)).await;
+}
+
+/// Remember applied changes in the undo journal. Returns the journal id, or None
+/// if nothing in `changes` can be reverted.
+async fn record_undo(
+    state: &AppState,
+    action: &'static str,
+    path: &str,
+    changes: Vec<Applied>,
+) -> Option<u64> {
+    let changes: Vec<Applied> = changes
+        .into_iter()
+        .filter(|c| !matches!(c, Applied::Untracked))
+        .collect();
+    if changes.is_empty() {
+        return None;
+    }
+
+    let (id, evicted) = state
+        .journal
+        .lock()
+        .unwrap()
+        .record(action, path.to_string(), changes);
+    for change in evicted {
+        change.commit().await;
+    }
+    Some(id)
+}
+
+/// Update the tree index for changed paths, drop their cached thumbnails and queue
+/// them for the content index and hashing. Refreshing re-reads whole folders, so it
+/// runs on the blocking pool rather than the worker serving requests.
+async fn reindex_paths<'a>(state: &AppState, paths: impl IntoIterator<Item = &'a Path>) {
+    let rels: Vec<String> = paths
+        .into_iter()
+        .filter_map(|path| path.strip_prefix(&state.upload_dir).ok())
+        .map(|rel| rel.to_string_lossy().to_string())
+        .collect();
+    if rels.is_empty() {
+        return;
+    }
+    for rel in &rels {
+        state.hashes.refresh(rel);
+        if let Some(index) = &state.content_index {
+            index.refresh(rel);
+        }
+    }
+    let (tree, upload_dir) = (state.tree.clone(), state.upload_dir.clone());
+    let _ = web::block(move || {
+        for rel in &rels {
+            tree.refresh(rel);
+            thumbnail::invalidate(&upload_dir, rel);
+        }
+    })
+    .await;
+}
+
+/// Reindex the paths applied changes touched, broadcast the action and journal it.
+/// Returns the undo id, if any of the changes can be undone.
+async fn publish_op(
+    state: &AppState,
+    action: &'static str,
+    path: &str,
+    changes: Vec<Applied>,
+) -> Option<u64> {
+    reindex_paths(state, changes.iter().flat_map(Applied::touched)).await;
+    broadcast_update(&state.broadcaster, action, path);
+    record_undo(state, action, path, changes).await
+}
+
+/// Journal an outcome, broadcast it and build the single-item response.
+async fn finish_op(state: &AppState, outcome: OpOutcome) -> HttpResponse {
+    let mut body = outcome.details;
+
+    if let Some(applied) = outcome.applied {
+        if let Some(id) = publish_op(state, outcome.action, &outcome.path, vec![applied]).await {
+            body["undo_id"] = id.into();
+        }
+    }
+
+    body["success"] = serde_json::Value::Bool(true);
+    HttpResponse::Ok().json(body)
+}
+
+#[derive(Deserialize)]
+struct RenameReq {
+    path: String,
+    new_name: String,
+}
+
+async fn apply_rename(state: &AppState, req: &RenameReq) -> Result<OpOutcome> {
+    let old_path = storage::normalize(&req.path);
+    let safe_name = req.new_name.replace(['/', '\\', '\0'], "_");
+
+    if old_path.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Invalid path for rename"));
+    }
+    reject_data_path(&old_path)?;
+    if find_item(state, &old_path).await?.is_none() {
+        return Err(actix_web::error::ErrorNotFound("Item not found"));
+    }
+
+    let new_path = storage::join(storage::split(&old_path).0, &safe_name);
+    reject_data_path(&new_path)?;
+
+    if find_item(state, &new_path).await?.is_some() {
         return Err(actix_web::error::ErrorConflict("Name already exists"));
     }
 
-    tokio::fs::rename(&old_path, &new_path).await?;
+    state.storage.rename(&old_path, &new_path).await?;
 
-    broadcast_update(&state.broadcaster, "rename", &body.path);
+    Ok(OpOutcome {
+        action: "rename",
+        path: req.path.clone(),
+        details: serde_json::json!({"new_name": safe_name}),
+        applied: Some(local_change(state, |root| Applied::Moved {
+            from: root.join(&old_path),
+            to: root.join(&new_path),
+        })),
+    })
+}
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({"success": true, "new_name": safe_name})))
+async fn rename_item(body: web::Json<RenameReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    let outcome = apply_rename(&state, &body).await?;
+    Ok(finish_op(&state, outcome).await)
 }
 
 #[derive(Deserialize)]
@@ -384,179 +1515,1278 @@ struct MoveReq {
     dest_dir: Option<String>,
 }
 
-async fn move_item(body: web::Json<MoveReq>, state: web::Data<AppState>) -> Result<HttpResponse> {
-    let src_path = resolve_path_safe(&state.upload_dir, Some(&body.path))
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
-    let dest_base = resolve_path_safe(&state.upload_dir, body.dest_dir.as_ref())
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid destination path"))?;
+async fn apply_move(state: &AppState, req: &MoveReq) -> Result<OpOutcome> {
+    let src_path = storage::normalize(&req.path);
+    let dest_base = storage::normalize(req.dest_dir.as_deref().unwrap_or(""));
 
-    if !src_path.exists() {
+    if src_path.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Invalid path"));
+    }
+    reject_data_path(&src_path)?;
+    if find_item(state, &src_path).await?.is_none() {
         return Err(actix_web::error::ErrorNotFound("Item not found"));
     }
 
-    let filename = src_path
-        .file_name()
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid path"))?;
-    let dest_path = dest_base.join(filename);
+    let dest_path = storage::join(&dest_base, storage::split(&src_path).1);
+    reject_data_path(&dest_path)?;
 
-    if dest_path.exists() {
+    if find_item(state, &dest_path).await?.is_some() {
         return Err(actix_web::error::ErrorConflict(
             "Item already exists in destination",
         ));
     }
+    if dest_path.starts_with(&format!("{}/", src_path)) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Cannot move a folder into itself",
+        ));
+    }
+
+    // Quotas the item is already counted in don't change
+    let quotas = quota::Tracker::new(state, &dest_path, false)
+        .await?
+        .without(&src_path);
+    if !quotas.is_empty() {
+        let stats = storage::measure(state.storage.as_ref(), &src_path).await?;
+        quotas.claim(&dest_path, stats.files, stats.bytes)?;
+    }
+
+    state.storage.mkdir(&dest_base).await?;
+    state.storage.rename(&src_path, &dest_path).await?;
+    quotas.finish().await;
+
+    Ok(OpOutcome {
+        action: "move",
+        path: req.path.clone(),
+        details: serde_json::json!({}),
+        applied: Some(local_change(state, |root| Applied::Moved {
+            from: root.join(&src_path),
+            to: root.join(&dest_path),
+        })),
+    })
+}
 
-    tokio::fs::create_dir_all(&dest_base).await?;
-    tokio::fs::rename(&src_path, &dest_path).await?;
+async fn move_item(body: web::Json<MoveReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    let outcome = apply_move(&state, &body).await?;
+    Ok(finish_op(&state, outcome).await)
+}
+
+/// What to do when the destination of a copy or upload already exists.
+#[derive(Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ConflictPolicy {
+    #[default]
+    Fail,
+    Rename,
+    Overwrite,
+    Skip,
+}
 
-    broadcast_update(&state.broadcaster, "move", &body.path);
+#[derive(Deserialize)]
+struct CopyReq {
+    path: String,
+    dest_dir: Option<String>,
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+#[derive(Serialize)]
+struct CopyProgressMessage<'a> {
+    action: &'a str,
+    path: &'a str,
+    done: copy::CopyStats,
+    total: copy::CopyStats,
+}
+
+fn broadcast_copy_progress(
+    tx: &Broadcaster,
+    path: &str,
+    done: copy::CopyStats,
+    total: copy::CopyStats,
+) {
+    let msg = serde_json::to_string(&CopyProgressMessage {
+        action: "copy_progress",
+        path,
+        done,
+        total,
+    })
+    .unwrap_or_default();
+    let _ = tx.send(msg);
+}
+
+async fn apply_copy(state: &AppState, req: &CopyReq) -> Result<OpOutcome> {
+    let src_path = storage::normalize(&req.path);
+    let dest_base = storage::normalize(req.dest_dir.as_deref().unwrap_or(""));
+
+    if src_path.is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("Invalid path"));
+    }
+    reject_data_path(&src_path)?;
+    let src = find_item(state, &src_path)
+        .await?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Item not found"))?;
+
+    if src.is_dir && (dest_base == src_path || dest_base.starts_with(&format!("{}/", src_path))) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Cannot copy a folder into itself",
+        ));
+    }
+
+    let mut dest_path = storage::join(&dest_base, &src.name);
+    reject_data_path(&dest_path)?;
+    let mut replacing = false;
+
+    if find_item(state, &dest_path).await?.is_some() {
+        match req.on_conflict {
+            ConflictPolicy::Fail => {
+                return Err(actix_web::error::ErrorConflict(
+                    "Item already exists in destination",
+                ))
+            }
+            ConflictPolicy::Skip => {
+                return Ok(OpOutcome {
+                    action: "copy",
+                    path: req.path.clone(),
+                    details: serde_json::json!({"skipped": true}),
+                    applied: None,
+                })
+            }
+            ConflictPolicy::Rename => dest_path = unique_path(state, &dest_path).await?,
+            ConflictPolicy::Overwrite if dest_path == src_path => {
+                return Err(actix_web::error::ErrorBadRequest(
+                    "Cannot overwrite an item with itself",
+                ))
+            }
+            ConflictPolicy::Overwrite => replacing = true,
+        }
+    }
+
+    state.storage.mkdir(&dest_base).await?;
+    let (stats, applied) = match state.storage.disk_root() {
+        Some(root) => {
+            let dest = root.join(&dest_path);
+            let (stats, replaced) =
+                copy_to_path(state, &root.join(&src_path), &dest, &dest_path).await?;
+            let applied = Applied::Created {
+                path: dest,
+                replaced,
+            };
+            (stats, applied)
+        }
+        // Other backends copy in one step, without progress or a staging copy
+        None => {
+            if replacing {
+                state.storage.delete(&dest_path).await?;
+            }
+            let stats = storage::measure(state.storage.as_ref(), &src_path).await?;
+            let _quotas = quota::check(state, &dest_path, stats.files, stats.bytes).await?;
+            state.storage.copy(&src_path, &dest_path).await?;
+            (stats, Applied::Untracked)
+        }
+    };
+
+    Ok(OpOutcome {
+        action: "copy",
+        path: dest_path.clone(),
+        details: serde_json::json!({
+            "path": dest_path,
+            "files": stats.files,
+            "bytes": stats.bytes,
+        }),
+        applied: Some(applied),
+    })
+}
+
+/// Copy `src_path` to `dest_path`, whose parent must exist, parking anything already
+/// there in the trash. `dest_rel` is checked against quotas and progress is broadcast
+/// under it. Returns the copy totals and the trashed item.
+async fn copy_to_path(
+    state: &AppState,
+    src_path: &Path,
+    dest_path: &Path,
+    dest_rel: &str,
+) -> Result<(copy::CopyStats, Option<PathBuf>)> {
+    let dest_base = dest_path
+        .parent()
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid destination path"))?;
+    let final_name = dest_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    // Copy into a hidden staging name first so clients never see a half-copied tree,
+    // and an overwrite only replaces the existing item once the copy has succeeded.
+    let quotas = quota::Tracker::new(state, dest_rel, false).await?;
+    if !quotas.is_empty() {
+        let src = src_path.to_path_buf();
+        let stats = web::block(move || copy::measure_tree(&src)).await??;
+        quotas.claim(dest_rel, stats.files, stats.bytes)?;
+    }
+
+    let staging_path = dest_base.join(format!(".{}.copy-{}", final_name, uuid::Uuid::new_v4()));
+    let tx = state.broadcaster.clone();
+    let progress_path = dest_rel.to_string();
+    let (src, staging) = (src_path.to_path_buf(), staging_path.clone());
+    let stats = web::block(move || {
+        let total = copy::measure_tree(&src)?;
+        let result = copy::copy_tree(&src, &staging, &mut |done| {
+            broadcast_copy_progress(&tx, &progress_path, done, total)
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_dir_all(&staging).or_else(|_| std::fs::remove_file(&staging));
+        }
+        result
+    })
+    .await??;
+
+    let replaced = if dest_path.exists() {
+        move_to_trash(state, dest_path).await?
+    } else {
+        None
+    };
+    if let Err(e) = tokio::fs::rename(&staging_path, dest_path).await {
+        if let Some(trash) = replaced {
+            tokio::fs::rename(&trash, dest_path).await?;
+        }
+        let _ = remove_path(&staging_path).await;
+        return Err(e.into());
+    }
+    quotas.finish().await;
+    Ok((stats, replaced))
+}
+
+async fn copy_item(body: web::Json<CopyReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    let outcome = apply_copy(&state, &body).await?;
+    Ok(finish_op(&state, outcome).await)
+}
+
+async fn apply_delete(state: &AppState, req: &DeleteReq) -> Result<OpOutcome> {
+    let rel_path = storage::normalize(&req.path);
+    // The root and the data dir hold the trash, so they could only be deleted for good
+    if rel_path.is_empty() || storage::is_within(&rel_path, DATA_DIR) {
+        return Err(actix_web::error::ErrorForbidden("Cannot delete this path"));
+    }
+
+    let applied = match (
+        find_item(state, &rel_path).await?,
+        state.storage.disk_root(),
+    ) {
+        (None, _) => None,
+        (Some(_), Some(root)) => {
+            let filepath = root.join(&rel_path);
+            let trash = move_to_trash(state, &filepath)
+                .await?
+                .ok_or_else(|| actix_web::error::ErrorForbidden("Cannot delete this path"))?;
+            Some(Applied::Trashed {
+                original: filepath,
+                trash,
+            })
+        }
+        (Some(_), None) => {
+            state.storage.delete(&rel_path).await?;
+            Some(Applied::Untracked)
+        }
+    };
+
+    Ok(OpOutcome {
+        action: "delete",
+        path: req.path.clone(),
+        details: serde_json::json!({}),
+        applied,
+    })
+}
+
+// === Batch Operations ===
+
+#[derive(Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum BatchOp {
+    Move(MoveReq),
+    Copy(CopyReq),
+    Delete(DeleteReq),
+    Rename(RenameReq),
+}
+
+impl BatchOp {
+    fn name(&self) -> &'static str {
+        match self {
+            BatchOp::Move(_) => "move",
+            BatchOp::Copy(_) => "copy",
+            BatchOp::Delete(_) => "delete",
+            BatchOp::Rename(_) => "rename",
+        }
+    }
+
+    fn path(&self) -> &str {
+        match self {
+            BatchOp::Move(req) => &req.path,
+            BatchOp::Copy(req) => &req.path,
+            BatchOp::Delete(req) => &req.path,
+            BatchOp::Rename(req) => &req.path,
+        }
+    }
+
+    /// Refuse the operation if its source or destination is in the data dir. A batch
+    /// checks every item up front, so none runs (and none needs rolling back) if one
+    /// would move the trash that rollback restores from.
+    fn reject_data_paths(&self) -> Result<()> {
+        reject_data_path(&storage::normalize(self.path()))?;
+        match self {
+            BatchOp::Move(MoveReq { dest_dir, .. }) | BatchOp::Copy(CopyReq { dest_dir, .. }) => {
+                reject_data_path(&storage::normalize(dest_dir.as_deref().unwrap_or("")))
+            }
+            BatchOp::Rename(req) => reject_data_path(&storage::join(
+                storage::split(&storage::normalize(&req.path)).0,
+                &req.new_name.replace(['/', '\\', '\0'], "_"),
+            )),
+            BatchOp::Delete(_) => Ok(()),
+        }
+    }
+}
+
+const MAX_BATCH_OPERATIONS: usize = 1000;
+
+#[derive(Deserialize)]
+struct BatchReq {
+    operations: Vec<BatchOp>,
+    /// Stop at the first failure and revert everything applied so far
+    #[serde(default)]
+    atomic: bool,
+}
+
+#[derive(Serialize)]
+struct BatchMessage {
+    action: String,
+    path: String,
+    items: Vec<WsMessage>,
+}
+
+async fn batch_operations(body: web::Json<BatchReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    if body.operations.len() > MAX_BATCH_OPERATIONS {
+        return Err(actix_web::error::ErrorBadRequest(format!(
+            "Too many operations (max {})",
+            MAX_BATCH_OPERATIONS
+        )));
+    }
+    for op in &body.operations {
+        op.reject_data_paths()?;
+    }
+
+    let mut results = Vec::with_capacity(body.operations.len());
+    let mut outcomes: Vec<(usize, OpOutcome)> = Vec::new();
+    let mut failed = false;
+
+    for (index, op) in body.operations.iter().enumerate() {
+        if failed && body.atomic {
+            results.push(serde_json::json!({
+                "index": index,
+                "op": op.name(),
+                "path": op.path(),
+                "success": false,
+                "skipped": true,
+            }));
+            continue;
+        }
+
+        let outcome = match op {
+            BatchOp::Move(req) => apply_move(&state, req).await,
+            BatchOp::Copy(req) => apply_copy(&state, req).await,
+            BatchOp::Delete(req) => apply_delete(&state, req).await,
+            BatchOp::Rename(req) => apply_rename(&state, req).await,
+        };
+
+        match outcome {
+            Ok(outcome) => {
+                results.push(serde_json::json!({
+                    "index": index,
+                    "op": op.name(),
+                    "path": op.path(),
+                    "success": true,
+                    "result": outcome.details,
+                }));
+                outcomes.push((index, outcome));
+            }
+            Err(e) => {
+                failed = true;
+                results.push(serde_json::json!({
+                    "index": index,
+                    "op": op.name(),
+                    "path": op.path(),
+                    "success": false,
+                    "status": e.as_response_error().status_code().as_u16(),
+                    "error": e.to_string(),
+                }));
+            }
+        }
+    }
+
+    let rolled_back = failed && body.atomic;
+    let mut items = Vec::new();
+    let mut undo_id = None;
+
+    if rolled_back {
+        let trash_dir = state.upload_dir.join(TRASH_DIR);
+        // Undo in reverse order so later operations that built on earlier ones unwind first
+        for (index, outcome) in outcomes.iter().rev() {
+            if let Some(applied) = &outcome.applied {
+                let result = &mut results[*index];
+                reindex_paths(&state, applied.touched()).await;
+                result["success"] = false.into();
+                match applied.revert(&trash_dir).await {
+                    Ok(parked) => {
+                        if let Some(parked) = parked {
+                            let _ = remove_path(&parked).await;
+                        }
+                        result["rolled_back"] = true.into();
+                    }
+                    Err(e) => {
+                        // Still changed on disk, so clients need to hear about it
+                        result["rolled_back"] = false.into();
+                        result["error"] = format!("Rollback failed: {}", e).into();
+                        items.push(WsMessage {
+                            action: outcome.action.to_string(),
+                            path: outcome.path.clone(),
+                        });
+                    }
+                }
+            }
+        }
+    } else {
+        let mut changes = Vec::new();
+        for (_, outcome) in outcomes {
+            if let Some(applied) = outcome.applied {
+                reindex_paths(&state, applied.touched()).await;
+                changes.push(applied);
+                items.push(WsMessage {
+                    action: outcome.action.to_string(),
+                    path: outcome.path,
+                });
+            }
+        }
+        // The whole batch is undone as one step
+        let path = format!(
+            "{} item{}",
+            items.len(),
+            if items.len() == 1 { "" } else { "s" }
+        );
+        undo_id = record_undo(&state, "batch", &path, changes).await;
+    }
+
+    // One aggregated event so clients refresh once instead of once per item
+    if !items.is_empty() {
+        let msg = serde_json::to_string(&BatchMessage {
+            action: "batch".to_string(),
+            path: String::new(),
+            items,
+        })
+        .unwrap_or_default();
+        let _ = state.broadcaster.send(msg);
+    }
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "success": !failed,
+        "rolled_back": rolled_back,
+        "results": results,
+        "undo_id": undo_id,
+    })))
+}
+
+// === Undo ===
+
+#[derive(Deserialize, Default)]
+struct UndoReq {
+    /// Journal entry to undo, defaults to the most recent one
+    id: Option<u64>,
+}
+
+async fn list_undo(state: RootState) -> Result<HttpResponse> {
+    let expired = state.journal.lock().unwrap().expire();
+    for change in expired {
+        change.commit().await;
+    }
+
+    let entries = state.journal.lock().unwrap().list();
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+async fn undo_operation(
+    body: Option<web::Json<UndoReq>>,
+    state: RootState,
+) -> Result<HttpResponse> {
+    let id = body.map(|b| b.into_inner()).unwrap_or_default().id;
+
+    let (expired, entry) = {
+        let mut journal = state.journal.lock().unwrap();
+        (journal.expire(), journal.take(id))
+    };
+    for change in expired {
+        change.commit().await;
+    }
+    let entry = entry.ok_or_else(|| actix_web::error::ErrorNotFound("Nothing to undo"))?;
+
+    // Checked as each change is reverted, newest first, since a change may only be
+    // revertible once the ones after it have been (rename a→b, then move b→d/b)
+    let trash_dir = state.upload_dir.join(TRASH_DIR);
+    let mut reverted = Vec::new();
+    let mut failure = None;
+    for change in entry.changes.iter().rev() {
+        if !change.can_revert() {
+            failure = Some(actix_web::error::ErrorConflict(
+                "Cannot undo: items have changed since",
+            ));
+            break;
+        }
+        match change.revert(&trash_dir).await {
+            Ok(parked) => reverted.push((change, parked)),
+            Err(e) => {
+                failure = Some(e.into());
+                break;
+            }
+        }
+    }
+
+    if let Some(e) = failure {
+        // Roll forward what was reverted and keep the entry, backups included
+        for (change, parked) in reverted.iter().rev() {
+            if let Err(e) = change.reapply(parked.as_deref()).await {
+                eprintln!("Undo {}: rolling forward failed: {}", entry.id, e);
+            }
+        }
+        reindex_paths(&state, entry.changes.iter().flat_map(Applied::touched)).await;
+        state.journal.lock().unwrap().put_back(entry);
+        return Err(e);
+    }
+    for (_, parked) in reverted {
+        if let Some(parked) = parked {
+            let _ = remove_path(&parked).await;
+        }
+    }
+    reindex_paths(&state, entry.changes.iter().flat_map(Applied::touched)).await;
+
+    broadcast_update(&state.broadcaster, "undo", &entry.path);
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "id": entry.id,
+        "action": entry.action,
+        "path": entry.path,
+    })))
+}
+
+/// Every folder in the selected root, or with `root=*` the folders of each root.
+async fn list_all_folders(req: HttpRequest, roots: web::Data<Roots>) -> Result<HttpResponse> {
+    let spans = spans_roots(&req);
+    let mut listed = Vec::new();
+    for state in roots.select(&req)? {
+        let tree = state.tree.clone();
+        let folders = web::block(move || {
+            let mut folders = vec![String::from("/")];
+            collect_folders(&tree, "", &mut folders);
+            folders
+        })
+        .await?;
+        if !spans {
+            return Ok(HttpResponse::Ok().json(folders));
+        }
+        listed.push(serde_json::json!({"root": state.root, "folders": folders}));
+    }
+    Ok(HttpResponse::Ok().json(listed))
+}
+
+fn spans_roots(req: &HttpRequest) -> bool {
+    web::Query::<RootQuery>::from_query(req.query_string())
+        .is_ok_and(|q| q.root.as_deref() == Some(ALL_ROOTS))
+}
+
+fn collect_folders(tree: &tree_index::TreeIndex, prefix: &str, folders: &mut Vec<String>) {
+    for entry in tree.children(prefix) {
+        if entry.is_dir {
+            let full_path = if prefix.is_empty() {
+                entry.name
+            } else {
+                format!("{}/{}", prefix, entry.name)
+            };
+            folders.push(full_path.clone());
+            collect_folders(tree, &full_path, folders);
+        }
+    }
+}
+
+/// Totals below a folder (files, folders, bytes), answered from the tree index.
+async fn folder_stats(state: RootState, query: web::Query<PathQuery>) -> Result<HttpResponse> {
+    let path = resolve_path_safe(&state.upload_dir, query.path.as_ref())
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let rel_path = relative_to_root(&state.upload_dir, &path);
+
+    let tree = state.tree.clone();
+    let totals = web::block(move || tree.totals(&rel_path))
+        .await?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Folder not found"))?;
+    Ok(HttpResponse::Ok().json(totals))
+}
+
+#[derive(Deserialize)]
+struct UsageTreeQuery {
+    path: Option<String>,
+    /// How many of the largest files to list
+    top: Option<usize>,
+}
+
+const DEFAULT_USAGE_TOP: usize = 20;
+
+/// Where the space below a folder goes (child folders, largest files, type groups),
+/// answered from the tree index.
+async fn usage_tree(state: RootState, query: web::Query<UsageTreeQuery>) -> Result<HttpResponse> {
+    // The tree index covers the upload dir, which only local storage keeps files in
+    if state.storage.disk_root().is_none() {
+        return Err(unavailable(&state, "Disk usage"));
+    }
+    let path = resolve_path_safe(&state.upload_dir, query.path.as_ref())
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let rel_path = relative_to_root(&state.upload_dir, &path);
+    if storage::is_within(&rel_path, DATA_DIR) {
+        return Err(actix_web::error::ErrorForbidden("Invalid path"));
+    }
+    let top = query.top.unwrap_or(DEFAULT_USAGE_TOP);
+
+    let tree = state.tree.clone();
+    let usage = web::block(move || tree.usage(&rel_path, top))
+        .await?
+        .ok_or_else(|| actix_web::error::ErrorNotFound("Folder not found"))?;
+    Ok(HttpResponse::Ok().json(usage))
+}
+
+#[derive(Deserialize)]
+struct DuplicatesQuery {
+    path: Option<String>,
+    /// Leave out files smaller than this
+    min_size: Option<u64>,
+}
+
+/// Identical files below a folder, grouped by content hash, with the space that
+/// keeping one copy of each would free.
+async fn list_duplicates(
+    state: RootState,
+    query: web::Query<DuplicatesQuery>,
+) -> Result<HttpResponse> {
+    if !state.hashes.is_enabled() {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "Content hashing disabled",
+        ));
+    }
+    let path = resolve_path_safe(&state.upload_dir, query.path.as_ref())
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let rel_path = relative_to_root(&state.upload_dir, &path);
+    let min_size = query.min_size.unwrap_or(0);
+
+    let hashes = state.hashes.clone();
+    let duplicates = web::block(move || hashes.duplicates(&rel_path, min_size)).await?;
+    Ok(HttpResponse::Ok().json(duplicates))
+}
+
+#[derive(Deserialize)]
+struct LinkDuplicatesReq {
+    /// The copy to keep
+    keep: String,
+    /// Copies to replace with hardlinks to it
+    paths: Vec<String>,
+}
+
+/// Replace duplicates with hardlinks to one copy, freeing their space. The contents are
+/// compared again first; a file that has changed since it was hashed fails with 409.
+async fn link_duplicates(
+    body: web::Json<LinkDuplicatesReq>,
+    state: RootState,
+) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    if !state.hashes.is_enabled() {
+        return Err(actix_web::error::ErrorServiceUnavailable(
+            "Content hashing disabled",
+        ));
+    }
+    let resolve = |path: &str| {
+        let rel = resolve_path_safe(&state.upload_dir, Some(&path.to_string()))
+            .map(|p| relative_to_root(&state.upload_dir, &p))
+            .filter(|rel| !rel.is_empty())
+            .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+        // Revisions and trash entries are what undo restores from
+        reject_data_path(&rel)?;
+        Ok::<_, actix_web::Error>(rel)
+    };
+    let keep = resolve(&body.keep)?;
+    let mut paths = Vec::with_capacity(body.paths.len());
+    for path in &body.paths {
+        let rel = resolve(path)?;
+        if rel != keep && !paths.contains(&rel) {
+            paths.push(rel);
+        }
+    }
+
+    // The replaced files are kept in the trash until the undo window ends
+    let (hashes, link_keep, link_paths) = (state.hashes.clone(), keep.clone(), paths.clone());
+    let trash_dir = state.upload_dir.join(TRASH_DIR);
+    let linked = web::block(move || hashes.link(&link_keep, &link_paths, &trash_dir)).await?;
+
+    // A failed run puts the files back, but may have touched them meanwhile
+    let abs_paths: Vec<PathBuf> = paths.iter().map(|p| state.upload_dir.join(p)).collect();
+    reindex_paths(&state, abs_paths.iter().map(PathBuf::as_path)).await;
+    let linked = linked.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => actix_web::error::ErrorNotFound("File not found"),
+        std::io::ErrorKind::InvalidInput | std::io::ErrorKind::InvalidData => {
+            actix_web::error::ErrorConflict(e.to_string())
+        }
+        _ => actix_web::error::ErrorInternalServerError(e.to_string()),
+    })?;
+    let mut changes = Vec::with_capacity(linked.len());
+    let mut linked_paths = Vec::with_capacity(linked.len());
+    for (path, backup) in linked {
+        broadcast_update(&state.broadcaster, "edit", &path);
+        changes.push(Applied::Edited {
+            path: state.upload_dir.join(&path),
+            backup,
+        });
+        linked_paths.push(path);
+    }
+    let undo_id = record_undo(&state, "link", &keep, changes).await;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "success": true,
+        "linked": linked_paths,
+        "undo_id": undo_id,
+    })))
+}
+
+#[derive(Clone, Serialize)]
+struct SearchResult {
+    name: String,
+    path: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+    /// Relevance, for ranked (fuzzy) searches
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<i64>,
+    /// Matched character indices in `name`, for highlighting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    positions: Option<Vec<usize>>,
+    /// Root the match is in, when searching every root
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+}
+
+const MAX_SEARCH_RESULTS: usize = 100;
+/// Entries a paged search may visit in total, including counting matches past the page.
+const SEARCH_COUNT_BUDGET: usize = 20_000;
+
+#[derive(Serialize)]
+struct SearchPage {
+    results: Vec<SearchResult>,
+    /// Pass as `cursor` to get the next page
+    next_cursor: Option<String>,
+    /// More matches exist beyond this page
+    truncated: bool,
+    /// Matches found in total; a lower bound unless `total_exact`
+    total_estimate: usize,
+    total_exact: bool,
+}
+
+/// `q` accepts the query language in `search_filter`; the other fields are the
+/// same filters as explicit parameters.
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    ext: Option<String>,
+    #[serde(rename = "type")]
+    type_group: Option<String>,
+    size: Option<String>,
+    modified: Option<String>,
+    #[serde(rename = "in")]
+    scope: Option<String>,
+    is: Option<String>,
+    mode: Option<String>,
+    /// `next_cursor` from the previous page
+    cursor: Option<String>,
+    limit: Option<usize>,
+    /// `ndjson` streams every match as it is found instead of returning a page
+    stream: Option<String>,
+}
+
+impl SearchQuery {
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(MAX_SEARCH_RESULTS)
+            .clamp(1, MAX_SEARCH_RESULTS)
+    }
+
+    fn filter(&self) -> Result<search_filter::SearchFilter> {
+        let params: Vec<(&str, &str)> = [
+            ("ext", &self.ext),
+            ("type", &self.type_group),
+            ("size", &self.size),
+            ("modified", &self.modified),
+            ("in", &self.scope),
+            ("is", &self.is),
+            ("mode", &self.mode),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.as_deref().map(|v| (key, v)))
+        .collect();
+
+        search_filter::SearchFilter::parse(&self.q, &params)
+            .map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid query: {}", e)))
+    }
+}
+
+async fn search_files(
+    req: HttpRequest,
+    roots: web::Data<Roots>,
+    query: web::Query<SearchQuery>,
+) -> Result<HttpResponse> {
+    let filter = query.filter()?;
+    if filter.is_empty() {
+        return Ok(HttpResponse::Ok().json(SearchPage {
+            results: Vec::new(),
+            next_cursor: None,
+            truncated: false,
+            total_estimate: 0,
+            total_exact: true,
+        }));
+    }
+    if spans_roots(&req) {
+        return search_all_roots(&roots, &query).await;
+    }
+    let state = roots.select(&req)?[0];
+
+    let prefix = search_prefix(state, &filter)?.ok_or_else(|| {
+        actix_web::error::ErrorNotFound(format!(
+            "Folder not found: {}",
+            filter.scope.as_deref().unwrap_or("")
+        ))
+    })?;
+
+    let after = query
+        .cursor
+        .as_deref()
+        .filter(|c| !c.is_empty())
+        .map(|c| c.split('/').map(str::to_string).collect());
+
+    if query.stream.as_deref() == Some("ndjson") {
+        return Ok(stream_search_results(state, prefix, filter, after));
+    }
+
+    let limit = query.limit();
+    let page = if filter.is_ranked() {
+        ranked_search(state, prefix, filter, query.cursor.as_deref(), limit).await?
+    } else {
+        collect_search(state, prefix, filter, after, limit).await?
+    };
+    Ok(HttpResponse::Ok().json(page))
+}
+
+/// Folder a search starts from, relative to the root; `None` if the root has no such folder.
+fn search_prefix(state: &AppState, filter: &search_filter::SearchFilter) -> Result<Option<String>> {
+    let root = resolve_path_safe(&state.upload_dir, filter.scope.as_ref())
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let prefix = relative_to_root(&state.upload_dir, &root);
+    // The trash, revisions and indexes aren't part of the tree users search
+    if storage::is_within(&prefix, DATA_DIR) {
+        return Err(actix_web::error::ErrorForbidden("Invalid path"));
+    }
+    Ok(root.is_dir().then_some(prefix))
+}
+
+/// The first page of matches in every root, merged. Paging and streaming need a
+/// single root, since each root keeps its own walk order.
+async fn search_all_roots(roots: &Roots, query: &SearchQuery) -> Result<HttpResponse> {
+    if query.stream.is_some() || query.cursor.as_deref().is_some_and(|c| !c.is_empty()) {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Paged and streamed searches work on a single root",
+        ));
+    }
+
+    let limit = query.limit();
+    let mut merged = SearchPage {
+        results: Vec::new(),
+        next_cursor: None,
+        truncated: false,
+        total_estimate: 0,
+        total_exact: true,
+    };
+    let mut ranked = false;
+    for state in &roots.0 {
+        let filter = query.filter()?;
+        let Some(prefix) = search_prefix(state, &filter)? else {
+            continue;
+        };
+        ranked = filter.is_ranked();
+        let page = if ranked {
+            ranked_search(state, prefix, filter, None, limit).await?
+        } else {
+            collect_search(state, prefix, filter, None, limit).await?
+        };
+        merged.truncated |= page.truncated;
+        merged.total_estimate += page.total_estimate;
+        merged.total_exact &= page.total_exact;
+        merged
+            .results
+            .extend(page.results.into_iter().map(|result| SearchResult {
+                root: Some(state.root.clone()),
+                ..result
+            }));
+    }
+
+    if ranked {
+        merged
+            .results
+            .sort_by_key(|r| std::cmp::Reverse(r.score.unwrap_or(0)));
+    } else {
+        sort_search_results(&mut merged.results);
+    }
+    merged.truncated |= merged.results.len() > limit;
+    merged.results.truncate(limit);
+    Ok(HttpResponse::Ok().json(merged))
+}
+
+/// One page of matches in walk order, sorted for display.
+async fn collect_search(
+    state: &AppState,
+    prefix: String,
+    filter: search_filter::SearchFilter,
+    after: Option<Vec<String>>,
+    limit: usize,
+) -> Result<SearchPage> {
+    let walk = SearchWalk {
+        filter,
+        after,
+        now: unix_now(),
+        sink: SearchSink::Collect {
+            results: Vec::new(),
+            limit,
+            extra_matches: 0,
+        },
+        visited: 0,
+    };
+    let walk = run_search(state, prefix, walk).await?;
+
+    let SearchSink::Collect {
+        mut results,
+        extra_matches,
+        ..
+    } = walk.sink
+    else {
+        unreachable!("collecting walk");
+    };
+
+    // The cursor is the last result in walk order, taken before sorting for display
+    let truncated = extra_matches > 0;
+    let next_cursor = if truncated {
+        results.last().map(|r| r.path.clone())
+    } else {
+        None
+    };
+    let total_estimate = results.len() + extra_matches;
+    let total_exact = walk.visited < SEARCH_COUNT_BUDGET;
+
+    sort_search_results(&mut results);
+    Ok(SearchPage {
+        results,
+        next_cursor,
+        truncated,
+        total_estimate,
+        total_exact,
+    })
+}
+
+/// Folders first, then by name
+fn sort_search_results(results: &mut [SearchResult]) {
+    results.sort_by(|a, b| {
+        b.is_dir
+            .cmp(&a.is_dir)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+}
+
+/// Rank every match within the visit budget and return one page of them, best first.
+/// The cursor is `<score>:<path>` of the last result, so pages don't depend on walk order.
+async fn ranked_search(
+    state: &AppState,
+    prefix: String,
+    filter: search_filter::SearchFilter,
+    cursor: Option<&str>,
+    limit: usize,
+) -> Result<SearchPage> {
+    let after = match cursor.filter(|c| !c.is_empty()) {
+        Some(cursor) => {
+            let (score, path) = cursor
+                .split_once(':')
+                .and_then(|(score, path)| Some((score.parse::<i64>().ok()?, path)))
+                .ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid cursor"))?;
+            Some((std::cmp::Reverse(score), path.to_string()))
+        }
+        None => None,
+    };
+
+    let walk = SearchWalk {
+        filter,
+        after: None,
+        now: unix_now(),
+        sink: SearchSink::Collect {
+            results: Vec::new(),
+            limit: usize::MAX,
+            extra_matches: 0,
+        },
+        visited: 0,
+    };
+    let walk = run_search(state, prefix, walk).await?;
+
+    let SearchSink::Collect { mut results, .. } = walk.sink else {
+        unreachable!("collecting walk");
+    };
+
+    let key = |r: &SearchResult| (std::cmp::Reverse(r.score.unwrap_or(0)), r.path.clone());
+    results.sort_by_cached_key(key);
+
+    let total_estimate = results.len();
+    let start = match &after {
+        Some(after) => results.partition_point(|r| key(r) <= *after),
+        None => 0,
+    };
+    let mut page: Vec<SearchResult> = results.drain(start..).collect();
+    let truncated = page.len() > limit;
+    page.truncate(limit);
+    let next_cursor = if truncated {
+        page.last()
+            .map(|r| format!("{}:{}", r.score.unwrap_or(0), r.path))
+    } else {
+        None
+    };
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({"success": true})))
+    Ok(SearchPage {
+        results: page,
+        next_cursor,
+        truncated,
+        total_estimate,
+        total_exact: walk.visited < SEARCH_COUNT_BUDGET,
+    })
 }
 
-async fn list_all_folders(state: web::Data<AppState>) -> Result<HttpResponse> {
-    let mut folders = vec![String::from("/")];
-    collect_folders(state.upload_dir.clone(), String::new(), &mut folders).await;
-    Ok(HttpResponse::Ok().json(folders))
-}
-
-#[async_recursion::async_recursion]
-async fn collect_folders(path: PathBuf, prefix: String, folders: &mut Vec<String>) {
-    if let Ok(mut dir) = tokio::fs::read_dir(&path).await {
-        while let Ok(Some(entry)) = dir.next_entry().await {
-            if let Ok(meta) = entry.metadata().await {
-                if meta.is_dir() {
-                    let name = entry.file_name().to_string_lossy().to_string();
-                    let full_path = if prefix.is_empty() {
-                        name.clone()
-                    } else {
-                        format!("{}/{}", prefix, name)
-                    };
-                    folders.push(full_path.clone());
-                    collect_folders(entry.path(), full_path, folders).await;
-                }
-            }
-        }
-    }
+/// Run a search walk on the blocking pool and hand the finished walk back.
+async fn run_search(state: &AppState, prefix: String, mut walk: SearchWalk) -> Result<SearchWalk> {
+    let tree = state.tree.clone();
+    Ok(web::block(move || {
+        collect_search_results(&tree, &prefix, &mut walk);
+        walk
+    })
+    .await?)
 }
 
-#[derive(Clone, Serialize)]
-struct SearchResult {
-    name: String,
-    path: String,
-    is_dir: bool,
-    size: u64,
-    modified: u64,
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
 }
 
-const MAX_SEARCH_RESULTS: usize = 100;
+/// Stream matches as NDJSON while the walk finds them. The walk runs on the blocking
+/// pool and stops as soon as the client disconnects and the response body is dropped.
+fn stream_search_results(
+    state: &AppState,
+    prefix: String,
+    filter: search_filter::SearchFilter,
+    after: Option<Vec<String>>,
+) -> HttpResponse {
+    let (tx, rx) = tokio::sync::mpsc::channel::<SearchResult>(64);
+
+    let tree = state.tree.clone();
+    actix_web::rt::task::spawn_blocking(move || {
+        let mut walk = SearchWalk {
+            filter,
+            after,
+            now: unix_now(),
+            sink: SearchSink::Stream(tx),
+            visited: 0,
+        };
+        collect_search_results(&tree, &prefix, &mut walk);
+    });
 
-#[derive(Deserialize)]
-struct SearchQuery {
-    q: String,
-}
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        let result = rx.recv().await?;
+        let mut line = serde_json::to_vec(&result).unwrap_or_default();
+        line.push(b'\n');
+        Some((Ok::<_, actix_web::Error>(web::Bytes::from(line)), rx))
+    });
 
-async fn search_files(
-    state: web::Data<AppState>,
-    query: web::Query<SearchQuery>,
-) -> Result<HttpResponse> {
-    let search_term = query.q.to_lowercase();
-    if search_term.is_empty() {
-        return Ok(HttpResponse::Ok().json(Vec::<SearchResult>::new()));
-    }
-
-    let mut results = Vec::new();
-    collect_search_results(
-        state.upload_dir.clone(),
-        String::new(),
-        &search_term,
-        &mut results,
-        MAX_SEARCH_RESULTS,
-    )
-    .await;
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body)
+}
 
-    // Sort: folders first, then by name
-    results.sort_by(|a, b| {
-        b.is_dir
-            .cmp(&a.is_dir)
-            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
-    });
+/// Where `collect_search_results` delivers matches.
+enum SearchSink {
+    /// Collect up to `limit` matches, then keep counting for the total estimate
+    Collect {
+        results: Vec<SearchResult>,
+        limit: usize,
+        extra_matches: usize,
+    },
+    /// Send every match to a streaming response
+    Stream(tokio::sync::mpsc::Sender<SearchResult>),
+}
 
-    Ok(HttpResponse::Ok().json(results))
+struct SearchWalk {
+    filter: search_filter::SearchFilter,
+    /// Resume after this path (from a pagination cursor), split into segments
+    after: Option<Vec<String>>,
+    /// Reference time for the recency boost
+    now: u64,
+    sink: SearchSink,
+    visited: usize,
 }
 
-#[async_recursion::async_recursion]
-async fn collect_search_results(
-    path: PathBuf,
-    prefix: String,
-    search_term: &str,
-    results: &mut Vec<SearchResult>,
-    limit: usize,
-) {
-    // Early termination if we've hit the limit
-    if results.len() >= limit {
-        return;
+impl SearchWalk {
+    /// Deliver a match. Returns false once the walk should stop.
+    fn push(&mut self, result: SearchResult) -> bool {
+        match &mut self.sink {
+            SearchSink::Collect {
+                results,
+                limit,
+                extra_matches,
+            } => {
+                if results.len() < *limit {
+                    results.push(result);
+                } else {
+                    *extra_matches += 1;
+                }
+                true
+            }
+            SearchSink::Stream(tx) => tx.blocking_send(result).is_ok(),
+        }
     }
 
-    if let Ok(mut dir) = tokio::fs::read_dir(&path).await {
-        while let Ok(Some(entry)) = dir.next_entry().await {
-            // Check limit before processing each entry
-            if results.len() >= limit {
-                return;
+    fn should_stop(&self) -> bool {
+        match &self.sink {
+            // Ranked searches collect everything, so the budget alone bounds them
+            SearchSink::Collect { .. } if self.filter.is_ranked() => {
+                self.visited >= SEARCH_COUNT_BUDGET
             }
+            // Once the page is full, only keep walking to estimate the total
+            SearchSink::Collect {
+                results, limit, ..
+            } => results.len() >= *limit && self.visited >= SEARCH_COUNT_BUDGET,
+            SearchSink::Stream(tx) => tx.is_closed(),
+        }
+    }
+}
 
-            if let Ok(meta) = entry.metadata().await {
-                let name = entry.file_name().to_string_lossy().to_string();
-                let full_path = if prefix.is_empty() {
-                    name.clone()
-                } else {
-                    format!("{}/{}", prefix, name)
-                };
+/// Walk the tree depth-first with entries sorted by name, so the order is stable
+/// between requests and a page can resume after its cursor.
+/// Returns false once the walk should stop.
+fn collect_search_results(
+    tree: &tree_index::TreeIndex,
+    prefix: &str,
+    walk: &mut SearchWalk,
+) -> bool {
+    for entry in tree.children(prefix) {
+        if walk.should_stop() {
+            return false;
+        }
 
-                // Check if name matches search term
-                if name.to_lowercase().contains(search_term) {
-                    let modified = meta
-                        .modified()
-                        .ok()
-                        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                        .map(|d| d.as_secs())
-                        .unwrap_or(0);
-
-                    results.push(SearchResult {
-                        name,
-                        path: full_path.clone(),
-                        is_dir: meta.is_dir(),
-                        size: meta.len(),
-                        modified,
-                    });
-                }
+        let full_path = if prefix.is_empty() {
+            entry.name.clone()
+        } else {
+            format!("{}/{}", prefix, entry.name)
+        };
 
-                // Recurse into directories (check limit again)
-                if meta.is_dir() && results.len() < limit {
-                    collect_search_results(entry.path(), full_path, search_term, results, limit)
-                        .await;
-                }
+        // Skip everything up to and including the cursor, but descend into its ancestors
+        let mut emit = true;
+        if let Some(after) = &walk.after {
+            let segments: Vec<&str> = full_path.split('/').collect();
+            let is_ancestor = segments.len() < after.len()
+                && segments.iter().zip(after.iter()).all(|(a, b)| *a == b);
+            if is_ancestor {
+                emit = false;
+            } else if segments.iter().copied().le(after.iter().map(String::as_str)) {
+                continue;
+            }
+        }
+        walk.visited += 1;
+
+        // Check if the entry passes the name pattern and filters
+        if emit
+            && walk.filter.matches(
+                &entry.name,
+                &full_path,
+                entry.is_dir,
+                entry.size,
+                entry.modified,
+            )
+        {
+            let rank = walk
+                .filter
+                .rank(&entry.name, &full_path, entry.modified, walk.now);
+            let (score, positions) = rank.unzip();
+            let keep_going = walk.push(SearchResult {
+                name: entry.name,
+                path: full_path.clone(),
+                is_dir: entry.is_dir,
+                size: entry.size,
+                modified: entry.modified,
+                score,
+                positions,
+                root: None,
+            });
+            if !keep_going {
+                return false;
             }
         }
+
+        if entry.is_dir && !collect_search_results(tree, &full_path, walk) {
+            return false;
+        }
     }
+
+    true
 }
 
-async fn delete_item(
-    body: web::Json<DeleteReq>,
-    state: web::Data<AppState>,
+#[derive(Deserialize)]
+struct ContentSearchQuery {
+    q: String,
+    limit: Option<usize>,
+}
+
+async fn search_content(
+    state: RootState,
+    query: web::Query<ContentSearchQuery>,
 ) -> Result<HttpResponse> {
-    let filepath = resolve_path_safe(&state.upload_dir, Some(&body.path))
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let index = state
+        .content_index
+        .clone()
+        .ok_or_else(|| actix_web::error::ErrorServiceUnavailable("Content index disabled"))?;
 
-    if filepath.exists() {
-        if filepath.is_dir() {
-            tokio::fs::remove_dir_all(&filepath).await?;
-        } else {
-            tokio::fs::remove_file(&filepath).await?;
-        }
-        broadcast_update(&state.broadcaster, "delete", &body.path);
+    if query.q.trim().is_empty() {
+        return Ok(HttpResponse::Ok().json(Vec::<content_index::ContentHit>::new()));
     }
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({"success": true})))
+    let limit = query
+        .limit
+        .unwrap_or(MAX_SEARCH_RESULTS)
+        .clamp(1, MAX_SEARCH_RESULTS);
+    let q = query.q.clone();
+    let hits = web::block(move || index.search(&q, limit))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(hits))
+}
+
+async fn delete_item(body: web::Json<DeleteReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    let outcome = apply_delete(&state, &body).await?;
+    Ok(finish_op(&state, outcome).await)
 }
 
 fn is_editable_extension(path: &Path) -> bool {
@@ -566,33 +2796,28 @@ fn is_editable_extension(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-async fn get_content(
-    state: web::Data<AppState>,
-    query: web::Query<PathQuery>,
-) -> Result<HttpResponse> {
+async fn get_content(state: RootState, query: web::Query<PathQuery>) -> Result<HttpResponse> {
     let path = query
         .path
         .as_ref()
         .ok_or_else(|| actix_web::error::ErrorBadRequest("path required"))?;
 
-    let filepath = resolve_path_safe(&state.upload_dir, Some(path))
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let rel_path = storage::normalize(path);
 
-    if !filepath.exists() || filepath.is_dir() {
+    if find_item(&state, &rel_path)
+        .await?
+        .is_none_or(|item| item.is_dir)
+    {
         return Err(actix_web::error::ErrorNotFound("File not found"));
     }
 
-    if !is_editable_extension(&filepath) {
+    if !is_editable_extension(Path::new(&rel_path)) {
         return Err(actix_web::error::ErrorBadRequest("File type not editable"));
     }
 
-    let content = tokio::fs::read_to_string(&filepath).await.map_err(|e| {
-        if e.kind() == std::io::ErrorKind::InvalidData {
-            actix_web::error::ErrorBadRequest("File is not valid UTF-8 text")
-        } else {
-            actix_web::error::ErrorInternalServerError(e)
-        }
-    })?;
+    let content = storage::read_all(state.storage.as_ref(), &rel_path).await?;
+    let content = String::from_utf8(content)
+        .map_err(|_| actix_web::error::ErrorBadRequest("File is not valid UTF-8 text"))?;
 
     Ok(HttpResponse::Ok()
         .content_type("text/plain; charset=utf-8")
@@ -605,26 +2830,74 @@ struct SaveContentReq {
     content: String,
 }
 
-async fn save_content(
-    body: web::Json<SaveContentReq>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
-    let filepath = resolve_path_safe(&state.upload_dir, Some(&body.path))
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+async fn save_content(body: web::Json<SaveContentReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
+    let rel_path = storage::normalize(&body.path);
+    reject_data_path(&rel_path)?;
+    let filepath = Path::new(&rel_path);
 
-    if !filepath.exists() || filepath.is_dir() {
-        return Err(actix_web::error::ErrorNotFound("File not found"));
-    }
+    let existing = find_item(&state, &rel_path)
+        .await?
+        .filter(|item| !item.is_dir)
+        .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
 
-    if !is_editable_extension(&filepath) {
+    if !is_editable_extension(filepath) {
         return Err(actix_web::error::ErrorBadRequest("File type not editable"));
     }
 
-    tokio::fs::write(&filepath, &body.content).await?;
+    // Notebooks are edited as raw JSON; refuse edits Jupyter could no longer open
+    let is_notebook = filepath
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("ipynb"));
+    if is_notebook {
+        if let Err(e) = serde_json::from_str::<serde_json::Value>(&body.content) {
+            return Err(actix_web::error::ErrorBadRequest(format!(
+                "Notebook is not valid JSON: {}",
+                e
+            )));
+        }
+    }
 
-    broadcast_update(&state.broadcaster, "edit", &body.path);
+    let _quotas = quota::check(
+        &state,
+        &rel_path,
+        0,
+        (body.content.len() as u64).saturating_sub(existing.size),
+    )
+    .await?;
+
+    let content = storage::once(body.content.clone());
+    let applied = match state.storage.disk_root() {
+        Some(root) => {
+            // Keep the previous version so the edit can be undone
+            let filepath = root.join(&rel_path);
+            let trash_dir = state.upload_dir.join(TRASH_DIR);
+            tokio::fs::create_dir_all(&trash_dir).await?;
+            let backup = trash_dir.join(uuid::Uuid::new_v4().to_string());
+            tokio::fs::copy(&filepath, &backup).await?;
+
+            if let Err(e) = state.storage.write(&rel_path, content).await {
+                let _ = tokio::fs::remove_file(&backup).await;
+                return Err(e.into());
+            }
+            Applied::Edited {
+                path: filepath,
+                backup,
+            }
+        }
+        None => {
+            state.storage.write(&rel_path, content).await?;
+            Applied::Untracked
+        }
+    };
 
-    Ok(HttpResponse::Ok().json(serde_json::json!({"success": true})))
+    let outcome = OpOutcome {
+        action: "edit",
+        path: body.path.clone(),
+        details: serde_json::json!({}),
+        applied: Some(applied),
+    };
+    Ok(finish_op(&state, outcome).await)
 }
 
 #[derive(Deserialize)]
@@ -633,10 +2906,8 @@ struct NewFileReq {
     filename: String,
 }
 
-async fn create_new_file(
-    body: web::Json<NewFileReq>,
-    state: web::Data<AppState>,
-) -> Result<HttpResponse> {
+async fn create_new_file(body: web::Json<NewFileReq>, state: RootState) -> Result<HttpResponse> {
+    check_writable(&state)?;
     // Validate filename has an editable extension
     let filename = body.filename.replace(['/', '\\', '\0'], "_");
     let filepath_check = Path::new(&filename);
@@ -645,56 +2916,31 @@ async fn create_new_file(
         return Err(actix_web::error::ErrorBadRequest("Invalid file extension"));
     }
 
-    let base = resolve_path_safe(&state.upload_dir, body.path.as_ref())
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
-    let filepath = base.join(&filename);
+    let rel_path = storage::join(body.path.as_deref().unwrap_or(""), &filename);
+    reject_data_path(&rel_path)?;
 
     // Prevent overwriting existing files
-    if filepath.exists() {
+    if find_item(&state, &rel_path).await?.is_some() {
         return Err(actix_web::error::ErrorConflict("File already exists"));
     }
+    let _quotas = quota::check(&state, &rel_path, 1, 0).await?;
 
-    // Ensure parent directory exists
-    tokio::fs::create_dir_all(&base).await?;
-
-    // Create empty file
-    tokio::fs::write(&filepath, "").await?;
-
-    let rel_path = body
-        .path
-        .as_ref()
-        .map(|p| format!("{}/{}", p, filename))
-        .unwrap_or(filename.clone());
+    // Create empty file; the backend creates missing parent folders
+    state
+        .storage
+        .write(&rel_path, storage::once(web::Bytes::new()))
+        .await?;
+    reindex_paths(&state, [state.upload_dir.join(&rel_path).as_path()]).await;
 
     broadcast_update(&state.broadcaster, "upload", &rel_path);
 
     Ok(HttpResponse::Ok().json(serde_json::json!({"success": true, "path": rel_path})))
 }
 
-async fn download_file(
-    state: web::Data<AppState>,
-    query: web::Query<PathQuery>,
-) -> Result<HttpResponse> {
-    let path = query
-        .path
-        .as_ref()
-        .ok_or_else(|| actix_web::error::ErrorBadRequest("path required"))?;
-
-    let filepath = resolve_path_safe(&state.upload_dir, Some(path))
-        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
-
-    if !filepath.exists() || filepath.is_dir() {
-        return Err(actix_web::error::ErrorNotFound("File not found"));
-    }
-
-    let filename = filepath
-        .file_name()
-        .and_then(|n| n.to_str())
-        .unwrap_or("download");
-
+fn content_type_for(filepath: &Path) -> String {
     // Get correct MIME type - override for common previewable types
     let ext = filepath.extension().and_then(|e| e.to_str()).unwrap_or("");
-    let content_type: String = match ext.to_lowercase().as_str() {
+    match ext.to_lowercase().as_str() {
         "pdf" => "application/pdf".to_string(),
         "mp4" => "video/mp4".to_string(),
         "webm" => "video/webm".to_string(),
@@ -713,23 +2959,134 @@ async fn download_file(
         "gif" => "image/gif".to_string(),
         "webp" => "image/webp".to_string(),
         "ico" => "image/x-icon".to_string(),
-        _ => mime_guess::from_path(&filepath)
+        _ => mime_guess::from_path(filepath)
             .first_or_octet_stream()
             .essence_str()
             .to_string(),
+    }
+}
+
+/// Stream a file out of an archive from a blocking thread. Fails before anything is
+/// sent if the archive can't be read or has no such file; the size recorded in the
+/// archive isn't trusted, so the body has no length.
+async fn stream_archive_entry(
+    archive_file: PathBuf,
+    inner_path: String,
+) -> std::io::Result<actix_web::body::BodyStream<storage::ByteStream>> {
+    let (found_tx, found_rx) = tokio::sync::oneshot::channel::<std::io::Result<()>>();
+    let (tx, rx) = tokio::sync::mpsc::channel::<std::io::Result<web::Bytes>>(4);
+    actix_web::rt::task::spawn_blocking(move || {
+        let mut found_tx = Some(found_tx);
+        let result = archive::with_entry(&archive_file, &inner_path, |reader| {
+            if let Some(found_tx) = found_tx.take() {
+                let _ = found_tx.send(Ok(()));
+            }
+            let mut buf = vec![0; 64 * 1024];
+            loop {
+                let n = reader.read(&mut buf)?;
+                // Stop early once the client has gone away
+                if n == 0 || tx.blocking_send(Ok(buf[..n].to_vec().into())).is_err() {
+                    return Ok(());
+                }
+            }
+        });
+        if let Err(e) = result {
+            match found_tx {
+                Some(found_tx) => {
+                    let _ = found_tx.send(Err(e));
+                }
+                None => {
+                    let _ = tx.blocking_send(Err(e));
+                }
+            }
+        }
+    });
+    found_rx
+        .await
+        .map_err(|_| std::io::Error::other("Archive reader stopped"))??;
+
+    let body: storage::ByteStream = Box::pin(futures_util::stream::unfold(rx, |mut rx| async {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    }));
+    Ok(actix_web::body::BodyStream::new(body))
+}
+
+/// The `(offset, length)` a `Range` header asks for in a file of `size` bytes. Only the
+/// first range is served, which is all media players and download managers ask for.
+fn requested_range(req: &HttpRequest, size: u64) -> Result<Option<(u64, u64)>> {
+    let Some(range) = req.headers().get("Range").and_then(|v| v.to_str().ok()) else {
+        return Ok(None);
     };
+    let range = actix_files::HttpRange::parse(range, size)
+        .ok()
+        .and_then(|ranges| ranges.into_iter().next())
+        .ok_or_else(|| {
+            actix_web::error::InternalError::from_response(
+                "Invalid range",
+                HttpResponse::RangeNotSatisfiable()
+                    .insert_header(("Content-Range", format!("bytes */{}", size)))
+                    .finish(),
+            )
+        })?;
+    Ok(Some((range.start, range.length)))
+}
 
-    let file_content = tokio::fs::read(&filepath).await?;
-    let file_size = file_content.len();
+async fn download_file(
+    req: HttpRequest,
+    state: RootState,
+    query: web::Query<PathQuery>,
+) -> Result<HttpResponse> {
+    let path = query
+        .path
+        .as_ref()
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("path required"))?;
 
     let mut response = HttpResponse::Ok();
 
+    let (filepath, file_content) =
+        if let Some((archive_path, inner_path)) = archive::split_archive_path(path) {
+            // Single member of an archive, streamed without extracting the rest
+            let archive_file =
+                resolve_archive_safe(plain_files(&state, "Archive browsing")?, archive_path)?;
+            let member_path = PathBuf::from(inner_path);
+            let body = stream_archive_entry(archive_file, inner_path.to_string())
+                .await
+                .map_err(archive_error)?;
+            (member_path, actix_web::body::BoxBody::new(body))
+        } else {
+            let rel_path = storage::normalize(path);
+            let file = find_item(&state, &rel_path)
+                .await?
+                .filter(|item| !item.is_dir)
+                .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
+
+            let range = requested_range(&req, file.size)?;
+            if let Some((start, length)) = range {
+                response
+                    .status(actix_web::http::StatusCode::PARTIAL_CONTENT)
+                    .insert_header((
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, start + length - 1, file.size),
+                    ));
+            }
+            response.insert_header(("Accept-Ranges", "bytes"));
+
+            // Streamed from the backend; Content-Length comes from the sized body
+            let stream = state.storage.read(&rel_path, range).await?;
+            let length = range.map_or(file.size, |(_, length)| length);
+            let body = actix_web::body::SizedStream::new(length, stream);
+            (PathBuf::from(rel_path), actix_web::body::BoxBody::new(body))
+        };
+
+    let filename = filepath
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("download");
+    let content_type = content_type_for(&filepath);
+
     // Set Content-Type
     response.insert_header(("Content-Type", content_type));
 
-    // Set Content-Length
-    response.insert_header(("Content-Length", file_size.to_string()));
-
     // Prevent MIME sniffing - browser must use our Content-Type
     response.insert_header(("X-Content-Type-Options", "nosniff"));
 
@@ -753,6 +3110,247 @@ async fn download_file(
     Ok(response.body(file_content))
 }
 
+/// Files a listing can queue for media extraction before further ones are dropped.
+const MEDIA_QUEUE_LEN: usize = 1024;
+
+/// Start the thread that extracts media details for files listings queue, caching
+/// them in the tree index.
+fn spawn_media_worker(
+    tree: Arc<tree_index::TreeIndex>,
+    upload_dir: PathBuf,
+) -> std::sync::mpsc::SyncSender<(String, u64)> {
+    let (tx, rx) = std::sync::mpsc::sync_channel::<(String, u64)>(MEDIA_QUEUE_LEN);
+    std::thread::spawn(move || {
+        for (rel_path, modified) in rx {
+            media_info(&tree, &upload_dir, &rel_path, modified);
+        }
+    });
+    tx
+}
+
+/// Media metadata for a file, extracted on first use and cached in the tree index.
+/// `None` if the file isn't an image, audio or video file.
+fn media_info(
+    tree: &tree_index::TreeIndex,
+    upload_dir: &Path,
+    rel_path: &str,
+    modified: u64,
+) -> Option<Arc<media::MediaInfo>> {
+    let kind = media::kind(rel_path)?;
+    if let Some(info) = tree.media(rel_path, modified) {
+        return Some(info);
+    }
+    // Unreadable files are cached as empty so they aren't retried on every listing
+    let info = media::extract(&upload_dir.join(rel_path))
+        .unwrap_or_else(|_| media::MediaInfo::empty(kind));
+    let info = Arc::new(info);
+    tree.set_media(rel_path, modified, info.clone());
+    Some(info)
+}
+
+async fn get_metadata(state: RootState, query: web::Query<PathQuery>) -> Result<HttpResponse> {
+    let filepath = resolve_path_safe(plain_files(&state, "Media metadata")?, query.path.as_ref())
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let meta = tokio::fs::metadata(&filepath)
+        .await
+        .ok()
+        .filter(|m| m.is_file())
+        .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    let tree = state.tree.clone();
+    let upload_dir = state.upload_dir.clone();
+    let rel_path = relative_to_root(&state.upload_dir, &filepath);
+    let info = web::block({
+        let rel_path = rel_path.clone();
+        move || media_info(&tree, &upload_dir, &rel_path, modified)
+    })
+    .await?
+    .ok_or_else(|| {
+        actix_web::error::ErrorUnsupportedMediaType("Not an image, audio or video file")
+    })?;
+
+    let mut body = serde_json::to_value(&*info)?;
+    body["path"] = rel_path.into();
+    body["size"] = meta.len().into();
+    body["modified"] = modified.into();
+    Ok(HttpResponse::Ok().json(body))
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    path: String,
+    /// Longest edge in pixels, rounded up to a supported size
+    size: Option<u32>,
+}
+
+async fn get_thumbnail(
+    req: HttpRequest,
+    state: RootState,
+    query: web::Query<ThumbnailQuery>,
+) -> Result<HttpResponse> {
+    let filepath = resolve_path_safe(plain_files(&state, "Thumbnails")?, Some(&query.path))
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+    let meta = tokio::fs::metadata(&filepath)
+        .await
+        .ok()
+        .filter(|m| m.is_file())
+        .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
+
+    if !thumbnail::is_supported(&filepath) {
+        return Err(actix_web::error::ErrorUnsupportedMediaType(
+            "Thumbnails are available for JPEG, PNG, GIF and WebP images",
+        ));
+    }
+
+    let modified = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let size = thumbnail::bucket(query.size.unwrap_or(thumbnail::DEFAULT_SIZE));
+
+    // The ETag changes whenever the source does
+    let etag = format!("\"{}-{}\"", modified, size);
+    let not_modified = req
+        .headers()
+        .get("If-None-Match")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v == etag);
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(("ETag", etag))
+            .finish());
+    }
+
+    let upload_dir = state.upload_dir.clone();
+    let rel_path = relative_to_root(&state.upload_dir, &filepath);
+    let thumb =
+        web::block(move || thumbnail::get_or_create(&upload_dir, &rel_path, modified, size))
+            .await?
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::InvalidData => actix_web::error::ErrorUnprocessableEntity(
+                    format!("Image could not be decoded: {}", e),
+                ),
+                _ => e.into(),
+            })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(thumb.content_type)
+        .insert_header(("ETag", etag))
+        .insert_header(("Cache-Control", "private, max-age=86400"))
+        .insert_header(("X-Content-Type-Options", "nosniff"))
+        .body(thumb.bytes))
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    path: String,
+    /// First row (tables) or line (code) of the page
+    offset: Option<usize>,
+    limit: Option<usize>,
+    /// `dark` picks the dark highlighting theme
+    theme: Option<String>,
+}
+
+async fn get_preview(state: RootState, query: web::Query<PreviewQuery>) -> Result<HttpResponse> {
+    let path = query.path.clone();
+    let base_dir = path
+        .rsplit_once('/')
+        .map(|(dir, _)| dir.to_string())
+        .unwrap_or_default();
+    let options = preview::Options {
+        base_dir,
+        offset: query.offset.unwrap_or(0),
+        limit: query.limit,
+        dark: query.theme.as_deref() == Some("dark"),
+    };
+
+    let rendered = if let Some((archive_path, inner_path)) = archive::split_archive_path(&path) {
+        let member_path = PathBuf::from(inner_path);
+        if preview::kind(&member_path).is_none() {
+            return Err(unsupported_preview());
+        }
+        let archive_file =
+            resolve_archive_safe(plain_files(&state, "Archive browsing")?, archive_path)?;
+        let inner_path = inner_path.to_string();
+        web::block(move || {
+            let content = archive::read_entry(&archive_file, &inner_path)?;
+            preview::render(
+                &member_path,
+                content.len() as u64,
+                std::io::Cursor::new(&content),
+                &options,
+            )
+        })
+        .await?
+    } else if let Some(base) = state.storage.local_root() {
+        let filepath = resolve_path_safe(base, Some(&path))
+            .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))?;
+        if !filepath.is_file() {
+            return Err(actix_web::error::ErrorNotFound("File not found"));
+        }
+        if preview::kind(&filepath).is_none() {
+            return Err(unsupported_preview());
+        }
+        web::block(move || {
+            let file = std::fs::File::open(&filepath)?;
+            let size = file.metadata()?.len();
+            preview::render(&filepath, size, std::io::BufReader::new(file), &options)
+        })
+        .await?
+    } else {
+        // Other backends are read through storage (decrypting, for encrypted roots)
+        // into memory, as archive members are
+        let rel_path = storage::normalize(&path);
+        let file = find_item(&state, &rel_path)
+            .await?
+            .filter(|item| !item.is_dir)
+            .ok_or_else(|| actix_web::error::ErrorNotFound("File not found"))?;
+        let filepath = PathBuf::from(&rel_path);
+        if preview::kind(&filepath).is_none() {
+            return Err(unsupported_preview());
+        }
+        if file.size > archive::MAX_ENTRY_BYTES {
+            return Err(actix_web::error::ErrorPayloadTooLarge(
+                "File is too large to preview from this root",
+            ));
+        }
+        let content = storage::read_all(state.storage.as_ref(), &rel_path).await?;
+        web::block(move || {
+            preview::render(
+                &filepath,
+                content.len() as u64,
+                std::io::Cursor::new(&content),
+                &options,
+            )
+        })
+        .await?
+    };
+
+    let rendered = rendered.map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => actix_web::error::ErrorNotFound(e.to_string()),
+        std::io::ErrorKind::InvalidData => {
+            actix_web::error::ErrorUnprocessableEntity(format!("File could not be parsed: {}", e))
+        }
+        std::io::ErrorKind::FileTooLarge => actix_web::error::ErrorPayloadTooLarge(e.to_string()),
+        _ => e.into(),
+    })?;
+    Ok(HttpResponse::Ok().json(rendered))
+}
+
+fn unsupported_preview() -> actix_web::Error {
+    actix_web::error::ErrorUnsupportedMediaType(
+        "Previews are available for Markdown, CSV/TSV, notebooks, editable text files and PDF/Office documents",
+    )
+}
+
 async fn serve_index() -> Result<HttpResponse> {
     Ok(HttpResponse::Ok()
         .content_type("text/html; charset=utf-8")
@@ -806,6 +3404,7 @@ async fn get_data(
 
 async fn save_data(
     state: web::Data<AppState>,
+    roots: web::Data<Roots>,
     path: web::Path<DataPath>,
     body: web::Bytes,
 ) -> Result<HttpResponse> {
@@ -826,8 +3425,10 @@ async fn save_data(
 
     match std::fs::write(&file_path, &body) {
         Ok(_) => {
-            // Broadcast to all clients for real-time sync
-            broadcast_update(&state.broadcaster, "data_sync", data_type);
+            // Broadcast to all clients for real-time sync, whichever root they are on
+            for root in &roots.0 {
+                broadcast_update(&root.broadcaster, "data_sync", data_type);
+            }
             Ok(HttpResponse::Ok().json(serde_json::json!({
                 "success": true
             })))
@@ -843,47 +3444,426 @@ async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::default().default_filter_or("info"));
 
     let settings = Settings::from_env();
-    tokio::fs::create_dir_all(&settings.upload_dir).await?;
+    let mut roots = Vec::with_capacity(settings.roots.len());
+    let s3_credentials = settings.s3_credentials.map(Arc::new);
+    let shared_bucket = settings.roots.len() > 1;
+    // One key file covers every root
+    let keys = settings.encryption_key_file.as_ref().map(|path| {
+        Arc::new(encryption::Keyring::load(path).unwrap_or_else(|e| {
+            eprintln!("Invalid encryption key file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }))
+    });
+    for root in &settings.roots {
+        tokio::fs::create_dir_all(&root.upload_dir).await?;
+
+        // The undo journal is in-memory, so anything left in the trash is unreachable
+        let _ = tokio::fs::remove_dir_all(root.upload_dir.join(TRASH_DIR)).await;
+
+        let (tx, _) = broadcast::channel::<String>(100);
+        // Several roots in one bucket each get a key prefix
+        let storage = settings.storage.open(
+            &root.upload_dir,
+            if shared_bucket { &root.name } else { "" },
+        )?;
+        let storage = match &keys {
+            Some(keys) => encryption::Encrypted::open(storage, keys.clone(), &root.name).await?,
+            None => storage,
+        };
+        if storage.local_root().is_none() {
+            warn_unreadable_features(
+                &root.name,
+                storage.as_ref(),
+                settings.hashing,
+                settings.content_index,
+            );
+        }
+        // Hashing reads the upload dir directly, so it needs local storage
+        let hashes = hash_index::HashIndex::open(
+            &root.upload_dir,
+            settings.hashing && storage.local_root().is_some(),
+        );
+        // Encrypted files are indexed with their plaintext sizes
+        let file_size: tree_index::FileSize = if keys.is_some() {
+            encryption::listed_size
+        } else {
+            |size| size
+        };
+        let tree = tree_index::TreeIndex::open(&root.upload_dir, settings.tree_index, file_size, {
+            let (tx, hashes) = (tx.clone(), hashes.clone());
+            move |changes| {
+                for (path, _) in &changes {
+                    hashes.refresh(path);
+                }
+                broadcast_external_changes(&tx, changes)
+            }
+        });
+        // Quotas count the revisions and the trash from indexes of their own, whose
+        // watchers need the folders to exist
+        let (revisions_dir, trash_dir) = (
+            root.upload_dir.join(REVISIONS_DIR),
+            root.upload_dir.join(TRASH_DIR),
+        );
+        let kept_trees = settings.tree_index && storage.disk_root().is_some();
+        if kept_trees {
+            tokio::fs::create_dir_all(&revisions_dir).await?;
+            tokio::fs::create_dir_all(&trash_dir).await?;
+        }
+        let revision_tree =
+            tree_index::TreeIndex::open(&revisions_dir, kept_trees, file_size, |_| {});
+        let trash_tree = tree_index::TreeIndex::open(&trash_dir, kept_trees, file_size, |_| {});
+        roots.push(web::Data::new(AppState {
+            root: root.name.clone(),
+            read_only: root.read_only,
+            quotas: root.quotas.clone(),
+            quota_ledger: Arc::default(),
+            broadcaster: tx.clone(),
+            upload_dir: root.upload_dir.clone(),
+            max_upload_bytes: root.max_upload_bytes,
+            journal: Arc::new(Mutex::new(journal::Journal::new(
+                settings.undo_retention_secs,
+            ))),
+            revisions: settings.revisions,
+            // Indexing reads the upload dir directly too
+            content_index: if settings.content_index && storage.local_root().is_some() {
+                content_index::ContentIndex::open(&root.upload_dir)
+                    .map_err(|e| eprintln!("Content index disabled for {}: {}", root.name, e))
+                    .ok()
+            } else {
+                None
+            },
+            media_queue: spawn_media_worker(tree.clone(), root.upload_dir.clone()),
+            tree,
+            revision_tree,
+            trash_tree,
+            hashes,
+            dav_locks: Arc::new(Mutex::new(webdav::Locks::default())),
+            s3: s3_credentials.clone(),
+            storage,
+        }));
+    }
+    let roots = web::Data::new(Roots(roots));
 
-    let (tx, _) = broadcast::channel::<String>(100);
-    let state = AppState {
-        broadcaster: tx,
-        upload_dir: settings.upload_dir.clone(),
-        max_upload_bytes: settings.max_upload_bytes,
-    };
+    // Purge retained copies once their undo window has passed, and old revisions at
+    // startup and hourly after
+    let expiry_roots = roots.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        for minute in 0u64.. {
+            interval.tick().await;
+            for state in &expiry_roots.0 {
+                if minute % 60 == 0 {
+                    prune_all_revisions(state).await;
+                }
+                let expired = state.journal.lock().unwrap().expire();
+                for change in expired {
+                    change.commit().await;
+                }
+                s3::purge_stale_uploads(&state.upload_dir).await;
+            }
+        }
+    });
 
-    println!(
-        "Boxy running on http://0.0.0.0:{} (uploads at {})",
-        settings.port,
-        state.upload_dir.to_string_lossy()
-    );
+    for state in &roots.0 {
+        println!(
+            "Boxy running on http://0.0.0.0:{} ({} at {}{})",
+            settings.port,
+            state.root,
+            state.upload_dir.to_string_lossy(),
+            if state.read_only { ", read-only" } else { "" }
+        );
+    }
+    // JSON and raw bodies are held to the largest upload limit; uploads check their root's
+    let max_upload_bytes = roots.0.iter().map(|state| state.max_upload_bytes).max();
 
     HttpServer::new(move || {
-        let app_state = state.clone();
+        let app_state = roots.default_root().clone();
         App::new()
-            .app_data(web::Data::new(app_state.clone()))
-            .app_data(web::PayloadConfig::new(app_state.max_upload_bytes))
+            .app_data(roots.clone())
+            .app_data(app_state.clone())
+            .app_data(web::PayloadConfig::new(
+                max_upload_bytes.unwrap_or(DEFAULT_MAX_UPLOAD_BYTES),
+            ))
             .wrap(Logger::default())
             .wrap(Compress::default())
             .route("/", web::get().to(serve_index))
             .route("/ws", web::get().to(ws_handler))
+            .route("/api/roots", web::get().to(list_roots))
+            .route("/api/usage", web::get().to(get_usage))
+            .route("/api/usage/tree", web::get().to(usage_tree))
+            .route("/api/duplicates", web::get().to(list_duplicates))
+            .route("/api/duplicates/link", web::post().to(link_duplicates))
             .route("/api/files", web::get().to(list_files))
             .route("/api/upload", web::post().to(upload_file))
             .route("/api/folder", web::post().to(create_folder))
             .route("/api/delete", web::post().to(delete_item))
             .route("/api/rename", web::post().to(rename_item))
             .route("/api/move", web::post().to(move_item))
+            .route("/api/copy", web::post().to(copy_item))
+            .route("/api/batch", web::post().to(batch_operations))
+            .route("/api/undo", web::get().to(list_undo))
+            .route("/api/undo", web::post().to(undo_operation))
             .route("/api/folders", web::get().to(list_all_folders))
+            .route("/api/stats", web::get().to(folder_stats))
             .route("/api/download", web::get().to(download_file))
+            .route("/api/thumbnail", web::get().to(get_thumbnail))
+            .route("/api/metadata", web::get().to(get_metadata))
+            .route("/api/preview", web::get().to(get_preview))
             .route("/api/search", web::get().to(search_files))
+            .route("/api/search/content", web::get().to(search_content))
             .route("/api/content", web::get().to(get_content))
             .route("/api/content", web::post().to(save_content))
             .route("/api/newfile", web::post().to(create_new_file))
             .route("/api/health", web::get().to(healthcheck))
             .route("/api/data/{data_type}", web::get().to(get_data))
             .route("/api/data/{data_type}", web::post().to(save_data))
+            .configure(|cfg| {
+                // Both serve the upload dir directly, so they need local storage
+                if app_state.storage.local_root().is_some() {
+                    cfg.service(web::scope(s3::PREFIX).default_service(web::to(s3::handle)))
+                        .service(
+                            web::scope(webdav::PREFIX).default_service(web::to(webdav::handle)),
+                        );
+                } else {
+                    cfg.service(web::scope(s3::PREFIX).default_service(web::to(no_s3)))
+                        .service(web::scope(webdav::PREFIX).default_service(web::to(no_webdav)));
+                }
+            })
     })
     .bind(("0.0.0.0", settings.port))?
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use serde_json::json;
+    use tree_index::Change;
+
+    /// A single local root in a fresh temp dir, with the background indexes off.
+    fn test_roots() -> (PathBuf, web::Data<Roots>) {
+        let dir = env::temp_dir().join(format!("boxy-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (tx, _) = broadcast::channel::<String>(16);
+        let tree = tree_index::TreeIndex::open(&dir, false, |size| size, |_| {});
+        let state = AppState {
+            root: "uploads".to_string(),
+            read_only: false,
+            quotas: Vec::new(),
+            quota_ledger: Arc::default(),
+            broadcaster: tx,
+            upload_dir: dir.clone(),
+            max_upload_bytes: 1 << 20,
+            journal: Arc::new(Mutex::new(journal::Journal::new(60))),
+            revisions: RevisionRetention {
+                keep: 5,
+                max_age_secs: 0,
+            },
+            content_index: None,
+            media_queue: spawn_media_worker(tree.clone(), dir.clone()),
+            tree,
+            revision_tree: tree_index::TreeIndex::open(
+                &dir.join(REVISIONS_DIR),
+                false,
+                |size| size,
+                |_| {},
+            ),
+            trash_tree: tree_index::TreeIndex::open(
+                &dir.join(TRASH_DIR),
+                false,
+                |size| size,
+                |_| {},
+            ),
+            hashes: hash_index::HashIndex::open(&dir, false),
+            dav_locks: Arc::new(Mutex::new(webdav::Locks::default())),
+            s3: None,
+            storage: Arc::new(storage::LocalStorage::new(&dir)),
+        };
+        (dir, web::Data::new(Roots(vec![web::Data::new(state)])))
+    }
+
+    fn upload(path: &str, filename: &str) -> TestRequest {
+        let body = format!(
+            "--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\r\nx\r\n--b--\r\n",
+            filename
+        );
+        TestRequest::post()
+            .uri(&format!("/api/upload?path={}", path))
+            .insert_header(("content-type", "multipart/form-data; boundary=b"))
+            .set_payload(body)
+    }
+
+    #[actix_web::test]
+    async fn changes_into_the_data_dir_are_refused() {
+        let (dir, roots) = test_roots();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::create_dir_all(dir.join(TRASH_DIR)).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(roots)
+                .route("/api/upload", web::post().to(upload_file))
+                .route("/api/folder", web::post().to(create_folder))
+                .route("/api/rename", web::post().to(rename_item))
+                .route("/api/move", web::post().to(move_item))
+                .route("/api/copy", web::post().to(copy_item))
+                .route("/api/content", web::post().to(save_content))
+                .route("/api/newfile", web::post().to(create_new_file)),
+        )
+        .await;
+
+        let post = |uri: &str, body: serde_json::Value| {
+            TestRequest::post().uri(uri).set_json(body).to_request()
+        };
+        let requests = [
+            post("/api/rename", json!({"path": "a.txt", "new_name": ".boxy"})),
+            post("/api/rename", json!({"path": ".boxy", "new_name": "x"})),
+            post(
+                "/api/move",
+                json!({"path": "a.txt", "dest_dir": ".boxy/trash"}),
+            ),
+            post("/api/move", json!({"path": ".boxy/trash", "dest_dir": "x"})),
+            post("/api/copy", json!({"path": "a.txt", "dest_dir": ".boxy"})),
+            post("/api/copy", json!({"path": ".boxy", "dest_dir": "x"})),
+            post("/api/folder", json!({"name": "index", "path": ".boxy"})),
+            post(
+                "/api/content",
+                json!({"path": ".boxy/a.txt", "content": "x"}),
+            ),
+            post(
+                "/api/newfile",
+                json!({"filename": "a.txt", "path": ".boxy"}),
+            ),
+            upload(".boxy/trash", "a.txt").to_request(),
+            upload("", ".boxy/journal.txt").to_request(),
+        ];
+        for req in requests {
+            let uri = req.uri().to_string();
+            let status = call_service(&app, req).await.status();
+            assert!(status.is_client_error(), "{} answered {}", uri, status);
+        }
+
+        // Nothing moved, and only the trash folder is in the data dir
+        assert!(dir.join("a.txt").is_file());
+        assert_eq!(std::fs::read_dir(dir.join(DATA_DIR)).unwrap().count(), 1);
+        assert!(std::fs::read_dir(dir.join(TRASH_DIR))
+            .unwrap()
+            .next()
+            .is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[actix_web::test]
+    async fn batches_touching_the_data_dir_run_nothing() {
+        let (dir, roots) = test_roots();
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+        std::fs::create_dir_all(dir.join(TRASH_DIR)).unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(roots)
+                .route("/api/batch", web::post().to(batch_operations)),
+        )
+        .await;
+
+        let batches = [
+            json!([{"op": "move", "path": ".boxy/trash", "dest_dir": "x"}]),
+            json!([{"op": "copy", "path": "a.txt", "dest_dir": ".boxy/trash"}]),
+            json!([{"op": "rename", "path": "a.txt", "new_name": ".boxy"}]),
+        ];
+        for operations in batches {
+            let mut operations = operations.as_array().unwrap().clone();
+            // The delete would go first and need the trash to roll back from
+            operations.insert(0, json!({"op": "delete", "path": "a.txt"}));
+            let req = TestRequest::post()
+                .uri("/api/batch")
+                .set_json(json!({"operations": operations, "atomic": true}))
+                .to_request();
+            assert_eq!(call_service(&app, req).await.status(), 403);
+            assert!(dir.join("a.txt").is_file());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn external_changes_broadcast_alone_or_as_a_batch() {
+        let (tx, mut rx) = broadcast::channel::<String>(4);
+        broadcast_external_changes(&tx, vec![("a.txt".into(), Change::Modified)]);
+        let msg: serde_json::Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(msg, json!({ "action": "edit", "path": "a.txt" }));
+
+        broadcast_external_changes(
+            &tx,
+            vec![
+                ("docs".into(), Change::Added { is_dir: true }),
+                ("docs/b.txt".into(), Change::Added { is_dir: false }),
+                ("c.txt".into(), Change::Removed),
+            ],
+        );
+        let msg: serde_json::Value = serde_json::from_str(&rx.try_recv().unwrap()).unwrap();
+        assert_eq!(
+            msg,
+            json!({
+                "action": "batch",
+                "path": "",
+                "items": [
+                    { "action": "folder", "path": "docs" },
+                    { "action": "upload", "path": "docs/b.txt" },
+                    { "action": "delete", "path": "c.txt" },
+                ],
+            })
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[actix_web::test]
+    async fn ranked_search_pages_follow_the_cursor() {
+        let (dir, roots) = test_roots();
+        std::fs::create_dir_all(dir.join("x")).unwrap();
+        for name in [
+            "report.txt",
+            "report-a.txt",
+            "report-b.txt",
+            "old-report.txt",
+            "x/report.md",
+        ] {
+            std::fs::write(dir.join(name), "r").unwrap();
+        }
+        let app = init_service(
+            App::new()
+                .app_data(roots)
+                .route("/api/search", web::get().to(search_files)),
+        )
+        .await;
+        let search = |cursor: &str| {
+            TestRequest::get()
+                .uri(&format!("/api/search?q=report&limit=3&cursor={}", cursor))
+                .to_request()
+        };
+
+        let first: serde_json::Value = read_body_json(call_service(&app, search("")).await).await;
+        let cursor = first["next_cursor"].as_str().expect("a second page");
+        assert_eq!(first["truncated"], true);
+        let (score, _) = cursor.split_once(':').unwrap();
+        assert!(score.parse::<i64>().is_ok(), "cursor {}", cursor);
+
+        let second: serde_json::Value =
+            read_body_json(call_service(&app, search(cursor)).await).await;
+        assert!(second["next_cursor"].is_null());
+
+        let paths = |page: &serde_json::Value| -> Vec<String> {
+            page["results"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|r| r["path"].as_str().unwrap().to_string())
+                .collect()
+        };
+        let (first_paths, second_paths) = (paths(&first), paths(&second));
+        assert_eq!(first_paths.len(), 3);
+        assert_eq!(second_paths.len(), 2);
+        assert!(first_paths.iter().all(|p| !second_paths.contains(p)));
+        assert_eq!(first["total_estimate"], 5);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
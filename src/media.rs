@@ -0,0 +1,456 @@
+// === Media Metadata ===
+// EXIF for photos (camera, lens, exposure, GPS, capture date, dimensions) and
+// container/codec details for audio and video (duration, bitrate, codecs, ID3,
+// MP4 and FLAC/Vorbis tags). Everything here is blocking; results are cached
+// per file in the tree index.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::Serialize;
+
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "tif", "tiff", "heic", "heif",
+];
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "wav", "ogg", "oga", "m4a", "aac"];
+const VIDEO_EXTENSIONS: &[&str] = &["mp4", "m4v", "mov", "mkv", "webm"];
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaKind {
+    Image,
+    Audio,
+    Video,
+}
+
+#[derive(Serialize)]
+pub struct Camera {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lens: Option<String>,
+    /// Seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exposure_time: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub f_number: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso: Option<u32>,
+    /// Millimetres
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub focal_length: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct Gps {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres above sea level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct MediaInfo {
+    pub kind: MediaKind,
+    /// Pixels as displayed, i.e. after EXIF orientation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    /// Seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<f64>,
+    /// Average bits per second over the whole file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub codecs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera: Option<Camera>,
+    /// Capture time from EXIF, `YYYY-MM-DDTHH:MM:SS` in the camera's local time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps: Option<Gps>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u32>,
+    /// Title, artist, album and similar tags
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+impl MediaInfo {
+    /// Metadata with nothing filled in, for files that couldn't be read.
+    pub fn empty(kind: MediaKind) -> Self {
+        MediaInfo {
+            kind,
+            width: None,
+            height: None,
+            duration: None,
+            bitrate: None,
+            codecs: Vec::new(),
+            sample_rate: None,
+            channels: None,
+            camera: None,
+            captured: None,
+            gps: None,
+            orientation: None,
+            tags: BTreeMap::new(),
+        }
+    }
+}
+
+/// Media kind of a file name, by extension.
+pub fn kind(name: &str) -> Option<MediaKind> {
+    let ext = name.rsplit_once('.')?.1.to_lowercase();
+    if IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Image)
+    } else if AUDIO_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Audio)
+    } else if VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        Some(MediaKind::Video)
+    } else {
+        None
+    }
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Read the metadata of a media file. Errors if the type isn't supported or the
+/// file can't be parsed at all; missing individual fields are simply left out.
+pub fn extract(path: &Path) -> io::Result<MediaInfo> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    match kind(name) {
+        Some(MediaKind::Image) => Ok(extract_image(path)),
+        Some(kind) => extract_av(path, kind),
+        None => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Not an image, audio or video file",
+        )),
+    }
+}
+
+// --- Images ---
+
+fn extract_image(path: &Path) -> MediaInfo {
+    let mut info = MediaInfo::empty(MediaKind::Image);
+
+    let dimensions = image::ImageReader::open(path)
+        .ok()
+        .and_then(|r| r.with_guessed_format().ok())
+        .and_then(|r| r.into_dimensions().ok());
+    if let Some((width, height)) = dimensions {
+        info.width = Some(width);
+        info.height = Some(height);
+    }
+
+    let exif = File::open(path).ok().and_then(|f| {
+        exif::Reader::new()
+            .read_from_container(&mut BufReader::new(f))
+            .ok()
+    });
+    if let Some(exif) = exif {
+        read_exif(&exif, &mut info);
+    }
+
+    // Rotated by 90° or 270°: the displayed image is the other way round
+    if matches!(info.orientation, Some(5..=8)) {
+        std::mem::swap(&mut info.width, &mut info.height);
+    }
+    info
+}
+
+fn read_exif(exif: &exif::Exif, info: &mut MediaInfo) {
+    use exif::{In, Tag, Value};
+
+    let field = |tag| exif.get_field(tag, In::PRIMARY).map(|f| &f.value);
+    let text = |tag| match field(tag)? {
+        Value::Ascii(parts) => {
+            let text = String::from_utf8_lossy(parts.first()?).trim().to_string();
+            (!text.is_empty()).then_some(text)
+        }
+        _ => None,
+    };
+    let rationals = |tag| match field(tag)? {
+        Value::Rational(values) => Some(values.iter().map(|r| r.to_f64()).collect::<Vec<_>>()),
+        _ => None,
+    };
+    let number = |tag| rationals(tag)?.first().copied().filter(|v| v.is_finite());
+    let uint = |tag| field(tag)?.get_uint(0);
+
+    let camera = Camera {
+        make: text(Tag::Make),
+        model: text(Tag::Model),
+        lens: text(Tag::LensModel),
+        exposure_time: number(Tag::ExposureTime),
+        f_number: number(Tag::FNumber),
+        iso: uint(Tag::PhotographicSensitivity),
+        focal_length: number(Tag::FocalLength),
+    };
+    if camera.make.is_some() || camera.model.is_some() || camera.lens.is_some() {
+        info.camera = Some(camera);
+    }
+
+    // `2024:05:01 14:03:22` -> `2024-05-01T14:03:22`
+    info.captured = text(Tag::DateTimeOriginal)
+        .or_else(|| text(Tag::DateTime))
+        .and_then(|dt| {
+            let (date, time) = dt.split_once(' ')?;
+            Some(format!("{}T{}", date.replace(':', "-"), time))
+        });
+    info.orientation = uint(Tag::Orientation);
+
+    if info.width.is_none() {
+        info.width = uint(Tag::PixelXDimension);
+        info.height = uint(Tag::PixelYDimension);
+    }
+
+    // Degrees, minutes, seconds plus a hemisphere reference
+    let coordinate = |tag, reference, negative: &str| {
+        let dms = rationals(tag)?;
+        let [degrees, minutes, seconds] = dms.as_slice() else {
+            return None;
+        };
+        let value = degrees + minutes / 60.0 + seconds / 3600.0;
+        let negate = text(reference).is_some_and(|r| r.eq_ignore_ascii_case(negative));
+        Some(if negate { -value } else { value })
+    };
+    let latitude = coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+    let longitude = coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+        let below_sea_level = uint(Tag::GPSAltitudeRef) == Some(1);
+        info.gps = Some(Gps {
+            latitude,
+            longitude,
+            altitude: number(Tag::GPSAltitude).map(|a| if below_sea_level { -a } else { a }),
+        });
+    }
+}
+
+// --- Audio and video ---
+
+fn extract_av(path: &Path, kind: MediaKind) -> io::Result<MediaInfo> {
+    let mut info = MediaInfo::empty(kind);
+    let size = path.metadata()?.len();
+    let probed = probe_av(path, &mut info);
+
+    let is_mp4 = matches!(
+        path.extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_lowercase)
+            .as_deref(),
+        Some("mp4" | "m4v" | "mov")
+    );
+    if kind == MediaKind::Video && is_mp4 {
+        read_mp4_video(path, &mut info);
+    }
+    // A video track alone is still worth reporting if the demuxer gave up
+    if let Err(e) = probed {
+        if info.width.is_none() {
+            return Err(e);
+        }
+    }
+
+    if let Some(duration) = info.duration.filter(|d| *d > 0.0) {
+        info.bitrate = Some((size as f64 * 8.0 / duration) as u64);
+    }
+    Ok(info)
+}
+
+/// Tags, codecs and duration from the container, via symphonia's demuxers.
+fn probe_av(path: &Path, info: &mut MediaInfo) -> io::Result<()> {
+    use symphonia::core::codecs::CODEC_TYPE_NULL;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(invalid_data)?;
+
+    // Tags can sit in front of the container (ID3v2) or inside it (MP4 atoms, Vorbis comments)
+    if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+        read_tags(revision, &mut info.tags);
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        read_tags(revision, &mut info.tags);
+    }
+
+    let codecs = symphonia::default::get_codecs();
+    for track in probed.format.tracks() {
+        let params = &track.codec_params;
+        if params.codec != CODEC_TYPE_NULL {
+            if let Some(codec) = codecs.get_codec(params.codec) {
+                info.codecs.push(codec.short_name.to_string());
+            }
+        }
+        if info.sample_rate.is_none() {
+            info.sample_rate = params.sample_rate;
+            info.channels = params.channels.map(|c| c.count() as u32);
+        }
+
+        let duration = match (params.time_base, params.n_frames) {
+            (Some(time_base), Some(frames)) => {
+                let time = time_base.calc_time(frames);
+                Some(time.seconds as f64 + time.frac)
+            }
+            (None, Some(frames)) => params.sample_rate.map(|rate| frames as f64 / rate as f64),
+            _ => None,
+        };
+        if let Some(duration) = duration {
+            info.duration = Some(info.duration.unwrap_or(0.0).max(duration));
+        }
+    }
+    Ok(())
+}
+
+fn read_tags(
+    revision: &symphonia::core::meta::MetadataRevision,
+    tags: &mut BTreeMap<String, String>,
+) {
+    use symphonia::core::meta::{StandardTagKey as Key, Value};
+
+    for tag in revision.tags() {
+        let name = match tag.std_key {
+            Some(Key::TrackTitle) => "title",
+            Some(Key::Artist) => "artist",
+            Some(Key::Album) => "album",
+            Some(Key::AlbumArtist) => "album_artist",
+            Some(Key::Composer) => "composer",
+            Some(Key::Genre) => "genre",
+            Some(Key::Date | Key::ReleaseDate) => "date",
+            Some(Key::TrackNumber) => "track",
+            Some(Key::DiscNumber) => "disc",
+            Some(Key::Comment) => "comment",
+            Some(Key::Encoder) => "encoder",
+            _ => continue,
+        };
+        if matches!(tag.value, Value::Binary(_)) {
+            continue;
+        }
+        // RIFF INFO strings come NUL-padded
+        let value = tag.value.to_string();
+        let value = value.trim_end_matches('\0').trim();
+        if !value.is_empty() {
+            tags.entry(name.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+    }
+}
+
+/// Dimensions and codec of the first video track of an MP4/QuickTime file, read from
+/// `moov/trak/tkhd` and the track's sample description. The audio demuxer skips these.
+fn read_mp4_video(path: &Path, info: &mut MediaInfo) {
+    let Ok(mut file) = File::open(path) else {
+        return;
+    };
+    let Ok(end) = file.seek(SeekFrom::End(0)) else {
+        return;
+    };
+    let Some(moov) = find_atom(&mut file, 0, end, b"moov") else {
+        return;
+    };
+
+    // Payloads found by `find_atom` end within the file, so these sums can't overflow
+    let (mut offset, moov_end) = (moov.0, moov.0 + moov.1);
+    while let Some((start, size)) = find_atom(&mut file, offset, moov_end, b"trak") {
+        offset = start + size;
+        let Some(stsd) = [b"mdia", b"minf", b"stbl", b"stsd"]
+            .iter()
+            .try_fold((start, start + size), |(s, e), name| {
+                find_atom(&mut file, s, e, name).map(|(s, size)| (s, s + size))
+            })
+        else {
+            continue;
+        };
+
+        // stsd: version/flags, entry count, then the first sample entry's size and type
+        let mut entry = [0u8; 16];
+        if read_at(&mut file, stsd.0, &mut entry).is_err() {
+            continue;
+        }
+        let codec = match &entry[12..16] {
+            b"avc1" | b"avc3" => "h264",
+            b"hvc1" | b"hev1" => "hevc",
+            b"av01" => "av1",
+            b"vp09" => "vp9",
+            b"mp4v" => "mpeg4",
+            b"apcn" | b"apch" | b"apcs" | b"apco" | b"ap4h" => "prores",
+            _ => continue,
+        };
+
+        // tkhd ends with 16.16 fixed-point width and height
+        let Some((tkhd, tkhd_size)) = find_atom(&mut file, start, start + size, b"tkhd") else {
+            continue;
+        };
+        let mut dims = [0u8; 8];
+        let dims_at = (tkhd + tkhd_size).checked_sub(8).filter(|&at| at >= tkhd);
+        if dims_at.is_some_and(|at| read_at(&mut file, at, &mut dims).is_ok()) {
+            let width = u32::from_be_bytes([dims[0], dims[1], dims[2], dims[3]]) >> 16;
+            let height = u32::from_be_bytes([dims[4], dims[5], dims[6], dims[7]]) >> 16;
+            if width > 0 && height > 0 {
+                info.width = Some(width);
+                info.height = Some(height);
+            }
+        }
+        info.codecs.insert(0, codec.to_string());
+        return;
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)
+}
+
+/// Find the first child atom named `name` between `start` and `end`.
+/// Returns the offset of its payload and the payload size, which always ends by `end`.
+/// Sizes come from the file, so any that overflow or overrun `end` stop the search.
+fn find_atom(file: &mut File, mut start: u64, end: u64, name: &[u8; 4]) -> Option<(u64, u64)> {
+    while start.checked_add(8)? <= end {
+        let mut header = [0u8; 8];
+        read_at(file, start, &mut header).ok()?;
+        let mut size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64;
+        let mut header_len = 8;
+        if size == 1 {
+            // 64-bit size follows the type
+            let mut large = [0u8; 8];
+            read_at(file, start.checked_add(8)?, &mut large).ok()?;
+            size = u64::from_be_bytes(large);
+            header_len = 16;
+        } else if size == 0 {
+            size = end.checked_sub(start)?;
+        }
+        let atom_end = start.checked_add(size)?;
+        if size < header_len || atom_end > end {
+            return None;
+        }
+        if &header[4..8] == name {
+            return Some((start + header_len, size - header_len));
+        }
+        start = atom_end;
+    }
+    None
+}
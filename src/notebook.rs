@@ -0,0 +1,252 @@
+// === Notebooks ===
+// Jupyter notebooks (nbformat 4) for previews: Markdown cells as sanitized HTML,
+// code cells highlighted in the kernel's language, and stored outputs as text,
+// images or raw HTML. HTML outputs are not sanitized; the UI renders them in a
+// sandboxed frame without scripts.
+
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::preview;
+
+/// Notebooks above this size (mostly embedded images) are not rendered.
+pub const MAX_NOTEBOOK_BYTES: u64 = 50 * 1024 * 1024; // 50 MB
+
+pub const DEFAULT_CELLS: usize = 50;
+pub const MAX_CELLS: usize = 500;
+
+#[derive(Deserialize)]
+struct RawNotebook {
+    #[serde(default)]
+    cells: Vec<RawCell>,
+    #[serde(default)]
+    metadata: Value,
+}
+
+#[derive(Deserialize)]
+struct RawCell {
+    cell_type: String,
+    #[serde(default)]
+    source: MultilineText,
+    #[serde(default)]
+    outputs: Vec<Value>,
+    execution_count: Option<u64>,
+}
+
+/// Notebook text fields are either a string or a list of lines.
+#[derive(Default, Deserialize)]
+#[serde(untagged)]
+enum MultilineText {
+    #[default]
+    Empty,
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl MultilineText {
+    fn into_string(self) -> String {
+        match self {
+            MultilineText::Empty => String::new(),
+            MultilineText::Text(text) => text,
+            MultilineText::Lines(lines) => lines.concat(),
+        }
+    }
+}
+
+fn multiline(value: &Value) -> String {
+    serde_json::from_value::<MultilineText>(value.clone())
+        .map(MultilineText::into_string)
+        .unwrap_or_default()
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Cell {
+    Markdown {
+        html: String,
+    },
+    Code {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        execution_count: Option<u64>,
+        /// Highlighted HTML, one fragment per line
+        lines: Vec<String>,
+        outputs: Vec<Output>,
+    },
+    Raw {
+        text: String,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Output {
+    /// `stdout` or `stderr`
+    Stream {
+        name: String,
+        text: String,
+    },
+    Text {
+        text: String,
+    },
+    /// Sanitized, from a `text/markdown` result
+    Markdown {
+        html: String,
+    },
+    /// Unsanitized; only ever render it sandboxed
+    Html {
+        html: String,
+    },
+    /// Base64 `data`, ready for a `data:` URL
+    Image {
+        mime: String,
+        data: String,
+    },
+    Error {
+        ename: String,
+        evalue: String,
+        traceback: String,
+    },
+}
+
+pub struct Notebook {
+    /// Kernel language, e.g. `python`
+    pub language: String,
+    pub cells: Vec<Cell>,
+    pub total_cells: usize,
+}
+
+/// Parse a notebook and render cells `offset..offset + limit`.
+pub fn render(
+    reader: impl Read,
+    base_dir: &str,
+    offset: usize,
+    limit: usize,
+    dark: bool,
+) -> io::Result<Notebook> {
+    let notebook: RawNotebook = serde_json::from_reader(io::BufReader::new(reader))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let language = ["/language_info/name", "/kernelspec/language"]
+        .iter()
+        .find_map(|pointer| notebook.metadata.pointer(pointer)?.as_str())
+        .unwrap_or("python")
+        .to_lowercase();
+
+    let total_cells = notebook.cells.len();
+    let cells = notebook
+        .cells
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|cell| {
+            let source = cell.source.into_string();
+            Ok(match cell.cell_type.as_str() {
+                "markdown" => Cell::Markdown {
+                    html: preview::markdown_html(&source, base_dir),
+                },
+                "code" => Cell::Code {
+                    execution_count: cell.execution_count,
+                    lines: preview::highlight_snippet(&source, &language, dark)?,
+                    outputs: cell.outputs.iter().filter_map(output).collect(),
+                },
+                _ => Cell::Raw { text: source },
+            })
+        })
+        .collect::<io::Result<_>>()?;
+
+    Ok(Notebook {
+        language,
+        cells,
+        total_cells,
+    })
+}
+
+/// Richest displayable representation of one stored output.
+fn output(output: &Value) -> Option<Output> {
+    match output.get("output_type")?.as_str()? {
+        "stream" => Some(Output::Stream {
+            name: output
+                .get("name")
+                .and_then(Value::as_str)
+                .unwrap_or("stdout")
+                .to_string(),
+            text: strip_ansi(&multiline(output.get("text")?)),
+        }),
+        "execute_result" | "display_data" => {
+            let data = output.get("data")?;
+            for mime in ["image/png", "image/jpeg", "image/gif"] {
+                if let Some(value) = data.get(mime) {
+                    let mut image = multiline(value);
+                    image.retain(|c| !c.is_whitespace());
+                    return Some(Output::Image {
+                        mime: mime.to_string(),
+                        data: image,
+                    });
+                }
+            }
+            if let Some(value) = data.get("text/html").or_else(|| data.get("image/svg+xml")) {
+                return Some(Output::Html {
+                    html: multiline(value),
+                });
+            }
+            if let Some(value) = data.get("text/markdown") {
+                return Some(Output::Markdown {
+                    html: preview::markdown_html(&multiline(value), ""),
+                });
+            }
+            data.get("text/plain").map(|value| Output::Text {
+                text: strip_ansi(&multiline(value)),
+            })
+        }
+        "error" => {
+            let field = |name: &str| {
+                output
+                    .get(name)
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let traceback = output
+                .get("traceback")
+                .and_then(Value::as_array)
+                .map(|lines| {
+                    lines
+                        .iter()
+                        .filter_map(Value::as_str)
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                })
+                .unwrap_or_default();
+            Some(Output::Error {
+                ename: field("ename"),
+                evalue: field("evalue"),
+                traceback: strip_ansi(&traceback),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Drop terminal colour codes (`ESC [ ... m`), which IPython puts in tracebacks.
+fn strip_ansi(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            if chars.clone().next() == Some('[') {
+                chars.next();
+                // Parameters and intermediates, up to the final byte
+                for c in chars.by_ref() {
+                    if ('@'..='~').contains(&c) {
+                        break;
+                    }
+                }
+            }
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
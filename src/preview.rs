@@ -0,0 +1,681 @@
+// === Previews ===
+// Server-rendered previews: Markdown to sanitized HTML, CSV/TSV to typed JSON rows,
+// source files to syntax-highlighted HTML lines, Jupyter notebooks to rendered cells
+// and PDF/Office documents to their extracted text structure. Tables, code, notebooks
+// and documents are paged; Markdown is capped. Everything here is blocking and works
+// on any reader, so archive members preview the same way as files on disk.
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::io::{self, BufRead, BufReader, Read, Seek};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use serde::Serialize;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+use crate::extract::{self, Section};
+use crate::notebook::{self, Cell};
+use crate::EDITABLE_EXTENSIONS;
+
+const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown"];
+const TABLE_EXTENSIONS: &[&str] = &["csv", "tsv"];
+
+/// Markdown beyond this is cut off before rendering.
+const MAX_MARKDOWN_BYTES: u64 = 1024 * 1024; // 1 MB
+
+pub const DEFAULT_TABLE_ROWS: usize = 100;
+pub const MAX_TABLE_ROWS: usize = 1000;
+/// Column types are inferred from this many leading rows, so every page agrees.
+const INFER_ROWS: usize = 1000;
+
+pub const DEFAULT_CODE_LINES: usize = 500;
+pub const MAX_CODE_LINES: usize = 5000;
+/// Larger files are paged as plain text; highlighting a late page means parsing everything before it.
+const MAX_HIGHLIGHT_BYTES: u64 = 4 * 1024 * 1024; // 4 MB
+/// Longer lines (minified code, data blobs) are shown plain and cut at `MAX_LINE_BYTES`.
+const MAX_HIGHLIGHT_LINE: usize = 16 * 1024;
+const MAX_LINE_BYTES: usize = 64 * 1024;
+
+/// Pages, slides or sheets per document page.
+pub const DEFAULT_DOCUMENT_SECTIONS: usize = 20;
+pub const MAX_DOCUMENT_SECTIONS: usize = 200;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Markdown,
+    Table,
+    Code,
+    Notebook,
+    Document,
+}
+
+pub fn kind(path: &Path) -> Option<Kind> {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    if MARKDOWN_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Kind::Markdown)
+    } else if TABLE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Kind::Table)
+    } else if ext == "ipynb" {
+        Some(Kind::Notebook)
+    } else if EDITABLE_EXTENSIONS.contains(&ext.as_str()) {
+        Some(Kind::Code)
+    } else if extract::is_document(path) {
+        Some(Kind::Document)
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Preview {
+    Markdown {
+        html: String,
+        truncated: bool,
+    },
+    Table {
+        columns: Vec<Column>,
+        rows: Vec<Vec<serde_json::Value>>,
+        offset: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_offset: Option<usize>,
+        /// Known once a page reaches the end of the file
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total_rows: Option<usize>,
+    },
+    Code {
+        language: String,
+        highlighted: bool,
+        /// One HTML fragment per line, without the trailing newline
+        lines: Vec<String>,
+        offset: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_offset: Option<usize>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total_lines: Option<usize>,
+        /// Some lines were too long and got cut
+        truncated: bool,
+    },
+    Notebook {
+        /// Kernel language the code cells are highlighted as
+        language: String,
+        cells: Vec<Cell>,
+        offset: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_offset: Option<usize>,
+        total_cells: usize,
+    },
+    Document {
+        /// File extension: `pdf`, `docx`, `pptx` or `xlsx`
+        format: String,
+        sections: Vec<Section>,
+        offset: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_offset: Option<usize>,
+        total_sections: usize,
+        /// Text extraction stopped at its size cap
+        truncated: bool,
+    },
+}
+
+#[derive(Serialize)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: ColumnType,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Integer,
+    Number,
+    Boolean,
+    Date,
+    String,
+}
+
+pub struct Options {
+    /// Folder of the previewed file, for resolving relative links in Markdown
+    pub base_dir: String,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub dark: bool,
+}
+
+/// Render a preview of `reader`, the contents of a `size`-byte file named `path`.
+pub fn render(
+    path: &Path,
+    size: u64,
+    reader: impl Read + Seek,
+    options: &Options,
+) -> io::Result<Preview> {
+    match kind(path) {
+        Some(Kind::Markdown) => markdown(reader, &options.base_dir),
+        Some(Kind::Table) => {
+            let tsv = path
+                .extension()
+                .is_some_and(|e| e.eq_ignore_ascii_case("tsv"));
+            let limit = options
+                .limit
+                .unwrap_or(DEFAULT_TABLE_ROWS)
+                .clamp(1, MAX_TABLE_ROWS);
+            table(
+                reader,
+                if tsv { b'\t' } else { b',' },
+                options.offset,
+                limit,
+            )
+        }
+        Some(Kind::Code) => {
+            let limit = options
+                .limit
+                .unwrap_or(DEFAULT_CODE_LINES)
+                .clamp(1, MAX_CODE_LINES);
+            code(path, size, reader, options.offset, limit, options.dark)
+        }
+        Some(Kind::Notebook) => {
+            if size > notebook::MAX_NOTEBOOK_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "Notebook is too large to preview",
+                ));
+            }
+            let limit = options
+                .limit
+                .unwrap_or(notebook::DEFAULT_CELLS)
+                .clamp(1, notebook::MAX_CELLS);
+            let nb = notebook::render(
+                reader,
+                &options.base_dir,
+                options.offset,
+                limit,
+                options.dark,
+            )?;
+            let end = options.offset.saturating_add(limit);
+            Ok(Preview::Notebook {
+                language: nb.language,
+                cells: nb.cells,
+                offset: options.offset,
+                next_offset: (nb.total_cells > end).then_some(end),
+                total_cells: nb.total_cells,
+            })
+        }
+        Some(Kind::Document) => {
+            if size > extract::MAX_EXTRACT_FILE_BYTES {
+                return Err(io::Error::new(
+                    io::ErrorKind::FileTooLarge,
+                    "Document is too large to preview",
+                ));
+            }
+            let limit = options
+                .limit
+                .unwrap_or(DEFAULT_DOCUMENT_SECTIONS)
+                .clamp(1, MAX_DOCUMENT_SECTIONS);
+            document(path, reader, options.offset, limit)
+        }
+        None => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "No preview for this file type",
+        )),
+    }
+}
+
+// --- Markdown ---
+
+fn markdown(reader: impl Read, base_dir: &str) -> io::Result<Preview> {
+    let mut bytes = Vec::new();
+    reader
+        .take(MAX_MARKDOWN_BYTES + 1)
+        .read_to_end(&mut bytes)?;
+    let truncated = bytes.len() as u64 > MAX_MARKDOWN_BYTES;
+    bytes.truncate(MAX_MARKDOWN_BYTES as usize);
+    let html = markdown_html(&String::from_utf8_lossy(&bytes), base_dir);
+    Ok(Preview::Markdown { html, truncated })
+}
+
+/// Sanitized HTML for Markdown text; relative links resolve against `base_dir`.
+pub fn markdown_html(text: &str, base_dir: &str) -> String {
+    use pulldown_cmark::{html, Options as MdOptions, Parser};
+
+    let options = MdOptions::ENABLE_TABLES
+        | MdOptions::ENABLE_TASKLISTS
+        | MdOptions::ENABLE_STRIKETHROUGH
+        | MdOptions::ENABLE_FOOTNOTES;
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, Parser::new_ext(text, options));
+
+    // Raw HTML in the document is allowed through pulldown-cmark, so this is the only gate
+    ammonia::Builder::default()
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_tag_attributes("td", ["style"])
+        .add_tag_attributes("th", ["style"])
+        .filter_style_properties(HashSet::from(["text-align"]))
+        .link_rel(Some("noopener noreferrer"))
+        .url_relative(ammonia::UrlRelative::Custom(Box::new(RelativeLinks(
+            base_dir.to_string(),
+        ))))
+        .clean(&unsafe_html)
+        .to_string()
+}
+
+/// Points relative links and images at the download endpoint, resolved against the
+/// Markdown file's folder. Links that climb out of the upload root are dropped.
+struct RelativeLinks(String);
+
+impl<'a> ammonia::UrlRelativeEvaluate<'a> for RelativeLinks {
+    fn evaluate<'url>(&self, url: &'url str) -> Option<Cow<'url, str>> {
+        relative_url(&self.0, url)
+    }
+}
+
+fn relative_url<'url>(base_dir: &str, url: &'url str) -> Option<Cow<'url, str>> {
+    if url.starts_with('#') {
+        return Some(Cow::Borrowed(url));
+    }
+    let (target, fragment) = match url.split_once('#') {
+        Some((target, fragment)) => (target, Some(fragment)),
+        None => (url, None),
+    };
+    let target = target.split('?').next().unwrap_or(target);
+
+    let target = decode_percent(target);
+    let mut parts: Vec<&str> = if target.starts_with('/') {
+        Vec::new()
+    } else {
+        base_dir.split('/').filter(|p| !p.is_empty()).collect()
+    };
+    for part in target.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            _ => parts.push(part),
+        }
+    }
+
+    let mut resolved = format!("/api/download?path={}", encode_query(&parts.join("/")));
+    if let Some(fragment) = fragment {
+        resolved.push('#');
+        resolved.push_str(fragment);
+    }
+    Some(Cow::Owned(resolved))
+}
+
+/// Markdown link targets are URLs, so `my%20file.png` names `my file.png`.
+fn decode_percent(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn encode_query(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~' | b'/') {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    out
+}
+
+// --- CSV / TSV ---
+
+/// One pass: the first `INFER_ROWS` records decide the column types, and the
+/// `offset..offset + limit` records are kept for the page.
+fn table(reader: impl Read, delimiter: u8, offset: usize, limit: usize) -> io::Result<Preview> {
+    let mut csv = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .has_headers(true)
+        .from_reader(reader);
+
+    let headers: Vec<String> = csv
+        .byte_headers()
+        .map_err(csv_error)?
+        .iter()
+        .map(|h| String::from_utf8_lossy(h).into_owned())
+        .collect();
+
+    let mut sample: Vec<Vec<String>> = Vec::new();
+    let mut page: Vec<Vec<String>> = Vec::new();
+    let mut record = csv::ByteRecord::new();
+    let mut index = 0;
+    let mut at_end = false;
+    // Offsets come from the query string, so page ends saturate rather than overflow
+    let end = offset.saturating_add(limit);
+    // One record past the page tells whether there is a next one
+    let stop = end.saturating_add(1).max(INFER_ROWS);
+    while index < stop {
+        if !csv.read_byte_record(&mut record).map_err(csv_error)? {
+            at_end = true;
+            break;
+        }
+        let cells = || {
+            record
+                .iter()
+                .map(|c| String::from_utf8_lossy(c).into_owned())
+                .collect::<Vec<_>>()
+        };
+        if index < INFER_ROWS {
+            sample.push(cells());
+        }
+        if index >= offset && index < end {
+            page.push(cells());
+        }
+        index += 1;
+    }
+
+    let width = sample
+        .iter()
+        .map(Vec::len)
+        .chain([headers.len()])
+        .max()
+        .unwrap_or(0);
+    let columns: Vec<Column> = (0..width)
+        .map(|i| Column {
+            name: headers
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| format!("column {}", i + 1)),
+            column_type: infer_type(sample.iter().filter_map(|row| row.get(i))),
+        })
+        .collect();
+
+    let rows = page
+        .into_iter()
+        .map(|row| {
+            (0..width)
+                .map(|i| typed_value(row.get(i).map(String::as_str), columns[i].column_type))
+                .collect()
+        })
+        .collect();
+
+    Ok(Preview::Table {
+        columns,
+        rows,
+        offset,
+        next_offset: (index > end).then_some(end),
+        total_rows: at_end.then_some(index),
+    })
+}
+
+fn csv_error(e: csv::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Narrowest type every non-empty cell fits, falling back to string.
+fn infer_type<'a>(cells: impl Iterator<Item = &'a String>) -> ColumnType {
+    let mut candidates = [
+        ColumnType::Integer,
+        ColumnType::Number,
+        ColumnType::Boolean,
+        ColumnType::Date,
+    ]
+    .to_vec();
+    let mut seen = false;
+    for cell in cells.map(|c| c.trim()).filter(|c| !c.is_empty()) {
+        seen = true;
+        candidates.retain(|t| parses_as(cell, *t));
+        if candidates.is_empty() {
+            return ColumnType::String;
+        }
+    }
+    if seen {
+        candidates[0]
+    } else {
+        ColumnType::String
+    }
+}
+
+fn parses_as(cell: &str, column_type: ColumnType) -> bool {
+    match column_type {
+        ColumnType::Integer => cell.parse::<i64>().is_ok(),
+        ColumnType::Number => cell.parse::<f64>().is_ok_and(f64::is_finite),
+        ColumnType::Boolean => parse_bool(cell).is_some(),
+        ColumnType::Date => is_date(cell),
+        ColumnType::String => true,
+    }
+}
+
+fn parse_bool(cell: &str) -> Option<bool> {
+    match cell.to_lowercase().as_str() {
+        "true" | "yes" => Some(true),
+        "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// ISO 8601 date, optionally followed by a time (`2024-05-01`, `2024-05-01T14:03:22Z`).
+fn is_date(cell: &str) -> bool {
+    let bytes = cell.as_bytes();
+    if bytes.len() < 10 || !cell.is_char_boundary(10) {
+        return false;
+    }
+    let digits = |range: std::ops::Range<usize>| bytes[range].iter().all(u8::is_ascii_digit);
+    let (date, rest) = cell.split_at(10);
+    let month: u32 = date[5..7].parse().unwrap_or(0);
+    let day: u32 = date[8..10].parse().unwrap_or(0);
+    digits(0..4)
+        && bytes[4] == b'-'
+        && digits(5..7)
+        && bytes[7] == b'-'
+        && digits(8..10)
+        && (1..=12).contains(&month)
+        && (1..=31).contains(&day)
+        && (rest.is_empty() || rest.starts_with('T') || rest.starts_with(' '))
+}
+
+fn typed_value(cell: Option<&str>, column_type: ColumnType) -> serde_json::Value {
+    use serde_json::Value;
+
+    let Some(cell) = cell else {
+        return Value::Null;
+    };
+    let trimmed = cell.trim();
+    if trimmed.is_empty() {
+        return Value::Null;
+    }
+    match column_type {
+        ColumnType::Integer => trimmed.parse::<i64>().map(Value::from).ok(),
+        ColumnType::Number => trimmed.parse::<f64>().map(Value::from).ok(),
+        ColumnType::Boolean => parse_bool(trimmed).map(Value::Bool),
+        ColumnType::Date | ColumnType::String => None,
+    }
+    .unwrap_or_else(|| Value::String(cell.to_string()))
+}
+
+// --- Source code ---
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAXES: OnceLock<SyntaxSet> = OnceLock::new();
+    SYNTAXES.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme(dark: bool) -> &'static Theme {
+    static THEMES: OnceLock<ThemeSet> = OnceLock::new();
+    let themes = THEMES.get_or_init(ThemeSet::load_defaults);
+    &themes.themes[if dark {
+        "base16-ocean.dark"
+    } else {
+        "InspiredGitHub"
+    }]
+}
+
+fn code(
+    path: &Path,
+    size: u64,
+    reader: impl Read,
+    offset: usize,
+    limit: usize,
+    dark: bool,
+) -> io::Result<Preview> {
+    let syntaxes = syntax_set();
+    let syntax = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .and_then(|e| syntaxes.find_syntax_by_extension(&e.to_lowercase()))
+        .unwrap_or_else(|| syntaxes.find_syntax_plain_text());
+    let highlight = size <= MAX_HIGHLIGHT_BYTES && syntax.name != "Plain Text";
+    let mut highlighter = highlight.then(|| HighlightLines::new(syntax, theme(dark)));
+
+    let mut reader = BufReader::new(reader);
+    let mut lines = Vec::new();
+    let mut line = Vec::new();
+    let mut index = 0;
+    let mut truncated = false;
+    let mut at_end = false;
+    let end = offset.saturating_add(limit);
+    while index <= end {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            at_end = true;
+            break;
+        }
+        let in_page = index >= offset && index < end;
+        // Lines before the page only matter for the highlighter's parse state
+        if in_page || (highlighter.is_some() && index < offset) {
+            let text = String::from_utf8_lossy(&line);
+            let html = match highlighter.as_mut() {
+                Some(h) if text.len() <= MAX_HIGHLIGHT_LINE => {
+                    let html = highlight_line(h, &text)?;
+                    in_page.then_some(html)
+                }
+                _ if in_page => {
+                    let (html, cut) = plain_line(&text);
+                    truncated |= cut;
+                    Some(html)
+                }
+                _ => None,
+            };
+            if let Some(html) = html {
+                lines.push(html);
+            }
+        }
+        index += 1;
+    }
+
+    Ok(Preview::Code {
+        language: syntax.name.clone(),
+        highlighted: highlighter.is_some(),
+        lines,
+        offset,
+        next_offset: (index > end).then_some(end),
+        total_lines: at_end.then_some(index),
+        truncated,
+    })
+}
+
+/// One line through the highlighter, as HTML without the line ending.
+fn highlight_line(highlighter: &mut HighlightLines, text: &str) -> io::Result<String> {
+    let regions = highlighter
+        .highlight_line(text, syntax_set())
+        .map_err(invalid_data)?;
+    // The newline is part of the last region
+    let regions: Vec<_> = regions
+        .into_iter()
+        .map(|(style, part)| (style, part.trim_end_matches(['\n', '\r'])))
+        .filter(|(_, part)| !part.is_empty())
+        .collect();
+    styled_line_to_highlighted_html(&regions, IncludeBackground::No).map_err(invalid_data)
+}
+
+/// An escaped line without highlighting, cut at `MAX_LINE_BYTES`. The flag says if it was cut.
+fn plain_line(text: &str) -> (String, bool) {
+    let text = text.trim_end_matches(['\n', '\r']);
+    let mut end = text.len().min(MAX_LINE_BYTES);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    (escape_html(&text[..end]), end < text.len())
+}
+
+/// Highlight a snippet, such as a notebook cell, written in `language` (`python`, `rust`, ...).
+pub fn highlight_snippet(source: &str, language: &str, dark: bool) -> io::Result<Vec<String>> {
+    let syntaxes = syntax_set();
+    let mut highlighter = syntaxes
+        .find_syntax_by_token(language)
+        .filter(|syntax| syntax.name != "Plain Text")
+        .map(|syntax| HighlightLines::new(syntax, theme(dark)));
+    LinesWithEndings::from(source)
+        .map(|line| match highlighter.as_mut() {
+            Some(h) if line.len() <= MAX_HIGHLIGHT_LINE => highlight_line(h, line),
+            _ => Ok(plain_line(line).0),
+        })
+        .collect()
+}
+
+// --- Documents ---
+
+fn document(
+    path: &Path,
+    reader: impl Read + Seek,
+    offset: usize,
+    limit: usize,
+) -> io::Result<Preview> {
+    let doc = extract::extract_document(path, reader)?;
+    let total_sections = doc.sections.len();
+    let end = offset.saturating_add(limit);
+    let sections = doc.sections.into_iter().skip(offset).take(limit).collect();
+    Ok(Preview::Document {
+        format: path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase(),
+        sections,
+        offset,
+        next_offset: (total_sections > end).then_some(end),
+        total_sections,
+        truncated: doc.truncated,
+    })
+}
+
+fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
@@ -0,0 +1,372 @@
+// === Quotas ===
+// Limits on the bytes and files stored below a folder of a root; the whole root is the
+// empty path. Usage comes from the tree index plus those of the trash and the kept
+// revisions (or a walk of the backend without local files), and changes are checked
+// before they land: uploads stop as soon as a file would go over, while copies, moves
+// and edits are refused up front. Replaced items aren't credited, so an overwrite near
+// the limit can be refused even though it would fit. Quotas are per root and folder;
+// per-user quotas still need an identity to key them by (WebDAV is unauthenticated
+// and S3 has a single key pair) and are left to a follow-up.
+//
+// Concurrent changes claim what they add in the root's `Ledger` under one lock, so two
+// uploads can't both spend the same room; a claim counts until the change is in the
+// tree index. Handlers that reindex the change themselves drop the tracker after that;
+// the others hand it to `Tracker::finish`.
+
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, Result};
+use serde::Serialize;
+
+use crate::{storage, tree_index::TreeIndex, AppState};
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Quota {
+    /// Folder the quota covers, relative to the root; empty for the whole root
+    pub path: String,
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+impl Quota {
+    /// `folder=bytes[:files]`, either limit may be left empty (`photos=:5000`).
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (path, limits) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected folder=bytes[:files], got {}", spec))?;
+        let (bytes, files) = limits.split_once(':').unwrap_or((limits, ""));
+        let number = |value: &str| match value.trim() {
+            "" => Ok(None),
+            value => value
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| format!("not a number in quota {}: {}", spec, value)),
+        };
+        let quota = Quota {
+            path: storage::normalize(path.trim()),
+            max_bytes: number(bytes)?,
+            max_files: number(files)?,
+        };
+        if quota.max_bytes.is_none() && quota.max_files.is_none() {
+            return Err(format!("quota sets no limit: {}", spec));
+        }
+        Ok(quota)
+    }
+
+    fn covers(&self, path: &str) -> bool {
+        storage::is_within(path, &self.path)
+    }
+}
+
+/// Files and bytes stored below `path`, zero if it doesn't exist yet. Kept revisions of
+/// files below `path` count too, and the trash counts against the whole root.
+pub async fn usage(state: &AppState, path: &str) -> Result<(u64, u64)> {
+    if state.storage.disk_root().is_none() {
+        let mut dirs = vec![path.to_string(), storage::join(crate::REVISIONS_DIR, path)];
+        if path.is_empty() {
+            dirs.push(crate::TRASH_DIR.to_string());
+        }
+        let (mut files, mut bytes) = (0, 0);
+        for dir in dirs {
+            let (dir_files, dir_bytes) = measured(state, &dir).await?;
+            files += dir_files;
+            bytes += dir_bytes;
+        }
+        return Ok((files, bytes));
+    }
+
+    // The revisions and the trash have tree indexes of their own, rooted at their folders
+    let mut trees = vec![state.tree.clone(), state.revision_tree.clone()];
+    if path.is_empty() {
+        trees.push(state.trash_tree.clone());
+    }
+    let path = path.to_string();
+    let totals = web::block(move || {
+        trees
+            .iter()
+            .filter_map(|tree| tree.totals(&path))
+            .fold((0, 0), |(files, bytes), t| {
+                (files + t.files, bytes + t.bytes)
+            })
+    })
+    .await?;
+    Ok(totals)
+}
+
+async fn measured(state: &AppState, path: &str) -> Result<(u64, u64)> {
+    match storage::measure(state.storage.as_ref(), path).await {
+        Ok(stats) => Ok((stats.files, stats.bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((0, 0)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    #[serde(flatten)]
+    pub quota: Quota,
+    pub used_files: u64,
+    pub used_bytes: u64,
+}
+
+/// Files and bytes a change adds at `path`, counted in the quotas on `folders`.
+struct Claim {
+    path: String,
+    folders: Vec<String>,
+    files: u64,
+    bytes: u64,
+}
+
+/// Claims of the changes under way in a root. A finished change's claims stay on the
+/// books of trackers that started before it, whose usage can't include it.
+#[derive(Default)]
+pub struct Ledger(Mutex<Books>);
+
+#[derive(Default)]
+struct Books {
+    next: u64,
+    /// Claims of live trackers, by the number they started at
+    live: Vec<(u64, Vec<Claim>)>,
+    /// Claims of finished changes, by the number they finished at
+    done: Vec<(u64, Claim)>,
+}
+
+impl Books {
+    /// Files and bytes claimed in the quota on `folder` by live trackers, and by
+    /// changes that finished after `since`.
+    fn claimed(&self, folder: &str, since: u64) -> (u64, u64) {
+        let live = self.live.iter().flat_map(|(_, claims)| claims);
+        let done = self
+            .done
+            .iter()
+            .filter(|(at, _)| *at > since)
+            .map(|(_, claim)| claim);
+        live.chain(done)
+            .filter(|claim| claim.folders.iter().any(|f| f == folder))
+            .fold((0, 0), |(files, bytes), claim| {
+                (files + claim.files, bytes + claim.bytes)
+            })
+    }
+}
+
+/// Usage of the quotas a change at `path` can run into. What the change adds is
+/// claimed as it goes, and released once the tracker is dropped, which must not happen
+/// before the change is in the tree index (see `finish`).
+pub struct Tracker {
+    root: String,
+    quotas: Vec<QuotaUsage>,
+    ledger: Arc<Ledger>,
+    /// Where finished claims are reindexed, for a local root
+    tree: Option<Arc<TreeIndex>>,
+    /// Number the tracker started at, `None` when no quota applies
+    since: Option<u64>,
+}
+
+impl Tracker {
+    /// Quotas covering `path`, and with `nested` also those on folders below it, for
+    /// uploads that create whole folder trees.
+    pub async fn new(state: &AppState, path: &str, nested: bool) -> Result<Self> {
+        let quotas: Vec<&Quota> = state
+            .quotas
+            .iter()
+            .filter(|q| q.covers(path) || (nested && storage::is_within(&q.path, path)))
+            .collect();
+        Self::measure(state, quotas).await
+    }
+
+    async fn measure(state: &AppState, quotas: Vec<&Quota>) -> Result<Self> {
+        let mut tracker = Self {
+            root: state.root.clone(),
+            quotas: Vec::with_capacity(quotas.len()),
+            ledger: state.quota_ledger.clone(),
+            tree: state.storage.disk_root().map(|_| state.tree.clone()),
+            since: None,
+        };
+        if quotas.is_empty() {
+            return Ok(tracker);
+        }
+
+        // Start before measuring, so a change finishing in between is counted twice
+        // rather than missed
+        {
+            let mut books = tracker.ledger.0.lock().unwrap();
+            let since = books.next;
+            books.next += 1;
+            books.live.push((since, Vec::new()));
+            tracker.since = Some(since);
+        }
+        for quota in quotas {
+            let (used_files, used_bytes) = usage(state, &quota.path).await?;
+            tracker.quotas.push(QuotaUsage {
+                quota: quota.clone(),
+                used_files,
+                used_bytes,
+            });
+        }
+        Ok(tracker)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.quotas.is_empty()
+    }
+
+    /// Forget quotas that also cover `path`; moving out of there doesn't change them.
+    pub fn without(mut self, path: &str) -> Self {
+        self.quotas.retain(|q| !q.quota.covers(path));
+        self
+    }
+
+    /// Claim `files` and `bytes` added at `path`, or refuse them if that goes over a
+    /// quota.
+    pub fn claim(&self, path: &str, files: u64, bytes: u64) -> Result<()> {
+        let Some(since) = self.since else {
+            return Ok(());
+        };
+        let covering: Vec<&QuotaUsage> = self
+            .quotas
+            .iter()
+            .filter(|q| q.quota.covers(path))
+            .collect();
+        if covering.is_empty() {
+            return Ok(());
+        }
+        let mut books = self.ledger.0.lock().unwrap();
+        for q in &covering {
+            let (claimed_files, claimed_bytes) = books.claimed(&q.quota.path, since);
+            let over_files = files > 0
+                && q.quota.max_files.is_some_and(|max| {
+                    q.used_files
+                        .saturating_add(claimed_files)
+                        .saturating_add(files)
+                        > max
+                });
+            let over_bytes = bytes > 0
+                && q.quota.max_bytes.is_some_and(|max| {
+                    q.used_bytes
+                        .saturating_add(claimed_bytes)
+                        .saturating_add(bytes)
+                        > max
+                });
+            if over_files || over_bytes {
+                return Err(exceeded(&self.root, &q.quota));
+            }
+        }
+        if let Some((_, claims)) = books.live.iter_mut().find(|(at, _)| *at == since) {
+            match claims.last_mut().filter(|claim| claim.path == path) {
+                Some(claim) => {
+                    claim.files += files;
+                    claim.bytes += bytes;
+                }
+                None => claims.push(Claim {
+                    path: path.to_string(),
+                    folders: covering.iter().map(|q| q.quota.path.clone()).collect(),
+                    files,
+                    bytes,
+                }),
+            }
+        }
+        Ok(())
+    }
+
+    /// Bring the tree index up to date with the claimed paths, then release the claims,
+    /// for changes the caller only reindexes once the tracker is gone. Trackers that
+    /// start later measure the change from the index.
+    pub async fn finish(self) {
+        let (Some(tree), Some(since)) = (self.tree.clone(), self.since) else {
+            return;
+        };
+        let paths: Vec<String> = {
+            let books = self.ledger.0.lock().unwrap();
+            books
+                .live
+                .iter()
+                .filter(|(at, _)| *at == since)
+                .flat_map(|(_, claims)| claims.iter().map(|claim| claim.path.clone()))
+                .collect()
+        };
+        let _ = web::block(move || {
+            for path in &paths {
+                tree.refresh(path);
+            }
+        })
+        .await;
+    }
+
+    /// The tightest quota covering `path` and the bytes left in it.
+    pub fn room(&self, path: &str) -> Option<(u64, &Quota)> {
+        let since = self.since?;
+        let books = self.ledger.0.lock().unwrap();
+        self.quotas
+            .iter()
+            .filter(|q| q.quota.covers(path))
+            .filter_map(|q| {
+                let max = q.quota.max_bytes?;
+                let (_, claimed) = books.claimed(&q.quota.path, since);
+                let used = q.used_bytes.saturating_add(claimed);
+                Some((max.saturating_sub(used), &q.quota))
+            })
+            .min_by_key(|(left, _)| *left)
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let Some(since) = self.since else {
+            return;
+        };
+        let mut books = self.ledger.0.lock().unwrap();
+        let claims = match books.live.iter().position(|(at, _)| *at == since) {
+            Some(index) => books.live.remove(index).1,
+            None => Vec::new(),
+        };
+        let at = books.next;
+        books.next += 1;
+        books
+            .done
+            .extend(claims.into_iter().map(|claim| (at, claim)));
+        // Only trackers that started before a finished change still need its claims
+        let oldest = books.live.iter().map(|(since, _)| *since).min();
+        books
+            .done
+            .retain(|(at, _)| oldest.is_some_and(|oldest| *at > oldest));
+    }
+}
+
+/// Claim `files` and `bytes` added at `path`, or refuse them if that takes a quota over
+/// its limit. Keep the tracker until the change is made.
+pub async fn check(state: &AppState, path: &str, files: u64, bytes: u64) -> Result<Tracker> {
+    if files == 0 && bytes == 0 {
+        return Tracker::measure(state, Vec::new()).await;
+    }
+    let tracker = Tracker::new(state, path, false).await?;
+    tracker.claim(path, files, bytes)?;
+    Ok(tracker)
+}
+
+/// A tracker for a single file written at `path`, with the new file claimed if
+/// `new_file`, and the most it may hold: `max_bytes`, or the room left in the quotas
+/// covering it, together with the quota to blame if that is the tighter limit. Bytes
+/// are claimed with the tracker as they are written.
+pub async fn file_limit(
+    state: &AppState,
+    path: &str,
+    max_bytes: u64,
+    new_file: bool,
+) -> Result<(Tracker, u64, Option<Quota>)> {
+    let tracker = Tracker::new(state, path, false).await?;
+    tracker.claim(path, u64::from(new_file), 0)?;
+    let (limit, quota) = match tracker.room(path) {
+        Some((left, quota)) if left < max_bytes => (left, Some(quota.clone())),
+        _ => (max_bytes, None),
+    };
+    Ok((tracker, limit, quota))
+}
+
+pub fn exceeded(root: &str, quota: &Quota) -> actix_web::Error {
+    let folder = if quota.path.is_empty() {
+        format!("root {}", root)
+    } else {
+        format!("{} in root {}", quota.path, root)
+    };
+    actix_web::error::ErrorInsufficientStorage(format!("Quota exceeded for {}", folder))
+}
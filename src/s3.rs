@@ -0,0 +1,1799 @@
+// === S3 API ===
+// A subset of the S3 REST API under `/s3`, path-style only (`/s3/<bucket>/<key>`), for
+// tools that only speak S3 (rclone, backup agents, AWS SDKs). Buckets are the top-level
+// folders of the upload dir and keys are paths below them; empty folders are listed as
+// `folder/` marker keys. Requests must be signed with SigV4 (header or presigned URL)
+// using the single key pair from `BOX_S3_ACCESS_KEY` / `BOX_S3_SECRET_KEY`.
+//
+// Writes go through the same helpers as the REST API: uploads keep the previous
+// version under `.boxy/revisions`, deletes and copies are journaled for undo, and every
+// change is reindexed and broadcast over `/ws`. Content types and `x-amz-meta-*`
+// headers are not stored, except `x-amz-meta-mtime` (rclone), which sets the file's
+// modification time. Multipart parts are staged under `.boxy/multipart/<upload id>`.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use base64::Engine;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    apply_delete, broadcast_update, civil_from_days, content_type_for, copy_to_path,
+    days_from_civil, keep_revision, publish_op, quota, reindex_paths, resolve_path_safe, AppState,
+    Applied, DeleteReq, DATA_DIR,
+};
+
+pub const PREFIX: &str = "/s3";
+
+const MULTIPART_DIR: &str = ".boxy/multipart";
+const S3_NS: &str = "http://s3.amazonaws.com/doc/2006-03-01/";
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+
+/// Signed requests must be dated within this many seconds of the server clock.
+const MAX_CLOCK_SKEW_SECS: i64 = 15 * 60;
+const MAX_PRESIGNED_SECS: i64 = 7 * 24 * 3600;
+
+const MAX_KEYS: usize = 1000;
+const MAX_PART_NUMBER: u32 = 10_000;
+/// DeleteObjects and CompleteMultipartUpload bodies.
+const MAX_XML_BODY: usize = 2 * 1024 * 1024;
+/// Multipart uploads that were neither completed nor aborted are dropped after a week.
+const STALE_UPLOAD_SECS: u64 = 7 * 24 * 3600;
+
+pub(crate) const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+/// Characters SigV4 leaves unescaped (RFC 3986 unreserved).
+pub(crate) const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+/// `encoding-type=url` listings keep the key's slashes.
+pub(crate) const KEY_ENCODE: &AsciiSet = &UNRESERVED.remove(b'/');
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+// --- Errors ---
+
+fn s3_error(status: StatusCode, code: &str, message: &str) -> actix_web::Error {
+    let body = format!(
+        "{}<Error><Code>{}</Code><Message>{}</Message></Error>",
+        XML_HEADER,
+        code,
+        escape_xml(message)
+    );
+    actix_web::error::InternalError::from_response(
+        message.to_string(),
+        HttpResponse::build(status)
+            .content_type("application/xml")
+            .body(body),
+    )
+    .into()
+}
+
+fn access_denied(message: &str) -> actix_web::Error {
+    s3_error(StatusCode::FORBIDDEN, "AccessDenied", message)
+}
+
+fn invalid_argument(message: &str) -> actix_web::Error {
+    s3_error(StatusCode::BAD_REQUEST, "InvalidArgument", message)
+}
+
+fn no_such_key() -> actix_web::Error {
+    s3_error(
+        StatusCode::NOT_FOUND,
+        "NoSuchKey",
+        "The specified key does not exist.",
+    )
+}
+
+fn no_such_upload() -> actix_web::Error {
+    s3_error(
+        StatusCode::NOT_FOUND,
+        "NoSuchUpload",
+        "The specified multipart upload does not exist.",
+    )
+}
+
+fn not_implemented() -> actix_web::Error {
+    s3_error(
+        StatusCode::NOT_IMPLEMENTED,
+        "NotImplemented",
+        "This operation is not supported.",
+    )
+}
+
+fn entity_too_large() -> actix_web::Error {
+    s3_error(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "EntityTooLarge",
+        "Your proposed upload exceeds the maximum allowed size.",
+    )
+}
+
+fn malformed_xml() -> actix_web::Error {
+    s3_error(
+        StatusCode::BAD_REQUEST,
+        "MalformedXML",
+        "The XML you provided was not well-formed.",
+    )
+}
+
+// --- Requests ---
+
+pub async fn handle(
+    req: HttpRequest,
+    body: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(credentials) = &state.s3 else {
+        return Err(access_denied(
+            "The S3 API is disabled. Set BOX_S3_ACCESS_KEY and BOX_S3_SECRET_KEY to enable it.",
+        ));
+    };
+    let payload = authenticate(&req, credentials)?;
+    let body = BodyReader::new(body, payload, &req)?;
+    let query = Query::parse(req.query_string());
+    let (bucket, key) = parse_target(req.path())?;
+    let method = req.method().as_str();
+    if state.read_only && method != "GET" && method != "HEAD" {
+        return Err(access_denied("This root is read-only."));
+    }
+
+    if bucket.is_empty() {
+        return match method {
+            "GET" => list_buckets(&state).await,
+            _ => Err(not_implemented()),
+        };
+    }
+    if key.is_empty() {
+        return match method {
+            "GET" if query.has("location") => Ok(xml_response(format!(
+                "<LocationConstraint xmlns=\"{}\"/>",
+                S3_NS
+            ))),
+            "GET" if query.has("uploads") || query.has("versions") => Err(not_implemented()),
+            "GET" => list_objects(&state, &bucket, &query).await,
+            "HEAD" => {
+                bucket_dir(&state, &bucket)?;
+                Ok(HttpResponse::Ok().finish())
+            }
+            "PUT" => create_bucket(&state, &bucket, body).await,
+            "DELETE" => delete_bucket(&state, &bucket).await,
+            "POST" if query.has("delete") => delete_objects(&state, &bucket, body).await,
+            _ => Err(not_implemented()),
+        };
+    }
+
+    let copy_source = header_str(&req, "x-amz-copy-source");
+    match method {
+        "GET" | "HEAD" if query.has("uploadId") => Err(not_implemented()),
+        "GET" | "HEAD" => get_object(&req, &state, &bucket, &key, &query).await,
+        "PUT" if query.has("uploadId") && copy_source.is_some() => Err(not_implemented()),
+        "PUT" if query.has("uploadId") => upload_part(&state, &bucket, &key, &query, body).await,
+        "PUT" if copy_source.is_some() => copy_object(&req, &state, &bucket, &key).await,
+        "PUT" => put_object(&req, &state, &bucket, &key, body).await,
+        "DELETE" if query.has("uploadId") => abort_upload(&state, &query).await,
+        "DELETE" => delete_object(&state, &bucket, &key).await,
+        "POST" if query.has("uploads") => create_upload(&req, &state, &bucket, &key).await,
+        "POST" if query.has("uploadId") => {
+            complete_upload(&state, &bucket, &key, &query, body).await
+        }
+        _ => Err(not_implemented()),
+    }
+}
+
+/// Decoded query parameters.
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(query: &str) -> Self {
+        Query(
+            query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(key), decode(value))
+                })
+                .collect(),
+        )
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn has(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+}
+
+fn decode(text: &str) -> String {
+    percent_decode_str(text).decode_utf8_lossy().into_owned()
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Bucket and key of a `/s3/<bucket>/<key>` URL path. Keys map onto relative paths, so
+/// empty, `.` and `..` segments are refused; a trailing `/` marks a folder.
+fn parse_target(url_path: &str) -> Result<(String, String)> {
+    let rest = url_path
+        .strip_prefix(PREFIX)
+        .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+        .ok_or_else(|| invalid_argument("Invalid path"))?;
+    let decoded = decode(rest.trim_start_matches('/'));
+    let (bucket, key) = decoded.split_once('/').unwrap_or((&decoded, ""));
+    if bucket == "." || bucket == ".." || bucket == DATA_DIR {
+        return Err(invalid_argument("Invalid bucket name"));
+    }
+    validate_key(key)?;
+    Ok((bucket.to_string(), key.to_string()))
+}
+
+fn validate_key(key: &str) -> Result<()> {
+    let body = key.strip_suffix('/').unwrap_or(key);
+    if !key.is_empty()
+        && (body.is_empty()
+            || body
+                .split('/')
+                .any(|s| s.is_empty() || s == "." || s == ".." || s.contains('\0')))
+    {
+        return Err(invalid_argument(
+            "Keys must be relative paths without empty, '.' or '..' segments",
+        ));
+    }
+    Ok(())
+}
+
+fn bucket_dir(state: &AppState, bucket: &str) -> Result<PathBuf> {
+    resolve_path_safe(&state.upload_dir, Some(&bucket.to_string()))
+        .filter(|path| path.is_dir())
+        .ok_or_else(|| {
+            s3_error(
+                StatusCode::NOT_FOUND,
+                "NoSuchBucket",
+                "The specified bucket does not exist.",
+            )
+        })
+}
+
+/// Upload-root relative path of an object.
+fn object_rel(bucket: &str, key: &str) -> String {
+    format!("{}/{}", bucket, key.trim_end_matches('/'))
+}
+
+fn object_path(state: &AppState, bucket: &str, key: &str) -> Result<PathBuf> {
+    bucket_dir(state, bucket)?;
+    resolve_path_safe(&state.upload_dir, Some(&object_rel(bucket, key)))
+        .ok_or_else(|| access_denied("Invalid key"))
+}
+
+/// Create the folders above a new object. Fails if one of them is a file.
+async fn ensure_parent(path: &Path) -> Result<()> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| invalid_argument("Invalid key"))?;
+    if let Err(e) = tokio::fs::create_dir_all(parent).await {
+        return Err(if parent.ancestors().any(Path::is_file) {
+            s3_error(
+                StatusCode::CONFLICT,
+                "ParentIsObject",
+                "A parent of the key is an object.",
+            )
+        } else {
+            e.into()
+        });
+    }
+    if path.is_dir() {
+        return Err(s3_error(
+            StatusCode::CONFLICT,
+            "ObjectExistsAsDirectory",
+            "The key is a folder.",
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn xml_response(body: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/xml")
+        .body(format!("{}{}", XML_HEADER, body))
+}
+
+fn modified_secs(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Same `"<mtime>-<size>"` ETag as WebDAV; S3 clients treat it as opaque.
+fn etag(modified: u64, size: u64) -> String {
+    format!("\"{}-{}\"", modified, size)
+}
+
+/// `2026-01-31T12:00:00.000Z`
+fn iso8601(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Parse an `x-amz-date` timestamp (`20260131T120000Z`) into unix seconds.
+fn parse_amz_date(value: &str) -> Option<i64> {
+    let b = value.as_bytes();
+    if b.len() != 16 || b[8] != b'T' || b[15] != b'Z' {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| value.get(range)?.parse::<i64>().ok();
+    let days = days_from_civil(num(0..4)?, num(4..6)?, num(6..8)?);
+    Some(days * 86400 + num(9..11)? * 3600 + num(11..13)? * 60 + num(13..15)?)
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Modification time requested through rclone's `x-amz-meta-mtime` (unix seconds,
+/// possibly fractional).
+fn requested_mtime(req: &HttpRequest) -> Option<filetime::FileTime> {
+    let secs: f64 = header_str(req, "x-amz-meta-mtime")?.trim().parse().ok()?;
+    (secs.is_finite() && secs >= 0.0).then(|| {
+        filetime::FileTime::from_unix_time(secs.trunc() as i64, (secs.fract() * 1e9) as u32)
+    })
+}
+
+// --- SigV4 ---
+
+pub(crate) fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn same_signature(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (x, y)| acc | (x ^ y))
+            == 0
+}
+
+/// What the request's signature says about its body.
+enum PayloadCheck {
+    Unsigned,
+    /// Hex SHA-256 the body must match
+    Sha256(String),
+    /// `aws-chunked` body; chunk signatures are verified when `signing` is set
+    Chunked {
+        signing: Option<ChunkSigning>,
+    },
+}
+
+struct ChunkSigning {
+    key: Vec<u8>,
+    date_time: String,
+    scope: String,
+    /// Signature of the previous chunk, seeded with the request signature
+    previous: String,
+}
+
+/// Verify the request's SigV4 signature, from the `Authorization` header or a presigned
+/// URL, and return how its body has to be checked.
+fn authenticate(req: &HttpRequest, credentials: &Credentials) -> Result<PayloadCheck> {
+    let query = Query::parse(req.query_string());
+    let presigned = query.get("X-Amz-Algorithm").is_some();
+
+    let (credential, signed_headers, signature, date_time) = if presigned {
+        if query.get("X-Amz-Algorithm") != Some("AWS4-HMAC-SHA256") {
+            return Err(access_denied("Only AWS4-HMAC-SHA256 is supported"));
+        }
+        let param = |name: &str| {
+            query.get(name).map(str::to_string).ok_or_else(|| {
+                s3_error(
+                    StatusCode::BAD_REQUEST,
+                    "AuthorizationQueryParametersError",
+                    &format!("Missing {}", name),
+                )
+            })
+        };
+        (
+            param("X-Amz-Credential")?,
+            param("X-Amz-SignedHeaders")?,
+            param("X-Amz-Signature")?,
+            param("X-Amz-Date")?,
+        )
+    } else {
+        let authorization = header_str(req, "Authorization")
+            .ok_or_else(|| access_denied("Anonymous access is not allowed"))?;
+        let fields = authorization
+            .strip_prefix("AWS4-HMAC-SHA256 ")
+            .ok_or_else(|| access_denied("Only AWS4-HMAC-SHA256 is supported"))?;
+        let mut parts: HashMap<&str, &str> = HashMap::new();
+        for field in fields.split(',') {
+            if let Some((name, value)) = field.trim().split_once('=') {
+                parts.insert(name, value);
+            }
+        }
+        let part = |name: &str| {
+            parts
+                .get(name)
+                .map(|v| v.to_string())
+                .ok_or_else(|| access_denied("Malformed Authorization header"))
+        };
+        let date_time = header_str(req, "x-amz-date")
+            .ok_or_else(|| access_denied("Missing x-amz-date header"))?;
+        (
+            part("Credential")?,
+            part("SignedHeaders")?,
+            part("Signature")?,
+            date_time.to_string(),
+        )
+    };
+
+    // <access key>/<date>/<region>/<service>/aws4_request
+    let scope_parts: Vec<&str> = credential.split('/').collect();
+    let [access_key, date, region, service, "aws4_request"] = scope_parts[..] else {
+        return Err(access_denied("Malformed credential scope"));
+    };
+    if access_key != credentials.access_key {
+        return Err(s3_error(
+            StatusCode::FORBIDDEN,
+            "InvalidAccessKeyId",
+            "The access key ID you provided does not exist in our records.",
+        ));
+    }
+    let signed_at = parse_amz_date(&date_time)
+        .filter(|_| date_time.starts_with(date))
+        .ok_or_else(|| access_denied("Invalid x-amz-date"))?;
+    let now = now_secs();
+    if presigned {
+        let expires: i64 = query
+            .get("X-Amz-Expires")
+            .and_then(|v| v.parse().ok())
+            .filter(|v| (1..=MAX_PRESIGNED_SECS).contains(v))
+            .ok_or_else(|| access_denied("Invalid X-Amz-Expires"))?;
+        if signed_at - now > MAX_CLOCK_SKEW_SECS || now > signed_at + expires {
+            return Err(access_denied("Request has expired"));
+        }
+    } else if (now - signed_at).abs() > MAX_CLOCK_SKEW_SECS {
+        return Err(s3_error(
+            StatusCode::FORBIDDEN,
+            "RequestTimeTooSkewed",
+            "The difference between the request time and the server's time is too large.",
+        ));
+    }
+
+    let payload_hash = if presigned {
+        UNSIGNED_PAYLOAD.to_string()
+    } else {
+        header_str(req, "x-amz-content-sha256")
+            .ok_or_else(|| {
+                s3_error(
+                    StatusCode::BAD_REQUEST,
+                    "InvalidRequest",
+                    "Missing required header x-amz-content-sha256",
+                )
+            })?
+            .to_string()
+    };
+
+    let canonical = canonical_request(req, &signed_headers, &payload_hash, presigned)?;
+    let scope = format!("{}/{}/{}/aws4_request", date, region, service);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        date_time,
+        scope,
+        sha256_hex(canonical.as_bytes())
+    );
+    let key = [date, region, service, "aws4_request"].iter().fold(
+        format!("AWS4{}", credentials.secret_key).into_bytes(),
+        |key, part| hmac(&key, part.as_bytes()),
+    );
+    let expected = hex::encode(hmac(&key, string_to_sign.as_bytes()));
+    if !same_signature(&expected, &signature) {
+        return Err(s3_error(
+            StatusCode::FORBIDDEN,
+            "SignatureDoesNotMatch",
+            "The request signature we calculated does not match the signature you provided.",
+        ));
+    }
+
+    Ok(match payload_hash.as_str() {
+        UNSIGNED_PAYLOAD => PayloadCheck::Unsigned,
+        hash if hash.starts_with("STREAMING-AWS4-HMAC-SHA256-PAYLOAD") => PayloadCheck::Chunked {
+            signing: Some(ChunkSigning {
+                key,
+                date_time,
+                scope,
+                previous: signature,
+            }),
+        },
+        hash if hash.starts_with("STREAMING-") => PayloadCheck::Chunked { signing: None },
+        hash => PayloadCheck::Sha256(hash.to_ascii_lowercase()),
+    })
+}
+
+fn canonical_request(
+    req: &HttpRequest,
+    signed_headers: &str,
+    payload_hash: &str,
+    presigned: bool,
+) -> Result<String> {
+    let path = req.path();
+    let uri: String = decode(path)
+        .split('/')
+        .map(|segment| utf8_percent_encode(segment, UNRESERVED).to_string())
+        .collect::<Vec<_>>()
+        .join("/");
+
+    let mut params: Vec<(String, String)> = Query::parse(req.query_string())
+        .0
+        .into_iter()
+        .filter(|(key, _)| !(presigned && key == "X-Amz-Signature"))
+        .map(|(key, value)| {
+            (
+                utf8_percent_encode(&key, UNRESERVED).to_string(),
+                utf8_percent_encode(&value, UNRESERVED).to_string(),
+            )
+        })
+        .collect();
+    params.sort();
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&");
+
+    let mut headers = String::new();
+    for name in signed_headers.split(';') {
+        let mut values: Vec<String> = req
+            .headers()
+            .get_all(name)
+            .filter_map(|v| v.to_str().ok())
+            .map(|v| v.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect();
+        if values.is_empty() && name == "host" {
+            values.push(req.connection_info().host().to_string());
+        }
+        if values.is_empty() {
+            return Err(access_denied(&format!("Signed header {} is missing", name)));
+        }
+        headers.push_str(&format!("{}:{}\n", name, values.join(",")));
+    }
+
+    Ok(format!(
+        "{}\n{}\n{}\n{}\n{}\n{}",
+        req.method().as_str(),
+        uri,
+        query,
+        headers,
+        signed_headers,
+        payload_hash
+    ))
+}
+
+// --- Request bodies ---
+
+/// Streams a request body, decoding `aws-chunked` framing and checking the signed
+/// payload hash, chunk signatures and `Content-MD5` as it goes.
+struct BodyReader {
+    payload: web::Payload,
+    check: PayloadCheck,
+    sha256: Sha256,
+    md5: Md5,
+    content_md5: Option<Vec<u8>>,
+    /// Length of the decoded body, when the client announced it
+    length: Option<u64>,
+    chunks: ChunkDecoder,
+}
+
+#[derive(Default)]
+struct ChunkDecoder {
+    buffer: Vec<u8>,
+    /// Bytes left in the current chunk
+    remaining: usize,
+    /// The current chunk's data is done and its trailing CRLF is due
+    chunk_end: bool,
+    signature: String,
+    chunk_hash: Sha256,
+    finished: bool,
+}
+
+impl BodyReader {
+    fn new(payload: web::Payload, check: PayloadCheck, req: &HttpRequest) -> Result<Self> {
+        let content_md5 = header_str(req, "Content-MD5")
+            .map(|value| {
+                base64::engine::general_purpose::STANDARD
+                    .decode(value.trim())
+                    .map_err(|_| {
+                        s3_error(
+                            StatusCode::BAD_REQUEST,
+                            "InvalidDigest",
+                            "Invalid Content-MD5",
+                        )
+                    })
+            })
+            .transpose()?;
+        let length_header = match check {
+            PayloadCheck::Chunked { .. } => "x-amz-decoded-content-length",
+            _ => "Content-Length",
+        };
+        Ok(Self {
+            payload,
+            check,
+            sha256: Sha256::new(),
+            md5: Md5::new(),
+            content_md5,
+            length: header_str(req, length_header).and_then(|v| v.parse().ok()),
+            chunks: ChunkDecoder::default(),
+        })
+    }
+
+    /// The next piece of the decoded body, or None at its end.
+    async fn next(&mut self) -> Result<Option<web::Bytes>> {
+        loop {
+            let Some(chunk) = self.payload.next().await else {
+                if matches!(self.check, PayloadCheck::Chunked { .. }) && !self.chunks.finished {
+                    return Err(s3_error(
+                        StatusCode::BAD_REQUEST,
+                        "IncompleteBody",
+                        "The request body ended before the final chunk.",
+                    ));
+                }
+                return Ok(None);
+            };
+            let chunk = chunk?;
+            let data = match &mut self.check {
+                PayloadCheck::Chunked { signing } => {
+                    web::Bytes::from(self.chunks.feed(&chunk, signing.as_mut())?)
+                }
+                _ => chunk,
+            };
+            if data.is_empty() {
+                continue;
+            }
+            if matches!(self.check, PayloadCheck::Sha256(_)) {
+                self.sha256.update(&data);
+            }
+            self.md5.update(&data);
+            return Ok(Some(data));
+        }
+    }
+
+    /// Check the digests of a fully read body and return its MD5.
+    fn finish(self) -> Result<[u8; 16]> {
+        if let PayloadCheck::Sha256(expected) = &self.check {
+            if hex::encode(self.sha256.finalize()) != *expected {
+                return Err(s3_error(
+                    StatusCode::BAD_REQUEST,
+                    "XAmzContentSHA256Mismatch",
+                    "The provided 'x-amz-content-sha256' header does not match what was computed.",
+                ));
+            }
+        }
+        let md5: [u8; 16] = self.md5.finalize().into();
+        if self
+            .content_md5
+            .as_ref()
+            .is_some_and(|expected| expected[..] != md5)
+        {
+            return Err(s3_error(
+                StatusCode::BAD_REQUEST,
+                "BadDigest",
+                "The Content-MD5 you specified did not match what we received.",
+            ));
+        }
+        Ok(md5)
+    }
+
+    /// Read a small body (XML) completely.
+    async fn read_all(mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        while let Some(data) = self.next().await? {
+            if bytes.len() + data.len() > limit {
+                return Err(entity_too_large());
+            }
+            bytes.extend_from_slice(&data);
+        }
+        self.finish()?;
+        Ok(bytes)
+    }
+
+    /// Stream the body into a new file at `path`, which is removed again on failure.
+    /// Returns the body's MD5.
+    async fn write_to(mut self, path: &Path, limit: usize) -> Result<[u8; 16]> {
+        if self.length.is_some_and(|len| len > limit as u64) {
+            return Err(entity_too_large());
+        }
+        let mut file = tokio::fs::File::create(path).await?;
+        let mut written = 0;
+        let result = async {
+            while let Some(data) = self.next().await? {
+                written += data.len();
+                if written > limit {
+                    return Err(entity_too_large());
+                }
+                file.write_all(&data).await?;
+            }
+            file.flush().await?;
+            self.finish()
+        }
+        .await;
+        drop(file);
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        result
+    }
+}
+
+impl ChunkDecoder {
+    /// Decode `aws-chunked` framing (`<hex size>[;chunk-signature=<sig>]\r\n<data>\r\n`,
+    /// ending with a zero-size chunk and optional trailers, which are ignored).
+    fn feed(&mut self, input: &[u8], mut signing: Option<&mut ChunkSigning>) -> Result<Vec<u8>> {
+        let invalid = || {
+            s3_error(
+                StatusCode::BAD_REQUEST,
+                "InvalidChunkEncoding",
+                "Malformed aws-chunked body.",
+            )
+        };
+        self.buffer.extend_from_slice(input);
+        let mut out = Vec::new();
+        let mut pos = 0;
+        while !self.finished {
+            if self.remaining > 0 {
+                let n = self.remaining.min(self.buffer.len() - pos);
+                if n == 0 {
+                    break;
+                }
+                let data = &self.buffer[pos..pos + n];
+                self.chunk_hash.update(data);
+                out.extend_from_slice(data);
+                self.remaining -= n;
+                pos += n;
+                continue;
+            }
+            if self.chunk_end {
+                if self.buffer.len() - pos < 2 {
+                    break;
+                }
+                if &self.buffer[pos..pos + 2] != b"\r\n" {
+                    return Err(invalid());
+                }
+                pos += 2;
+                self.chunk_end = false;
+                self.verify(signing.as_deref_mut())?;
+                continue;
+            }
+            let Some(end) = self.buffer[pos..].windows(2).position(|w| w == b"\r\n") else {
+                if self.buffer.len() - pos > 4096 {
+                    return Err(invalid());
+                }
+                break;
+            };
+            let line = std::str::from_utf8(&self.buffer[pos..pos + end]).map_err(|_| invalid())?;
+            let (size, extension) = line.split_once(';').unwrap_or((line, ""));
+            let size = usize::from_str_radix(size.trim(), 16).map_err(|_| invalid())?;
+            self.signature = extension
+                .trim()
+                .strip_prefix("chunk-signature=")
+                .unwrap_or_default()
+                .to_string();
+            pos += end + 2;
+            self.chunk_hash = Sha256::new();
+            self.remaining = size;
+            if size == 0 {
+                self.verify(signing.as_deref_mut())?;
+                self.finished = true;
+            } else {
+                self.chunk_end = true;
+            }
+        }
+        self.buffer.drain(..pos);
+        Ok(out)
+    }
+
+    fn verify(&mut self, signing: Option<&mut ChunkSigning>) -> Result<()> {
+        let Some(signing) = signing else {
+            return Ok(());
+        };
+        let hash = std::mem::take(&mut self.chunk_hash);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256-PAYLOAD\n{}\n{}\n{}\n{}\n{}",
+            signing.date_time,
+            signing.scope,
+            signing.previous,
+            EMPTY_SHA256,
+            hex::encode(hash.finalize())
+        );
+        let expected = hex::encode(hmac(&signing.key, string_to_sign.as_bytes()));
+        if !same_signature(&expected, &self.signature) {
+            return Err(s3_error(
+                StatusCode::FORBIDDEN,
+                "SignatureDoesNotMatch",
+                "A chunk signature does not match.",
+            ));
+        }
+        signing.previous = expected;
+        Ok(())
+    }
+}
+
+/// Children of elements named `record`, as local name → text, in document order.
+pub(crate) fn xml_records(body: &[u8], record: &str) -> Result<Vec<HashMap<String, String>>> {
+    let mut reader = quick_xml::Reader::from_reader(body);
+    reader.config_mut().trim_text(true);
+    let mut records = Vec::new();
+    let mut current: Option<HashMap<String, String>> = None;
+    let mut field = None;
+    loop {
+        match reader.read_event().map_err(|_| malformed_xml())? {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                if name == record {
+                    current = Some(HashMap::new());
+                } else if current.is_some() {
+                    field = Some(name);
+                }
+            }
+            Event::Text(t) => {
+                if let (Some(fields), Some(name)) = (current.as_mut(), &field) {
+                    let text = t.unescape().map_err(|_| malformed_xml())?;
+                    fields.entry(name.clone()).or_default().push_str(&text);
+                }
+            }
+            Event::End(e) => {
+                if e.local_name().as_ref() == record.as_bytes() {
+                    records.extend(current.take());
+                }
+                field = None;
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(records)
+}
+
+// --- Buckets ---
+
+async fn list_buckets(state: &AppState) -> Result<HttpResponse> {
+    let tree = state.tree.clone();
+    let entries = web::block(move || tree.children("")).await?;
+    let buckets: String = entries
+        .iter()
+        .filter(|entry| entry.is_dir)
+        .map(|entry| {
+            format!(
+                "<Bucket><Name>{}</Name><CreationDate>{}</CreationDate></Bucket>",
+                escape_xml(&entry.name),
+                iso8601(entry.modified)
+            )
+        })
+        .collect();
+    Ok(xml_response(format!(
+        "<ListAllMyBucketsResult xmlns=\"{}\"><Owner><ID>boxy</ID><DisplayName>boxy</DisplayName></Owner><Buckets>{}</Buckets></ListAllMyBucketsResult>",
+        S3_NS, buckets
+    )))
+}
+
+async fn create_bucket(state: &AppState, bucket: &str, body: BodyReader) -> Result<HttpResponse> {
+    body.read_all(MAX_XML_BODY).await?;
+    if bucket.starts_with('.') {
+        return Err(s3_error(
+            StatusCode::BAD_REQUEST,
+            "InvalidBucketName",
+            "The specified bucket is not valid.",
+        ));
+    }
+    let path = resolve_path_safe(&state.upload_dir, Some(&bucket.to_string()))
+        .ok_or_else(|| access_denied("Invalid bucket name"))?;
+    if path.exists() {
+        return Err(s3_error(
+            StatusCode::CONFLICT,
+            "BucketAlreadyOwnedByYou",
+            "Your previous request to create the named bucket succeeded and you already own it.",
+        ));
+    }
+    tokio::fs::create_dir(&path).await?;
+    reindex_paths(state, [path.as_path()]).await;
+    broadcast_update(&state.broadcaster, "folder", bucket);
+    Ok(HttpResponse::Ok()
+        .insert_header((header::LOCATION, format!("/{}", bucket)))
+        .finish())
+}
+
+async fn delete_bucket(state: &AppState, bucket: &str) -> Result<HttpResponse> {
+    let path = bucket_dir(state, bucket)?;
+    if std::fs::read_dir(&path)?.next().is_some() {
+        return Err(s3_error(
+            StatusCode::CONFLICT,
+            "BucketNotEmpty",
+            "The bucket you tried to delete is not empty.",
+        ));
+    }
+    delete_path(state, bucket).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// --- Listing ---
+
+struct ObjectEntry {
+    key: String,
+    size: u64,
+    modified: u64,
+}
+
+/// Keys below `prefix`, sorted. Only the folders the prefix reaches are read. With
+/// `shallow` (the `/` delimiter) that is just the folder the prefix points into, and its
+/// subfolders come back as `sub/` keys for the delimiter to fold into common prefixes;
+/// otherwise the subtree is walked and empty folders appear as `folder/` markers.
+fn collect_keys(
+    tree: &crate::tree_index::TreeIndex,
+    bucket: &str,
+    prefix: &str,
+    shallow: bool,
+) -> Vec<ObjectEntry> {
+    let start = prefix.rfind('/').map_or("", |i| &prefix[..i]);
+    let mut keys = Vec::new();
+    let mut pending = vec![(start.to_string(), 0)];
+    while let Some((dir, modified)) = pending.pop() {
+        let children = tree.children(&format!("{}/{}", bucket, dir));
+        if children.is_empty() && dir != start {
+            keys.push(ObjectEntry {
+                key: format!("{}/", dir),
+                size: 0,
+                modified,
+            });
+        }
+        for child in children {
+            let key = if dir.is_empty() {
+                child.name
+            } else {
+                format!("{}/{}", dir, child.name)
+            };
+            if !child.is_dir {
+                keys.push(ObjectEntry {
+                    key,
+                    size: child.size,
+                    modified: child.modified,
+                });
+            } else if shallow {
+                keys.push(ObjectEntry {
+                    key: format!("{}/", key),
+                    size: 0,
+                    modified: child.modified,
+                });
+            } else if format!("{}/", key).starts_with(prefix) {
+                pending.push((key, child.modified));
+            }
+        }
+    }
+    keys.retain(|entry| entry.key.starts_with(prefix));
+    keys.sort_by(|a, b| a.key.cmp(&b.key));
+    keys
+}
+
+/// ListObjectsV2 (`list-type=2`) and the original ListObjects, which rclone still uses
+/// for S3-compatible providers.
+async fn list_objects(state: &AppState, bucket: &str, query: &Query) -> Result<HttpResponse> {
+    bucket_dir(state, bucket)?;
+    let v2 = query.get("list-type") == Some("2");
+    let prefix = query.get("prefix").unwrap_or_default().to_string();
+    let delimiter = query.get("delimiter").unwrap_or_default().to_string();
+    let max_keys = match query.get("max-keys") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| invalid_argument("Invalid max-keys"))?
+            .min(MAX_KEYS),
+        None => MAX_KEYS,
+    };
+    let url_encoded = query.get("encoding-type") == Some("url");
+    let encode = |text: &str| {
+        if url_encoded {
+            utf8_percent_encode(text, KEY_ENCODE).to_string()
+        } else {
+            escape_xml(text)
+        }
+    };
+
+    let continuation = query.get("continuation-token");
+    let after = if v2 {
+        match continuation {
+            Some(token) => {
+                let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(token)
+                    .ok()
+                    .and_then(|bytes| String::from_utf8(bytes).ok())
+                    .ok_or_else(|| {
+                        invalid_argument("The continuation token provided is incorrect")
+                    })?;
+                Some(decoded)
+            }
+            None => query.get("start-after").map(str::to_string),
+        }
+    } else {
+        query.get("marker").map(str::to_string)
+    };
+
+    let tree = state.tree.clone();
+    let (bucket_name, list_prefix) = (bucket.to_string(), prefix.clone());
+    let shallow = delimiter == "/";
+    let entries =
+        web::block(move || collect_keys(&tree, &bucket_name, &list_prefix, shallow)).await?;
+
+    let mut contents = String::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut count = 0;
+    let mut last = None;
+    let mut truncated = false;
+    for entry in &entries {
+        if let Some(after) = &after {
+            // A marker that is a common prefix skips everything it rolled up
+            let rolled_up = !delimiter.is_empty()
+                && after.ends_with(delimiter.as_str())
+                && entry.key.starts_with(after.as_str());
+            if entry.key.as_str() <= after.as_str() || rolled_up {
+                continue;
+            }
+        }
+        let rolled_up = (!delimiter.is_empty())
+            .then(|| entry.key[prefix.len()..].find(delimiter.as_str()))
+            .flatten()
+            .map(|i| entry.key[..prefix.len() + i + delimiter.len()].to_string());
+        if let Some(common) = &rolled_up {
+            if common_prefixes.last() == Some(common) {
+                continue;
+            }
+        }
+        if count == max_keys {
+            truncated = true;
+            break;
+        }
+        count += 1;
+        match rolled_up {
+            Some(common) => {
+                last = Some(common.clone());
+                common_prefixes.push(common);
+            }
+            None => {
+                last = Some(entry.key.clone());
+                contents.push_str(&format!(
+                    "<Contents><Key>{}</Key><LastModified>{}</LastModified><ETag>{}</ETag><Size>{}</Size><StorageClass>STANDARD</StorageClass></Contents>",
+                    encode(&entry.key),
+                    iso8601(entry.modified),
+                    escape_xml(&etag(entry.modified, entry.size)),
+                    entry.size
+                ));
+            }
+        }
+    }
+
+    let mut xml = format!(
+        "<ListBucketResult xmlns=\"{}\"><Name>{}</Name><Prefix>{}</Prefix><MaxKeys>{}</MaxKeys><IsTruncated>{}</IsTruncated>",
+        S3_NS,
+        escape_xml(bucket),
+        encode(&prefix),
+        max_keys,
+        truncated
+    );
+    if !delimiter.is_empty() {
+        xml.push_str(&format!("<Delimiter>{}</Delimiter>", encode(&delimiter)));
+    }
+    if url_encoded {
+        xml.push_str("<EncodingType>url</EncodingType>");
+    }
+    let next = last.filter(|_| truncated);
+    if v2 {
+        xml.push_str(&format!("<KeyCount>{}</KeyCount>", count));
+        if let Some(token) = continuation {
+            xml.push_str(&format!(
+                "<ContinuationToken>{}</ContinuationToken>",
+                escape_xml(token)
+            ));
+        }
+        if let Some(start_after) = query.get("start-after") {
+            xml.push_str(&format!("<StartAfter>{}</StartAfter>", encode(start_after)));
+        }
+        if let Some(next) = next {
+            xml.push_str(&format!(
+                "<NextContinuationToken>{}</NextContinuationToken>",
+                base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(next)
+            ));
+        }
+    } else {
+        xml.push_str(&format!(
+            "<Marker>{}</Marker>",
+            encode(query.get("marker").unwrap_or_default())
+        ));
+        if let Some(next) = next {
+            xml.push_str(&format!("<NextMarker>{}</NextMarker>", encode(&next)));
+        }
+    }
+    xml.push_str(&contents);
+    for common in common_prefixes {
+        xml.push_str(&format!(
+            "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+            encode(&common)
+        ));
+    }
+    xml.push_str("</ListBucketResult>");
+    Ok(xml_response(xml))
+}
+
+// --- Objects ---
+
+async fn get_object(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    query: &Query,
+) -> Result<HttpResponse> {
+    let path = object_path(state, bucket, key)?;
+    let meta = tokio::fs::metadata(&path)
+        .await
+        .map_err(|_| no_such_key())?;
+    // Folders only exist as `folder/` marker objects
+    if meta.is_dir() != key.ends_with('/') {
+        return Err(no_such_key());
+    }
+
+    let modified = modified_secs(&meta);
+    let size = if meta.is_dir() { 0 } else { meta.len() };
+    let etag = etag(modified, size);
+    let last_modified =
+        header::HttpDate::from(UNIX_EPOCH + std::time::Duration::from_secs(modified));
+    if let Some(response) = check_preconditions(req, &etag, modified) {
+        return Ok(response);
+    }
+
+    let content_type = match query.get("response-content-type") {
+        Some(content_type) => content_type.to_string(),
+        None if meta.is_dir() => "application/x-directory".to_string(),
+        None => content_type_for(&path),
+    };
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::LAST_MODIFIED, last_modified.to_string()))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    if let Some(disposition) = query.get("response-content-disposition") {
+        response.insert_header((header::CONTENT_DISPOSITION, disposition));
+    }
+    if meta.is_dir() {
+        return Ok(response.finish());
+    }
+
+    let (start, length) = match header_str(req, "Range").filter(|_| size > 0) {
+        Some(range) => {
+            let range = actix_files::HttpRange::parse(range, size)
+                .ok()
+                .and_then(|ranges| ranges.into_iter().next())
+                .ok_or_else(|| {
+                    actix_web::error::InternalError::from_response(
+                        "Invalid range",
+                        HttpResponse::RangeNotSatisfiable()
+                            .insert_header((header::CONTENT_RANGE, format!("bytes */{}", size)))
+                            .content_type("application/xml")
+                            .body(format!(
+                                "{}<Error><Code>InvalidRange</Code><Message>The requested range is not satisfiable</Message></Error>",
+                                XML_HEADER
+                            )),
+                    )
+                })?;
+            response.status(StatusCode::PARTIAL_CONTENT).insert_header((
+                header::CONTENT_RANGE,
+                format!(
+                    "bytes {}-{}/{}",
+                    range.start,
+                    range.start + range.length - 1,
+                    size
+                ),
+            ));
+            (range.start, range.length)
+        }
+        None => (0, size),
+    };
+
+    let mut file = tokio::fs::File::open(&path).await?;
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let stream = futures_util::stream::unfold((file, length), |(mut file, remaining)| async move {
+        if remaining == 0 {
+            return None;
+        }
+        let mut buf = vec![0; remaining.min(64 * 1024) as usize];
+        match file.read(&mut buf).await {
+            // The file shrank while it was being sent
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(web::Bytes::from(buf)), (file, remaining - n as u64)))
+            }
+            Err(e) => Some((Err(e), (file, 0))),
+        }
+    });
+    Ok(response.body(actix_web::body::SizedStream::new(length, stream)))
+}
+
+/// Evaluate `If-Match`, `If-Unmodified-Since`, `If-None-Match` and `If-Modified-Since`
+/// against our ETag (which is why objects aren't served through `NamedFile`).
+fn check_preconditions(req: &HttpRequest, etag: &str, modified: u64) -> Option<HttpResponse> {
+    let matches = |value: &str| {
+        value
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    };
+    let date = |name: &str| {
+        let date: header::HttpDate = header_str(req, name)?.parse().ok()?;
+        SystemTime::from(date)
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|d| d.as_secs())
+    };
+
+    let failed = match header_str(req, "If-Match") {
+        Some(value) => !matches(value),
+        None => date("If-Unmodified-Since").is_some_and(|since| modified > since),
+    };
+    if failed {
+        return Some(
+            HttpResponse::PreconditionFailed()
+                .content_type("application/xml")
+                .body(format!(
+                    "{}<Error><Code>PreconditionFailed</Code><Message>At least one of the preconditions you specified did not hold.</Message></Error>",
+                    XML_HEADER
+                )),
+        );
+    }
+    let unchanged = match header_str(req, "If-None-Match") {
+        Some(value) => matches(value),
+        None => date("If-Modified-Since").is_some_and(|since| modified <= since),
+    };
+    unchanged.then(|| {
+        HttpResponse::NotModified()
+            .insert_header((header::ETAG, etag))
+            .finish()
+    })
+}
+
+async fn put_object(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    body: BodyReader,
+) -> Result<HttpResponse> {
+    let path = object_path(state, bucket, key)?;
+    let rel = object_rel(bucket, key);
+
+    // `folder/` markers create the folder
+    if key.ends_with('/') {
+        body.read_all(0).await?;
+        if path.is_file() {
+            return Err(s3_error(
+                StatusCode::CONFLICT,
+                "ParentIsObject",
+                "The folder name is an object.",
+            ));
+        }
+        tokio::fs::create_dir_all(&path).await.map_err(|_| {
+            s3_error(
+                StatusCode::CONFLICT,
+                "ParentIsObject",
+                "A parent of the key is an object.",
+            )
+        })?;
+        reindex_paths(state, [path.as_path()]).await;
+        broadcast_update(&state.broadcaster, "folder", &rel);
+        let modified = std::fs::metadata(&path).map(|m| modified_secs(&m))?;
+        return Ok(HttpResponse::Ok()
+            .insert_header((header::ETAG, etag(modified, 0)))
+            .finish());
+    }
+
+    ensure_parent(&path).await?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| invalid_argument("Invalid key"))?;
+    // Stream to a staging file so a failed upload leaves the old version alone
+    let staging = parent.join(format!(".upload-{}", uuid::Uuid::new_v4()));
+    let (quotas, limit) = object_limit(state, &rel, &path).await?;
+    body.write_to(&staging, limit).await?;
+    // Concurrent uploads may have used up the room in the meantime
+    let size = tokio::fs::metadata(&staging).await?.len();
+    if quotas.claim(&rel, 0, size).is_err() {
+        let _ = tokio::fs::remove_file(&staging).await;
+        return Err(entity_too_large());
+    }
+    replace_with(state, &staging, &path, requested_mtime(req)).await?;
+
+    broadcast_update(&state.broadcaster, "upload", &rel);
+    let meta = tokio::fs::metadata(&path).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, etag(modified_secs(&meta), meta.len())))
+        .finish())
+}
+
+/// Most an object at `rel` may hold: the upload limit, or what is left of its quotas,
+/// with the tracker to claim its bytes with.
+async fn object_limit(state: &AppState, rel: &str, path: &Path) -> Result<(quota::Tracker, usize)> {
+    let (quotas, limit, _) =
+        quota::file_limit(state, rel, state.max_upload_bytes as u64, !path.exists())
+            .await
+            .map_err(|_| entity_too_large())?;
+    Ok((quotas, limit as usize))
+}
+
+/// Move a finished upload into place, keeping the version it replaces.
+async fn replace_with(
+    state: &AppState,
+    staging: &Path,
+    path: &Path,
+    mtime: Option<filetime::FileTime>,
+) -> Result<()> {
+    if let Some(mtime) = mtime {
+        let _ = filetime::set_file_mtime(staging, mtime);
+    }
+    if path.exists() {
+        if let Err(e) = keep_revision(state, path).await {
+            let _ = tokio::fs::remove_file(staging).await;
+            return Err(e.into());
+        }
+    }
+    tokio::fs::rename(staging, path).await?;
+    reindex_paths(state, [path]).await;
+    Ok(())
+}
+
+/// Delete an upload-root relative path through the trash, journaled for undo.
+async fn delete_path(state: &AppState, rel: &str) -> Result<()> {
+    let outcome = apply_delete(
+        state,
+        &DeleteReq {
+            path: rel.to_string(),
+        },
+    )
+    .await?;
+    if let Some(applied) = outcome.applied {
+        publish_op(state, outcome.action, rel, vec![applied]).await;
+    }
+    Ok(())
+}
+
+/// Whether deleting `key` would remove anything: files, and folders only through
+/// their (empty) `folder/` marker.
+fn deletable(path: &Path, key: &str) -> bool {
+    if key.ends_with('/') {
+        path.is_dir() && std::fs::read_dir(path).is_ok_and(|mut d| d.next().is_none())
+    } else {
+        path.is_file()
+    }
+}
+
+async fn delete_object(state: &AppState, bucket: &str, key: &str) -> Result<HttpResponse> {
+    let path = object_path(state, bucket, key)?;
+    // Deleting a missing key succeeds, as in S3
+    if deletable(&path, key) {
+        delete_path(state, &object_rel(bucket, key)).await?;
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// DeleteObjects: up to 1000 keys in one request, journaled as a single undo step.
+async fn delete_objects(state: &AppState, bucket: &str, body: BodyReader) -> Result<HttpResponse> {
+    bucket_dir(state, bucket)?;
+    let body = body.read_all(MAX_XML_BODY).await?;
+    let objects = xml_records(&body, "Object")?;
+    if objects.len() > MAX_KEYS {
+        return Err(malformed_xml());
+    }
+    let quiet = xml_records(&body, "Delete")?
+        .first()
+        .and_then(|fields| fields.get("Quiet").cloned())
+        .is_some_and(|quiet| quiet.eq_ignore_ascii_case("true"));
+
+    let mut results = String::new();
+    let mut changes = Vec::new();
+    for object in objects {
+        let key = object.get("Key").cloned().unwrap_or_default();
+        let deleted = async {
+            validate_key(&key)?;
+            if key.is_empty() {
+                return Err(invalid_argument("Empty key"));
+            }
+            let path = object_path(state, bucket, &key)?;
+            if deletable(&path, &key) {
+                let outcome = apply_delete(
+                    state,
+                    &DeleteReq {
+                        path: object_rel(bucket, &key),
+                    },
+                )
+                .await?;
+                changes.extend(outcome.applied);
+            }
+            Ok(())
+        }
+        .await;
+        match deleted {
+            Ok(()) if !quiet => results.push_str(&format!(
+                "<Deleted><Key>{}</Key></Deleted>",
+                escape_xml(&key)
+            )),
+            Ok(()) => {}
+            Err(e) => results.push_str(&format!(
+                "<Error><Key>{}</Key><Code>AccessDenied</Code><Message>{}</Message></Error>",
+                escape_xml(&key),
+                escape_xml(&e.to_string())
+            )),
+        }
+    }
+    if !changes.is_empty() {
+        publish_op(state, "delete", bucket, changes).await;
+    }
+    Ok(xml_response(format!(
+        "<DeleteResult xmlns=\"{}\">{}</DeleteResult>",
+        S3_NS, results
+    )))
+}
+
+async fn copy_object(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+) -> Result<HttpResponse> {
+    let source = header_str(req, "x-amz-copy-source").unwrap_or_default();
+    let source = source.split('?').next().unwrap_or_default();
+    let (src_bucket, src_key) =
+        parse_target(&format!("{}/{}", PREFIX, source.trim_start_matches('/')))?;
+    if src_key.is_empty() || src_key.ends_with('/') || key.ends_with('/') {
+        return Err(invalid_argument("Only objects can be copied"));
+    }
+    let src = object_path(state, &src_bucket, &src_key)?;
+    if !src.is_file() {
+        return Err(no_such_key());
+    }
+    let dest = object_path(state, bucket, key)?;
+    let replace_metadata = header_str(req, "x-amz-metadata-directive") == Some("REPLACE");
+
+    if src == dest {
+        // Copying onto itself only updates metadata, of which we keep the mtime
+        if !replace_metadata {
+            return Err(s3_error(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                "This copy request is illegal because it is trying to copy an object to itself without changing the object's metadata.",
+            ));
+        }
+        if let Some(mtime) = requested_mtime(req) {
+            filetime::set_file_mtime(&dest, mtime)?;
+            reindex_paths(state, [dest.as_path()]).await;
+            broadcast_update(&state.broadcaster, "edit", &object_rel(bucket, key));
+        }
+    } else {
+        ensure_parent(&dest).await?;
+        let dest_rel = object_rel(bucket, key);
+        let (_, replaced) = copy_to_path(state, &src, &dest, &dest_rel).await?;
+        if let Some(mtime) = requested_mtime(req).filter(|_| replace_metadata) {
+            let _ = filetime::set_file_mtime(&dest, mtime);
+        }
+        publish_op(
+            state,
+            "copy",
+            &dest_rel,
+            vec![Applied::Created {
+                path: dest.clone(),
+                replaced,
+            }],
+        )
+        .await;
+    }
+
+    let meta = tokio::fs::metadata(&dest).await?;
+    let modified = modified_secs(&meta);
+    Ok(xml_response(format!(
+        "<CopyObjectResult><LastModified>{}</LastModified><ETag>{}</ETag></CopyObjectResult>",
+        iso8601(modified),
+        escape_xml(&etag(modified, meta.len()))
+    )))
+}
+
+// --- Multipart uploads ---
+
+#[derive(Serialize, Deserialize)]
+struct Upload {
+    bucket: String,
+    key: String,
+    /// From `x-amz-meta-mtime` on initiation, as unix seconds
+    mtime: Option<(i64, u32)>,
+}
+
+/// Directory of an in-progress upload. Upload ids are UUIDs, anything else is unknown.
+fn upload_dir(state: &AppState, query: &Query) -> Result<PathBuf> {
+    let id = query
+        .get("uploadId")
+        .and_then(|id| uuid::Uuid::parse_str(id).ok())
+        .ok_or_else(no_such_upload)?;
+    let dir = state
+        .upload_dir
+        .join(MULTIPART_DIR)
+        .join(id.simple().to_string());
+    if dir.is_dir() {
+        Ok(dir)
+    } else {
+        Err(no_such_upload())
+    }
+}
+
+async fn load_upload(dir: &Path, bucket: &str, key: &str) -> Result<Upload> {
+    let json = tokio::fs::read(dir.join("upload.json"))
+        .await
+        .map_err(|_| no_such_upload())?;
+    let upload: Upload = serde_json::from_slice(&json).map_err(|_| no_such_upload())?;
+    if upload.bucket != bucket || upload.key != key {
+        return Err(no_such_upload());
+    }
+    Ok(upload)
+}
+
+async fn create_upload(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+) -> Result<HttpResponse> {
+    if key.ends_with('/') {
+        return Err(invalid_argument("Folders can't be uploaded in parts"));
+    }
+    object_path(state, bucket, key)?;
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let dir = state.upload_dir.join(MULTIPART_DIR).join(&id);
+    tokio::fs::create_dir_all(&dir).await?;
+    let upload = Upload {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        mtime: requested_mtime(req).map(|t| (t.unix_seconds(), t.nanoseconds())),
+    };
+    tokio::fs::write(dir.join("upload.json"), serde_json::to_vec(&upload)?).await?;
+
+    Ok(xml_response(format!(
+        "<InitiateMultipartUploadResult xmlns=\"{}\"><Bucket>{}</Bucket><Key>{}</Key><UploadId>{}</UploadId></InitiateMultipartUploadResult>",
+        S3_NS,
+        escape_xml(bucket),
+        escape_xml(key),
+        id
+    )))
+}
+
+/// Parts are stored as `<number>.<md5 hex>`, so a part's ETag is part of its name.
+fn part_prefix(number: u32) -> String {
+    format!("{:05}.", number)
+}
+
+async fn upload_part(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    query: &Query,
+    body: BodyReader,
+) -> Result<HttpResponse> {
+    let dir = upload_dir(state, query)?;
+    load_upload(&dir, bucket, key).await?;
+    let number: u32 = query
+        .get("partNumber")
+        .and_then(|n| n.parse().ok())
+        .filter(|n| (1..=MAX_PART_NUMBER).contains(n))
+        .ok_or_else(|| invalid_argument("Part number must be an integer between 1 and 10000"))?;
+
+    // The parts staged so far count against the object's limit (and so its quota), so
+    // an upload can't stage more than it will ever be allowed to complete
+    let prefix = part_prefix(number);
+    let mut staged = 0;
+    let mut replaced = Vec::new();
+    let mut entries = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with(&prefix) {
+            replaced.push(entry.path());
+        } else if name.as_bytes().first().is_some_and(u8::is_ascii_digit) {
+            staged += entry.metadata().await?.len() as usize;
+        }
+    }
+    let path = object_path(state, bucket, key)?;
+    let (_, limit) = object_limit(state, &object_rel(bucket, key), &path).await?;
+    let room = limit.checked_sub(staged).ok_or_else(entity_too_large)?;
+
+    let staging = dir.join(format!(".part-{}", uuid::Uuid::new_v4()));
+    let md5 = hex::encode(body.write_to(&staging, room).await?);
+
+    // A part uploaded again replaces the earlier one
+    for path in replaced {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+    tokio::fs::rename(&staging, dir.join(format!("{}{}", prefix, md5))).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, format!("\"{}\"", md5)))
+        .finish())
+}
+
+async fn complete_upload(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    query: &Query,
+    body: BodyReader,
+) -> Result<HttpResponse> {
+    let dir = upload_dir(state, query)?;
+    let upload = load_upload(&dir, bucket, key).await?;
+    let body = body.read_all(MAX_XML_BODY).await?;
+
+    let invalid_part = || {
+        s3_error(
+            StatusCode::BAD_REQUEST,
+            "InvalidPart",
+            "One or more of the specified parts could not be found.",
+        )
+    };
+    let mut parts = Vec::new();
+    let mut total = 0;
+    let mut previous = 0;
+    for part in xml_records(&body, "Part")? {
+        let number: u32 = part
+            .get("PartNumber")
+            .and_then(|n| n.trim().parse().ok())
+            .ok_or_else(malformed_xml)?;
+        if number <= previous {
+            return Err(s3_error(
+                StatusCode::BAD_REQUEST,
+                "InvalidPartOrder",
+                "The list of parts was not in ascending order.",
+            ));
+        }
+        previous = number;
+        let md5 = part
+            .get("ETag")
+            .map(|etag| etag.trim().trim_matches('"').to_ascii_lowercase())
+            .filter(|md5| md5.len() == 32 && md5.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(invalid_part)?;
+        let path = dir.join(format!("{}{}", part_prefix(number), md5));
+        total += tokio::fs::metadata(&path)
+            .await
+            .map_err(|_| invalid_part())?
+            .len();
+        parts.push(path);
+    }
+    if parts.is_empty() {
+        return Err(malformed_xml());
+    }
+    let path = object_path(state, bucket, key)?;
+    let rel = object_rel(bucket, key);
+    let (quotas, limit) = object_limit(state, &rel, &path).await?;
+    if total > limit as u64 || quotas.claim(&rel, 0, total).is_err() {
+        return Err(entity_too_large());
+    }
+
+    ensure_parent(&path).await?;
+    let parent = path
+        .parent()
+        .ok_or_else(|| invalid_argument("Invalid key"))?;
+    let staging = parent.join(format!(".upload-{}", uuid::Uuid::new_v4()));
+    let target = staging.clone();
+    web::block(move || -> std::io::Result<()> {
+        let result = (|| {
+            let mut out = std::io::BufWriter::new(std::fs::File::create(&target)?);
+            for part in &parts {
+                std::io::copy(&mut std::fs::File::open(part)?, &mut out)?;
+            }
+            std::io::Write::flush(&mut out)
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&target);
+        }
+        result
+    })
+    .await??;
+
+    let mtime = upload
+        .mtime
+        .map(|(secs, nanos)| filetime::FileTime::from_unix_time(secs, nanos));
+    replace_with(state, &staging, &path, mtime).await?;
+    let _ = tokio::fs::remove_dir_all(&dir).await;
+    broadcast_update(&state.broadcaster, "upload", &rel);
+
+    let meta = tokio::fs::metadata(&path).await?;
+    Ok(xml_response(format!(
+        "<CompleteMultipartUploadResult xmlns=\"{}\"><Location>{}/{}/{}</Location><Bucket>{}</Bucket><Key>{}</Key><ETag>{}</ETag></CompleteMultipartUploadResult>",
+        S3_NS,
+        PREFIX,
+        escape_xml(bucket),
+        escape_xml(&utf8_percent_encode(key, KEY_ENCODE).to_string()),
+        escape_xml(bucket),
+        escape_xml(key),
+        escape_xml(&etag(modified_secs(&meta), meta.len()))
+    )))
+}
+
+async fn abort_upload(state: &AppState, query: &Query) -> Result<HttpResponse> {
+    let dir = upload_dir(state, query)?;
+    tokio::fs::remove_dir_all(&dir).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Drop multipart uploads that were abandoned more than a week ago.
+pub async fn purge_stale_uploads(upload_dir: &Path) {
+    let Ok(mut entries) = tokio::fs::read_dir(upload_dir.join(MULTIPART_DIR)).await else {
+        return;
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let stale = entry
+            .metadata()
+            .await
+            .ok()
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age.as_secs() > STALE_UPLOAD_SECS);
+        if stale {
+            let _ = tokio::fs::remove_dir_all(entry.path()).await;
+        }
+    }
+}
@@ -0,0 +1,401 @@
+// === Search Filters ===
+// Filters and the small query language behind `/api/search`, e.g.
+// `report ext:pdf size:>10MB modified:<2026-01-01 in:reports is:file`.
+// The same keys are accepted as query parameters (`?ext=pdf&size=>10MB`).
+// Names match fuzzily by default and results are ranked by `rank()`.
+
+use crate::fuzzy;
+
+/// File type groups, kept in sync with `getFileCategory()` in the UI.
+pub const TYPE_GROUPS: &[(&str, &[&str])] = &[
+    ("images", &["png", "jpg", "jpeg", "gif", "svg", "webp", "ico", "bmp"]),
+    (
+        "documents",
+        &[
+            "pdf", "doc", "docx", "txt", "rtf", "odt", "xls", "xlsx", "csv", "ppt", "pptx",
+        ],
+    ),
+    (
+        "code",
+        &[
+            "js", "ts", "jsx", "tsx", "html", "css", "json", "py", "rs", "go", "java", "c", "cpp",
+            "h", "php", "rb", "sh", "yml", "yaml", "xml", "sql", "md",
+        ],
+    ),
+    (
+        "media",
+        &[
+            "mp4", "mov", "avi", "mkv", "webm", "wmv", "mp3", "wav", "flac", "aac", "ogg", "m4a",
+        ],
+    ),
+];
+
+/// Ranking boosts for recently modified entries: (max age in seconds, bonus).
+const RECENCY_BOOSTS: &[(u64, i64)] = &[(86400, 12), (7 * 86400, 8), (30 * 86400, 4)];
+
+/// Cap on compiled regex size so a hostile pattern can't eat memory.
+const MAX_REGEX_BYTES: usize = 1024 * 1024;
+
+/// Type group of a file name: one of the `TYPE_GROUPS` keys, or `other`.
+pub fn type_group(name: &str) -> &'static str {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) => ext.to_lowercase(),
+        None => return "other",
+    };
+    TYPE_GROUPS
+        .iter()
+        .find(|(_, exts)| exts.contains(&ext.as_str()))
+        .map(|(group, _)| *group)
+        .unwrap_or("other")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Kind {
+    Files,
+    Folders,
+}
+
+enum NameMatcher {
+    /// No name pattern, only the other filters apply
+    Any,
+    /// Lowercased pattern; matched against the full path when it contains `/`
+    Fuzzy(Vec<char>, bool),
+    Substring(String),
+    Glob(globset::GlobMatcher, bool),
+    Regex(regex::Regex),
+}
+
+pub struct SearchFilter {
+    matcher: NameMatcher,
+    extensions: Vec<String>,
+    type_group: Option<String>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    /// Inclusive lower bound, unix seconds
+    modified_after: Option<u64>,
+    /// Exclusive upper bound, unix seconds
+    modified_before: Option<u64>,
+    /// Folder to search in, relative to the upload root
+    pub scope: Option<String>,
+    kind: Option<Kind>,
+}
+
+/// Split a query on whitespace, keeping double-quoted parts together.
+fn tokenize(query: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in query.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// Parse `10MB`, `1.5 GiB`, `512k` or a plain byte count.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid size '{}'", value))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        "t" | "tb" | "tib" => 1024 * 1024 * 1024 * 1024,
+        _ => return Err(format!("Unknown size unit in '{}'", value)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Parse `YYYY-MM-DD` (UTC midnight) or unix seconds.
+fn parse_date(value: &str) -> Result<u64, String> {
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs);
+    }
+    let invalid = || format!("Invalid date '{}', expected YYYY-MM-DD", value);
+    let parts: Vec<&str> = value.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return Err(invalid());
+    };
+    let (year, month, day): (i64, i64, i64) = (
+        year.parse().map_err(|_| invalid())?,
+        month.parse().map_err(|_| invalid())?,
+        day.parse().map_err(|_| invalid())?,
+    );
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    Ok((crate::days_from_civil(year, month, day) * 86400).max(0) as u64)
+}
+
+/// Parse a comparison (`>x`, `>=x`, `<x`, `<=x`, `x..y`, or `x`) into inclusive-exclusive
+/// bounds. `exact` gives the bounds for a bare value.
+fn parse_range(
+    value: &str,
+    parse: fn(&str) -> Result<u64, String>,
+    exact: fn(u64) -> (u64, u64),
+) -> Result<(Option<u64>, Option<u64>), String> {
+    if let Some(v) = value.strip_prefix(">=") {
+        Ok((Some(parse(v)?), None))
+    } else if let Some(v) = value.strip_prefix("<=") {
+        Ok((None, Some(exact(parse(v)?).1)))
+    } else if let Some(v) = value.strip_prefix('>') {
+        Ok((Some(exact(parse(v)?).1), None))
+    } else if let Some(v) = value.strip_prefix('<') {
+        Ok((None, Some(parse(v)?)))
+    } else if let Some((low, high)) = value.split_once("..") {
+        let low = if low.is_empty() { None } else { Some(parse(low)?) };
+        let high = if high.is_empty() {
+            None
+        } else {
+            Some(exact(parse(high)?).1)
+        };
+        Ok((low, high))
+    } else {
+        let (low, high) = exact(parse(value)?);
+        Ok((Some(low), Some(high)))
+    }
+}
+
+impl SearchFilter {
+    /// Build a filter from a query string plus explicit `(key, value)` parameters.
+    /// Parameters use the same keys as the query language and win over it.
+    pub fn parse(query: &str, params: &[(&str, &str)]) -> Result<Self, String> {
+        let mut filter = SearchFilter {
+            matcher: NameMatcher::Any,
+            extensions: Vec::new(),
+            type_group: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+            scope: None,
+            kind: None,
+        };
+        let mut mode = String::from("fuzzy");
+        let mut terms = Vec::new();
+
+        for token in tokenize(query) {
+            match token.split_once(':') {
+                Some((key, value)) if Self::is_key(key) => {
+                    filter.apply(key, value, &mut mode)?;
+                }
+                _ => terms.push(token),
+            }
+        }
+        for (key, value) in params {
+            filter.apply(key, value, &mut mode)?;
+        }
+
+        let pattern = terms.join(" ");
+        if !pattern.is_empty() {
+            filter.matcher = match mode.as_str() {
+                "fuzzy" => NameMatcher::Fuzzy(fuzzy::pattern(&pattern), pattern.contains('/')),
+                "substring" => NameMatcher::Substring(pattern.to_lowercase()),
+                "glob" => {
+                    let glob = globset::GlobBuilder::new(&pattern)
+                        .case_insensitive(true)
+                        .build()
+                        .map_err(|e| format!("Invalid glob: {}", e.kind()))?;
+                    NameMatcher::Glob(glob.compile_matcher(), pattern.contains('/'))
+                }
+                "regex" => NameMatcher::Regex(
+                    regex::RegexBuilder::new(&pattern)
+                        .case_insensitive(true)
+                        .size_limit(MAX_REGEX_BYTES)
+                        .build()
+                        .map_err(|e| format!("Invalid regex: {}", e))?,
+                ),
+                other => {
+                    return Err(format!(
+                        "Unknown mode '{}', expected fuzzy, substring, glob or regex",
+                        other
+                    ))
+                }
+            };
+        }
+
+        Ok(filter)
+    }
+
+    fn is_key(key: &str) -> bool {
+        matches!(
+            key,
+            "ext" | "type" | "size" | "modified" | "in" | "is" | "mode"
+        )
+    }
+
+    fn apply(&mut self, key: &str, value: &str, mode: &mut String) -> Result<(), String> {
+        let value = value.trim();
+        if value.is_empty() {
+            return Err(format!("Missing value for '{}:'", key));
+        }
+
+        match key {
+            "ext" => {
+                self.extensions = value
+                    .split(',')
+                    .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                    .filter(|e| !e.is_empty())
+                    .collect();
+            }
+            "type" => {
+                let group = value.to_lowercase();
+                if group != "other" && !TYPE_GROUPS.iter().any(|(g, _)| *g == group) {
+                    return Err(format!(
+                        "Unknown type '{}', expected images, documents, code, media or other",
+                        value
+                    ));
+                }
+                self.type_group = Some(group);
+            }
+            "size" => {
+                // A bare size matches exactly
+                (self.min_size, self.max_size) =
+                    parse_range(value, parse_size, |v| (v, v.saturating_add(1)))?;
+            }
+            "modified" => {
+                // A bare date covers that whole day
+                (self.modified_after, self.modified_before) =
+                    parse_range(value, parse_date, |v| {
+                        if v % 86400 == 0 {
+                            (v, v + 86400)
+                        } else {
+                            (v, v + 1)
+                        }
+                    })?;
+            }
+            "in" => self.scope = Some(value.trim_matches('/').to_string()),
+            "is" => {
+                self.kind = Some(match value.to_lowercase().as_str() {
+                    "file" | "files" => Kind::Files,
+                    "folder" | "folders" | "dir" => Kind::Folders,
+                    _ => return Err(format!("Unknown 'is:{}', expected file or folder", value)),
+                });
+            }
+            "mode" => *mode = value.to_lowercase(),
+            _ => return Err(format!("Unknown filter '{}'", key)),
+        }
+        Ok(())
+    }
+
+    /// True if the filter would match everything (an empty search).
+    pub fn is_empty(&self) -> bool {
+        matches!(self.matcher, NameMatcher::Any)
+            && self.extensions.is_empty()
+            && self.type_group.is_none()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.modified_after.is_none()
+            && self.modified_before.is_none()
+            && self.kind.is_none()
+    }
+
+    /// `path` is relative to the upload root.
+    pub fn matches(&self, name: &str, path: &str, is_dir: bool, size: u64, modified: u64) -> bool {
+        let name_matches = match &self.matcher {
+            NameMatcher::Any => true,
+            NameMatcher::Fuzzy(pattern, full_path) => {
+                fuzzy::is_match(pattern, if *full_path { path } else { name })
+            }
+            NameMatcher::Substring(term) => name.to_lowercase().contains(term),
+            NameMatcher::Glob(glob, full_path) => glob.is_match(if *full_path { path } else { name }),
+            NameMatcher::Regex(re) => re.is_match(name),
+        };
+        if !name_matches {
+            return false;
+        }
+
+        match self.kind {
+            Some(Kind::Files) if is_dir => return false,
+            Some(Kind::Folders) if !is_dir => return false,
+            _ => {}
+        }
+
+        // Extension, type and size only make sense for files
+        let file_filters = !self.extensions.is_empty()
+            || self.type_group.is_some()
+            || self.min_size.is_some()
+            || self.max_size.is_some();
+        if is_dir && file_filters {
+            return false;
+        }
+
+        if !self.extensions.is_empty() {
+            let ext = name
+                .rsplit_once('.')
+                .map(|(_, e)| e.to_lowercase())
+                .unwrap_or_default();
+            if !self.extensions.contains(&ext) {
+                return false;
+            }
+        }
+        if let Some(group) = &self.type_group {
+            if type_group(name) != group {
+                return false;
+            }
+        }
+
+        self.min_size.is_none_or(|min| size >= min)
+            && self.max_size.is_none_or(|max| size < max)
+            && self.modified_after.is_none_or(|after| modified >= after)
+            && self.modified_before.is_none_or(|before| modified < before)
+    }
+
+    /// Whether results are ordered by `rank()` rather than by name.
+    pub fn is_ranked(&self) -> bool {
+        matches!(self.matcher, NameMatcher::Fuzzy(..))
+    }
+
+    /// Relevance of a matching entry and the matched character positions in `name`,
+    /// for ranked (fuzzy) searches. Recently modified entries get a boost.
+    pub fn rank(
+        &self,
+        name: &str,
+        path: &str,
+        modified: u64,
+        now: u64,
+    ) -> Option<(i64, Vec<usize>)> {
+        let NameMatcher::Fuzzy(pattern, full_path) = &self.matcher else {
+            return None;
+        };
+
+        let (score, positions) = if *full_path {
+            // Only the part of the path that is the name gets highlighted
+            let (score, positions) = fuzzy::score(pattern, path)?;
+            let offset = path.chars().count() - name.chars().count();
+            let positions = positions
+                .into_iter()
+                .filter_map(|p| p.checked_sub(offset))
+                .collect();
+            (score, positions)
+        } else {
+            fuzzy::score(pattern, name)?
+        };
+
+        let age = now.saturating_sub(modified);
+        let recency = RECENCY_BOOSTS
+            .iter()
+            .find(|(max_age, _)| age < *max_age)
+            .map(|(_, bonus)| *bonus)
+            .unwrap_or(0);
+
+        Some((score + recency, positions))
+    }
+}
@@ -0,0 +1,1810 @@
+// === Storage Backends ===
+// The core file API (listing, upload, download, editing, folders, rename, move, copy and
+// delete) reads and writes through `AppState::storage`, so the upload tree can live on
+// local disk (default), in memory (test runs), in a content-addressed blob store that
+// keeps each distinct content once, or in an S3 bucket. `BOX_STORAGE` picks the backend
+// at startup.
+//
+// Paths are relative to the upload root, `/`-separated, without `.` or `..` segments;
+// the empty path is the root. Features that work on real files (tree and content
+// indexes, thumbnails, previews, archives, trash and undo, WebDAV, the S3 API) need
+// `local_root()` and only see the local upload dir on the other backends.
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::HttpDate;
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use percent_encoding::utf8_percent_encode;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::s3::{self, Credentials};
+use crate::{civil_from_days, copy, days_from_civil, remove_path, resolve_path_safe};
+
+/// File contents as they are read or written.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>;
+
+/// Buffer size for reading local files.
+const READ_CHUNK: usize = 64 * 1024;
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Unix seconds, 0 if the backend doesn't know
+    pub modified: u64,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    /// Folder on local disk holding the files, if this backend has one.
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Folder on local disk the files are stored in as they are, for moving them aside
+    /// unread: to the trash, to kept revisions and back for undo. Unlike `local_root`,
+    /// what is stored there may not be readable as it is.
+    fn disk_root(&self) -> Option<&Path> {
+        self.local_root()
+    }
+
+    /// Whether `copy` only adds references to the same contents, so copies and kept
+    /// versions take no extra space.
+    fn copies_are_free(&self) -> bool {
+        false
+    }
+
+    /// Children of the folder at `path`.
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>>;
+
+    /// The item at `path`; `NotFound` if there is none.
+    async fn stat(&self, path: &str) -> io::Result<Entry>;
+
+    /// Contents of the file at `path`, or `(offset, length)` bytes of it.
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream>;
+
+    /// Store `body` as the file at `path`, creating missing parent folders. An existing
+    /// file is only replaced once the whole body has been written. Returns the size.
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64>;
+
+    /// Move a file or folder to `to`, which must not exist yet.
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Copy a file or folder with everything in it to `to`, which must not exist yet.
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Remove a file, or a folder with everything in it.
+    async fn delete(&self, path: &str) -> io::Result<()>;
+
+    /// Create a folder and any missing parents.
+    async fn mkdir(&self, path: &str) -> io::Result<()>;
+}
+
+/// Backend selected with `BOX_STORAGE`.
+pub enum Backend {
+    Local,
+    Memory,
+    Dedup,
+    S3(S3Config),
+}
+
+#[derive(Clone)]
+pub struct S3Config {
+    /// Base URL of the service, e.g. `http://localhost:9000`
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub credentials: Credentials,
+}
+
+impl Backend {
+    /// `BOX_STORAGE` is `local` (default), `memory`, `dedup` or `s3`; the S3 backend is configured
+    /// with `BOX_STORAGE_S3_ENDPOINT`, `_BUCKET`, `_REGION`, `_ACCESS_KEY` and `_SECRET_KEY`.
+    pub fn from_env() -> Result<Self, String> {
+        let var = |name: &str| env::var(name).ok().filter(|v| !v.is_empty());
+        let required = |name: &str| var(name).ok_or_else(|| format!("{} is not set", name));
+        match var("BOX_STORAGE").as_deref().unwrap_or("local") {
+            "local" => Ok(Backend::Local),
+            "memory" => Ok(Backend::Memory),
+            "dedup" => Ok(Backend::Dedup),
+            "s3" => Ok(Backend::S3(S3Config {
+                endpoint: required("BOX_STORAGE_S3_ENDPOINT")?,
+                bucket: required("BOX_STORAGE_S3_BUCKET")?,
+                region: var("BOX_STORAGE_S3_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+                credentials: Credentials {
+                    access_key: required("BOX_STORAGE_S3_ACCESS_KEY")?,
+                    secret_key: required("BOX_STORAGE_S3_SECRET_KEY")?,
+                },
+            })),
+            other => Err(format!("Unknown BOX_STORAGE backend: {}", other)),
+        }
+    }
+
+    /// Storage for one root. Local and dedup roots are their own folder and memory roots
+    /// are separate stores; S3 roots share the bucket, each below its key `prefix`.
+    pub fn open(&self, upload_dir: &Path, prefix: &str) -> io::Result<Arc<dyn Storage>> {
+        Ok(match self {
+            Backend::Local => Arc::new(LocalStorage::new(upload_dir)),
+            Backend::Memory => Arc::new(MemoryStorage::default()),
+            Backend::Dedup => Arc::new(BlobStorage::open(upload_dir)?),
+            Backend::S3(config) if prefix.is_empty() => Arc::new(S3Storage::new(config.clone())?),
+            Backend::S3(config) => Arc::new(Prefixed {
+                inner: S3Storage::new(config.clone())?,
+                prefix: normalize(prefix),
+            }),
+        })
+    }
+}
+
+// --- Paths & Streams ---
+
+/// Storage form of a client path: no empty, `.` or `..` segments and no outer slashes.
+pub fn normalize(path: &str) -> String {
+    path.split('/')
+        .filter(|s| !s.is_empty() && *s != "." && *s != "..")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+pub fn join(parent: &str, name: &str) -> String {
+    normalize(&format!("{}/{}", parent, name))
+}
+
+/// `(parent, name)` of a normalized path.
+pub fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// A body that is already in memory.
+pub fn once(data: impl Into<Bytes>) -> ByteStream {
+    let data = data.into();
+    Box::pin(futures_util::stream::once(async move { Ok(data) }))
+}
+
+/// Read a whole file into memory.
+pub async fn read_all(storage: &dyn Storage, path: &str) -> io::Result<Vec<u8>> {
+    let mut stream = storage.read(path, None).await?;
+    let mut data = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk?);
+    }
+    Ok(data)
+}
+
+/// Files and bytes in the item at `path`, counted by walking folders.
+pub async fn measure(storage: &dyn Storage, path: &str) -> io::Result<copy::CopyStats> {
+    let mut stats = copy::CopyStats::default();
+    let mut pending = vec![storage
+        .stat(path)
+        .await
+        .map(|entry| (normalize(path), entry))?];
+    while let Some((path, entry)) = pending.pop() {
+        if !entry.is_dir {
+            stats.files += 1;
+            stats.bytes += entry.size;
+            continue;
+        }
+        for child in storage.list(&path).await? {
+            // Kept versions in the data dir aren't part of the tree
+            if path.is_empty() && child.name == crate::DATA_DIR {
+                continue;
+            }
+            pending.push((join(&path, &child.name), child));
+        }
+    }
+    Ok(stats)
+}
+
+/// Stream an async reader in `READ_CHUNK` pieces, ending after the first error.
+fn reader_stream(reader: impl AsyncRead + Send + Unpin + 'static) -> ByteStream {
+    Box::pin(futures_util::stream::unfold(
+        Some(reader),
+        |reader| async move {
+            let mut reader = reader?;
+            let mut buf = vec![0; READ_CHUNK];
+            match reader.read(&mut buf).await {
+                Ok(0) => None,
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Bytes::from(buf)), Some(reader)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        },
+    ))
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or folder")
+}
+
+fn already_exists() -> io::Error {
+    io::Error::new(io::ErrorKind::AlreadyExists, "Destination already exists")
+}
+
+fn into_itself() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Cannot move or copy a folder into itself",
+    )
+}
+
+pub fn is_within(path: &str, folder: &str) -> bool {
+    folder.is_empty() || path == folder || path.starts_with(&format!("{}/", folder))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// --- Local Filesystem ---
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: &Path) -> Self {
+        Self {
+            root: root.to_path_buf(),
+        }
+    }
+
+    /// Path on disk, refusing anything that escapes the root through symlinks.
+    fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        resolve_path_safe(&self.root, Some(&normalize(path))).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::PermissionDenied, "Path is outside the root")
+        })
+    }
+}
+
+fn local_entry(name: String, meta: &std::fs::Metadata) -> Entry {
+    Entry {
+        name,
+        is_dir: meta.is_dir(),
+        size: if meta.is_dir() { 0 } else { meta.len() },
+        modified: meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    }
+}
+
+async fn write_file(path: &Path, mut body: ByteStream) -> io::Result<u64> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        file.write_all(&chunk).await?;
+        size += chunk.len() as u64;
+    }
+    file.flush().await?;
+    Ok(size)
+}
+
+#[async_trait]
+impl Storage for LocalStorage {
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let mut dir = tokio::fs::read_dir(self.resolve(path)?).await?;
+        let mut entries = Vec::new();
+        while let Some(item) = dir.next_entry().await? {
+            // Skip items removed while listing
+            if let Ok(meta) = tokio::fs::metadata(item.path()).await {
+                entries.push(local_entry(
+                    item.file_name().to_string_lossy().into_owned(),
+                    &meta,
+                ));
+            }
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Entry> {
+        let meta = tokio::fs::metadata(self.resolve(path)?).await?;
+        let path = normalize(path);
+        Ok(local_entry(split(&path).1.to_string(), &meta))
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let mut file = tokio::fs::File::open(self.resolve(path)?).await?;
+        if file.metadata().await?.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, "Not a file"));
+        }
+        let length = match range {
+            Some((offset, length)) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                length
+            }
+            None => u64::MAX,
+        };
+        Ok(reader_stream(file.take(length)))
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        let target = self.resolve(path)?;
+        let (parent, name) = match (target.parent(), target.file_name()) {
+            (Some(parent), Some(name)) if !normalize(path).is_empty() => (parent, name),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid path")),
+        };
+        tokio::fs::create_dir_all(parent).await?;
+
+        // Hidden staging name, so the old version stays until the new one is complete
+        let staging = parent.join(format!(
+            ".{}.upload-{}",
+            name.to_string_lossy(),
+            uuid::Uuid::new_v4()
+        ));
+        let result = match write_file(&staging, body).await {
+            Ok(size) => tokio::fs::rename(&staging, &target).await.map(|_| size),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            let _ = tokio::fs::remove_file(&staging).await;
+        }
+        result
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (src, dest) = (self.resolve(from)?, self.resolve(to)?);
+        if dest.starts_with(&src) {
+            return Err(into_itself());
+        }
+        if tokio::fs::symlink_metadata(&dest).await.is_ok() {
+            return Err(already_exists());
+        }
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&src, &dest).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let (src, dest) = (self.resolve(from)?, self.resolve(to)?);
+        if dest.starts_with(&src) {
+            return Err(into_itself());
+        }
+        if tokio::fs::symlink_metadata(&dest).await.is_ok() {
+            return Err(already_exists());
+        }
+        if let Some(parent) = dest.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        web::block(move || copy::copy_tree(&src, &dest, &mut |_| {}))
+            .await
+            .map_err(io::Error::other)??;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        if normalize(path).is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot delete the root",
+            ));
+        }
+        remove_path(&self.resolve(path)?).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        tokio::fs::create_dir_all(self.resolve(path)?).await
+    }
+}
+
+// --- Path Maps ---
+// The in-memory and content-addressed backends keep their tree as a map from path to
+// node, with folders as nodes of their own; these helpers work on either.
+
+trait PathNode: Clone {
+    fn folder(modified: u64) -> Self;
+    fn is_dir(&self) -> bool;
+    fn entry(&self, path: &str) -> Entry;
+}
+
+fn map_is_dir<N: PathNode>(nodes: &BTreeMap<String, N>, path: &str) -> bool {
+    path.is_empty() || nodes.get(path).is_some_and(N::is_dir)
+}
+
+/// Create the folders above `path`, failing if one of them is a file.
+fn map_parents<N: PathNode>(nodes: &mut BTreeMap<String, N>, path: &str) -> io::Result<()> {
+    let mut parent = split(path).0;
+    let mut missing = Vec::new();
+    while !parent.is_empty() {
+        match nodes.get(parent) {
+            Some(node) if !node.is_dir() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    "A parent folder is a file",
+                ))
+            }
+            Some(_) => break,
+            None => missing.push(parent.to_string()),
+        }
+        parent = split(parent).0;
+    }
+    let modified = now_secs();
+    for folder in missing {
+        nodes.insert(folder, N::folder(modified));
+    }
+    Ok(())
+}
+
+/// Paths of the item at `path` and everything below it.
+fn map_subtree<N>(nodes: &BTreeMap<String, N>, path: &str) -> Vec<String> {
+    nodes
+        .keys()
+        .filter(|key| is_within(key, path))
+        .cloned()
+        .collect()
+}
+
+fn map_list<N: PathNode>(nodes: &BTreeMap<String, N>, path: &str) -> io::Result<Vec<Entry>> {
+    let path = normalize(path);
+    if !map_is_dir(nodes, &path) {
+        return Err(not_found());
+    }
+    Ok(nodes
+        .iter()
+        .filter(|(key, _)| !key.is_empty() && split(key).0 == path)
+        .map(|(key, node)| node.entry(key))
+        .collect())
+}
+
+fn map_stat<N: PathNode>(nodes: &BTreeMap<String, N>, path: &str) -> io::Result<Entry> {
+    let path = normalize(path);
+    if path.is_empty() {
+        return Ok(Entry {
+            name: String::new(),
+            is_dir: true,
+            size: 0,
+            modified: 0,
+        });
+    }
+    nodes
+        .get(&path)
+        .map(|node| node.entry(&path))
+        .ok_or_else(not_found)
+}
+
+/// Shared part of rename and copy: checks both ends and maps every path below `from`
+/// to its new place. Returns the new paths.
+fn map_relocate<N: PathNode>(
+    nodes: &mut BTreeMap<String, N>,
+    from: &str,
+    to: &str,
+    keep_source: bool,
+) -> io::Result<Vec<String>> {
+    let (from, to) = (normalize(from), normalize(to));
+    if from.is_empty() || is_within(&to, &from) {
+        return Err(into_itself());
+    }
+    if !nodes.contains_key(&from) {
+        return Err(not_found());
+    }
+    if nodes.contains_key(&to) {
+        return Err(already_exists());
+    }
+    map_parents(nodes, &to)?;
+    let mut moved = Vec::new();
+    for key in map_subtree(nodes, &from) {
+        let new_key = format!("{}{}", to, &key[from.len()..]);
+        let node = if keep_source {
+            nodes[&key].clone()
+        } else {
+            nodes.remove(&key).expect("key was just listed")
+        };
+        nodes.insert(new_key.clone(), node);
+        moved.push(new_key);
+    }
+    Ok(moved)
+}
+
+/// Take out the item at `path` and everything below it.
+fn map_remove<N>(nodes: &mut BTreeMap<String, N>, path: &str) -> io::Result<Vec<N>> {
+    let path = normalize(path);
+    if path.is_empty() || !nodes.contains_key(&path) {
+        return Err(not_found());
+    }
+    Ok(map_subtree(nodes, &path)
+        .into_iter()
+        .filter_map(|key| nodes.remove(&key))
+        .collect())
+}
+
+/// Add a folder unless it exists; false if there was one already.
+fn map_mkdir<N: PathNode>(nodes: &mut BTreeMap<String, N>, path: &str) -> io::Result<bool> {
+    let path = normalize(path);
+    if map_is_dir(nodes, &path) {
+        return Ok(false);
+    }
+    if nodes.contains_key(&path) {
+        return Err(already_exists());
+    }
+    map_parents(nodes, &path)?;
+    nodes.insert(path, N::folder(now_secs()));
+    Ok(true)
+}
+
+// --- In-Memory ---
+
+/// Keeps everything in a map keyed by path, for test runs that shouldn't touch disk.
+/// Contents are lost when the server stops.
+#[derive(Default)]
+pub struct MemoryStorage {
+    nodes: Mutex<BTreeMap<String, Node>>,
+}
+
+#[derive(Clone)]
+struct Node {
+    /// None for folders
+    data: Option<Bytes>,
+    modified: u64,
+}
+
+impl PathNode for Node {
+    fn folder(modified: u64) -> Self {
+        Node {
+            data: None,
+            modified,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.data.is_none()
+    }
+
+    fn entry(&self, path: &str) -> Entry {
+        Entry {
+            name: split(path).1.to_string(),
+            is_dir: self.data.is_none(),
+            size: self.data.as_ref().map_or(0, |d| d.len() as u64),
+            modified: self.modified,
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        map_list(&self.nodes.lock().unwrap(), path)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Entry> {
+        map_stat(&self.nodes.lock().unwrap(), path)
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let nodes = self.nodes.lock().unwrap();
+        let node = nodes.get(&normalize(path)).ok_or_else(not_found)?;
+        let data = node
+            .data
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::IsADirectory, "Not a file"))?;
+        let data = match range {
+            Some((offset, length)) => {
+                let start = (offset as usize).min(data.len());
+                let end = start.saturating_add(length as usize).min(data.len());
+                data.slice(start..end)
+            }
+            None => data,
+        };
+        Ok(once(data))
+    }
+
+    async fn write(&self, path: &str, mut body: ByteStream) -> io::Result<u64> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"));
+        }
+        let mut data = Vec::new();
+        while let Some(chunk) = body.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+
+        let mut nodes = self.nodes.lock().unwrap();
+        if map_is_dir(&nodes, &path) {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, "Not a file"));
+        }
+        map_parents(&mut nodes, &path)?;
+        let size = data.len() as u64;
+        nodes.insert(
+            path,
+            Node {
+                data: Some(Bytes::from(data)),
+                modified: now_secs(),
+            },
+        );
+        Ok(size)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        map_relocate(&mut self.nodes.lock().unwrap(), from, to, false).map(|_| ())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        map_relocate(&mut self.nodes.lock().unwrap(), from, to, true).map(|_| ())
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        map_remove(&mut self.nodes.lock().unwrap(), path).map(|_| ())
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        map_mkdir(&mut self.nodes.lock().unwrap(), path).map(|_| ())
+    }
+}
+
+// --- Content-Addressed ---
+
+const BLOB_DIR: &str = ".boxy/blobs";
+const BLOB_TREE: &str = ".boxy/tree.json";
+
+/// Stores each distinct content once, as a blob named by its BLAKE3 hash under
+/// `.boxy/blobs`, with the folder tree kept as metadata in `.boxy/tree.json`. Files are
+/// references to blobs, so copies and kept versions cost no space; a blob is deleted
+/// once nothing refers to it. Blobs are written before the tree that refers to them and
+/// deleted after it, so a crash can only leave unreferenced blobs, which opening the
+/// store collects. Changes are made to a copy of the tree, which replaces it once saved,
+/// so a failed save leaves the tree as it is on disk.
+pub struct BlobStorage {
+    blobs: PathBuf,
+    tree_file: PathBuf,
+    /// Held across the tree's save, so saves land in the order of the changes
+    tree: tokio::sync::Mutex<BlobTree>,
+}
+
+#[derive(Default)]
+struct BlobTree {
+    nodes: BTreeMap<String, BlobNode>,
+    /// References to each blob from `nodes`
+    refs: HashMap<String, u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct BlobNode {
+    /// Hash of the contents, none for folders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<String>,
+    #[serde(default)]
+    size: u64,
+    modified: u64,
+}
+
+impl PathNode for BlobNode {
+    fn folder(modified: u64) -> Self {
+        BlobNode {
+            blob: None,
+            size: 0,
+            modified,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        self.blob.is_none()
+    }
+
+    fn entry(&self, path: &str) -> Entry {
+        Entry {
+            name: split(path).1.to_string(),
+            is_dir: self.blob.is_none(),
+            size: self.size,
+            modified: self.modified,
+        }
+    }
+}
+
+impl BlobStorage {
+    /// Load the tree for `upload_dir`, count the references to each blob and delete
+    /// the blobs (and staging files) nothing refers to. Blobs without a saved tree are
+    /// refused rather than collected, as the tree has most likely been lost.
+    pub fn open(upload_dir: &Path) -> io::Result<Self> {
+        let blobs = upload_dir.join(BLOB_DIR);
+        let tree_file = upload_dir.join(BLOB_TREE);
+        std::fs::create_dir_all(blobs.join("staging"))?;
+        // Blobs sit in folders named by their first two hash digits
+        let mut blob_dirs = Vec::new();
+        for dir in std::fs::read_dir(&blobs)?.flatten() {
+            if dir.file_type()?.is_dir() {
+                blob_dirs.push(dir);
+            }
+        }
+        let nodes: BTreeMap<String, BlobNode> = match std::fs::read(&tree_file) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                for dir in blob_dirs.iter().filter(|dir| dir.file_name() != "staging") {
+                    if std::fs::read_dir(dir.path())?.next().is_some() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "{} holds blobs but {} is missing; restore it, or remove \
+                                 the blobs to start empty",
+                                blobs.display(),
+                                tree_file.display()
+                            ),
+                        ));
+                    }
+                }
+                BTreeMap::new()
+            }
+            Err(e) => return Err(e),
+        };
+        let mut tree = BlobTree {
+            nodes,
+            refs: HashMap::new(),
+        };
+        for blob in tree.nodes.values().filter_map(|n| n.blob.clone()) {
+            *tree.refs.entry(blob).or_default() += 1;
+        }
+
+        let mut collected = 0;
+        for dir in blob_dirs {
+            for blob in std::fs::read_dir(dir.path())?.flatten() {
+                let name = blob.file_name().to_string_lossy().into_owned();
+                if dir.file_name() == "staging" || !tree.refs.contains_key(&name) {
+                    std::fs::remove_file(blob.path())?;
+                    collected += 1;
+                }
+            }
+        }
+        println!(
+            "Blob store: {} files in {} blobs, {} unreferenced blobs removed",
+            tree.nodes.values().filter(|n| n.blob.is_some()).count(),
+            tree.refs.len(),
+            collected
+        );
+
+        Ok(Self {
+            blobs,
+            tree_file,
+            tree: tokio::sync::Mutex::new(tree),
+        })
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.blobs.join(&hash[..2]).join(hash)
+    }
+
+    /// Replace the saved tree with `nodes`, synced to disk before it takes the old
+    /// one's place.
+    async fn save(&self, nodes: &BTreeMap<String, BlobNode>) -> io::Result<()> {
+        let data = serde_json::to_vec(nodes).map_err(io::Error::other)?;
+        let staging = self.tree_file.with_extension("json.tmp");
+        let mut file = tokio::fs::File::create(&staging).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&staging, &self.tree_file).await
+    }
+
+    /// Drop a reference to each blob of `nodes`, deleting blobs left without any. Only
+    /// after the tree without them is saved.
+    async fn release(&self, tree: &mut BlobTree, nodes: impl IntoIterator<Item = BlobNode>) {
+        for blob in nodes.into_iter().filter_map(|n| n.blob) {
+            let Some(count) = tree.refs.get_mut(&blob) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                tree.refs.remove(&blob);
+                let _ = tokio::fs::remove_file(self.blob_path(&blob)).await;
+            }
+        }
+    }
+
+    /// Stream `body` into a staging file, hashing it on the way.
+    async fn stage(&self, staging: &Path, mut body: ByteStream) -> io::Result<(String, u64)> {
+        let mut file = tokio::fs::File::create(staging).await?;
+        let mut hasher = blake3::Hasher::new();
+        let mut size = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            size += chunk.len() as u64;
+        }
+        file.sync_all().await?;
+        Ok((hasher.finalize().to_hex().to_string(), size))
+    }
+}
+
+#[async_trait]
+impl Storage for BlobStorage {
+    fn copies_are_free(&self) -> bool {
+        true
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        map_list(&self.tree.lock().await.nodes, path)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Entry> {
+        map_stat(&self.tree.lock().await.nodes, path)
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        // Opened under the lock, so a delete can't remove the blob in between
+        let mut file = {
+            let tree = self.tree.lock().await;
+            let node = tree.nodes.get(&normalize(path)).ok_or_else(not_found)?;
+            let blob = node
+                .blob
+                .as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::IsADirectory, "Not a file"))?;
+            tokio::fs::File::open(self.blob_path(blob)).await?
+        };
+        let length = match range {
+            Some((offset, length)) => {
+                file.seek(SeekFrom::Start(offset)).await?;
+                length
+            }
+            None => u64::MAX,
+        };
+        Ok(reader_stream(file.take(length)))
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"));
+        }
+        let staging = self
+            .blobs
+            .join("staging")
+            .join(uuid::Uuid::new_v4().to_string());
+        let (hash, size) = match self.stage(&staging, body).await {
+            Ok(staged) => staged,
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&staging).await;
+                return Err(e);
+            }
+        };
+
+        let mut tree = self.tree.lock().await;
+        let mut nodes = tree.nodes.clone();
+        let checked = if map_is_dir(&nodes, &path) {
+            Err(io::Error::new(io::ErrorKind::IsADirectory, "Not a file"))
+        } else {
+            map_parents(&mut nodes, &path)
+        };
+        // Content that is already stored only gains a reference
+        let filed = match checked {
+            Ok(()) if tree.refs.contains_key(&hash) => Ok(false),
+            Ok(()) => {
+                let blob = self.blob_path(&hash);
+                match tokio::fs::create_dir_all(blob.parent().unwrap_or(&self.blobs)).await {
+                    Ok(()) => tokio::fs::rename(&staging, &blob).await.map(|_| true),
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if !matches!(filed, Ok(true)) {
+            let _ = tokio::fs::remove_file(&staging).await;
+        }
+        let filed = filed?;
+
+        let node = BlobNode {
+            blob: Some(hash.clone()),
+            size,
+            modified: now_secs(),
+        };
+        let old = nodes.insert(path, node);
+        if let Err(e) = self.save(&nodes).await {
+            if filed {
+                let _ = tokio::fs::remove_file(self.blob_path(&hash)).await;
+            }
+            return Err(e);
+        }
+        tree.nodes = nodes;
+        *tree.refs.entry(hash).or_default() += 1;
+        self.release(&mut tree, old).await;
+        Ok(size)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut tree = self.tree.lock().await;
+        let mut nodes = tree.nodes.clone();
+        map_relocate(&mut nodes, from, to, false)?;
+        self.save(&nodes).await?;
+        tree.nodes = nodes;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut tree = self.tree.lock().await;
+        let tree = &mut *tree;
+        let mut nodes = tree.nodes.clone();
+        let copied = map_relocate(&mut nodes, from, to, true)?;
+        self.save(&nodes).await?;
+        for path in copied {
+            if let Some(blob) = &nodes[&path].blob {
+                *tree.refs.entry(blob.clone()).or_default() += 1;
+            }
+        }
+        tree.nodes = nodes;
+        Ok(())
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        let mut tree = self.tree.lock().await;
+        let mut nodes = tree.nodes.clone();
+        let removed = map_remove(&mut nodes, path)?;
+        self.save(&nodes).await?;
+        tree.nodes = nodes;
+        self.release(&mut tree, removed).await;
+        Ok(())
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        let mut tree = self.tree.lock().await;
+        let mut nodes = tree.nodes.clone();
+        if map_mkdir(&mut nodes, path)? {
+            self.save(&nodes).await?;
+            tree.nodes = nodes;
+        }
+        Ok(())
+    }
+}
+
+// --- S3 ---
+
+/// Part size for multipart uploads; S3 wants at least 5 MiB for all but the last part.
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Stores files as objects in one bucket, addressed path-style, with folders as
+/// `folder/` marker objects. Works with AWS, MinIO and Boxy's own `/s3` API.
+/// Renames are a copy followed by a delete.
+pub struct S3Storage {
+    client: reqwest::Client,
+    endpoint: String,
+    host: String,
+    bucket: String,
+    region: String,
+    credentials: Credentials,
+}
+
+struct Object {
+    key: String,
+    size: u64,
+    modified: u64,
+}
+
+fn s3_response_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Malformed S3 response")
+}
+
+/// Unix seconds for a listing's `LastModified` (`2026-01-31T12:00:00.000Z`).
+fn parse_iso8601(value: &str) -> Option<u64> {
+    let num = |range: std::ops::Range<usize>| value.get(range)?.parse::<i64>().ok();
+    let days = days_from_civil(num(0..4)?, num(5..7)?, num(8..10)?);
+    let secs = days * 86400 + num(11..13)? * 3600 + num(14..16)? * 60 + num(17..19)?;
+    u64::try_from(secs).ok()
+}
+
+/// `x-amz-date` timestamp (`20260131T120000Z`) for unix seconds.
+fn amz_date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let rem = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+impl S3Storage {
+    pub fn new(config: S3Config) -> io::Result<Self> {
+        let endpoint = config.endpoint.trim_end_matches('/').to_string();
+        let url = reqwest::Url::parse(&endpoint)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            (Some(host), None) => host.to_string(),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "S3 endpoint has no host",
+                ))
+            }
+        };
+        Ok(Self {
+            client: reqwest::Client::new(),
+            endpoint,
+            host,
+            bucket: config.bucket,
+            region: config.region,
+            credentials: config.credentials,
+        })
+    }
+
+    /// A SigV4-signed request for `key`. Bodies are sent as `UNSIGNED-PAYLOAD`, so they
+    /// can stream; `headers` are added to the signature.
+    fn request(
+        &self,
+        method: reqwest::Method,
+        key: &str,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+    ) -> io::Result<reqwest::RequestBuilder> {
+        let mut params: Vec<(String, String)> = query
+            .iter()
+            .map(|(name, value)| {
+                (
+                    utf8_percent_encode(name, s3::UNRESERVED).to_string(),
+                    utf8_percent_encode(value, s3::UNRESERVED).to_string(),
+                )
+            })
+            .collect();
+        params.sort();
+        let query = params
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let mut url = format!(
+            "{}/{}/{}",
+            self.endpoint,
+            self.bucket,
+            utf8_percent_encode(key, s3::KEY_ENCODE)
+        );
+        if !query.is_empty() {
+            url = format!("{}?{}", url, query);
+        }
+        let url = reqwest::Url::parse(&url).map_err(io::Error::other)?;
+
+        let date_time = amz_date(now_secs());
+        let mut signed: Vec<(String, String)> = vec![
+            ("host".to_string(), self.host.clone()),
+            (
+                "x-amz-content-sha256".to_string(),
+                s3::UNSIGNED_PAYLOAD.to_string(),
+            ),
+            ("x-amz-date".to_string(), date_time.clone()),
+        ];
+        signed.extend(
+            headers
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.trim().to_string())),
+        );
+        signed.sort();
+        let signed_headers = signed
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical = format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            method.as_str(),
+            url.path(),
+            query,
+            signed
+                .iter()
+                .map(|(name, value)| format!("{}:{}\n", name, value))
+                .collect::<String>(),
+            signed_headers,
+            s3::UNSIGNED_PAYLOAD
+        );
+
+        let date = &date_time[..8];
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            date_time,
+            scope,
+            s3::sha256_hex(canonical.as_bytes())
+        );
+        let key = [date, self.region.as_str(), "s3", "aws4_request"]
+            .iter()
+            .fold(
+                format!("AWS4{}", self.credentials.secret_key).into_bytes(),
+                |key, part| s3::hmac(&key, part.as_bytes()),
+            );
+        let signature = hex::encode(s3::hmac(&key, string_to_sign.as_bytes()));
+
+        let mut builder = self.client.request(method, url).header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{},SignedHeaders={},Signature={}",
+                self.credentials.access_key, scope, signed_headers, signature
+            ),
+        );
+        for (name, value) in signed.into_iter().filter(|(name, _)| name != "host") {
+            builder = builder.header(name, value);
+        }
+        Ok(builder)
+    }
+
+    /// Send a request, turning S3 errors into `io::Error`s with a matching kind.
+    async fn send(&self, request: reqwest::RequestBuilder) -> io::Result<reqwest::Response> {
+        let response = request.send().await.map_err(io::Error::other)?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.bytes().await.unwrap_or_default();
+        let code = s3::xml_records(&body, "Error")
+            .ok()
+            .and_then(|records| records.into_iter().next())
+            .and_then(|mut fields| fields.remove("Code"))
+            .unwrap_or_else(|| status.to_string());
+        let kind = match status.as_u16() {
+            404 => io::ErrorKind::NotFound,
+            403 => io::ErrorKind::PermissionDenied,
+            _ => io::ErrorKind::Other,
+        };
+        Err(io::Error::new(kind, format!("S3 request failed: {}", code)))
+    }
+
+    /// One ListObjectsV2 page: objects, common prefixes and the continuation token.
+    async fn list_page(
+        &self,
+        prefix: &str,
+        shallow: bool,
+        token: Option<&str>,
+        max_keys: Option<usize>,
+    ) -> io::Result<(Vec<Object>, Vec<String>, Option<String>)> {
+        let max_keys = max_keys.map(|n| n.to_string());
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if shallow {
+            query.push(("delimiter", "/"));
+        }
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        if let Some(max_keys) = &max_keys {
+            query.push(("max-keys", max_keys));
+        }
+        let response = self
+            .send(self.request(reqwest::Method::GET, "", &query, &[])?)
+            .await?;
+        let body = response.bytes().await.map_err(io::Error::other)?;
+
+        let objects = s3::xml_records(&body, "Contents")
+            .map_err(|_| s3_response_error())?
+            .into_iter()
+            .filter_map(|fields| {
+                Some(Object {
+                    key: fields.get("Key")?.clone(),
+                    size: fields.get("Size")?.parse().ok()?,
+                    modified: fields
+                        .get("LastModified")
+                        .and_then(|v| parse_iso8601(v))
+                        .unwrap_or(0),
+                })
+            })
+            .collect();
+        let prefixes = s3::xml_records(&body, "CommonPrefixes")
+            .map_err(|_| s3_response_error())?
+            .into_iter()
+            .filter_map(|mut fields| fields.remove("Prefix"))
+            .collect();
+        let next = s3::xml_records(&body, "ListBucketResult")
+            .map_err(|_| s3_response_error())?
+            .into_iter()
+            .next()
+            .filter(|fields| fields.get("IsTruncated").is_some_and(|v| v == "true"))
+            .and_then(|mut fields| fields.remove("NextContinuationToken"));
+        Ok((objects, prefixes, next))
+    }
+
+    /// Every object below `prefix`, across all pages.
+    async fn list_all(&self, prefix: &str) -> io::Result<Vec<Object>> {
+        let mut objects = Vec::new();
+        let mut token = None;
+        loop {
+            let (page, _, next) = self
+                .list_page(prefix, false, token.as_deref(), None)
+                .await?;
+            objects.extend(page);
+            match next {
+                Some(next) => token = Some(next),
+                None => return Ok(objects),
+            }
+        }
+    }
+
+    async fn head(&self, key: &str) -> io::Result<Entry> {
+        let response = self
+            .send(self.request(reqwest::Method::HEAD, key, &[], &[])?)
+            .await?;
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        Ok(Entry {
+            name: split(key).1.to_string(),
+            is_dir: false,
+            size: header("content-length")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0),
+            modified: header("last-modified")
+                .and_then(|v| v.parse::<HttpDate>().ok())
+                .and_then(|d| SystemTime::from(d).duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        })
+    }
+
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        self.send(
+            self.request(reqwest::Method::PUT, key, &[], &[])?
+                .body(data),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn copy_object(&self, from: &str, to: &str) -> io::Result<()> {
+        let source = format!(
+            "/{}/{}",
+            self.bucket,
+            utf8_percent_encode(from, s3::KEY_ENCODE)
+        );
+        self.send(self.request(
+            reqwest::Method::PUT,
+            to,
+            &[],
+            &[("x-amz-copy-source", source)],
+        )?)
+        .await?;
+        Ok(())
+    }
+
+    async fn delete_object(&self, key: &str) -> io::Result<()> {
+        self.send(self.request(reqwest::Method::DELETE, key, &[], &[])?)
+            .await?;
+        Ok(())
+    }
+
+    /// Store a large body with a multipart upload, `first` being the first full part.
+    async fn write_multipart(
+        &self,
+        key: &str,
+        first: Vec<u8>,
+        body: &mut ByteStream,
+    ) -> io::Result<u64> {
+        let response = self
+            .send(self.request(reqwest::Method::POST, key, &[("uploads", "")], &[])?)
+            .await?;
+        let body_xml = response.bytes().await.map_err(io::Error::other)?;
+        let upload_id = s3::xml_records(&body_xml, "InitiateMultipartUploadResult")
+            .map_err(|_| s3_response_error())?
+            .into_iter()
+            .next()
+            .and_then(|mut fields| fields.remove("UploadId"))
+            .ok_or_else(s3_response_error)?;
+
+        let mut etags = Vec::new();
+        let result = async {
+            let mut size = first.len() as u64;
+            let mut part = Some(first);
+            let mut buffer = Vec::new();
+            let mut finished = false;
+            loop {
+                if let Some(data) = part.take() {
+                    let number = (etags.len() + 1).to_string();
+                    let response = self
+                        .send(
+                            self.request(
+                                reqwest::Method::PUT,
+                                key,
+                                &[("partNumber", &number), ("uploadId", &upload_id)],
+                                &[],
+                            )?
+                            .body(data),
+                        )
+                        .await?;
+                    let etag = response
+                        .headers()
+                        .get("etag")
+                        .and_then(|v| v.to_str().ok())
+                        .ok_or_else(s3_response_error)?;
+                    etags.push(etag.to_string());
+                }
+                if finished {
+                    break;
+                }
+                match body.next().await.transpose()? {
+                    Some(chunk) => {
+                        size += chunk.len() as u64;
+                        buffer.extend_from_slice(&chunk);
+                        if buffer.len() >= PART_SIZE {
+                            part = Some(buffer.drain(..PART_SIZE).collect());
+                        }
+                    }
+                    None => {
+                        finished = true;
+                        if !buffer.is_empty() {
+                            part = Some(std::mem::take(&mut buffer));
+                        }
+                    }
+                }
+            }
+
+            let parts: String = etags
+                .iter()
+                .enumerate()
+                .map(|(i, etag)| {
+                    format!(
+                        "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                        i + 1,
+                        s3::escape_xml(etag)
+                    )
+                })
+                .collect();
+            let response = self
+                .send(
+                    self.request(reqwest::Method::POST, key, &[("uploadId", &upload_id)], &[])?
+                        .body(format!(
+                            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+                            parts
+                        )),
+                )
+                .await?;
+            // S3 may report a failed completion in a 200 response
+            let body = response.bytes().await.map_err(io::Error::other)?;
+            if !s3::xml_records(&body, "Error")
+                .unwrap_or_default()
+                .is_empty()
+            {
+                return Err(io::Error::other("S3 multipart upload failed"));
+            }
+            Ok(size)
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = self
+                .send(self.request(
+                    reqwest::Method::DELETE,
+                    key,
+                    &[("uploadId", &upload_id)],
+                    &[],
+                )?)
+                .await;
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl Storage for S3Storage {
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        let path = normalize(path);
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+
+        let mut entries = Vec::new();
+        let mut token = None;
+        loop {
+            let (objects, prefixes, next) = self
+                .list_page(&prefix, true, token.as_deref(), None)
+                .await?;
+            entries.extend(
+                objects
+                    .into_iter()
+                    .filter(|object| object.key != prefix)
+                    .map(|object| Entry {
+                        name: object.key[prefix.len()..].to_string(),
+                        is_dir: false,
+                        size: object.size,
+                        modified: object.modified,
+                    }),
+            );
+            entries.extend(prefixes.into_iter().map(|folder| Entry {
+                name: folder[prefix.len()..].trim_end_matches('/').to_string(),
+                is_dir: true,
+                size: 0,
+                modified: 0,
+            }));
+            match next {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+
+        if entries.is_empty() && !path.is_empty() && !self.stat(&path).await?.is_dir {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, "Not a folder"));
+        }
+        Ok(entries)
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Entry> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Ok(Entry {
+                name: String::new(),
+                is_dir: true,
+                size: 0,
+                modified: 0,
+            });
+        }
+        match self.head(&path).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => return result,
+        }
+
+        // Folders exist through their marker or the objects below them
+        let (objects, prefixes, _) = self
+            .list_page(&format!("{}/", path), true, None, Some(1))
+            .await?;
+        if objects.is_empty() && prefixes.is_empty() {
+            return Err(not_found());
+        }
+        Ok(Entry {
+            name: split(&path).1.to_string(),
+            is_dir: true,
+            size: 0,
+            modified: objects
+                .iter()
+                .find(|object| object.key.ends_with('/'))
+                .map_or(0, |object| object.modified),
+        })
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        let headers = match range {
+            Some((_, 0)) => return Ok(once(Bytes::new())),
+            Some((offset, length)) => vec![(
+                "range",
+                format!("bytes={}-{}", offset, offset.saturating_add(length - 1)),
+            )],
+            None => Vec::new(),
+        };
+        let response = self
+            .send(self.request(reqwest::Method::GET, &normalize(path), &[], &headers)?)
+            .await?;
+        Ok(Box::pin(response.bytes_stream().map_err(io::Error::other)))
+    }
+
+    async fn write(&self, path: &str, mut body: ByteStream) -> io::Result<u64> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"));
+        }
+
+        // Small bodies go up in one PUT, larger ones as a multipart upload
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.next().await {
+            buffer.extend_from_slice(&chunk?);
+            if buffer.len() >= PART_SIZE {
+                return self.write_multipart(&path, buffer, &mut body).await;
+            }
+        }
+        let size = buffer.len() as u64;
+        self.put(&path, buffer).await?;
+        Ok(size)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        self.copy(from, to).await?;
+        self.delete(from).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        if from.is_empty() || is_within(&to, &from) {
+            return Err(into_itself());
+        }
+        match self.stat(&to).await {
+            Ok(_) => return Err(already_exists()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if !self.stat(&from).await?.is_dir {
+            return self.copy_object(&from, &to).await;
+        }
+
+        let prefix = format!("{}/", from);
+        for object in self.list_all(&prefix).await? {
+            let dest = format!("{}/{}", to, &object.key[prefix.len()..]);
+            if object.key.ends_with('/') {
+                self.put(&dest, Vec::new()).await?;
+            } else {
+                self.copy_object(&object.key, &dest).await?;
+            }
+        }
+        self.mkdir(&to).await
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot delete the root",
+            ));
+        }
+        if !self.stat(&path).await?.is_dir {
+            return self.delete_object(&path).await;
+        }
+
+        // Servers backed by a filesystem list emptied folders as markers afterwards,
+        // so keep going until nothing is left
+        let prefix = format!("{}/", path);
+        let mut previous = Vec::new();
+        loop {
+            let mut keys: Vec<String> = self
+                .list_all(&prefix)
+                .await?
+                .into_iter()
+                .map(|object| object.key)
+                .collect();
+            if keys.is_empty() {
+                break;
+            }
+            // Deepest first, so folder markers go after their contents
+            keys.sort_by_key(|key| std::cmp::Reverse(key.len()));
+            if keys == previous {
+                return Err(io::Error::other("S3 folder could not be deleted"));
+            }
+            for key in &keys {
+                self.delete_object(key).await?;
+            }
+            previous = keys;
+        }
+        match self.delete_object(&prefix).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        let path = normalize(path);
+        if path.is_empty() {
+            return Ok(());
+        }
+        self.put(&format!("{}/", path), Vec::new()).await
+    }
+}
+
+// --- Prefixed ---
+
+/// Another backend seen from below `prefix`, so several roots can share one bucket.
+pub struct Prefixed<S> {
+    inner: S,
+    prefix: String,
+}
+
+impl<S> Prefixed<S> {
+    fn key(&self, path: &str) -> String {
+        join(&self.prefix, path)
+    }
+}
+
+#[async_trait]
+impl<S: Storage> Storage for Prefixed<S> {
+    fn copies_are_free(&self) -> bool {
+        self.inner.copies_are_free()
+    }
+
+    async fn list(&self, path: &str) -> io::Result<Vec<Entry>> {
+        match self.inner.list(&self.key(path)).await {
+            // A root that has nothing in it yet is empty rather than missing
+            Err(e) if e.kind() == io::ErrorKind::NotFound && normalize(path).is_empty() => {
+                Ok(Vec::new())
+            }
+            result => result,
+        }
+    }
+
+    async fn stat(&self, path: &str) -> io::Result<Entry> {
+        if normalize(path).is_empty() {
+            return Ok(Entry {
+                name: String::new(),
+                is_dir: true,
+                size: 0,
+                modified: 0,
+            });
+        }
+        self.inner.stat(&self.key(path)).await
+    }
+
+    async fn read(&self, path: &str, range: Option<(u64, u64)>) -> io::Result<ByteStream> {
+        self.inner.read(&self.key(path), range).await
+    }
+
+    async fn write(&self, path: &str, body: ByteStream) -> io::Result<u64> {
+        if normalize(path).is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid path"));
+        }
+        self.inner.write(&self.key(path), body).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if normalize(from).is_empty() {
+            return Err(into_itself());
+        }
+        self.inner.rename(&self.key(from), &self.key(to)).await
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        if normalize(from).is_empty() {
+            return Err(into_itself());
+        }
+        self.inner.copy(&self.key(from), &self.key(to)).await
+    }
+
+    async fn delete(&self, path: &str) -> io::Result<()> {
+        if normalize(path).is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot delete the root",
+            ));
+        }
+        self.inner.delete(&self.key(path)).await
+    }
+
+    async fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.inner.mkdir(&self.key(path)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        let mut names: Vec<&str> = entries.iter().map(|e| e.name.as_str()).collect();
+        names.sort_unstable();
+        names
+    }
+
+    fn kind<T>(result: io::Result<T>) -> io::ErrorKind {
+        result.err().expect("the call should fail").kind()
+    }
+
+    async fn text(storage: &dyn Storage, path: &str) -> String {
+        String::from_utf8(read_all(storage, path).await.unwrap()).unwrap()
+    }
+
+    /// What the handlers rely on from every path-map backend.
+    async fn check_semantics(storage: &dyn Storage) {
+        // Writes create missing parents, and overwrite files but not folders
+        assert_eq!(storage.write("a/b/c.txt", once("hello")).await.unwrap(), 5);
+        assert!(storage.stat("a/b").await.unwrap().is_dir);
+        storage.write("a/b/c.txt", once("hello!")).await.unwrap();
+        assert_eq!(storage.stat("/a//b/c.txt").await.unwrap().size, 6);
+        let over_folder = storage.write("a/b", once("x")).await;
+        assert_eq!(kind(over_folder), io::ErrorKind::IsADirectory);
+        let under_file = storage.write("a/b/c.txt/d", once("x")).await;
+        assert_eq!(kind(under_file), io::ErrorKind::NotADirectory);
+
+        // Ranges are clamped to the file
+        let ranged = |offset, length| async move {
+            let mut stream = storage
+                .read("a/b/c.txt", Some((offset, length)))
+                .await
+                .unwrap();
+            let mut data = Vec::new();
+            while let Some(chunk) = stream.next().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            data
+        };
+        assert_eq!(ranged(1, 3).await, b"ell");
+        assert_eq!(ranged(4, 100).await, b"o!");
+        assert!(ranged(100, 5).await.is_empty());
+        assert_eq!(
+            kind(storage.read("a", None).await),
+            io::ErrorKind::IsADirectory
+        );
+
+        // Listings hold direct children only; the root always exists
+        storage.write("a/top.txt", once("t")).await.unwrap();
+        assert_eq!(names(&storage.list("a").await.unwrap()), ["b", "top.txt"]);
+        assert_eq!(names(&storage.list("").await.unwrap()), ["a"]);
+        assert!(storage.stat("").await.unwrap().is_dir);
+        assert_eq!(
+            kind(storage.list("a/top.txt").await),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(kind(storage.stat("nope").await), io::ErrorKind::NotFound);
+
+        // Folders are made once; a file in the way is an error
+        storage.mkdir("x/y").await.unwrap();
+        storage.mkdir("x/y").await.unwrap();
+        assert_eq!(
+            kind(storage.mkdir("a/top.txt").await),
+            io::ErrorKind::AlreadyExists
+        );
+
+        // Renames move whole subtrees, never onto an existing item or into themselves
+        storage.rename("a/b", "x/y/b").await.unwrap();
+        assert_eq!(text(storage, "x/y/b/c.txt").await, "hello!");
+        assert_eq!(kind(storage.stat("a/b").await), io::ErrorKind::NotFound);
+        assert_eq!(
+            kind(storage.rename("x/y/b", "a/top.txt").await),
+            io::ErrorKind::AlreadyExists
+        );
+        assert_eq!(
+            kind(storage.rename("x", "x/y/z").await),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            kind(storage.rename("gone", "new").await),
+            io::ErrorKind::NotFound
+        );
+
+        // Copies keep the source and are independent of it afterwards
+        storage.copy("x/y", "copy/y").await.unwrap();
+        storage.write("x/y/b/c.txt", once("changed")).await.unwrap();
+        assert_eq!(text(storage, "copy/y/b/c.txt").await, "hello!");
+        assert_eq!(text(storage, "x/y/b/c.txt").await, "changed");
+
+        // Deletes remove subtrees; the root can't be deleted
+        storage.delete("x").await.unwrap();
+        assert_eq!(
+            kind(storage.stat("x/y/b/c.txt").await),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(text(storage, "copy/y/b/c.txt").await, "hello!");
+        assert_eq!(kind(storage.delete("").await), io::ErrorKind::NotFound);
+        assert_eq!(kind(storage.delete("x").await), io::ErrorKind::NotFound);
+
+        // Usage leaves out the data dir at the root
+        storage
+            .write(&join(crate::REVISIONS_DIR, "copy/y/b/c.txt/1"), once("old"))
+            .await
+            .unwrap();
+        let stats = measure(storage, "").await.unwrap();
+        assert_eq!((stats.files, stats.bytes), (2, 7));
+    }
+
+    #[tokio::test]
+    async fn memory_storage_semantics() {
+        check_semantics(&MemoryStorage::default()).await;
+    }
+
+    #[tokio::test]
+    async fn blob_storage_semantics() {
+        let dir = std::env::temp_dir().join(format!("boxy-test-{}", uuid::Uuid::new_v4()));
+        check_semantics(&BlobStorage::open(&dir).unwrap()).await;
+
+        // The tree survives reopening, and only the contents still referred to are kept
+        let reopened = BlobStorage::open(&dir).unwrap();
+        assert_eq!(text(&reopened, "copy/y/b/c.txt").await, "hello!");
+        let blobs = std::fs::read_dir(dir.join(BLOB_DIR))
+            .unwrap()
+            .flatten()
+            .filter(|d| d.file_name() != "staging")
+            .flat_map(|d| std::fs::read_dir(d.path()).unwrap())
+            .count();
+        assert_eq!(blobs, 3);
+
+        // Blobs without their tree are left alone
+        std::fs::remove_file(dir.join(BLOB_TREE)).unwrap();
+        assert!(BlobStorage::open(&dir).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
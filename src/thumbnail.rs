@@ -0,0 +1,136 @@
+// === Thumbnails ===
+// Downscaled previews for the grid, cached under `.boxy/thumbnails/<path>/`.
+// Cache files are named `<mtime>-<size>.<ext>`, so an edited image never matches
+// an old entry; `invalidate` drops a path's entries when it is edited, moved or deleted.
+
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader};
+
+const THUMBNAIL_DIR: &str = ".boxy/thumbnails";
+
+/// Supported thumbnail edges in pixels; requests are rounded up to one of these
+/// so the cache holds a bounded number of variants per image.
+const SIZES: &[u32] = &[64, 128, 256, 512, 1024];
+pub const DEFAULT_SIZE: u32 = 256;
+
+const JPEG_QUALITY: u8 = 80;
+
+pub struct Thumbnail {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+pub fn is_supported(path: &Path) -> bool {
+    ImageFormat::from_path(path)
+        .map(|f| {
+            matches!(
+                f,
+                ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Gif | ImageFormat::WebP
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Round a requested edge length up to the nearest supported size.
+pub fn bucket(size: u32) -> u32 {
+    SIZES
+        .iter()
+        .copied()
+        .find(|s| *s >= size)
+        .unwrap_or(SIZES[SIZES.len() - 1])
+}
+
+fn cache_dir(upload_dir: &Path, rel_path: &str) -> PathBuf {
+    upload_dir.join(THUMBNAIL_DIR).join(rel_path)
+}
+
+/// Drop cached thumbnails for a path, or for everything below a folder.
+pub fn invalidate(upload_dir: &Path, rel_path: &str) {
+    if rel_path.is_empty() {
+        return;
+    }
+    let _ = fs::remove_dir_all(cache_dir(upload_dir, rel_path));
+}
+
+/// Thumbnail of `rel_path` fitting in `size`×`size`, from the cache or freshly rendered.
+/// `modified` is the source's mtime in seconds and part of the cache key.
+pub fn get_or_create(
+    upload_dir: &Path,
+    rel_path: &str,
+    modified: u64,
+    size: u32,
+) -> io::Result<Thumbnail> {
+    let dir = cache_dir(upload_dir, rel_path);
+    let stem = format!("{}-{}", modified, size);
+    for (ext, content_type) in [("jpg", "image/jpeg"), ("png", "image/png")] {
+        if let Ok(bytes) = fs::read(dir.join(format!("{}.{}", stem, ext))) {
+            return Ok(Thumbnail {
+                bytes,
+                content_type,
+            });
+        }
+    }
+
+    let thumbnail = render(&upload_dir.join(rel_path), size)?;
+
+    // Entries for older versions of the file are dead weight now
+    let current = format!("{}-", modified);
+    for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+        if !entry.file_name().to_string_lossy().starts_with(&current) {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+    fs::create_dir_all(&dir)?;
+    let ext = if thumbnail.content_type == "image/png" {
+        "png"
+    } else {
+        "jpg"
+    };
+    fs::write(dir.join(format!("{}.{}", stem, ext)), &thumbnail.bytes)?;
+    Ok(thumbnail)
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Decode (first frame for GIFs), apply EXIF orientation and downscale.
+/// Images with transparency become PNGs, everything else JPEG.
+fn render(path: &Path, size: u32) -> io::Result<Thumbnail> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(invalid_data)?;
+    let orientation = decoder.orientation().map_err(invalid_data)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(invalid_data)?;
+    image.apply_orientation(orientation);
+
+    // Never upscale small images
+    if image.width() > size || image.height() > size {
+        image = image.thumbnail(size, size);
+    }
+
+    let mut bytes = Vec::new();
+    if image.color().has_alpha() {
+        image
+            .write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)
+            .map_err(invalid_data)?;
+        Ok(Thumbnail {
+            bytes,
+            content_type: "image/png",
+        })
+    } else {
+        let encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY);
+        image
+            .to_rgb8()
+            .write_with_encoder(encoder)
+            .map_err(invalid_data)?;
+        Ok(Thumbnail {
+            bytes,
+            content_type: "image/jpeg",
+        })
+    }
+}
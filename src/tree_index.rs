@@ -0,0 +1,758 @@
+// === Tree Index ===
+// In-memory copy of the upload tree (names, sizes, mtimes, types) with per-folder
+// totals, so listings, search and stats don't have to walk the disk.
+// It is built by a background thread at startup and kept current by the API
+// mutation paths (`refresh`) and a filesystem watcher for changes made outside Boxy,
+// which are also reported to a callback so other indexes and clients can catch up.
+// Until the first build finishes, every query falls back to reading the disk.
+// Files also carry their media metadata once it has been extracted, which is
+// dropped along with the node whenever the file changes. Folder totals are broken
+// down by type group, and `usage` keeps each folder's largest files until something
+// below it changes.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::media::MediaInfo;
+use crate::search_filter::{type_group, TYPE_GROUPS};
+
+/// Watcher events arriving within this window are applied together.
+const WATCH_DELAY: Duration = Duration::from_millis(100);
+
+/// `TYPE_GROUPS`, then `other`.
+const GROUP_COUNT: usize = TYPE_GROUPS.len() + 1;
+/// Largest files remembered per folder for `usage`.
+const MAX_LARGEST: usize = 100;
+/// Folders whose largest files are cached at once.
+const MAX_CACHED_USAGE: usize = 256;
+
+/// A directory entry, as returned by `TreeIndex::children`.
+pub struct TreeEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: u64,
+    pub media: Option<Arc<MediaInfo>>,
+}
+
+/// Everything below a folder, not counting the folder itself.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Totals {
+    pub files: u64,
+    pub folders: u64,
+    pub bytes: u64,
+    /// Files and bytes per type group, in `GROUP_COUNT` order
+    #[serde(skip)]
+    by_type: [GroupTotals; GROUP_COUNT],
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.files += other.files;
+        self.folders += other.folders;
+        self.bytes += other.bytes;
+        for (group, other) in self.by_type.iter_mut().zip(other.by_type) {
+            group.files += other.files;
+            group.bytes += other.bytes;
+        }
+    }
+
+    fn sub(&mut self, other: Totals) {
+        self.files = self.files.saturating_sub(other.files);
+        self.folders = self.folders.saturating_sub(other.folders);
+        self.bytes = self.bytes.saturating_sub(other.bytes);
+        for (group, other) in self.by_type.iter_mut().zip(other.by_type) {
+            group.files = group.files.saturating_sub(other.files);
+            group.bytes = group.bytes.saturating_sub(other.bytes);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct GroupTotals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Index into `Totals::by_type` for a file name.
+fn group_index(name: &str) -> u8 {
+    let group = type_group(name);
+    TYPE_GROUPS
+        .iter()
+        .position(|(g, _)| *g == group)
+        .unwrap_or(TYPE_GROUPS.len()) as u8
+}
+
+fn group_name(index: usize) -> &'static str {
+    TYPE_GROUPS.get(index).map_or("other", |(g, _)| *g)
+}
+
+/// Where the space below a folder goes, as returned by `TreeIndex::usage`.
+#[derive(Serialize)]
+pub struct FolderUsage {
+    pub path: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+    /// Files directly in the folder
+    pub own_files: GroupTotals,
+    /// Child folders, largest first
+    pub folders: Vec<ChildUsage>,
+    /// Largest files anywhere below the folder, largest first
+    pub largest: Vec<LargeFile>,
+    /// Type groups by size, largest first
+    pub types: Vec<TypeUsage>,
+}
+
+#[derive(Serialize)]
+pub struct ChildUsage {
+    pub name: String,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+#[derive(Clone, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LargeFile {
+    pub size: u64,
+    pub path: String,
+    pub modified: u64,
+}
+
+#[derive(Serialize)]
+pub struct TypeUsage {
+    pub group: &'static str,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+struct Node {
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+    /// Folders only
+    totals: Totals,
+    children: BTreeMap<String, Node>,
+    media: Option<Arc<MediaInfo>>,
+    /// Files only, see `group_index`
+    group: u8,
+}
+
+impl Node {
+    fn from_meta(name: &str, meta: &fs::Metadata, file_size: FileSize) -> Self {
+        Node {
+            is_dir: meta.is_dir(),
+            size: if meta.is_file() {
+                file_size(meta.len())
+            } else {
+                meta.len()
+            },
+            modified: modified_secs(meta),
+            totals: Totals::default(),
+            children: BTreeMap::new(),
+            media: None,
+            group: group_index(name),
+        }
+    }
+
+    /// What this node adds to its parent's totals.
+    fn contribution(&self) -> Totals {
+        if self.is_dir {
+            let mut totals = self.totals;
+            totals.folders += 1;
+            totals
+        } else {
+            let mut totals = Totals {
+                files: 1,
+                folders: 0,
+                bytes: self.size,
+                ..Totals::default()
+            };
+            totals.by_type[self.group as usize] = GroupTotals {
+                files: 1,
+                bytes: self.size,
+            };
+            totals
+        }
+    }
+
+    fn entry(&self, name: &str) -> TreeEntry {
+        TreeEntry {
+            name: name.to_string(),
+            is_dir: self.is_dir,
+            size: self.size,
+            modified: self.modified,
+            media: self.media.clone(),
+        }
+    }
+}
+
+fn modified_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn is_data_path(rel_path: &str) -> bool {
+    rel_path == crate::DATA_DIR || rel_path.starts_with(&format!("{}/", crate::DATA_DIR))
+}
+
+fn segments(rel_path: &str) -> Vec<&str> {
+    rel_path.split('/').filter(|s| !s.is_empty()).collect()
+}
+
+/// Read a path and everything below it from disk. Symlinks are recorded, not followed.
+fn scan(abs_path: &Path, meta: &fs::Metadata, is_root: bool, file_size: FileSize) -> Node {
+    let name = abs_path.file_name().unwrap_or_default().to_string_lossy();
+    let mut node = Node::from_meta(&name, meta, file_size);
+    if !node.is_dir {
+        return node;
+    }
+    if let Ok(entries) = fs::read_dir(abs_path) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if is_root && name == crate::DATA_DIR {
+                continue;
+            }
+            let Ok(meta) = entry.metadata() else {
+                continue;
+            };
+            let child = scan(&entry.path(), &meta, false, file_size);
+            node.totals.add(child.contribution());
+            node.children.insert(name, child);
+        }
+    }
+    node
+}
+
+/// Replace the node at `path` (relative to `node`) with `new`, keeping the totals of
+/// every folder on the way up in step. Returns false if a parent isn't indexed.
+fn replace(node: &mut Node, path: &[&str], new: Option<Node>) -> bool {
+    let (first, rest) = match path {
+        [] => return false,
+        [first, rest @ ..] => (*first, rest),
+    };
+
+    if rest.is_empty() {
+        if let Some(old) = node.children.remove(first) {
+            node.totals.sub(old.contribution());
+        }
+        if let Some(new) = new {
+            node.totals.add(new.contribution());
+            node.children.insert(first.to_string(), new);
+        }
+        return true;
+    }
+
+    let Some(child) = node.children.get_mut(first) else {
+        return false;
+    };
+    let before = child.contribution();
+    if !replace(child, rest, new) {
+        return false;
+    }
+    let after = child.contribution();
+    node.totals.sub(before);
+    node.totals.add(after);
+    true
+}
+
+/// The `MAX_LARGEST` largest files below a folder at `prefix`, largest first.
+fn largest_files(node: &Node, prefix: &str) -> Vec<LargeFile> {
+    fn walk(node: &Node, prefix: &str, heap: &mut BinaryHeap<Reverse<LargeFile>>) {
+        for (name, child) in &node.children {
+            let path = if prefix.is_empty() {
+                name.clone()
+            } else {
+                format!("{}/{}", prefix, name)
+            };
+            if child.is_dir {
+                walk(child, &path, heap);
+                continue;
+            }
+            if heap.len() == MAX_LARGEST && heap.peek().is_some_and(|min| min.0.size >= child.size)
+            {
+                continue;
+            }
+            heap.push(Reverse(LargeFile {
+                size: child.size,
+                path,
+                modified: child.modified,
+            }));
+            if heap.len() > MAX_LARGEST {
+                heap.pop();
+            }
+        }
+    }
+    let mut heap = BinaryHeap::new();
+    walk(node, prefix, &mut heap);
+    // Ascending order of `Reverse` is largest first
+    heap.into_sorted_vec().into_iter().map(|f| f.0).collect()
+}
+
+fn folder_usage(node: &Node, path: String, largest: &[LargeFile]) -> FolderUsage {
+    let mut own_files = GroupTotals::default();
+    let mut folders = Vec::new();
+    for (name, child) in &node.children {
+        if child.is_dir {
+            folders.push(ChildUsage {
+                name: name.clone(),
+                totals: child.totals,
+            });
+        } else {
+            own_files.files += 1;
+            own_files.bytes += child.size;
+        }
+    }
+    folders.sort_by_key(|f| Reverse(f.totals.bytes));
+
+    let mut types: Vec<TypeUsage> = (node.totals.by_type.iter().enumerate())
+        .filter(|(_, group)| group.files > 0)
+        .map(|(index, group)| TypeUsage {
+            group: group_name(index),
+            files: group.files,
+            bytes: group.bytes,
+        })
+        .collect();
+    types.sort_by_key(|t| Reverse(t.bytes));
+
+    FolderUsage {
+        path,
+        totals: node.totals,
+        own_files,
+        folders,
+        largest: largest.to_vec(),
+        types,
+    }
+}
+
+fn find<'a>(node: &'a Node, path: &[&str]) -> Option<&'a Node> {
+    path.iter()
+        .try_fold(node, |node, name| node.children.get(*name))
+}
+
+fn find_mut<'a>(node: &'a mut Node, path: &[&str]) -> Option<&'a mut Node> {
+    path.iter()
+        .try_fold(node, |node, name| node.children.get_mut(*name))
+}
+
+/// What a change made outside Boxy did to a path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added { is_dir: bool },
+    Modified,
+    Removed,
+}
+
+enum Job {
+    /// Re-read a path and everything below it
+    Deep(String),
+    /// Re-read only a path's own metadata (a folder's mtime after its contents changed)
+    Shallow(String),
+}
+
+/// Maps a file's length on disk to the size the index reports.
+pub type FileSize = fn(u64) -> u64;
+
+pub struct TreeIndex {
+    upload_dir: PathBuf,
+    file_size: FileSize,
+    root: RwLock<Node>,
+    ready: AtomicBool,
+    /// Changes made while the index is still being built are applied after it
+    pending: Option<mpsc::Sender<Job>>,
+    /// `largest_files` of folders by path, dropped when anything below them changes
+    largest: Mutex<HashMap<String, Arc<Vec<LargeFile>>>>,
+}
+
+impl TreeIndex {
+    /// Create the index for `upload_dir`. When enabled, a background thread builds it
+    /// and then applies filesystem watcher events; otherwise every query reads the disk.
+    /// `on_change` gets each burst of watcher events that changed the index, i.e. those
+    /// the API hadn't already refreshed. `file_size` gives the size of a file from its
+    /// length on disk, which differs for encrypted files.
+    pub fn open(
+        upload_dir: &Path,
+        enabled: bool,
+        file_size: FileSize,
+        on_change: impl Fn(Vec<(String, Change)>) + Send + 'static,
+    ) -> Arc<Self> {
+        let (jobs_tx, jobs) = mpsc::channel();
+        let index = Arc::new(TreeIndex {
+            upload_dir: upload_dir.to_path_buf(),
+            file_size,
+            root: RwLock::new(Node {
+                is_dir: true,
+                size: 0,
+                modified: 0,
+                totals: Totals::default(),
+                children: BTreeMap::new(),
+                media: None,
+                group: 0,
+            }),
+            ready: AtomicBool::new(false),
+            pending: enabled.then(|| jobs_tx.clone()),
+            largest: Mutex::new(HashMap::new()),
+        });
+        if !enabled {
+            return index;
+        }
+
+        // Start watching before the build so nothing that changes meanwhile is missed
+        let watcher = watch(upload_dir, jobs_tx)
+            .map_err(|e| eprintln!("Tree index: filesystem watcher disabled: {}", e))
+            .ok();
+
+        let builder = index.clone();
+        let spawned = std::thread::Builder::new()
+            .name("tree-indexer".to_string())
+            .spawn(move || {
+                let _watcher = watcher;
+                builder.run(jobs, on_change)
+            });
+        if let Err(e) = spawned {
+            eprintln!("Tree index disabled: {}", e);
+        }
+        index
+    }
+
+    fn run(&self, jobs: mpsc::Receiver<Job>, on_change: impl Fn(Vec<(String, Change)>)) {
+        let started = Instant::now();
+        self.rebuild();
+        self.ready.store(true, Ordering::Release);
+        let totals = self.root.read().unwrap().totals;
+        println!(
+            "Tree index: {} files, {} folders in {:.1?}",
+            totals.files,
+            totals.folders,
+            started.elapsed()
+        );
+
+        while let Ok(first) = jobs.recv() {
+            // Collapse a burst of events (e.g. a large copy) into one pass per path
+            let mut pending: HashMap<String, bool> = HashMap::new();
+            let mut queue = |job| match job {
+                Job::Deep(path) => {
+                    pending.insert(path, true);
+                }
+                Job::Shallow(path) => {
+                    pending.entry(path).or_insert(false);
+                }
+            };
+            queue(first);
+            while let Ok(job) = jobs.recv_timeout(WATCH_DELAY) {
+                queue(job);
+            }
+
+            let mut paths: Vec<(String, bool)> = pending.into_iter().collect();
+            paths.sort();
+            let mut changes = Vec::new();
+            for (path, deep) in paths {
+                if deep {
+                    let before = self.fingerprint(&path);
+                    self.refresh(&path);
+                    let change = match (before, self.fingerprint(&path)) {
+                        (None, Some((is_dir, _, _))) => Some(Change::Added { is_dir }),
+                        (Some(_), None) => Some(Change::Removed),
+                        (Some(before), Some(after)) if before != after => Some(Change::Modified),
+                        _ => None,
+                    };
+                    // Dot-names are staging files (uploads, copies) until they're renamed
+                    let hidden = path.rsplit('/').next().is_some_and(|n| n.starts_with('.'));
+                    if let Some(change) = change.filter(|_| !hidden) {
+                        changes.push((path, change));
+                    }
+                } else {
+                    self.touch(&path);
+                }
+            }
+            if !changes.is_empty() {
+                on_change(changes);
+            }
+        }
+    }
+
+    /// Whether a path is indexed, and its size and mtime for files. A folder's own
+    /// metadata changes with its entries, which report their own events.
+    fn fingerprint(&self, rel_path: &str) -> Option<(bool, u64, u64)> {
+        let root = self.root.read().unwrap();
+        let node = find(&root, &segments(rel_path))?;
+        Some(if node.is_dir {
+            (true, 0, 0)
+        } else {
+            (false, node.size, node.modified)
+        })
+    }
+
+    fn rebuild(&self) {
+        let root = match fs::metadata(&self.upload_dir) {
+            Ok(meta) => scan(&self.upload_dir, &meta, true, self.file_size),
+            Err(_) => return,
+        };
+        *self.root.write().unwrap() = root;
+        self.largest.lock().unwrap().clear();
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Re-read a path (relative to the upload root) and everything below it, after it
+    /// was created, changed or removed. The parent folder's mtime is refreshed as well.
+    pub fn refresh(&self, rel_path: &str) {
+        if is_data_path(rel_path) {
+            return;
+        }
+        if !self.is_ready() {
+            if let Some(pending) = &self.pending {
+                let _ = pending.send(Job::Deep(rel_path.to_string()));
+            }
+            return;
+        }
+        let path = segments(rel_path);
+        if path.is_empty() {
+            self.rebuild();
+            return;
+        }
+
+        // Scan without holding the lock; the write only swaps the result in
+        let abs_path = self.upload_dir.join(rel_path);
+        let node = fs::symlink_metadata(&abs_path)
+            .ok()
+            .map(|meta| scan(&abs_path, &meta, false, self.file_size));
+
+        let replaced = replace(&mut self.root.write().unwrap(), &path, node);
+        // After the swap, so a `usage` call that read the old tree can't re-cache it
+        let changed = path.join("/");
+        self.largest
+            .lock()
+            .unwrap()
+            .retain(|folder, _| !crate::storage::is_within(&changed, folder));
+        if !replaced {
+            // A parent isn't indexed yet (created outside Boxy), index from there
+            return self.refresh(&path[..path.len() - 1].join("/"));
+        }
+        self.touch(&path[..path.len() - 1].join("/"));
+    }
+
+    /// Re-read a single folder's own size and mtime.
+    fn touch(&self, rel_path: &str) {
+        if is_data_path(rel_path) {
+            return;
+        }
+        let meta = fs::symlink_metadata(self.upload_dir.join(rel_path)).ok();
+        if let Some(meta) = meta.filter(|m| m.is_dir()) {
+            let mut root = self.root.write().unwrap();
+            if let Some(node) = find_mut(&mut root, &segments(rel_path)).filter(|n| n.is_dir) {
+                node.size = meta.len();
+                node.modified = modified_secs(&meta);
+                return;
+            }
+        }
+        // Files (whose size feeds the totals above them), removals and unindexed folders
+        self.refresh(rel_path);
+    }
+
+    /// Entries of a folder sorted by name, skipping the data directory at the root.
+    /// Empty if the folder doesn't exist.
+    pub fn children(&self, rel_path: &str) -> Vec<TreeEntry> {
+        if self.is_ready() {
+            let root = self.root.read().unwrap();
+            if let Some(node) = find(&root, &segments(rel_path)).filter(|n| n.is_dir) {
+                return node
+                    .children
+                    .iter()
+                    .map(|(name, child)| child.entry(name))
+                    .collect();
+            }
+        }
+        self.read_dir(rel_path)
+    }
+
+    fn read_dir(&self, rel_path: &str) -> Vec<TreeEntry> {
+        let is_root = segments(rel_path).is_empty();
+        let mut entries: Vec<TreeEntry> = fs::read_dir(self.upload_dir.join(rel_path))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if is_root && name == crate::DATA_DIR {
+                    return None;
+                }
+                let meta = entry.metadata().ok()?;
+                Some(Node::from_meta(&name, &meta, self.file_size).entry(&name))
+            })
+            .collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        entries
+    }
+
+    /// Media metadata cached for a file, if it was extracted from the version
+    /// modified at `modified`.
+    pub fn media(&self, rel_path: &str, modified: u64) -> Option<Arc<MediaInfo>> {
+        let root = self.root.read().unwrap();
+        find(&root, &segments(rel_path))
+            .filter(|node| node.modified == modified)?
+            .media
+            .clone()
+    }
+
+    /// Cache media metadata for a file, unless it has changed since `modified`.
+    pub fn set_media(&self, rel_path: &str, modified: u64, media: Arc<MediaInfo>) {
+        let mut root = self.root.write().unwrap();
+        if let Some(node) = find_mut(&mut root, &segments(rel_path)) {
+            if !node.is_dir && node.modified == modified {
+                node.media = Some(media);
+            }
+        }
+    }
+
+    /// Totals for a folder, or `None` if it doesn't exist.
+    pub fn totals(&self, rel_path: &str) -> Option<Totals> {
+        let path = segments(rel_path);
+        if self.is_ready() {
+            let root = self.root.read().unwrap();
+            if let Some(node) = find(&root, &path).filter(|n| n.is_dir) {
+                return Some(node.totals);
+            }
+        }
+
+        let abs_path = self.upload_dir.join(rel_path);
+        let meta = fs::metadata(&abs_path).ok().filter(|m| m.is_dir())?;
+        Some(scan(&abs_path, &meta, path.is_empty(), self.file_size).totals)
+    }
+
+    /// Where the space below a folder goes: its totals, child folders, `top` largest
+    /// files and type groups. `None` if the folder doesn't exist.
+    pub fn usage(&self, rel_path: &str, top: usize) -> Option<FolderUsage> {
+        let path = segments(rel_path);
+        let key = path.join("/");
+        let top = top.min(MAX_LARGEST);
+        if self.is_ready() {
+            let root = self.root.read().unwrap();
+            if let Some(node) = find(&root, &path).filter(|n| n.is_dir) {
+                // Cached while the tree is read-locked, so no refresh can slip in between
+                let cached = self.largest.lock().unwrap().get(&key).cloned();
+                let largest = cached.unwrap_or_else(|| {
+                    let largest = Arc::new(largest_files(node, &key));
+                    let mut cache = self.largest.lock().unwrap();
+                    if cache.len() >= MAX_CACHED_USAGE {
+                        cache.clear();
+                    }
+                    cache.insert(key.clone(), largest.clone());
+                    largest
+                });
+                let top = top.min(largest.len());
+                return Some(folder_usage(node, key, &largest[..top]));
+            }
+        }
+
+        let abs_path = self.upload_dir.join(rel_path);
+        let meta = fs::metadata(&abs_path).ok().filter(|m| m.is_dir())?;
+        let node = scan(&abs_path, &meta, path.is_empty(), self.file_size);
+        let mut largest = largest_files(&node, &key);
+        largest.truncate(top);
+        Some(folder_usage(&node, key, &largest))
+    }
+}
+
+/// Watch the upload tree recursively, turning events into index jobs.
+fn watch(upload_dir: &Path, jobs: mpsc::Sender<Job>) -> notify::Result<notify::RecommendedWatcher> {
+    use notify::{EventKind, RecursiveMode, Watcher};
+
+    // Events carry absolute paths, so match them against the canonical root
+    let root = upload_dir.canonicalize()?;
+    let prefix = root.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+        for path in &event.paths {
+            let Ok(rel) = path.strip_prefix(&prefix) else {
+                continue;
+            };
+            let rel = rel.to_string_lossy().to_string();
+            if is_data_path(&rel) {
+                continue;
+            }
+            // A folder's own mtime changes with every entry added to it; the entry
+            // reports its own event, so only the metadata needs re-reading
+            let job = match event.kind {
+                EventKind::Access(_) => continue,
+                _ if rel.is_empty() => Job::Shallow(rel),
+                EventKind::Modify(notify::event::ModifyKind::Metadata(_)) => Job::Shallow(rel),
+                _ => Job::Deep(rel),
+            };
+            let _ = jobs.send(job);
+        }
+    })?;
+    watcher.watch(&root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Waits for the next burst the watcher reports, if any arrives in time.
+    fn next(changes: &mpsc::Receiver<Vec<(String, Change)>>) -> Option<Vec<(String, Change)>> {
+        changes.recv_timeout(Duration::from_secs(2)).ok()
+    }
+
+    #[test]
+    fn watcher_reports_outside_changes_once() {
+        let dir = std::env::temp_dir().join(format!("boxy-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (tx, changes) = mpsc::channel();
+        let index = TreeIndex::open(
+            &dir,
+            true,
+            |size| size,
+            move |burst| {
+                let _ = tx.send(burst);
+            },
+        );
+        while !index.is_ready() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+        assert_eq!(
+            next(&changes).unwrap(),
+            [("a.txt".to_string(), Change::Added { is_dir: false })]
+        );
+        fs::write(dir.join("a.txt"), "hello again").unwrap();
+        assert_eq!(
+            next(&changes).unwrap(),
+            [("a.txt".to_string(), Change::Modified)]
+        );
+        fs::create_dir(dir.join("docs")).unwrap();
+        assert_eq!(
+            next(&changes).unwrap(),
+            [("docs".to_string(), Change::Added { is_dir: true })]
+        );
+        fs::remove_file(dir.join("a.txt")).unwrap();
+        assert_eq!(
+            next(&changes).unwrap(),
+            [("a.txt".to_string(), Change::Removed)]
+        );
+
+        // Changes the API already refreshed, staging files and the data dir stay quiet
+        fs::write(dir.join("docs/b.txt"), "boxy").unwrap();
+        index.refresh("docs/b.txt");
+        fs::write(dir.join(".upload-1.tmp"), "partial").unwrap();
+        fs::create_dir_all(dir.join(crate::DATA_DIR)).unwrap();
+        fs::write(dir.join(crate::DATA_DIR).join("journal"), "x").unwrap();
+        assert_eq!(next(&changes), None);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1119 @@
+// === WebDAV ===
+// Class 1 and 2 WebDAV under `/dav`, so the upload dir can be mounted as a network
+// drive from Finder, Explorer or Nautilus. Paths go through `resolve_path_safe`,
+// deletes and moves use the same trash and undo journal as the REST API, and every
+// change is reindexed and broadcast so open web UIs stay in sync. Locks are kept in
+// memory only; they are advisory for the REST API and enforced for DAV clients.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, Result};
+use futures_util::StreamExt;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use quick_xml::events::Event;
+use quick_xml::name::ResolveResult;
+use quick_xml::NsReader;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    apply_delete, broadcast_update, content_type_for, copy, copy_to_path, keep_revision,
+    move_to_trash, publish_op, quota, reindex_paths, resolve_path_safe, AppState, Applied,
+    DeleteReq, DATA_DIR,
+};
+
+pub const PREFIX: &str = "/dav";
+
+const DAV_NS: &str = "DAV:";
+const WIN32_NS: &str = "urn:schemas-microsoft-com:";
+
+/// Request bodies (PROPFIND, PROPPATCH, LOCK) are small XML documents.
+const MAX_XML_BODY: usize = 1024 * 1024;
+
+const DEFAULT_LOCK_SECS: u64 = 3600;
+const MAX_LOCK_SECS: u64 = 24 * 3600;
+
+const ALLOW: &str =
+    "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, COPY, MOVE, PROPFIND, PROPPATCH, LOCK, UNLOCK";
+
+/// Path segment characters that stay unescaped in hrefs.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+// --- Locks ---
+
+struct Lock {
+    token: String,
+    /// Locked resource, relative to the upload root
+    path: String,
+    /// Depth infinity: the lock covers everything below `path`
+    deep: bool,
+    exclusive: bool,
+    owner: Option<String>,
+    timeout: Duration,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct Locks {
+    locks: Vec<Lock>,
+}
+
+/// Whether `path` is `ancestor` or somewhere below it.
+fn is_within(path: &str, ancestor: &str) -> bool {
+    ancestor.is_empty()
+        || path == ancestor
+        || path
+            .strip_prefix(ancestor)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+impl Locks {
+    fn purge(&mut self) {
+        let now = Instant::now();
+        self.locks.retain(|lock| lock.expires > now);
+    }
+
+    /// Locks that apply to `path` itself, directly or through a deep lock on a parent.
+    fn covering<'a>(&'a self, path: &'a str) -> impl Iterator<Item = &'a Lock> {
+        self.locks
+            .iter()
+            .filter(move |lock| lock.path == path || (lock.deep && is_within(path, &lock.path)))
+    }
+
+    /// A lock on `path` (or below it, when `subtree`) whose token wasn't submitted.
+    fn blocking(&mut self, path: &str, subtree: bool, tokens: &[String]) -> Option<String> {
+        self.purge();
+        self.locks
+            .iter()
+            .filter(|lock| {
+                lock.path == path
+                    || (lock.deep && is_within(path, &lock.path))
+                    || (subtree && is_within(&lock.path, path))
+            })
+            .find(|lock| !tokens.contains(&lock.token))
+            .map(|lock| lock.path.clone())
+    }
+
+    /// Drop the locks on `path` and everything below it (after a delete or move).
+    fn remove_subtree(&mut self, path: &str) {
+        self.locks.retain(|lock| !is_within(&lock.path, path));
+    }
+}
+
+// --- Requests ---
+
+pub async fn handle(
+    req: HttpRequest,
+    body: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse> {
+    let Some(rel) = request_path(req.path()) else {
+        return Ok(HttpResponse::NotFound().finish());
+    };
+    let read_only_method = matches!(
+        req.method().as_str(),
+        "OPTIONS" | "GET" | "HEAD" | "PROPFIND"
+    );
+    if state.read_only && !read_only_method {
+        return Ok(HttpResponse::Forbidden().body("Root is read-only"));
+    }
+
+    match req.method().as_str() {
+        "OPTIONS" => Ok(HttpResponse::Ok()
+            .insert_header(("DAV", "1, 2"))
+            .insert_header(("Allow", ALLOW))
+            .insert_header(("MS-Author-Via", "DAV"))
+            .finish()),
+        "GET" | "HEAD" => get(&req, &state, &rel).await,
+        "PUT" => put(&req, body, &state, &rel).await,
+        "DELETE" => delete(&req, &state, &rel).await,
+        "MKCOL" => mkcol(&req, body, &state, &rel).await,
+        "COPY" => copy_or_move(&req, &state, &rel, false).await,
+        "MOVE" => copy_or_move(&req, &state, &rel, true).await,
+        "PROPFIND" => propfind(&req, body, &state, &rel).await,
+        "PROPPATCH" => proppatch(&req, body, &state, &rel).await,
+        "LOCK" => lock(&req, body, &state, &rel).await,
+        "UNLOCK" => unlock(&req, &state, &rel).await,
+        _ => Ok(HttpResponse::MethodNotAllowed()
+            .insert_header(("Allow", ALLOW))
+            .finish()),
+    }
+}
+
+/// Upload-root relative path of a `/dav/...` URL path, or None for `.boxy` and
+/// anything outside `/dav`.
+fn request_path(url_path: &str) -> Option<String> {
+    let rest = url_path.strip_prefix(PREFIX)?;
+    if !rest.is_empty() && !rest.starts_with('/') {
+        return None;
+    }
+    let decoded = percent_decode_str(rest).decode_utf8_lossy();
+    let segments: Vec<&str> = decoded
+        .split('/')
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect();
+    if segments.contains(&"..") || segments.first() == Some(&DATA_DIR) {
+        return None;
+    }
+    Some(segments.join("/"))
+}
+
+fn href(rel: &str, is_dir: bool) -> String {
+    let mut href = String::from(PREFIX);
+    for segment in rel.split('/').filter(|s| !s.is_empty()) {
+        href.push('/');
+        href.extend(utf8_percent_encode(segment, SEGMENT));
+    }
+    if is_dir {
+        href.push('/');
+    }
+    href
+}
+
+fn resolve(state: &AppState, rel: &str) -> Result<std::path::PathBuf> {
+    resolve_path_safe(&state.upload_dir, Some(&rel.to_string()))
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Invalid path"))
+}
+
+fn header_str<'a>(req: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    req.headers().get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Lock tokens submitted in the `If` header. Only the tokens are honoured; ETag
+/// conditions and `Not` lists are ignored.
+fn submitted_tokens(req: &HttpRequest) -> Vec<String> {
+    let Some(value) = header_str(req, "If") else {
+        return Vec::new();
+    };
+    value
+        .split('<')
+        .filter_map(|part| part.split_once('>').map(|(token, _)| token))
+        .filter(|token| token.starts_with("opaquelocktoken:"))
+        .map(str::to_string)
+        .collect()
+}
+
+/// 423 Locked unless every lock on `rel` (and below it, when `subtree`) was submitted.
+fn check_locks(req: &HttpRequest, state: &AppState, rel: &str, subtree: bool) -> Result<()> {
+    let tokens = submitted_tokens(req);
+    let blocking = state
+        .dav_locks
+        .lock()
+        .unwrap()
+        .blocking(rel, subtree, &tokens);
+    match blocking {
+        Some(locked) => {
+            let body = format!(
+                "{}<D:error xmlns:D=\"DAV:\"><D:lock-token-submitted><D:href>{}</D:href></D:lock-token-submitted></D:error>",
+                XML_HEADER,
+                escape_xml(&href(&locked, false))
+            );
+            Err(actix_web::error::InternalError::from_response(
+                "Locked",
+                HttpResponse::build(StatusCode::LOCKED)
+                    .content_type("application/xml; charset=utf-8")
+                    .body(body),
+            )
+            .into())
+        }
+        None => Ok(()),
+    }
+}
+
+async fn read_body(mut body: web::Payload) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > MAX_XML_BODY {
+            return Err(actix_web::error::ErrorPayloadTooLarge(
+                "Request body too large",
+            ));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(bytes)
+}
+
+fn mtime_secs(modified: SystemTime) -> u64 {
+    modified
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn etag(modified: u64, size: u64) -> String {
+    format!("\"{}-{}\"", modified, size)
+}
+
+// --- GET, PUT, DELETE, MKCOL ---
+
+async fn get(req: &HttpRequest, state: &AppState, rel: &str) -> Result<HttpResponse> {
+    let filepath = resolve(state, rel)?;
+    let meta = tokio::fs::metadata(&filepath)
+        .await
+        .map_err(|_| actix_web::error::ErrorNotFound("Not found"))?;
+    if meta.is_dir() {
+        return Ok(HttpResponse::MethodNotAllowed()
+            .insert_header(("Allow", ALLOW))
+            .finish());
+    }
+
+    // Same ETag as PROPFIND reports, so clients can compare the two
+    let etag = etag(mtime_secs(meta.modified()?), meta.len());
+    let file = actix_files::NamedFile::open_async(&filepath)
+        .await?
+        .use_etag(false)
+        .set_content_type(
+            content_type_for(&filepath)
+                .parse()
+                .unwrap_or(mime_guess::mime::APPLICATION_OCTET_STREAM),
+        );
+    let mut response = file.into_response(req);
+    if let Ok(value) = header::HeaderValue::from_str(&etag) {
+        response.headers_mut().insert(header::ETAG, value);
+    }
+    Ok(response)
+}
+
+async fn put(
+    req: &HttpRequest,
+    mut body: web::Payload,
+    state: &AppState,
+    rel: &str,
+) -> Result<HttpResponse> {
+    if rel.is_empty() {
+        return Ok(HttpResponse::MethodNotAllowed().finish());
+    }
+    let target = resolve(state, rel)?;
+    if target.is_dir() {
+        return Ok(HttpResponse::MethodNotAllowed().finish());
+    }
+    let parent = target
+        .parent()
+        .filter(|p| p.is_dir())
+        .ok_or_else(|| actix_web::error::ErrorConflict("Parent folder does not exist"))?;
+    check_locks(req, state, rel, false)?;
+
+    // Held to the upload limit, or to what is left of the quotas if that is less
+    let (quotas, limit, quota) =
+        quota::file_limit(state, rel, state.max_upload_bytes as u64, !target.exists()).await?;
+    let too_large = || match &quota {
+        Some(quota) => quota::exceeded(&state.root, quota),
+        None => actix_web::error::ErrorPayloadTooLarge("File too large"),
+    };
+    let declared = header_str(req, "Content-Length")
+        .or_else(|| header_str(req, "X-Expected-Entity-Length"))
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > limit) {
+        return Err(too_large());
+    }
+
+    // Stream to a staging file so a failed upload leaves the old version alone
+    let staging = parent.join(format!(".upload-{}", uuid::Uuid::new_v4()));
+    let mut file = tokio::fs::File::create(&staging).await?;
+    let mut written = 0;
+    while let Some(chunk) = body.next().await {
+        let result = match chunk {
+            Ok(data) if written + data.len() as u64 > limit => Err(too_large()),
+            Ok(data) => match quotas.claim(rel, 0, data.len() as u64) {
+                Ok(()) => {
+                    written += data.len() as u64;
+                    file.write_all(&data).await.map_err(Into::into)
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            drop(file);
+            let _ = tokio::fs::remove_file(&staging).await;
+            return Err(e);
+        }
+    }
+    file.flush().await?;
+    drop(file);
+
+    let existed = target.exists();
+    if existed {
+        keep_revision(state, &target).await?;
+    }
+    tokio::fs::rename(&staging, &target).await?;
+
+    reindex_paths(state, [target.as_path()]).await;
+    broadcast_update(&state.broadcaster, "upload", rel);
+    Ok(if existed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::Created().finish()
+    })
+}
+
+async fn delete(req: &HttpRequest, state: &AppState, rel: &str) -> Result<HttpResponse> {
+    if rel.is_empty() {
+        return Err(actix_web::error::ErrorForbidden("Cannot delete the root"));
+    }
+    if !resolve(state, rel)?.exists() {
+        return Err(actix_web::error::ErrorNotFound("Not found"));
+    }
+    check_locks(req, state, rel, true)?;
+
+    let outcome = apply_delete(
+        state,
+        &DeleteReq {
+            path: rel.to_string(),
+        },
+    )
+    .await?;
+    if let Some(applied) = outcome.applied {
+        publish_op(state, outcome.action, rel, vec![applied]).await;
+    }
+    state.dav_locks.lock().unwrap().remove_subtree(rel);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn mkcol(
+    req: &HttpRequest,
+    body: web::Payload,
+    state: &AppState,
+    rel: &str,
+) -> Result<HttpResponse> {
+    if !read_body(body).await?.is_empty() {
+        return Err(actix_web::error::ErrorUnsupportedMediaType(
+            "MKCOL request bodies are not supported",
+        ));
+    }
+    let folder = resolve(state, rel)?;
+    if rel.is_empty() || folder.exists() {
+        return Ok(HttpResponse::MethodNotAllowed()
+            .insert_header(("Allow", ALLOW))
+            .finish());
+    }
+    if !folder.parent().is_some_and(|p| p.is_dir()) {
+        return Err(actix_web::error::ErrorConflict(
+            "Parent folder does not exist",
+        ));
+    }
+    check_locks(req, state, rel, false)?;
+
+    tokio::fs::create_dir(&folder).await?;
+    reindex_paths(state, [folder.as_path()]).await;
+    broadcast_update(&state.broadcaster, "folder", rel);
+    Ok(HttpResponse::Created().finish())
+}
+
+// --- COPY, MOVE ---
+
+/// Upload-root relative path named by a `Destination` header (absolute URL or path).
+fn destination_path(req: &HttpRequest) -> Result<String> {
+    let value = header_str(req, "Destination")
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Destination header required"))?;
+    let path = match value.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+        None => value,
+    };
+    let path = path.split(['?', '#']).next().unwrap_or(path);
+    request_path(path).ok_or_else(|| {
+        actix_web::error::InternalError::from_response(
+            "Destination outside WebDAV root",
+            HttpResponse::BadGateway().finish(),
+        )
+        .into()
+    })
+}
+
+async fn copy_or_move(
+    req: &HttpRequest,
+    state: &AppState,
+    rel: &str,
+    is_move: bool,
+) -> Result<HttpResponse> {
+    let src = resolve(state, rel)?;
+    if !src.exists() {
+        return Err(actix_web::error::ErrorNotFound("Not found"));
+    }
+    let dest_rel = destination_path(req)?;
+    let dest = resolve(state, &dest_rel)?;
+
+    if rel.is_empty() || dest_rel.is_empty() || dest_rel == rel {
+        return Err(actix_web::error::ErrorForbidden(
+            "Source and destination conflict",
+        ));
+    }
+    if src.is_dir() && is_within(&dest_rel, rel) {
+        return Err(actix_web::error::ErrorForbidden(
+            "Cannot copy or move a folder into itself",
+        ));
+    }
+    // Overwriting a folder the source is in would trash the source along with it
+    if is_within(rel, &dest_rel) {
+        return Err(actix_web::error::ErrorForbidden(
+            "Cannot overwrite a folder containing the source",
+        ));
+    }
+    if !dest.parent().is_some_and(|p| p.is_dir()) {
+        return Err(actix_web::error::ErrorConflict(
+            "Destination folder does not exist",
+        ));
+    }
+    let existed = dest.exists();
+    let overwrite = !header_str(req, "Overwrite").is_some_and(|v| v.eq_ignore_ascii_case("F"));
+    if existed && !overwrite {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+    if is_move {
+        check_locks(req, state, rel, true)?;
+    }
+    check_locks(req, state, &dest_rel, true)?;
+
+    let mut changes = Vec::new();
+    if is_move {
+        // Quotas the item is already counted in don't change
+        let quotas = quota::Tracker::new(state, &dest_rel, false)
+            .await?
+            .without(rel);
+        if !quotas.is_empty() {
+            let src = src.clone();
+            let stats = web::block(move || copy::measure_tree(&src)).await??;
+            quotas.claim(&dest_rel, stats.files, stats.bytes)?;
+        }
+        let replaced = if existed {
+            move_to_trash(state, &dest).await?
+        } else {
+            None
+        };
+        if let Err(e) = tokio::fs::rename(&src, &dest).await {
+            if let Some(trash) = replaced {
+                tokio::fs::rename(&trash, &dest).await?;
+            }
+            return Err(e.into());
+        }
+        if let Some(trash) = replaced {
+            changes.push(Applied::Trashed {
+                original: dest.clone(),
+                trash,
+            });
+        }
+        changes.push(Applied::Moved {
+            from: src,
+            to: dest,
+        });
+        state.dav_locks.lock().unwrap().remove_subtree(rel);
+        publish_op(state, "move", rel, changes).await;
+    } else {
+        // Depth 0 copies a folder without its members
+        let shallow = src.is_dir() && header_str(req, "Depth") == Some("0");
+        let replaced = if shallow {
+            let replaced = if existed {
+                move_to_trash(state, &dest).await?
+            } else {
+                None
+            };
+            if let Err(e) = tokio::fs::create_dir(&dest).await {
+                if let Some(trash) = replaced {
+                    tokio::fs::rename(&trash, &dest).await?;
+                }
+                return Err(e.into());
+            }
+            replaced
+        } else {
+            copy_to_path(state, &src, &dest, &dest_rel).await?.1
+        };
+        changes.push(Applied::Created {
+            path: dest,
+            replaced,
+        });
+        publish_op(state, "copy", &dest_rel, changes).await;
+    }
+
+    Ok(if existed {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::Created().finish()
+    })
+}
+
+// --- XML ---
+
+const XML_HEADER: &str = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n";
+
+fn escape_xml(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Minimal namespace-resolved element tree of a request body.
+#[derive(Default)]
+struct Element {
+    ns: String,
+    name: String,
+    text: String,
+    children: Vec<Element>,
+}
+
+impl Element {
+    fn is(&self, ns: &str, name: &str) -> bool {
+        self.ns == ns && self.name == name
+    }
+
+    fn child(&self, ns: &str, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.is(ns, name))
+    }
+
+    /// Text of this element and everything below it.
+    fn all_text(&self) -> String {
+        let mut text = self.text.clone();
+        for child in &self.children {
+            text.push_str(&child.all_text());
+        }
+        text
+    }
+}
+
+fn parse_xml(bytes: &[u8]) -> Result<Option<Element>> {
+    let bad_request = |e: quick_xml::Error| {
+        actix_web::error::ErrorBadRequest(format!("Malformed XML body: {}", e))
+    };
+    let mut reader = NsReader::from_reader(bytes);
+    reader.config_mut().trim_text(true);
+
+    let mut stack: Vec<Element> = Vec::new();
+    let mut root = None;
+    loop {
+        let (ns, event) = reader.read_resolved_event().map_err(bad_request)?;
+        let ns = match ns {
+            ResolveResult::Bound(ns) => String::from_utf8_lossy(ns.as_ref()).into_owned(),
+            _ => String::new(),
+        };
+        let is_empty = matches!(event, Event::Empty(_));
+        match event {
+            Event::Start(e) | Event::Empty(e) => {
+                let element = Element {
+                    ns,
+                    name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(),
+                    ..Default::default()
+                };
+                if is_empty {
+                    match stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => root = Some(element),
+                    }
+                } else {
+                    stack.push(element);
+                }
+            }
+            Event::End(_) => {
+                let element = stack.pop().unwrap_or_default();
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => root = Some(element),
+                }
+            }
+            Event::Text(t) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&t.unescape().map_err(bad_request)?);
+                }
+            }
+            Event::CData(t) => {
+                if let Some(current) = stack.last_mut() {
+                    current.text.push_str(&String::from_utf8_lossy(&t));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(root)
+}
+
+fn multistatus(responses: &str) -> HttpResponse {
+    HttpResponse::build(StatusCode::MULTI_STATUS)
+        .content_type("application/xml; charset=utf-8")
+        .body(format!(
+            "{}<D:multistatus xmlns:D=\"DAV:\">{}</D:multistatus>",
+            XML_HEADER, responses
+        ))
+}
+
+fn propstat(props: &str, status: &str) -> String {
+    format!(
+        "<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 {}</D:status></D:propstat>",
+        props, status
+    )
+}
+
+/// An empty property element, in its own namespace unless it is a DAV: one.
+fn empty_prop(ns: &str, name: &str) -> String {
+    if ns == DAV_NS {
+        format!("<D:{}/>", name)
+    } else {
+        format!("<x:{} xmlns:x=\"{}\"/>", name, escape_xml(ns))
+    }
+}
+
+// --- PROPFIND, PROPPATCH ---
+
+struct Resource {
+    rel: String,
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+/// Live properties we report for every resource.
+const LIVE_PROPS: &[&str] = &[
+    "displayname",
+    "resourcetype",
+    "getcontentlength",
+    "getcontenttype",
+    "getetag",
+    "getlastmodified",
+    "supportedlock",
+    "lockdiscovery",
+];
+
+fn live_prop(resource: &Resource, name: &str, locks: &Locks) -> Option<String> {
+    let file_only = |value: String| (!resource.is_dir).then_some(value);
+    match name {
+        "displayname" => Some(format!(
+            "<D:displayname>{}</D:displayname>",
+            escape_xml(&resource.name)
+        )),
+        "resourcetype" if resource.is_dir => {
+            Some("<D:resourcetype><D:collection/></D:resourcetype>".to_string())
+        }
+        "resourcetype" => Some("<D:resourcetype/>".to_string()),
+        "getcontentlength" => file_only(format!(
+            "<D:getcontentlength>{}</D:getcontentlength>",
+            resource.size
+        )),
+        "getcontenttype" => file_only(format!(
+            "<D:getcontenttype>{}</D:getcontenttype>",
+            escape_xml(&content_type_for(std::path::Path::new(&resource.name)))
+        )),
+        "getetag" => file_only(format!(
+            "<D:getetag>{}</D:getetag>",
+            escape_xml(&etag(resource.modified, resource.size))
+        )),
+        "getlastmodified" => {
+            let time = std::time::UNIX_EPOCH + Duration::from_secs(resource.modified);
+            Some(format!(
+                "<D:getlastmodified>{}</D:getlastmodified>",
+                header::HttpDate::from(time)
+            ))
+        }
+        "supportedlock" => Some(
+            "<D:supportedlock>\
+             <D:lockentry><D:lockscope><D:exclusive/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+             <D:lockentry><D:lockscope><D:shared/></D:lockscope><D:locktype><D:write/></D:locktype></D:lockentry>\
+             </D:supportedlock>"
+                .to_string(),
+        ),
+        "lockdiscovery" => Some(lock_discovery(locks.covering(&resource.rel))),
+        _ => None,
+    }
+}
+
+enum PropRequest {
+    All,
+    Names,
+    Some(Vec<(String, String)>),
+}
+
+fn prop_request(body: &[u8]) -> Result<PropRequest> {
+    let Some(root) = parse_xml(body)? else {
+        return Ok(PropRequest::All);
+    };
+    if !root.is(DAV_NS, "propfind") {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Expected a propfind body",
+        ));
+    }
+    if root.child(DAV_NS, "propname").is_some() {
+        return Ok(PropRequest::Names);
+    }
+    match root.child(DAV_NS, "prop") {
+        Some(prop) => Ok(PropRequest::Some(
+            prop.children
+                .iter()
+                .map(|p| (p.ns.clone(), p.name.clone()))
+                .collect(),
+        )),
+        None => Ok(PropRequest::All),
+    }
+}
+
+fn prop_response(resource: &Resource, request: &PropRequest, locks: &Locks) -> String {
+    let mut found = String::new();
+    let mut missing = String::new();
+    match request {
+        PropRequest::All => {
+            for name in LIVE_PROPS {
+                if let Some(value) = live_prop(resource, name, locks) {
+                    found.push_str(&value);
+                }
+            }
+        }
+        PropRequest::Names => {
+            for name in LIVE_PROPS {
+                if live_prop(resource, name, locks).is_some() {
+                    found.push_str(&empty_prop(DAV_NS, name));
+                }
+            }
+        }
+        PropRequest::Some(names) => {
+            for (ns, name) in names {
+                let value = (ns == DAV_NS)
+                    .then(|| live_prop(resource, name, locks))
+                    .flatten();
+                match value {
+                    Some(value) => found.push_str(&value),
+                    None => missing.push_str(&empty_prop(ns, name)),
+                }
+            }
+        }
+    }
+
+    let mut response = format!(
+        "<D:response><D:href>{}</D:href>",
+        escape_xml(&href(&resource.rel, resource.is_dir))
+    );
+    if !found.is_empty() {
+        response.push_str(&propstat(&found, "200 OK"));
+    }
+    if !missing.is_empty() {
+        response.push_str(&propstat(&missing, "404 Not Found"));
+    }
+    response.push_str("</D:response>");
+    response
+}
+
+async fn propfind(
+    req: &HttpRequest,
+    body: web::Payload,
+    state: &AppState,
+    rel: &str,
+) -> Result<HttpResponse> {
+    let request = prop_request(&read_body(body).await?)?;
+    let filepath = resolve(state, rel)?;
+    let meta = tokio::fs::metadata(&filepath)
+        .await
+        .map_err(|_| actix_web::error::ErrorNotFound("Not found"))?;
+
+    // Listing a whole tree in one response is refused (RFC 4918 9.1). Clients that
+    // leave out Depth get one level, which is what they want in practice.
+    let depth = header_str(req, "Depth").unwrap_or("1");
+    if depth.eq_ignore_ascii_case("infinity") {
+        let body = format!(
+            "{}<D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>",
+            XML_HEADER
+        );
+        return Ok(HttpResponse::Forbidden()
+            .content_type("application/xml; charset=utf-8")
+            .body(body));
+    }
+
+    let mut resources = vec![Resource {
+        rel: rel.to_string(),
+        name: rel.rsplit('/').next().unwrap_or_default().to_string(),
+        is_dir: meta.is_dir(),
+        size: meta.len(),
+        modified: mtime_secs(meta.modified()?),
+    }];
+    if meta.is_dir() && depth == "1" {
+        let tree = state.tree.clone();
+        let dir = rel.to_string();
+        let children = web::block(move || tree.children(&dir)).await?;
+        resources.extend(children.into_iter().map(|child| Resource {
+            rel: if rel.is_empty() {
+                child.name.clone()
+            } else {
+                format!("{}/{}", rel, child.name)
+            },
+            name: child.name,
+            is_dir: child.is_dir,
+            size: child.size,
+            modified: child.modified,
+        }));
+    }
+
+    let mut locks = state.dav_locks.lock().unwrap();
+    locks.purge();
+    let responses: String = resources
+        .iter()
+        .map(|resource| prop_response(resource, &request, &locks))
+        .collect();
+    Ok(multistatus(&responses))
+}
+
+/// Only modification times can be set (`getlastmodified`, or Windows'
+/// `Win32LastModifiedTime`). The other Win32 timestamps and attributes Explorer sends
+/// are accepted and dropped; anything else is refused, as dead properties aren't stored.
+async fn proppatch(
+    req: &HttpRequest,
+    body: web::Payload,
+    state: &AppState,
+    rel: &str,
+) -> Result<HttpResponse> {
+    let filepath = resolve(state, rel)?;
+    if !filepath.exists() {
+        return Err(actix_web::error::ErrorNotFound("Not found"));
+    }
+    check_locks(req, state, rel, false)?;
+
+    let root = parse_xml(&read_body(body).await?)?
+        .filter(|root| root.is(DAV_NS, "propertyupdate"))
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Expected a propertyupdate body"))?;
+
+    let mut accepted = String::new();
+    let mut refused = String::new();
+    let mut mtime = None;
+    for action in &root.children {
+        let is_set = action.is(DAV_NS, "set");
+        for prop in action
+            .child(DAV_NS, "prop")
+            .map(|p| p.children.as_slice())
+            .unwrap_or_default()
+        {
+            let settable_time = (prop.is(DAV_NS, "getlastmodified")
+                || prop.is(WIN32_NS, "Win32LastModifiedTime"))
+                && is_set;
+            let ignored = prop.ns == WIN32_NS && prop.name.starts_with("Win32");
+            if settable_time {
+                let value = prop.all_text();
+                let time = value
+                    .trim()
+                    .parse::<header::HttpDate>()
+                    .map_err(|_| actix_web::error::ErrorBadRequest("Invalid date"))?;
+                mtime = Some(SystemTime::from(time));
+            }
+            if settable_time || ignored {
+                accepted.push_str(&empty_prop(&prop.ns, &prop.name));
+            } else {
+                refused.push_str(&empty_prop(&prop.ns, &prop.name));
+            }
+        }
+    }
+
+    // All or nothing: one refused property fails the others with 424
+    let mut response = format!(
+        "<D:response><D:href>{}</D:href>",
+        escape_xml(&href(rel, filepath.is_dir()))
+    );
+    if refused.is_empty() {
+        if let Some(time) = mtime {
+            filetime::set_file_mtime(&filepath, filetime::FileTime::from_system_time(time))?;
+            reindex_paths(state, [filepath.as_path()]).await;
+            broadcast_update(&state.broadcaster, "edit", rel);
+        }
+        if !accepted.is_empty() {
+            response.push_str(&propstat(&accepted, "200 OK"));
+        }
+    } else {
+        response.push_str(&propstat(&refused, "403 Forbidden"));
+        if !accepted.is_empty() {
+            response.push_str(&propstat(&accepted, "424 Failed Dependency"));
+        }
+    }
+    response.push_str("</D:response>");
+    Ok(multistatus(&response))
+}
+
+// --- LOCK, UNLOCK ---
+
+fn lock_discovery<'a>(locks: impl Iterator<Item = &'a Lock>) -> String {
+    let mut xml = String::from("<D:lockdiscovery>");
+    for lock in locks {
+        xml.push_str(&format!(
+            "<D:activelock><D:locktype><D:write/></D:locktype><D:lockscope>{}</D:lockscope>\
+             <D:depth>{}</D:depth>{}<D:timeout>Second-{}</D:timeout>\
+             <D:locktoken><D:href>{}</D:href></D:locktoken>\
+             <D:lockroot><D:href>{}</D:href></D:lockroot></D:activelock>",
+            if lock.exclusive {
+                "<D:exclusive/>"
+            } else {
+                "<D:shared/>"
+            },
+            if lock.deep { "infinity" } else { "0" },
+            lock.owner
+                .as_ref()
+                .map(|owner| format!("<D:owner><D:href>{}</D:href></D:owner>", escape_xml(owner)))
+                .unwrap_or_default(),
+            lock.expires
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+            escape_xml(&lock.token),
+            escape_xml(&href(&lock.path, false)),
+        ));
+    }
+    xml.push_str("</D:lockdiscovery>");
+    xml
+}
+
+fn lock_response(status: StatusCode, token: &str, discovery: String) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type("application/xml; charset=utf-8")
+        .insert_header(("Lock-Token", format!("<{}>", token)))
+        .body(format!(
+            "{}<D:prop xmlns:D=\"DAV:\">{}</D:prop>",
+            XML_HEADER, discovery
+        ))
+}
+
+/// Requested lock lifetime from a `Timeout` header (`Second-600`, `Infinite`), capped.
+fn lock_timeout(req: &HttpRequest) -> Duration {
+    let requested = header_str(req, "Timeout").and_then(|value| {
+        value.split(',').map(str::trim).find_map(|t| {
+            if t.eq_ignore_ascii_case("infinite") {
+                Some(MAX_LOCK_SECS)
+            } else {
+                t.strip_prefix("Second-")?.parse().ok()
+            }
+        })
+    });
+    Duration::from_secs(requested.unwrap_or(DEFAULT_LOCK_SECS).min(MAX_LOCK_SECS))
+}
+
+async fn lock(
+    req: &HttpRequest,
+    body: web::Payload,
+    state: &AppState,
+    rel: &str,
+) -> Result<HttpResponse> {
+    let filepath = resolve(state, rel)?;
+    let timeout = lock_timeout(req);
+    let info = parse_xml(&read_body(body).await?)?;
+
+    // No body: refresh the lock named in the If header
+    let Some(info) = info else {
+        let tokens = submitted_tokens(req);
+        let mut locks = state.dav_locks.lock().unwrap();
+        locks.purge();
+        let Some(lock) = locks.locks.iter_mut().find(|l| {
+            tokens.contains(&l.token) && (l.path == rel || (l.deep && is_within(rel, &l.path)))
+        }) else {
+            return Err(actix_web::error::ErrorPreconditionFailed(
+                "No matching lock to refresh",
+            ));
+        };
+        lock.timeout = timeout;
+        lock.expires = Instant::now() + timeout;
+        let token = lock.token.clone();
+        let discovery = lock_discovery(locks.locks.iter().filter(|l| l.token == token));
+        return Ok(lock_response(StatusCode::OK, &token, discovery));
+    };
+
+    if !info.is(DAV_NS, "lockinfo") {
+        return Err(actix_web::error::ErrorBadRequest(
+            "Expected a lockinfo body",
+        ));
+    }
+    let exclusive = info
+        .child(DAV_NS, "lockscope")
+        .is_none_or(|scope| scope.child(DAV_NS, "shared").is_none());
+    let owner = info
+        .child(DAV_NS, "owner")
+        .map(|owner| owner.all_text().trim().to_string())
+        .filter(|owner| !owner.is_empty());
+    let deep = match header_str(req, "Depth") {
+        None => true,
+        Some(d) if d.eq_ignore_ascii_case("infinity") => true,
+        Some("0") => false,
+        Some(_) => {
+            return Err(actix_web::error::ErrorBadRequest(
+                "Depth must be 0 or infinity",
+            ))
+        }
+    };
+
+    let token = format!("opaquelocktoken:{}", uuid::Uuid::new_v4());
+    {
+        let mut locks = state.dav_locks.lock().unwrap();
+        locks.purge();
+        let conflict = locks.locks.iter().any(|l| {
+            let overlaps = l.path == rel
+                || (l.deep && is_within(rel, &l.path))
+                || (deep && is_within(&l.path, rel));
+            overlaps && (exclusive || l.exclusive)
+        });
+        if conflict {
+            return Ok(HttpResponse::build(StatusCode::LOCKED).finish());
+        }
+        locks.locks.push(Lock {
+            token: token.clone(),
+            path: rel.to_string(),
+            deep,
+            exclusive,
+            owner,
+            timeout,
+            expires: Instant::now() + timeout,
+        });
+    }
+
+    // Locking an unmapped URL creates an empty file there
+    let mut status = StatusCode::OK;
+    if !filepath.exists() {
+        let created = match filepath.parent() {
+            Some(parent) if parent.is_dir() && !rel.is_empty() => {
+                tokio::fs::write(&filepath, b"").await
+            }
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "Parent folder does not exist",
+            )),
+        };
+        if let Err(e) = created {
+            state
+                .dav_locks
+                .lock()
+                .unwrap()
+                .locks
+                .retain(|l| l.token != token);
+            return Err(actix_web::error::ErrorConflict(e.to_string()));
+        }
+        reindex_paths(state, [filepath.as_path()]).await;
+        broadcast_update(&state.broadcaster, "upload", rel);
+        status = StatusCode::CREATED;
+    }
+
+    let locks = state.dav_locks.lock().unwrap();
+    let discovery = lock_discovery(locks.locks.iter().filter(|l| l.token == token));
+    Ok(lock_response(status, &token, discovery))
+}
+
+async fn unlock(req: &HttpRequest, state: &AppState, rel: &str) -> Result<HttpResponse> {
+    let token = header_str(req, "Lock-Token")
+        .map(|t| {
+            t.trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string()
+        })
+        .ok_or_else(|| actix_web::error::ErrorBadRequest("Lock-Token header required"))?;
+
+    let mut locks = state.dav_locks.lock().unwrap();
+    locks.purge();
+    let before = locks.locks.len();
+    locks
+        .locks
+        .retain(|l| !(l.token == token && (l.path == rel || (l.deep && is_within(rel, &l.path)))));
+    if locks.locks.len() == before {
+        return Err(actix_web::error::ErrorConflict(
+            "Lock token does not match this resource",
+        ));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}